@@ -36,6 +36,13 @@ fn kmeans(b: &mut Bencher) {
     b.iter(_unstable_internal_kmeans_bench())
 }
 
+/// Nearest color search used by remapping. Palette colors in leaves of the search tree are stored as planes of r/g/b/a,
+/// which lets distances be computed 4 at a time (about 20% faster than checking one color at a time).
+#[bench]
+fn nearest(b: &mut Bencher) {
+    b.iter(_unstable_internal_nearest_bench())
+}
+
 #[bench]
 fn remap_floyd(b: &mut Bencher) {
     let img = lodepng::decode32_file("/Users/kornel/Desktop/canvas.png").unwrap();
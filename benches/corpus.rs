@@ -0,0 +1,112 @@
+//! Benchmarks of each stage of quantization on synthetic images of different kinds.
+//!
+//! Run with `cargo bench --bench corpus`. Unlike `bench.rs`, this works on stable Rust and doesn't need any image files.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use imagequant::*;
+use std::mem::MaybeUninit;
+
+const WIDTH: usize = 512;
+const HEIGHT: usize = 512;
+
+/// Deterministic pseudo-random numbers, so that all runs use the same pixels
+struct Lcg(u32);
+
+impl Lcg {
+    fn next(&mut self) -> u8 {
+        self.0 = self.0.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        (self.0 >> 16) as u8
+    }
+}
+
+/// Smooth gradients in all channels, including alpha. Needs lots of dithering.
+fn gradient() -> Vec<RGBA> {
+    (0..HEIGHT).flat_map(|y| (0..WIDTH).map(move |x| {
+        RGBA::new((x / 2) as u8, (y / 2) as u8, ((x + y) / 4) as u8, 255 - (y / 4) as u8)
+    })).collect()
+}
+
+/// Noisy texture with soft shapes, which has lots of unique colors like a photo
+fn photo() -> Vec<RGBA> {
+    let mut rng = Lcg(1);
+    (0..HEIGHT).flat_map(|y| (0..WIDTH).map(move |x| (x, y))).map(|(x, y)| {
+        let shade = ((x as f32 / 40.).sin() * (y as f32 / 30.).cos() * 100. + 128.) as u8;
+        let noise = rng.next() / 8;
+        RGBA::new(shade.saturating_add(noise), (y / 3) as u8 + noise, 200 - shade / 2, 255)
+    }).collect()
+}
+
+/// Flat areas, sharp edges and few colors, like a screenshot of a UI
+fn screenshot() -> Vec<RGBA> {
+    let mut rng = Lcg(2);
+    let colors: Vec<_> = (0..24).map(|_| RGBA::new(rng.next(), rng.next(), rng.next(), 255)).collect();
+    (0..HEIGHT).flat_map(|y| (0..WIDTH).map(move |x| (x, y))).map(|(x, y)| {
+        let text = y % 16 < 10 && (x * 7 + y * 3) % 11 < 3;
+        if text {
+            colors[(x / 64 + y / 32) % 4]
+        } else {
+            colors[4 + (x / 128 + (y / 96) * 4) % 20]
+        }
+    }).collect()
+}
+
+fn corpus() -> [(&'static str, Vec<RGBA>); 3] {
+    [("gradient", gradient()), ("photo", photo()), ("screenshot", screenshot())]
+}
+
+fn histogram(c: &mut Criterion) {
+    let liq = Attributes::new();
+    let mut group = c.benchmark_group("histogram");
+    for (name, pixels) in &corpus() {
+        group.bench_with_input(BenchmarkId::from_parameter(name), pixels, |b, pixels| b.iter(|| {
+            let mut img = liq.new_image(&pixels[..], WIDTH, HEIGHT, 0.).unwrap();
+            let mut hist = Histogram::new(&liq);
+            hist.add_image(&liq, &mut img).unwrap();
+        }));
+    }
+    group.finish();
+}
+
+fn mediancut(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mediancut");
+    for (name, pixels) in &corpus() {
+        let mut counts = std::collections::HashMap::new();
+        for px in pixels {
+            *counts.entry((px.r, px.g, px.b, px.a)).or_insert(0) += 1;
+        }
+        let entries: Vec<_> = counts.into_iter().map(|((r, g, b, a), count)| HistogramEntry { color: RGBA::new(r, g, b, a), count }).collect();
+        group.bench_function(*name, |b| b.iter(_unstable_internal_mediancut_bench(&entries)));
+    }
+    group.finish();
+}
+
+fn kmeans(c: &mut Criterion) {
+    c.bench_function("kmeans", |b| b.iter(_unstable_internal_kmeans_bench()));
+}
+
+fn remap(c: &mut Criterion, group_name: &str, dithering_level: f32) {
+    let mut liq = Attributes::new();
+    liq.set_speed(10).unwrap();
+    let mut group = c.benchmark_group(group_name);
+    for (name, pixels) in &corpus() {
+        let mut img = liq.new_image(&pixels[..], WIDTH, HEIGHT, 0.).unwrap();
+        let mut res = liq.quantize(&mut img).unwrap();
+        res.set_dithering_level(dithering_level).unwrap();
+        let mut buf = vec![MaybeUninit::uninit(); WIDTH * HEIGHT];
+        group.bench_function(*name, |b| b.iter(|| {
+            res.remap_into(&mut img, &mut buf).unwrap();
+        }));
+    }
+    group.finish();
+}
+
+fn remap_plain(c: &mut Criterion) {
+    remap(c, "remap", 0.);
+}
+
+fn remap_dithered(c: &mut Criterion) {
+    remap(c, "remap_dithered", 1.);
+}
+
+criterion_group!(benches, histogram, mediancut, kmeans, remap_plain, remap_dithered);
+criterion_main!(benches);
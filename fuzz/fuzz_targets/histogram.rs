@@ -0,0 +1,33 @@
+#![no_main]
+//! Arbitrary histogram entries, fixed colors and settings
+
+use imagequant::{Attributes, Histogram, HistogramEntry, RGBA};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let (settings, data) = match data.split_first_chunk::<4>() {
+        Some(s) => s,
+        None => return,
+    };
+    let [max_colors, speed, fixed, gamma] = *settings;
+
+    let mut attr = Attributes::new();
+    let _ = attr.set_max_colors(max_colors.into());
+    let _ = attr.set_speed(speed.into());
+
+    let mut hist = Histogram::new(&attr);
+    let (fixed, entries) = data.split_at((usize::from(fixed) * 4).min(data.len()));
+    for c in fixed.chunks_exact(4) {
+        let _ = hist.add_fixed_color(RGBA::new(c[0], c[1], c[2], c[3]), 0.);
+    }
+    let entries: Vec<_> = entries.chunks_exact(6).map(|c| HistogramEntry {
+        color: RGBA::new(c[0], c[1], c[2], c[3]),
+        count: u16::from_le_bytes([c[4], c[5]]).into(),
+    }).collect();
+    let _ = hist.add_colors(&entries, f64::from(gamma) / 255.);
+
+    if let Ok(mut res) = hist.quantize(&attr) {
+        let _ = res.palette_vec();
+        let _ = res.quantization_quality();
+    }
+});
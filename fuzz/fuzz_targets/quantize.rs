@@ -0,0 +1,27 @@
+#![no_main]
+//! Arbitrary dimensions, pixels and quality settings
+
+use imagequant::{Attributes, RGBA};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let (settings, data) = match data.split_first_chunk::<6>() {
+        Some(s) => s,
+        None => return,
+    };
+    let [width, height, min_quality, max_quality, speed, posterize] = *settings;
+
+    let mut attr = Attributes::new();
+    let _ = attr.set_quality(min_quality, max_quality);
+    let _ = attr.set_speed(speed.into());
+    let _ = attr.set_min_posterization(posterize);
+
+    let pixels: Vec<RGBA> = data.chunks_exact(4).map(|c| RGBA::new(c[0], c[1], c[2], c[3])).collect();
+    let mut img = match attr.new_image(&pixels[..], width.into(), height.into(), 0.) {
+        Ok(img) => img,
+        Err(_) => return,
+    };
+    if let Ok(mut res) = attr.quantize(&mut img) {
+        let _ = res.palette_vec();
+    }
+});
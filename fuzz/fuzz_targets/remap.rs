@@ -0,0 +1,38 @@
+#![no_main]
+//! Arbitrary dimensions, strides and dithering of remapped images
+
+use imagequant::{Attributes, RGBA};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let (settings, data) = match data.split_first_chunk::<5>() {
+        Some(s) => s,
+        None => return,
+    };
+    let [width, height, stride_extra, dither, max_colors] = *settings;
+    let (width, height) = (usize::from(width), usize::from(height));
+
+    let mut attr = Attributes::new();
+    let _ = attr.set_max_colors(max_colors.into());
+    let _ = attr.set_speed(10);
+
+    let pixels: Vec<RGBA> = data.chunks_exact(4).map(|c| RGBA::new(c[0], c[1], c[2], c[3])).collect();
+    let stride = width + usize::from(stride_extra);
+    let mut img = match attr.new_image_stride(&pixels[..], width, height, stride, 0.) {
+        Ok(img) => img,
+        Err(_) => return,
+    };
+    let mut res = match attr.quantize(&mut img) {
+        Ok(res) => res,
+        Err(_) => return,
+    };
+    let _ = res.set_dithering_level(f32::from(dither) / 255.);
+    let _ = res.remapped(&mut img);
+
+    // the remapped image is still usable for a second remapping with the same palette
+    let mut img = match attr.new_image_stride(&pixels[..], width, height, stride, 0.) {
+        Ok(img) => img,
+        Err(_) => return,
+    };
+    let _ = res.remapped(&mut img);
+});
@@ -78,6 +78,7 @@ LIQ_EXPORT void liq_attr_destroy(liq_attr *attr) LIQ_NONNULL;
 
 LIQ_EXPORT LIQ_USERESULT liq_histogram* liq_histogram_create(const liq_attr* attr);
 LIQ_EXPORT liq_error liq_histogram_add_image(liq_histogram *hist, const liq_attr *attr, liq_image* image) LIQ_NONNULL;
+LIQ_EXPORT liq_error liq_histogram_add_image_weighted(liq_histogram *hist, const liq_attr *attr, liq_image* image, float weight) LIQ_NONNULL;
 LIQ_EXPORT liq_error liq_histogram_add_colors(liq_histogram *hist, const liq_attr *attr, const liq_histogram_entry entries[], int num_entries, double gamma) LIQ_NONNULL;
 LIQ_EXPORT liq_error liq_histogram_add_fixed_color(liq_histogram *hist, liq_color color, double gamma) LIQ_NONNULL;
 LIQ_EXPORT void liq_histogram_destroy(liq_histogram *hist) LIQ_NONNULL;
@@ -94,6 +95,14 @@ LIQ_EXPORT liq_error liq_set_quality(liq_attr* attr, int minimum, int maximum) L
 LIQ_EXPORT LIQ_USERESULT int liq_get_min_quality(const liq_attr* attr) LIQ_NONNULL;
 LIQ_EXPORT LIQ_USERESULT int liq_get_max_quality(const liq_attr* attr) LIQ_NONNULL;
 LIQ_EXPORT void liq_set_last_index_transparent(liq_attr* attr, int is_last) LIQ_NONNULL;
+LIQ_EXPORT liq_error liq_set_max_pixel_error(liq_attr* attr, double max_error) LIQ_NONNULL;
+LIQ_EXPORT LIQ_USERESULT double liq_get_max_pixel_error(const liq_attr* attr) LIQ_NONNULL;
+LIQ_EXPORT liq_error liq_set_duotone(liq_attr* attr, liq_color dark, liq_color light) LIQ_NONNULL;
+LIQ_EXPORT liq_error liq_set_screenshot_mode(liq_attr* attr, int enabled) LIQ_NONNULL;
+LIQ_EXPORT void liq_set_preserve_extremes(liq_attr* attr, int enabled) LIQ_NONNULL;
+LIQ_EXPORT void liq_set_auto_levels(liq_attr* attr, int enabled) LIQ_NONNULL;
+LIQ_EXPORT liq_error liq_set_alpha_levels(liq_attr* attr, unsigned int levels) LIQ_NONNULL;
+LIQ_EXPORT liq_error liq_set_histogram_precision(liq_attr* attr, int bits) LIQ_NONNULL;
 
 typedef void liq_log_callback_function(const liq_attr*, const char *message, void* user_info);
 typedef void liq_log_flush_callback_function(const liq_attr*, void* user_info);
@@ -110,6 +119,11 @@ LIQ_EXPORT LIQ_USERESULT liq_image *liq_image_create_rgba(const liq_attr *attr,
 
 typedef void liq_image_get_rgba_row_callback(liq_color row_out[], int row, int width, void* user_info);
 LIQ_EXPORT LIQ_USERESULT liq_image *liq_image_create_custom(const liq_attr *attr, liq_image_get_rgba_row_callback *row_callback, void* user_info, int width, int height, double gamma);
+/* free_callback (may be NULL) is called with user data when the image doesn't need it any more. If the image can't be created, the caller keeps the user data. */
+typedef void liq_image_user_info_free_callback(void* user_info);
+LIQ_EXPORT LIQ_USERESULT liq_image *liq_image_create_custom_with_free(const liq_attr *attr, liq_image_get_rgba_row_callback *row_callback, void* user_info, liq_image_user_info_free_callback *free_callback, int width, int height, double gamma);
+/* row_user_info is an array of height pointers, one passed to the callback for each row */
+LIQ_EXPORT LIQ_USERESULT liq_image *liq_image_create_custom_rows(const liq_attr *attr, liq_image_get_rgba_row_callback *row_callback, void *const row_user_info[], liq_image_user_info_free_callback *free_callback, int width, int height, double gamma);
 
 LIQ_EXPORT liq_error liq_image_set_memory_ownership(liq_image *image, int ownership_flags) LIQ_NONNULL;
 LIQ_EXPORT liq_error liq_image_set_background(liq_image *img, liq_image *background_image) LIQ_NONNULL;
@@ -135,6 +149,7 @@ LIQ_EXPORT double liq_get_quantization_error(const liq_result *result) LIQ_NONNU
 LIQ_EXPORT int liq_get_quantization_quality(const liq_result *result) LIQ_NONNULL;
 LIQ_EXPORT double liq_get_remapping_error(const liq_result *result) LIQ_NONNULL;
 LIQ_EXPORT int liq_get_remapping_quality(const liq_result *result) LIQ_NONNULL;
+LIQ_EXPORT int liq_get_transparent_index(const liq_result *result) LIQ_NONNULL;
 
 LIQ_EXPORT void liq_result_destroy(liq_result *) LIQ_NONNULL;
 
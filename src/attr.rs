@@ -1,29 +1,56 @@
-use crate::error::{liq_error, LIQ_OK, LIQ_VALUE_OUT_OF_RANGE};
-use crate::ffi::MagicTag;
-use crate::ffi::LIQ_ATTR_MAGIC;
-use crate::ffi::LIQ_FREED_MAGIC;
+use crate::constraint::PaletteConstraint;
+use crate::cvd::ColorVisionDeficiency;
+use crate::error::{liq_error, QuantizeError, LIQ_OK, LIQ_UNSUPPORTED, LIQ_VALUE_OUT_OF_RANGE};
+use crate::extended::ExtendedQuantizationResult;
+#[cfg(feature = "capi")]
+use crate::ffi::{MagicTag, LIQ_ATTR_MAGIC, LIQ_FREED_MAGIC};
 use crate::hist::Histogram;
 use crate::image::Image;
-use crate::pal::PalLen;
-use crate::pal::RGBA;
+use crate::labels::LabelFallback;
+use crate::pal::{PalIndex, PalLen, MAX_COLORS};
+use crate::pal::{f_pixel, gamma_lut, RGBA};
 use crate::quant::{mse_to_quality, quality_to_mse, QuantizationResult};
-use crate::remap::DitherMapMode;
+use crate::remap::{mse_to_standard_mse, standard_mse_to_mse, DitherMapMode};
+use std::borrow::Cow;
+use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::sync::Arc;
 
+/// Receives log messages, see `Attributes::set_log_callback`
+pub type LogCallback = dyn Fn(&Attributes, &str) + Send + Sync;
+
+/// Receives log events, see `Attributes::set_event_callback`
+pub(crate) type EventCallback = dyn Fn(&Attributes, &LogEvent) + Send + Sync;
+
 #[derive(Clone)]
 pub struct Attributes {
+    #[cfg(feature = "capi")]
     pub(crate) magic_header: MagicTag,
     pub(crate) max_colors: PalLen,
     target_mse: f64,
     max_mse: Option<f64>,
+    pub(crate) max_pixel_error: Option<f64>,
+    pub(crate) min_entry_population: f32,
+    /// In internal MSE units, used only if `preserve_rare_colors` is enabled
+    pub(crate) rare_color_distinctness: f32,
+    pub(crate) duotone: Option<(RGBA, RGBA)>,
+    pub(crate) label_mode: Option<LabelFallback>,
     kmeans_iteration_limit: f64,
     kmeans_iterations: u16,
     feedback_loop_trials: u16,
     pub(crate) max_histogram_entries: u32,
     min_posterization_output: u8,
     min_posterization_input: u8,
-    pub(crate) last_index_transparent: bool,
+    /// Bits per channel kept in the histogram, 0 if it's automatic
+    histogram_precision: u8,
+    pub(crate) alpha_levels: u8,
+    pub(crate) transparent_placement: TransparentPlacement,
+    pub(crate) overshoot_strategy: OvershootStrategy,
+    pub(crate) refinement: Refinement,
     pub(crate) use_contrast_maps: bool,
+    flags: AttrFlags,
+    pub(crate) color_vision_deficiency: Option<ColorVisionDeficiency>,
+    pub(crate) palette_constraint: Option<PaletteConstraint>,
     pub(crate) use_dither_map: DitherMapMode,
     speed: u8,
     pub(crate) progress_stage1: u8,
@@ -31,8 +58,25 @@ pub struct Attributes {
     pub(crate) progress_stage3: u8,
 
     progress_callback: Option<Arc<dyn Fn(f32) -> ControlFlow + Send + Sync>>,
-    log_callback: Option<Arc<dyn Fn(&Attributes, &str) + Send + Sync>>,
+    log_callback: Option<Arc<LogCallback>>,
     log_flush_callback: Option<Arc<dyn Fn(&Attributes) + Send + Sync>>,
+    event_callback: Option<Arc<EventCallback>>,
+}
+
+bitflags::bitflags! {
+    /// Switches of the settings. Packed, since `Attributes` are kept small.
+    struct AttrFlags: u16 {
+        const SCREENSHOT_MODE = 1;
+        const KEEP_EXACT_COLORS = 1 << 1;
+        const EXACT_FINAL_ITERATION = 1 << 2;
+        const PRESERVE_RARE_COLORS = 1 << 3;
+        const UNDITHERED_FLAT_AREAS = 1 << 4;
+        const GRADIENT_PRIORITY = 1 << 5;
+        const PRESERVE_EXTREMES = 1 << 6;
+        const AUTO_LEVELS = 1 << 7;
+        const ALPHA_WEIGHTED_COLORS = 1 << 8;
+        const AUTO_SPEED = 1 << 9;
+    }
 }
 
 impl Attributes {
@@ -43,18 +87,31 @@ impl Attributes {
     #[must_use]
     pub fn new() -> Self {
         let mut attr = Self {
+            #[cfg(feature = "capi")]
             magic_header: LIQ_ATTR_MAGIC,
             target_mse: 0.,
             max_mse: None,
+            max_pixel_error: None,
+            min_entry_population: 0.,
+            rare_color_distinctness: standard_mse_to_mse(Self::DEFAULT_RARE_COLOR_DISTINCTNESS) as f32,
+            duotone: None,
+            label_mode: None,
             max_colors: 256,
-            last_index_transparent: false,
+            transparent_placement: TransparentPlacement::First,
+            overshoot_strategy: OvershootStrategy::Balanced,
+            refinement: Refinement::Full,
             kmeans_iteration_limit: 0.,
             max_histogram_entries: 0,
             min_posterization_output: 0,
             min_posterization_input: 0,
+            histogram_precision: 0,
+            alpha_levels: 0,
             kmeans_iterations: 0,
             feedback_loop_trials: 0,
             use_contrast_maps: false,
+            flags: AttrFlags::empty(),
+            color_vision_deficiency: None,
+            palette_constraint: None,
             use_dither_map: DitherMapMode::None,
             speed: 0,
             progress_stage1: 0,
@@ -63,15 +120,16 @@ impl Attributes {
             progress_callback: None,
             log_callback: None,
             log_flush_callback: None,
+            event_callback: None,
         };
         attr.set_speed(4);
         attr
     }
 
-    /// It's better to use `set_quality()`
+    /// 2-256 colors (up to [`MAX_COLORS`] with the `large-palettes` feature). It's better to use `set_quality()`
     #[inline]
     pub fn set_max_colors(&mut self, colors: u32) -> liq_error {
-        if !(2..=256).contains(&colors) {
+        if !(2..=MAX_COLORS as u32).contains(&colors) {
             return LIQ_VALUE_OUT_OF_RANGE;
         }
         self.max_colors = colors as PalLen;
@@ -97,6 +155,49 @@ impl Attributes {
         self.min_posterization_output
     }
 
+    /// Bits of precision (5-8) of each channel of colors collected in the histogram. 0 makes it automatic (the default).
+    ///
+    /// Automatically, fastest speeds drop a bit, and another bit or two are dropped if an image has too many colors,
+    /// which merges similar colors before the palette is chosen, and limits the best achievable quality of smooth gradients.
+    /// 8 keeps colors exact, however many there are, at the cost of memory and time. Lower values are faster.
+    #[inline]
+    pub fn set_histogram_precision(&mut self, bits: u8) -> liq_error {
+        if bits != 0 && !(5..=8).contains(&bits) {
+            return LIQ_VALUE_OUT_OF_RANGE;
+        }
+        self.histogram_precision = bits;
+        LIQ_OK
+    }
+
+    /// Reads value set with `set_histogram_precision`
+    #[inline(always)]
+    #[must_use]
+    pub fn histogram_precision(&self) -> u8 {
+        self.histogram_precision
+    }
+
+    /// Quantizes alpha to `levels` evenly spaced values (including 0 and 255), independently from color.
+    ///
+    /// Useful for engines that store alpha in a separate low-bit channel, e.g. 8 levels for 3-bit alpha.
+    /// Median cut separates alpha levels first, and alpha of the final palette is rounded to the levels.
+    /// When remapping without dithering, alpha of pixels is rounded to the levels too, before the nearest color is searched.
+    /// 0 turns it off (the default), otherwise 2-255.
+    #[inline]
+    pub fn set_alpha_levels(&mut self, levels: u32) -> liq_error {
+        if levels == 1 || levels > 255 {
+            return LIQ_VALUE_OUT_OF_RANGE;
+        }
+        self.alpha_levels = levels as u8;
+        LIQ_OK
+    }
+
+    /// Reads value set with `set_alpha_levels`
+    #[inline(always)]
+    #[must_use]
+    pub fn alpha_levels(&self) -> u32 {
+        self.alpha_levels.into()
+    }
+
     /// Range 0-100, roughly like JPEG.
     ///
     /// If minimum quality can't be met, quantization will fail.
@@ -120,6 +221,348 @@ impl Attributes {
         )
     }
 
+    /// Limits error of the worst pixel, rather than the average error of the whole image (minimax).
+    ///
+    /// The value is in the same units as [`QuantizationResult::quantization_error`].
+    /// The palette will be refined to bring every color of the image within this error of a palette entry,
+    /// and if that's not possible, quantization will fail with `LIQ_QUALITY_TOO_LOW`.
+    ///
+    /// Useful for scientific or medical images, where a single badly mapped area is unacceptable. Default is no limit.
+    pub fn set_max_pixel_error(&mut self, max_error: f64) -> liq_error {
+        if max_error.is_nan() || max_error <= 0. {
+            return LIQ_VALUE_OUT_OF_RANGE;
+        }
+        self.max_pixel_error = Some(standard_mse_to_mse(max_error));
+        LIQ_OK
+    }
+
+    /// Reads value set with `set_max_pixel_error`
+    #[must_use]
+    pub fn max_pixel_error(&self) -> Option<f64> {
+        self.max_pixel_error.map(mse_to_standard_mse)
+    }
+
+    /// Removes palette entries that are the closest color for less than this fraction (0-1) of the image's pixels,
+    /// and merges their pixels into the remaining entries. Fixed colors are always kept.
+    ///
+    /// Useful for tiny palettes (e.g. 32 colors or fewer), where an entry spent on a few outlier pixels is a big loss
+    /// for the rest of the image. The palette may end up with fewer colors. Pixels are weighed as in the histogram,
+    /// so e.g. importance maps count. It has no effect with `Refinement::None`. Default is 0 (no limit).
+    ///
+    /// It conflicts with `set_preserve_rare_colors`, which runs afterwards and adds back entries for rare colors,
+    /// so these entries are kept even if they're used by fewer pixels than this.
+    pub fn set_min_entry_population(&mut self, fraction: f32) -> liq_error {
+        if !(0. ..=1.).contains(&fraction) {
+            return LIQ_VALUE_OUT_OF_RANGE;
+        }
+        self.min_entry_population = fraction;
+        LIQ_OK
+    }
+
+    /// Reads value set with `set_min_entry_population`
+    #[must_use]
+    pub fn min_entry_population(&self) -> f32 {
+        self.min_entry_population
+    }
+
+    /// Default of `set_rare_color_distinctness`, about a difference of 20 levels of green
+    pub const DEFAULT_RARE_COLOR_DISTINCTNESS: f64 = 150.;
+
+    /// Makes sure that colors which are clearly different from the rest of the image get a palette entry,
+    /// even if they're used by very few pixels, e.g. a small red warning icon on a gray screenshot.
+    ///
+    /// After refinement, colors that are further than `set_rare_color_distinctness` from every palette entry get an entry
+    /// (replacing the least used one if the palette is full), and their weight is boosted so that K-means keeps it.
+    /// At most a quarter of the palette is used for them. It has no effect with `Refinement::None`. Default is `false`.
+    ///
+    /// Entries are added after `set_min_entry_population` has removed unpopular ones, so rare colors win when both are enabled.
+    #[inline(always)]
+    pub fn set_preserve_rare_colors(&mut self, enabled: bool) {
+        self.flags.set(AttrFlags::PRESERVE_RARE_COLORS, enabled);
+    }
+
+    /// Reads value set with `set_preserve_rare_colors`
+    #[inline(always)]
+    #[must_use]
+    pub fn preserve_rare_colors(&self) -> bool {
+        self.flags.contains(AttrFlags::PRESERVE_RARE_COLORS)
+    }
+
+    /// How different from all palette colors a color must be to be preserved by `set_preserve_rare_colors`.
+    ///
+    /// The value is in the same units as [`QuantizationResult::quantization_error`]. Default is [`Self::DEFAULT_RARE_COLOR_DISTINCTNESS`].
+    pub fn set_rare_color_distinctness(&mut self, min_error: f64) -> liq_error {
+        if min_error.is_nan() || min_error <= 0. {
+            return LIQ_VALUE_OUT_OF_RANGE;
+        }
+        self.rare_color_distinctness = standard_mse_to_mse(min_error) as f32;
+        LIQ_OK
+    }
+
+    /// Reads value set with `set_rare_color_distinctness`
+    #[must_use]
+    pub fn rare_color_distinctness(&self) -> f64 {
+        mse_to_standard_mse(f64::from(self.rare_color_distinctness))
+    }
+
+    /// Makes the palette only of `dark`, `light`, and mixes of these two colors, e.g. for printing with one ink on tinted paper.
+    ///
+    /// Images are matched by brightness, so `light` must be brighter than `dark`. The number of colors is set with `set_max_colors`
+    /// (2 gives just `dark` and `light`). Tones in between are placed where the image needs them most, and at most 64 are used.
+    /// Quality limits of `set_quality` and `set_max_pixel_error` apply as usual, but the tones aren't moved to meet them.
+    ///
+    /// Transparency isn't supported: tones have only alpha of `dark` and `light`, and translucent pixels are matched by
+    /// their brightness blended with black. Images with alpha should be composed onto a background first.
+    pub fn set_duotone(&mut self, dark: RGBA, light: RGBA) -> liq_error {
+        let luma = |c: RGBA| 299 * u32::from(c.r) + 587 * u32::from(c.g) + 114 * u32::from(c.b);
+        if luma(light) <= luma(dark) {
+            return LIQ_VALUE_OUT_OF_RANGE;
+        }
+        self.duotone = Some((dark, light));
+        LIQ_OK
+    }
+
+    /// Reads colors set with `set_duotone`
+    #[must_use]
+    pub fn duotone(&self) -> Option<(RGBA, RGBA)> {
+        self.duotone
+    }
+
+    /// Treats colors as categories, e.g. of segmentation maps, map tiles, or annotation overlays, which must never be mixed.
+    ///
+    /// The palette is made of the most frequent colors of the image, exactly as they are (and fixed colors), without averaging any colors.
+    /// Remapping is without dithering, and pixels keep their color if it's in the palette. Pixels of other colors
+    /// are remapped as `fallback` says. Quality settings, `set_duotone` and other settings that choose palette colors aren't used.
+    #[inline(always)]
+    pub fn set_label_mode(&mut self, fallback: Option<LabelFallback>) {
+        self.label_mode = fallback;
+    }
+
+    /// Reads value set with `set_label_mode`
+    #[inline(always)]
+    #[must_use]
+    pub fn label_mode(&self) -> Option<LabelFallback> {
+        self.label_mode
+    }
+
+    /// Tunes image analysis for screenshots and other images with text on flat backgrounds.
+    ///
+    /// Colors of text and backgrounds are weighted up, and anti-aliased pixels at edges of text are weighted down,
+    /// so that the palette keeps exact text colors instead of averages of them with their anti-aliasing.
+    /// It has no effect on images that have an importance map set.
+    ///
+    /// Image analysis needs the `dither-maps` feature.
+    #[inline(always)]
+    #[cfg(feature = "dither-maps")]
+    pub fn set_screenshot_mode(&mut self, enabled: bool) {
+        self.flags.set(AttrFlags::SCREENSHOT_MODE, enabled);
+    }
+
+    /// Reads value set with `set_screenshot_mode`
+    #[inline(always)]
+    #[must_use]
+    #[cfg(feature = "dither-maps")]
+    pub fn screenshot_mode(&self) -> bool {
+        self.flags.contains(AttrFlags::SCREENSHOT_MODE)
+    }
+
+    /// Palette entries that are exactly a color of the image aren't moved by K-means refinement,
+    /// neither during quantization nor when the palette is refined for a remapped image.
+    ///
+    /// Colors of flat UI elements and text stay exact, instead of drifting towards averages with similar colors.
+    #[inline(always)]
+    pub fn set_keep_exact_colors(&mut self, enabled: bool) {
+        self.flags.set(AttrFlags::KEEP_EXACT_COLORS, enabled);
+    }
+
+    /// Reads value set with `set_keep_exact_colors`
+    #[inline(always)]
+    #[must_use]
+    pub fn keep_exact_colors(&self) -> bool {
+        self.flags.contains(AttrFlags::KEEP_EXACT_COLORS)
+    }
+
+    /// Ends refinement of the palette with one more K-means iteration, in which every color of the histogram
+    /// is matched with its truly nearest palette entry, rather than by the faster search that takes shortcuts.
+    ///
+    /// It's slower, but makes `quantization_error` and `quantization_quality` exact, e.g. for benchmarking.
+    /// It has no effect with `Refinement::None`.
+    #[inline(always)]
+    pub fn set_exact_final_iteration(&mut self, enabled: bool) {
+        self.flags.set(AttrFlags::EXACT_FINAL_ITERATION, enabled);
+    }
+
+    /// Reads value set with `set_exact_final_iteration`
+    #[inline(always)]
+    #[must_use]
+    pub fn exact_final_iteration(&self) -> bool {
+        self.flags.contains(AttrFlags::EXACT_FINAL_ITERATION)
+    }
+
+    /// Pixels that have the same color as a neighbor (solid areas, rather than gradients or photos) aren't dithered.
+    ///
+    /// This applies when remapping images that have been quantized (or added to a histogram) with these attributes.
+    ///
+    /// Flat areas are marked in the dither map, which needs the `dither-maps` feature.
+    #[inline(always)]
+    #[cfg(feature = "dither-maps")]
+    pub fn set_undithered_flat_areas(&mut self, enabled: bool) {
+        self.flags.set(AttrFlags::UNDITHERED_FLAT_AREAS, enabled);
+    }
+
+    /// Reads value set with `set_undithered_flat_areas`
+    #[inline(always)]
+    #[must_use]
+    #[cfg(feature = "dither-maps")]
+    pub fn undithered_flat_areas(&self) -> bool {
+        self.flags.contains(AttrFlags::UNDITHERED_FLAT_AREAS)
+    }
+
+    /// Settings for screenshots and UI: `set_screenshot_mode`, `set_keep_exact_colors` and `set_undithered_flat_areas` are enabled,
+    /// and speed is set to 6, because such images have few colors, and gain little from slower settings.
+    ///
+    /// Images that have no more colors than the palette keep all their colors exactly (unless quality is limited).
+    /// Use [`Image::looks_like_screenshot`](crate::Image::looks_like_screenshot) to decide if it's worth using.
+    ///
+    /// Without the `dither-maps` feature, only `set_keep_exact_colors` and the speed have an effect.
+    pub fn set_screenshot_preset(&mut self) {
+        self.set_speed(6);
+        self.flags.insert(AttrFlags::SCREENSHOT_MODE | AttrFlags::KEEP_EXACT_COLORS | AttrFlags::UNDITHERED_FLAT_AREAS);
+    }
+
+    /// Gives more palette entries to large smooth areas, such as skies, by weighting colors by how little they vary locally.
+    ///
+    /// This reduces banding in gradients, at the cost of precision of small detailed areas, which hide quantization errors better.
+    /// It has no effect on images that have an importance map set, and `set_screenshot_mode` takes precedence.
+    ///
+    /// Local variance is measured with the same maps as adaptive dithering, so it needs the `dither-maps` feature.
+    #[inline(always)]
+    #[cfg(feature = "dither-maps")]
+    pub fn set_gradient_priority(&mut self, enabled: bool) {
+        self.flags.set(AttrFlags::GRADIENT_PRIORITY, enabled);
+    }
+
+    /// Reads value set with `set_gradient_priority`
+    #[inline(always)]
+    #[must_use]
+    #[cfg(feature = "dither-maps")]
+    pub fn gradient_priority(&self) -> bool {
+        self.flags.contains(AttrFlags::GRADIENT_PRIORITY)
+    }
+
+    /// Settings for photos: `set_gradient_priority` is enabled, settings of `set_screenshot_preset` are disabled,
+    /// and speed is set to 3, because photos have many colors, and smooth gradients benefit from more K-means iterations.
+    ///
+    /// Without the `dither-maps` feature, gradient priority has no effect.
+    pub fn set_photo_preset(&mut self) {
+        self.set_speed(3);
+        self.flags.insert(AttrFlags::GRADIENT_PRIORITY);
+        self.flags.remove(AttrFlags::SCREENSHOT_MODE | AttrFlags::KEEP_EXACT_COLORS | AttrFlags::UNDITHERED_FLAT_AREAS);
+    }
+
+    /// Settings for RGBA5551 textures and other hardware with 5-bit color channels and 1-bit (punch-through) alpha:
+    /// `set_min_posterization(3)` and `set_alpha_levels(2)`.
+    ///
+    /// Palette colors are exactly representable in 5 bits per channel, and are either opaque or fully transparent.
+    /// Translucent pixels are thresholded at 50% alpha, or dithered between transparent and opaque
+    /// along with colors, if the result has a dithering level set (see `QuantizationResult::set_dithering_level`).
+    pub fn set_rgba5551_preset(&mut self) {
+        self.min_posterization_output = 3;
+        self.alpha_levels = 2;
+    }
+
+    /// Keeps pure black, pure white and fully transparent colors exact, for crisp documents and diagrams.
+    ///
+    /// Each of them that covers at least 0.1% of an image is added to the palette as a fixed color,
+    /// so that it isn't nudged towards similar colors. See also `Image::add_fixed_color`.
+    #[inline(always)]
+    pub fn set_preserve_extremes(&mut self, enabled: bool) {
+        self.flags.set(AttrFlags::PRESERVE_EXTREMES, enabled);
+    }
+
+    /// Reads value set with `set_preserve_extremes`
+    #[inline(always)]
+    #[must_use]
+    pub fn preserve_extremes(&self) -> bool {
+        self.flags.contains(AttrFlags::PRESERVE_EXTREMES)
+    }
+
+    /// Stretches levels of low-contrast images, such as faded scans, before quantization.
+    ///
+    /// Black and white points of each channel are found in the histogram, and colors are stretched to the full range,
+    /// so that the palette spends its entries on the colors that are actually used. The palette is mapped back to the original levels,
+    /// and the transform is available from `QuantizationResult::auto_levels`.
+    ///
+    /// It's not used when the histogram has fixed colors, or with `set_duotone`.
+    #[inline(always)]
+    pub fn set_auto_levels(&mut self, enabled: bool) {
+        self.flags.set(AttrFlags::AUTO_LEVELS, enabled);
+    }
+
+    /// Reads value set with `set_auto_levels`
+    #[inline(always)]
+    #[must_use]
+    pub fn auto_levels(&self) -> bool {
+        self.flags.contains(AttrFlags::AUTO_LEVELS)
+    }
+
+    /// Weighs colors by their alpha, so that pixels contribute to the palette only as much as they're visible.
+    ///
+    /// Some encoders leave garbage RGB under fully or nearly transparent pixels. With this option fully transparent pixels
+    /// don't pull colors of visible palette entries, and translucent pixels count in proportion to their opacity,
+    /// so the palette is spent on colors that are actually seen. Transparency itself still gets a palette entry.
+    #[inline(always)]
+    pub fn set_alpha_weighted_colors(&mut self, enabled: bool) {
+        self.flags.set(AttrFlags::ALPHA_WEIGHTED_COLORS, enabled);
+    }
+
+    /// Reads value set with `set_alpha_weighted_colors`
+    #[inline(always)]
+    #[must_use]
+    pub fn alpha_weighted_colors(&self) -> bool {
+        self.flags.contains(AttrFlags::ALPHA_WEIGHTED_COLORS)
+    }
+
+    /// Makes palettes for viewers with a color vision deficiency.
+    ///
+    /// Differences between colors that are hard to tell apart with the deficiency (along its confusion lines) count less when choosing the palette,
+    /// so that palette entries are spent on differences the viewer can see. Useful for charts and maps, where colors carry meaning.
+    /// Quantization error and quality are then measured as seen with the deficiency. Remapping is unchanged.
+    ///
+    /// It's not used with `set_duotone`, nor by [`PaletteOptimizer`](crate::PaletteOptimizer) and `quantize_extended`.
+    #[inline(always)]
+    pub fn set_color_vision_deficiency(&mut self, cvd: Option<ColorVisionDeficiency>) {
+        self.color_vision_deficiency = cvd;
+    }
+
+    /// Reads value set with `set_color_vision_deficiency`
+    #[inline(always)]
+    #[must_use]
+    pub fn color_vision_deficiency(&self) -> Option<ColorVisionDeficiency> {
+        self.color_vision_deficiency
+    }
+
+    /// Limits colors of generated palettes, e.g. to shades of sepia, or to a range of hues.
+    ///
+    /// Palette colors are moved to the closest allowed colors after every K-means iteration, so they settle on the best allowed colors.
+    /// Fixed colors aren't limited. The palette isn't adjusted during remapping, since that would move it away from the allowed colors.
+    ///
+    /// It's not used with `set_duotone`, nor by [`PaletteOptimizer`](crate::PaletteOptimizer) and `quantize_extended`.
+    pub fn set_palette_constraint(&mut self, constraint: Option<PaletteConstraint>) -> liq_error {
+        if matches!(&constraint, Some(c) if !c.is_valid()) {
+            return LIQ_VALUE_OUT_OF_RANGE;
+        }
+        self.palette_constraint = constraint;
+        LIQ_OK
+    }
+
+    /// Reads value set with `set_palette_constraint`
+    #[inline(always)]
+    #[must_use]
+    pub fn palette_constraint(&self) -> Option<&PaletteConstraint> {
+        self.palette_constraint.as_ref()
+    }
+
     /// 1-10.
     ///
     /// Faster speeds generate images of lower quality, but may be useful
@@ -153,10 +596,75 @@ impl Attributes {
 
     /// Move transparent color to the last entry in the palette
     ///
-    /// This is less efficient for PNG, but required by some broken software
+    /// This is less efficient for PNG, but required by some broken software.
+    /// Same as `set_transparent_placement(TransparentPlacement::Last)`, or `First` if `false`.
     #[inline(always)]
     pub fn set_last_index_transparent(&mut self, is_last: bool) {
-        self.last_index_transparent = is_last;
+        self.transparent_placement = if is_last { TransparentPlacement::Last } else { TransparentPlacement::First };
+    }
+
+    /// Where transparent colors are put in the palette. Default is [`TransparentPlacement::First`].
+    ///
+    /// Fixed colors are sorted like all other colors, so use `Image::add_fixed_color_tagged` to find them in the palette.
+    #[inline(always)]
+    pub fn set_transparent_placement(&mut self, placement: TransparentPlacement) {
+        self.transparent_placement = placement;
+    }
+
+    /// Reads value set with `set_transparent_placement` or `set_last_index_transparent`
+    #[inline(always)]
+    #[must_use]
+    pub fn transparent_placement(&self) -> TransparentPlacement {
+        self.transparent_placement
+    }
+
+    /// How much error above the target quality median cut aims for, so that palettes can come in under `max_colors`.
+    /// Default is [`OvershootStrategy::Balanced`].
+    ///
+    /// This matters for size-constrained outputs, where fewer colors make smaller files. It has no effect on the speeds that don't repeat median cut.
+    #[inline(always)]
+    pub fn set_overshoot_strategy(&mut self, strategy: OvershootStrategy) {
+        self.overshoot_strategy = strategy;
+    }
+
+    /// Reads value set with `set_overshoot_strategy`
+    #[inline(always)]
+    #[must_use]
+    pub fn overshoot_strategy(&self) -> OvershootStrategy {
+        self.overshoot_strategy
+    }
+
+    /// How much K-means moves median cut's palette towards a better one. Default is [`Refinement::Full`].
+    ///
+    /// Less refinement is faster, at a cost of quality, and [`Refinement::None`] gives the raw output of median cut.
+    /// It doesn't affect remapping, which still adjusts the palette to the remapped image (unless colors are fixed).
+    #[inline(always)]
+    pub fn set_refinement(&mut self, refinement: Refinement) {
+        self.refinement = refinement;
+    }
+
+    /// Reads value set with `set_refinement`
+    #[inline(always)]
+    #[must_use]
+    pub fn refinement(&self) -> Refinement {
+        self.refinement
+    }
+
+    /// Picks speed for each image from its size, instead of the speed set with `set_speed`.
+    ///
+    /// Small images, like icons, are quantized with the slowest, best speed, and the speed gradually increases with the number of pixels,
+    /// so that huge scans are quantized in a reasonable time. Useful for batch tools that get images of all sizes.
+    /// Speed is picked when quantizing an image (`quantize` and similar methods), but not when images are added to a `Histogram`.
+    #[inline(always)]
+    pub fn set_auto_speed(&mut self, enabled: bool) {
+        self.flags.set(AttrFlags::AUTO_SPEED, enabled);
+    }
+
+    /// Reads value set with `set_auto_speed`
+    #[inline(always)]
+    #[must_use]
+    pub fn auto_speed(&self) -> bool {
+        self.flags.contains(AttrFlags::AUTO_SPEED)
     }
 
     /// Return currently set speed/quality trade-off setting
@@ -166,6 +674,31 @@ impl Attributes {
         self.speed.into()
     }
 
+    /// What the current speed and quality settings mean for the algorithm, for logging or reasoning about its behavior.
+    ///
+    /// Numbers of iterations and trials are maximums, which are reduced for images with many distinct colors.
+    #[must_use]
+    pub fn effective_settings(&self) -> EffectiveSettings {
+        let (min_quality, target_quality) = self.quality();
+        EffectiveSettings {
+            speed: self.speed,
+            min_quality,
+            target_quality,
+            feedback_loop_trials: if self.refinement == Refinement::Full { self.feedback_loop_trials } else { 0 },
+            kmeans_iterations: match self.refinement {
+                Refinement::Full => self.kmeans_iterations,
+                Refinement::Quick => 1,
+                Refinement::None => 0,
+            },
+            kmeans_iteration_limit: self.kmeans_iteration_limit,
+            max_histogram_entries: self.histogram_entries_limit(),
+            input_posterization: self.input_posterization(),
+            dither_map: self.use_dither_map != DitherMapMode::None,
+            dither_map_on_huge_images: self.use_dither_map == DitherMapMode::Always,
+            contrast_maps: self.use_contrast_maps,
+        }
+    }
+
     /// Return max number of colors set
     #[inline(always)]
     #[must_use]
@@ -201,10 +734,89 @@ impl Attributes {
     }
 
     /// Generate palette for the image
-    pub fn quantize(&mut self, image: &mut Image<'_, '_>) -> Result<QuantizationResult, liq_error> {
-        let mut hist = Histogram::new(self);
-        hist.add_image(self, image)?;
-        hist.quantize_internal(self, false)
+    ///
+    /// `Attributes` are not modified, so they can be shared between threads quantizing different images at the same time.
+    pub fn quantize(&self, image: &mut Image<'_, '_>) -> Result<QuantizationResult, liq_error> {
+        Ok(self.quantize_detailed(image)?)
+    }
+
+    /// Like `quantize()`, but if quality is too low, the error has the palette that was found anyway,
+    /// so it can be accepted without quantizing again. See [`QuantizeError`](crate::QuantizeError).
+    pub fn quantize_detailed(&self, image: &mut Image<'_, '_>) -> Result<QuantizationResult, QuantizeError> {
+        let attr = self.for_image(image);
+        let mut hist = Histogram::new(&attr);
+        hist.add_image(&attr, image)?;
+        hist.quantize_internal(&attr, false)
+    }
+
+    /// Like `quantize_detailed()`, but if quality is too low, retries with each of the `fallbacks` in order,
+    /// e.g. with more colors or a lower minimum quality.
+    ///
+    /// The image is added to the histogram only once, using these `Attributes`, and the histogram is reused for every attempt.
+    /// See [`Histogram::quantize_with_fallback`](crate::Histogram::quantize_with_fallback).
+    pub fn quantize_with_fallback(&self, image: &mut Image<'_, '_>, fallbacks: &[Attributes]) -> Result<QuantizationResult, QuantizeError> {
+        let attr = self.for_image(image);
+        let fallbacks: Vec<_> = fallbacks.iter().map(|f| f.for_image(image)).collect();
+        let mut hist = Histogram::new(&attr);
+        hist.add_image(&attr, image)?;
+        hist.quantize_first_acceptable(std::iter::once(&*attr).chain(fallbacks.iter().map(|f| &**f)), false)
+    }
+
+    /// Keeps colors of an existing palette at their indices, and adds at most `new_colors` colors chosen for the image,
+    /// e.g. to grow a global palette of a screen recording frame by frame, without changing indices of colors already in use.
+    ///
+    /// Base colors must be distinct, and are never changed. New colors follow them, starting at index `base.len()`.
+    /// Fewer colors are added if the base palette is good enough for the quality settings.
+    /// Fixed colors of the image that aren't in the base palette take slots of the new colors.
+    pub fn quantize_with_base_palette(&self, image: &mut Image<'_, '_>, base: &[RGBA], new_colors: u32) -> Result<QuantizationResult, liq_error> {
+        let mut attr = self.for_image(image).into_owned();
+        attr.set_max_colors((base.len() as u32).saturating_add(new_colors)).ok()?;
+        let lut = gamma_lut(image.gamma());
+        let base_f: Vec<_> = base.iter().map(|&c| f_pixel::from_rgba(&lut, c)).collect();
+        if base_f.iter().enumerate().any(|(i, c)| base_f[..i].contains(c)) {
+            return Err(LIQ_VALUE_OUT_OF_RANGE);
+        }
+
+        let mut hist = Histogram::new(&attr);
+        hist.add_image(&attr, image)?;
+        for &c in base {
+            hist.add_fixed_color(c, image.gamma()).ok()?;
+        }
+        let mut res = hist.quantize_internal(&attr, false)?;
+        if !res.palette.move_to_front(&base_f) {
+            return Err(LIQ_UNSUPPORTED);
+        }
+        Ok(res)
+    }
+
+    /// Generate one palette shared by all levels of a mip chain (the same image at several scales), and remap every level to it.
+    ///
+    /// Using the same palette for all levels avoids shimmering when a texture switches between mip levels.
+    /// `weights` sets importance of each level in the histogram. It can be empty, which weighs every pixel of every level equally
+    /// (so the largest level dominates), or have one weight (0-256) per level.
+    ///
+    /// Returns indices of pixels of each level, in the same order as `levels`.
+    pub fn quantize_mip_chain(&self, levels: &mut [Image<'_, '_>], weights: &[f32]) -> Result<(QuantizationResult, Vec<Vec<PalIndex>>), liq_error> {
+        if levels.is_empty() || (!weights.is_empty() && weights.len() != levels.len()) {
+            return Err(LIQ_VALUE_OUT_OF_RANGE);
+        }
+        let attr = self.for_image(&levels[0]);
+        let mut hist = Histogram::new(&attr);
+        for (i, level) in levels.iter_mut().enumerate() {
+            hist.add_image_weighted(&attr, level, weights.get(i).copied().unwrap_or(1.))?;
+        }
+        // histogram's colors are never refined during remapping, so every level gets the identical palette
+        let mut res = hist.quantize(&attr)?;
+        let indices = levels.iter_mut().map(|level| res.remapped(level).map(|(_, indices)| indices)).collect::<Result<_, _>>()?;
+        Ok((res, indices))
+    }
+
+    /// Generate palette of up to [`MAX_EXTENDED_COLORS`](crate::MAX_EXTENDED_COLORS) colors, for formats that allow more than 256 colors.
+    ///
+    /// `max_colors` is used instead of `set_max_colors`. Images are remapped to 16-bit indices with
+    /// [`ExtendedQuantizationResult::remap_into_u16`]. Fixed colors and gamma-encoded alpha are not supported.
+    pub fn quantize_extended(&self, image: &mut Image<'_, '_>, max_colors: u32) -> Result<ExtendedQuantizationResult, liq_error> {
+        crate::extended::quantize_extended(&self.for_image(image), image, max_colors)
     }
 
     /// Set callback function to be called every time the library wants to print a message.
@@ -215,11 +827,26 @@ impl Attributes {
         self.log_callback = Some(Arc::new(callback));
     }
 
+    /// Like `set_log_callback`, but takes a callback that may be shared with other code.
+    #[inline]
+    pub fn set_log_callback_arc(&mut self, callback: Arc<LogCallback>) {
+        self.log_callback = Some(callback);
+    }
+
     #[inline]
     pub fn set_log_flush_callback<F: Fn(&Attributes) + Send + Sync + 'static>(&mut self, callback: F) {
         self.log_flush_callback = Some(Arc::new(callback));
     }
 
+    /// Set callback function to be called with details of quantization, such as progress of color selection
+    /// or quality that is too low, as typed values rather than English messages, e.g. for translating them in a GUI.
+    ///
+    /// Events are also sent, formatted, to the log callback (see `set_log_callback`).
+    #[inline]
+    pub fn set_event_callback<F: Fn(&Attributes, &LogEvent) + Send + Sync + 'static>(&mut self, callback: F) {
+        self.event_callback = Some(Arc::new(callback));
+    }
+
     /// Set callback function to be called every time the library makes a progress.
     /// It can be used to cancel operation early.
     ///
@@ -229,6 +856,14 @@ impl Attributes {
         self.progress_callback = Some(Arc::new(callback));
     }
 
+    /// Like `set_progress_callback`, but takes a callback that may be shared with other code.
+    ///
+    /// The callback may be called from multiple threads at once if these `Attributes` are used by multiple threads.
+    #[inline]
+    pub fn set_progress_callback_arc(&mut self, callback: Arc<dyn Fn(f32) -> ControlFlow + Send + Sync>) {
+        self.progress_callback = Some(callback);
+    }
+
     // true == abort
     #[inline]
     pub(crate) fn progress(self: &Attributes, percent: f32) -> bool {
@@ -239,7 +874,25 @@ impl Attributes {
         }
     }
 
-    #[inline(always)]
+    /// These attributes, with messages going to the image's log callback, if it has one (see `Image::set_log_callback`),
+    /// and speed picked for the size of the image, if it's automatic (see `set_auto_speed`)
+    pub(crate) fn for_image(&self, image: &Image<'_, '_>) -> Cow<'_, Attributes> {
+        let speed = if self.auto_speed() { Some(auto_speed(image.width() * image.height())) } else { None };
+        if image.log_callback.is_none() && (speed.is_none() || speed == Some(self.speed)) {
+            return Cow::Borrowed(self);
+        }
+        let mut attr = self.clone();
+        if let Some(callback) = &image.log_callback {
+            attr.log_callback = Some(callback.clone());
+            attr.log_flush_callback = None;
+        }
+        if let Some(speed) = speed {
+            attr.verbose_print(format!("  speed {} for {}×{} image", speed, image.width(), image.height()));
+            attr.set_speed(speed.into());
+        }
+        Cow::Owned(attr)
+    }
+
     pub(crate) fn verbose_print(self: &Attributes, msg: impl AsRef<str>) {
         fn _print(a: &Attributes, msg: &str) {
             if let Some(f) = &a.log_callback {
@@ -249,6 +902,16 @@ impl Attributes {
         _print(self, msg.as_ref());
     }
 
+    /// Sends the event to the event callback, and as a message to the log callback
+    pub(crate) fn verbose_event(&self, event: LogEvent) {
+        if let Some(f) = &self.event_callback {
+            f(self, &event);
+        }
+        if self.log_callback.is_some() {
+            self.verbose_print(event.to_string());
+        }
+    }
+
     #[inline]
     pub(crate) fn verbose_printf_flush(self: &Attributes) {
         if let Some(f) = &self.log_flush_callback {
@@ -309,14 +972,57 @@ impl Attributes {
 
     #[inline]
     pub(crate) fn posterize_bits(&self) -> u8 {
-        self.min_posterization_output.max(self.min_posterization_input)
+        self.min_posterization_output.max(self.input_posterization())
+    }
+
+    #[inline]
+    fn input_posterization(&self) -> u8 {
+        if self.histogram_precision != 0 { 8 - self.histogram_precision } else { self.min_posterization_input }
+    }
+
+    /// The histogram is posterized further when it has more colors than this. It's never done for fixed `histogram_precision`.
+    #[inline]
+    pub(crate) fn histogram_entries_limit(&self) -> u32 {
+        if self.histogram_precision != 0 { u32::MAX } else { self.max_histogram_entries }
+    }
+
+    /// Hashes settings that affect the palette. Callbacks and progress reporting don't.
+    pub(crate) fn hash_quantization_settings<H: Hasher>(&self, state: &mut H) {
+        self.max_colors.hash(state);
+        self.target_mse.to_bits().hash(state);
+        self.max_mse.map(f64::to_bits).hash(state);
+        self.max_pixel_error.map(f64::to_bits).hash(state);
+        self.min_entry_population.to_bits().hash(state);
+        self.rare_color_distinctness.to_bits().hash(state);
+        self.duotone.hash(state);
+        self.label_mode.hash(state);
+        self.kmeans_iteration_limit.to_bits().hash(state);
+        self.kmeans_iterations.hash(state);
+        self.feedback_loop_trials.hash(state);
+        self.max_histogram_entries.hash(state);
+        self.min_posterization_output.hash(state);
+        self.min_posterization_input.hash(state);
+        self.histogram_precision.hash(state);
+        self.alpha_levels.hash(state);
+        (self.transparent_placement as u8).hash(state);
+        (self.overshoot_strategy as u8).hash(state);
+        (self.refinement as u8).hash(state);
+        self.use_contrast_maps.hash(state);
+        self.flags.bits().hash(state);
+        self.color_vision_deficiency.map(|cvd| cvd as u8).hash(state);
+        self.palette_constraint.hash(state);
+        (self.use_dither_map as u8).hash(state);
+        self.speed.hash(state);
     }
 }
 
 impl Drop for Attributes {
     fn drop(&mut self) {
         self.verbose_printf_flush();
-        self.magic_header = LIQ_FREED_MAGIC;
+        #[cfg(feature = "capi")]
+        {
+            self.magic_header = LIQ_FREED_MAGIC;
+        }
     }
 }
 
@@ -327,6 +1033,128 @@ impl Default for Attributes {
     }
 }
 
+/// Speed for `Attributes::set_auto_speed`: the best for icons, the default around a megapixel, and the fastest for huge scans
+fn auto_speed(pixels: usize) -> u8 {
+    /// Largest number of pixels for each speed
+    const STEPS: [(usize, u8); 5] = [(128 * 128, 1), (512 * 512, 3), (2 << 20, 4), (8 << 20, 6), (32 << 20, 8)];
+    STEPS.iter().find(|&&(max, _)| pixels <= max).map_or(10, |&(_, speed)| speed)
+}
+
+/// Settings implied by speed and quality, see [`Attributes::effective_settings`]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct EffectiveSettings {
+    /// 1-10, set with `set_speed`
+    pub speed: u8,
+    /// 0-100, set with `set_quality`
+    pub min_quality: u8,
+    /// 0-100, set with `set_quality`
+    pub target_quality: u8,
+    /// Number of times median cut is repeated with weights adjusted to the previous palette's errors
+    pub feedback_loop_trials: u16,
+    /// Number of K-means iterations refining the final palette
+    pub kmeans_iterations: u16,
+    /// K-means stops early when an iteration improves the error by less than this (in internal units)
+    pub kmeans_iteration_limit: f64,
+    /// Histograms with more distinct colors are sampled coarser, by dropping least significant bits of the colors
+    pub max_histogram_entries: u32,
+    /// Least significant bits of input colors that are ignored
+    pub input_posterization: u8,
+    /// Dithering is weaker on edges and in noise, which need edge detection before remapping
+    pub dither_map: bool,
+    /// The dither map is used even for images over 4 megapixels
+    pub dither_map_on_huge_images: bool,
+    /// Palette puts more weight on colors in smooth areas and edges, which need detecting them before quantization
+    pub contrast_maps: bool,
+}
+
+/// Position of transparent colors in the palette, see [`Attributes::set_transparent_placement`]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TransparentPlacement {
+    /// Transparent colors go before all opaque colors, which keeps PNG's tRNS chunk short
+    First,
+    /// The most transparent color goes to the last index, for software that expects it there
+    Last,
+    /// Transparent colors aren't moved, so the palette is sorted only by popularity of the colors
+    Keep,
+}
+
+/// How the search for a palette overshoots the target error, see [`Attributes::set_overshoot_strategy`]
+///
+/// Median cut stops splitting boxes when the error is under its target, so a target above the quality setting gives fewer colors.
+/// Palettes that turn out better than needed raise the overshoot for the next trial, and palettes that are worse reset it.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum OvershootStrategy {
+    /// The target is the quality setting, so palettes use as many colors as help the quality
+    Off,
+    /// Starts 2% over the target, and grows by 10% after each palette that is good enough
+    Conservative,
+    /// Starts 5% over the target, and grows by 25% after each palette that is good enough
+    Balanced,
+    /// Starts 10% over the target, and grows by 50% after each palette that is good enough.
+    /// Palettes often have fewer colors than `max_colors`, at quality closer to the minimum.
+    Aggressive,
+}
+
+impl OvershootStrategy {
+    /// Overshoot of the first trial
+    pub(crate) fn initial(self) -> f64 {
+        match self {
+            Self::Off => 1.,
+            Self::Conservative => 1.02,
+            Self::Balanced => 1.05,
+            Self::Aggressive => 1.1,
+        }
+    }
+
+    /// Multiplier of the overshoot after a palette with error under the target
+    pub(crate) fn growth(self) -> f64 {
+        match self {
+            Self::Off => 1.,
+            Self::Conservative => 1.1,
+            Self::Balanced => 1.25,
+            Self::Aggressive => 1.5,
+        }
+    }
+}
+
+/// Amount of K-means refinement of palettes, see [`Attributes::set_refinement`]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Refinement {
+    /// Palette is made by a single median cut, without K-means.
+    /// The error is measured only if quality limits need it, and `set_max_pixel_error` still adjusts the palette.
+    None,
+    /// Palette is made by a single median cut, followed by one K-means iteration
+    Quick,
+    /// Median cut is repeated with feedback from K-means, and the best palette is refined with as many iterations as speed allows
+    Full,
+}
+
+/// Details of quantization sent to [`Attributes::set_event_callback`]
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum LogEvent {
+    /// Progress (0-100) of the search for the best palette
+    ColorsSelected { percent: u8 },
+    /// Error of the palette is over the limit of `set_quality`.
+    /// Both are in the same units as [`QuantizationResult::quantization_error`](crate::QuantizationResult::quantization_error).
+    MseExceeded { mse: f64, limit: f64 },
+    /// Transparent entries have been moved to the start of the palette (see [`TransparentPlacement::First`]),
+    /// so the PNG `tRNS` chunk needs only this many entries
+    TrnsEliminated { count: usize },
+}
+
+impl fmt::Display for LogEvent {
+    #[cold]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::ColorsSelected { percent } => write!(f, "  selecting colors...{}%", percent),
+            Self::MseExceeded { mse, limit } => write!(f, "  image degradation MSE={:0.3} (Q={}) exceeded limit of {:0.3} ({})",
+                mse, mse_to_quality(standard_mse_to_mse(mse)), limit, mse_to_quality(standard_mse_to_mse(limit))),
+            Self::TrnsEliminated { count } => write!(f, "  eliminated opaque tRNS-chunk entries...{} entr{} transparent", count, if count == 1 { "y" } else { "ies" }),
+        }
+    }
+}
+
 /// Result of callback in [`Attributes::set_progress_callback`]
 #[repr(C)]
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
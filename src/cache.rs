@@ -0,0 +1,117 @@
+//! Reusing palettes of histograms that have been quantized before
+
+use crate::attr::Attributes;
+use crate::error::*;
+use crate::hist::Histogram;
+use crate::quant::QuantizationResult;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::Hasher;
+
+/// Identifies contents of a [`Histogram`] together with [`Attributes`] that affect its palette.
+///
+/// Keys are only meaningful within the same version of the library, so don't store them across program runs.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct QuantCacheKey(pub(crate) u64);
+
+impl QuantCacheKey {
+    /// Order in which colors have been added to the histogram doesn't matter. Callbacks set on `attr` don't affect the key.
+    #[must_use]
+    pub fn new(hist: &Histogram, attr: &Attributes) -> Self {
+        let mut state = DefaultHasher::new();
+        hist.hash_contents(&mut state);
+        attr.hash_quantization_settings(&mut state);
+        Self(state.finish())
+    }
+
+    /// The hash, e.g. to use the key in your own storage
+    #[inline]
+    #[must_use]
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+/// Small cache of palettes, for tile-based or repeated-asset pipelines where identical histograms come up again.
+///
+/// The cache doesn't quantize by itself: [`lookup`](QuantCache::lookup) before quantizing, and [`insert`](QuantCache::insert) the result after.
+/// When it's full, the least recently used palette is dropped.
+///
+/// ```rust,ignore
+/// let key = QuantCacheKey::new(&hist, &attr);
+/// let res = match cache.lookup(key) {
+///     Some(res) => res,
+///     None => {
+///         let res = hist.quantize(&attr)?;
+///         cache.insert(key, &res);
+///         res
+///     },
+/// };
+/// ```
+pub struct QuantCache {
+    capacity: usize,
+    /// Most recently used last
+    entries: VecDeque<(QuantCacheKey, QuantizationResult)>,
+}
+
+impl QuantCache {
+    /// Keeps up to `capacity` palettes
+    pub fn new(capacity: usize) -> Result<Self, liq_error> {
+        if capacity == 0 {
+            return Err(LIQ_VALUE_OUT_OF_RANGE);
+        }
+        Ok(Self {
+            capacity,
+            entries: VecDeque::new(),
+        })
+    }
+
+    /// New result with the palette cached for the key, as if it has just been quantized.
+    ///
+    /// Dithering level and the progress callback are not cached, so set them again on the result.
+    pub fn lookup(&mut self, key: QuantCacheKey) -> Option<QuantizationResult> {
+        let pos = self.entries.iter().position(|(k, _)| *k == key)?;
+        let entry = self.entries.remove(pos)?;
+        let res = entry.1.unremapped_copy();
+        self.entries.push_back(entry);
+        Some(res)
+    }
+
+    /// Caches palette of the result, replacing any palette cached for the same key.
+    ///
+    /// It's best to insert results right after quantization, since palettes improved by remapping one image may not suit other images.
+    pub fn insert(&mut self, key: QuantCacheKey, result: &QuantizationResult) {
+        self.remove(key);
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((key, result.unremapped_copy()));
+    }
+
+    /// Returns `true` if there was a palette for the key
+    pub fn remove(&mut self, key: QuantCacheKey) -> bool {
+        match self.entries.iter().position(|(k, _)| *k == key) {
+            Some(pos) => self.entries.remove(pos).is_some(),
+            None => false,
+        }
+    }
+
+    /// Number of cached palettes
+    #[inline]
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Drops all cached palettes
+    #[inline]
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
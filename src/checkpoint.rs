@@ -0,0 +1,278 @@
+//! Saving palettes with their settings, to remap images in another process (see `QuantizationResult::write_checkpoint`)
+
+use crate::cache::QuantCacheKey;
+use crate::labels::LabelFallback;
+use crate::levels::Levels;
+use crate::pal::{f_pixel, AlphaEncoding, PalF, PalIndex, PalPop, ARGBF, MAX_COLORS, RGBA};
+use crate::quant::QuantizationResult;
+use crate::remap::{DitherLevel, DitherMapMode, ProtectedColor};
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8; 8] = b"LIQCKPT\0";
+
+/// Incremented whenever the format changes. Checkpoints of other versions aren't read.
+const VERSION: u8 = 2;
+
+#[cold]
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Little-endian values, in the order they're written
+struct Writer<W>(W);
+
+impl<W: Write> Writer<W> {
+    fn u8(&mut self, v: u8) -> io::Result<()> {
+        self.0.write_all(&[v])
+    }
+
+    fn u16(&mut self, v: u16) -> io::Result<()> {
+        self.0.write_all(&v.to_le_bytes())
+    }
+
+    fn u32(&mut self, v: u32) -> io::Result<()> {
+        self.0.write_all(&v.to_le_bytes())
+    }
+
+    fn f32(&mut self, v: f32) -> io::Result<()> {
+        self.0.write_all(&v.to_le_bytes())
+    }
+
+    fn f64(&mut self, v: f64) -> io::Result<()> {
+        self.0.write_all(&v.to_le_bytes())
+    }
+
+    fn rgba(&mut self, c: RGBA) -> io::Result<()> {
+        self.0.write_all(&[c.r, c.g, c.b, c.a])
+    }
+
+    fn f_pixel(&mut self, px: &f_pixel) -> io::Result<()> {
+        for &c in &[px.a, px.r, px.g, px.b] {
+            self.f32(c)?;
+        }
+        Ok(())
+    }
+}
+
+struct Reader<R>(R);
+
+impl<R: Read> Reader<R> {
+    fn bytes<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut buf = [0; N];
+        self.0.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes()?))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes()?))
+    }
+
+    fn f32(&mut self) -> io::Result<f32> {
+        let v = f32::from_le_bytes(self.bytes()?);
+        if !v.is_finite() {
+            return Err(invalid_data("bad number"));
+        }
+        Ok(v)
+    }
+
+    fn f64(&mut self) -> io::Result<f64> {
+        let v = f64::from_le_bytes(self.bytes()?);
+        if !v.is_finite() {
+            return Err(invalid_data("bad number"));
+        }
+        Ok(v)
+    }
+
+    fn rgba(&mut self) -> io::Result<RGBA> {
+        let [r, g, b, a] = self.bytes()?;
+        Ok(RGBA::new(r, g, b, a))
+    }
+
+    fn f_pixel(&mut self) -> io::Result<f_pixel> {
+        Ok(f_pixel(ARGBF { a: self.f32()?, r: self.f32()?, g: self.f32()?, b: self.f32()? }))
+    }
+
+    /// 0 is `false`, 1 is `true`
+    fn flag(&mut self) -> io::Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid_data("bad flag")),
+        }
+    }
+}
+
+impl QuantizationResult {
+    /// Saves the palette, together with settings needed to remap images with it, so that remapping can continue in another process or on another machine.
+    ///
+    /// This splits CPU-heavy palette generation from memory-heavy remapping. Restore the result with [`read_checkpoint`](Self::read_checkpoint).
+    /// The optional `histogram_digest`, such as [`QuantCacheKey::new`] of the histogram that has been quantized, is saved along,
+    /// so that the other side can check that the palette is for the images it expects.
+    ///
+    /// State of remapping (e.g. palette refined for an image) and callbacks aren't saved.
+    /// Checkpoints can only be read by the same version of the library.
+    pub fn write_checkpoint(&self, histogram_digest: Option<QuantCacheKey>, out: impl Write) -> io::Result<()> {
+        let mut w = Writer(out);
+        w.0.write_all(MAGIC)?;
+        w.u8(VERSION)?;
+        match histogram_digest {
+            Some(key) => { w.u8(1)?; w.0.write_all(&key.as_u64().to_le_bytes())?; },
+            None => w.u8(0)?,
+        }
+
+        w.f64(self.gamma)?;
+        w.u8(match self.alpha_encoding { AlphaEncoding::Linear => 0, AlphaEncoding::Gamma => 1 })?;
+        match self.palette_error {
+            Some(e) => { w.u8(1)?; w.f64(e)?; },
+            None => w.u8(0)?,
+        }
+        w.u8(self.min_posterization_output)?;
+        w.u8(self.use_dither_map as u8)?;
+        match self.dither_level {
+            DitherLevel::Fixed(level) => { w.u8(0)?; w.f32(level)?; },
+            DitherLevel::Auto => w.u8(1)?,
+        }
+        w.u8(u8::from(self.gif_interlaced()))?;
+        w.u8(u8::from(self.remap_cache()))?;
+        w.u8(u8::from(self.alpha_weighted()))?;
+
+        w.u32(self.protected_colors.len() as u32)?;
+        for p in &self.protected_colors {
+            match *p {
+                ProtectedColor::Fixed(color, tolerance) => { w.u8(0)?; w.f_pixel(&color)?; w.f32(tolerance)?; },
+                ProtectedColor::Undithered(color) => { w.u8(1)?; w.rgba(color)?; },
+            }
+        }
+
+        let palette = &self.palette;
+        w.u16(palette.len() as u16)?;
+        for (color, pop) in palette.iter() {
+            w.f_pixel(color)?;
+            w.u8(u8::from(pop.is_fixed()))?;
+            w.f32(pop.popularity())?;
+        }
+        match palette.levels() {
+            Some(levels) => {
+                let (black, white) = levels.black_and_white_points();
+                w.u8(1)?;
+                w.rgba(black)?;
+                w.rgba(white)?;
+            },
+            None => w.u8(0)?,
+        }
+        w.u8(palette.alpha_levels())?;
+        w.u16(palette.tags().len() as u16)?;
+        for &(tag, idx) in palette.tags() {
+            w.u32(tag)?;
+            w.u16(u16::from(idx))?;
+        }
+        match palette.label_fallback() {
+            None => w.u8(0)?,
+            Some(LabelFallback::Nearest) => w.u8(1)?,
+            Some(LabelFallback::Color(color)) => { w.u8(2)?; w.rgba(color)?; },
+        }
+        w.0.flush()
+    }
+
+    /// Restores a result saved with [`write_checkpoint`](Self::write_checkpoint), and the histogram digest saved with it.
+    ///
+    /// The result can remap images as if it has just been quantized.
+    pub fn read_checkpoint(input: impl Read) -> io::Result<(Self, Option<QuantCacheKey>)> {
+        let mut r = Reader(input);
+        if &r.bytes::<8>()? != MAGIC {
+            return Err(invalid_data("not a checkpoint"));
+        }
+        if r.u8()? != VERSION {
+            return Err(invalid_data("unsupported checkpoint version"));
+        }
+        let histogram_digest = if r.flag()? { Some(QuantCacheKey(u64::from_le_bytes(r.bytes()?))) } else { None };
+
+        let gamma = r.f64()?;
+        if !(gamma > 0. && gamma <= 1.) {
+            return Err(invalid_data("bad gamma"));
+        }
+        let alpha_encoding = if r.flag()? { AlphaEncoding::Gamma } else { AlphaEncoding::Linear };
+        let palette_error = if r.flag()? { Some(r.f64()?) } else { None };
+        let min_posterization_output = r.u8()?;
+        if min_posterization_output > 7 {
+            return Err(invalid_data("bad posterization"));
+        }
+        let use_dither_map = match r.u8()? {
+            0 => DitherMapMode::None,
+            1 => DitherMapMode::Enabled,
+            2 => DitherMapMode::Always,
+            _ => return Err(invalid_data("bad dither map mode")),
+        };
+        let dither_level = if r.flag()? { DitherLevel::Auto } else { DitherLevel::Fixed(r.f32()?) };
+        if !dither_level.is_valid() {
+            return Err(invalid_data("bad dithering level"));
+        }
+        let (gif_interlaced, remap_cache, alpha_weighted) = (r.flag()?, r.flag()?, r.flag()?);
+
+        let protected_count = r.u32()? as usize;
+        if protected_count > 1 << 16 {
+            return Err(invalid_data("too many protected colors"));
+        }
+        let mut protected_colors = Vec::with_capacity(protected_count);
+        for _ in 0..protected_count {
+            protected_colors.push(if r.flag()? {
+                ProtectedColor::Undithered(r.rgba()?)
+            } else {
+                ProtectedColor::Fixed(r.f_pixel()?, r.f32()?)
+            });
+        }
+
+        let count = r.u16()? as usize;
+        if count == 0 || count > MAX_COLORS {
+            return Err(invalid_data("bad number of colors"));
+        }
+        let mut palette = PalF::new();
+        for _ in 0..count {
+            let color = r.f_pixel()?;
+            let fixed = r.flag()?;
+            let popularity = r.f32()?;
+            if popularity < 0. || (fixed && popularity == 0.) {
+                return Err(invalid_data("bad popularity"));
+            }
+            let pop = PalPop::new(popularity);
+            palette.push(color, if fixed { pop.to_fixed() } else { pop });
+        }
+        if r.flag()? {
+            let (black, white) = (r.rgba()?, r.rgba()?);
+            palette.set_levels(Some(Levels::from_black_and_white_points(black, white).ok_or_else(|| invalid_data("bad levels"))?));
+        }
+        palette.set_alpha_levels(r.u8()?);
+        let tag_count = r.u16()? as usize;
+        let mut tags = Vec::with_capacity(tag_count.min(count));
+        for _ in 0..tag_count {
+            let tag = r.u32()?;
+            let idx = r.u16()?;
+            if idx as usize >= count {
+                return Err(invalid_data("bad tagged index"));
+            }
+            tags.push((tag, idx as PalIndex));
+        }
+        palette.restore_tags(tags);
+        palette.set_label_fallback(match r.u8()? {
+            0 => None,
+            1 => Some(LabelFallback::Nearest),
+            2 => Some(LabelFallback::Color(r.rgba()?)),
+            _ => return Err(invalid_data("bad label fallback")),
+        });
+
+        let mut res = Self::from_parts(palette, protected_colors, gamma, alpha_encoding, palette_error, min_posterization_output, use_dither_map);
+        res.dither_level = dither_level;
+        res.set_gif_interlaced(gif_interlaced);
+        res.set_remap_cache(remap_cache);
+        res.set_alpha_weighted(alpha_weighted);
+        Ok((res, histogram_digest))
+    }
+}
@@ -0,0 +1,140 @@
+//! Comparison of two results remapping the same image (see `compare_on`)
+
+use crate::error::*;
+use crate::image::Image;
+use crate::pal::{f_pixel, gamma_lut, RGBA};
+use crate::quant::QuantizationResult;
+use crate::remap::mse_to_standard_mse;
+use crate::rows::temp_buf;
+use fallible_collections::FallibleVec;
+
+/// SSIM is computed in blocks of this many pixels square
+const SSIM_BLOCK: usize = 8;
+
+/// Which result remapped a pixel closer to the original, see [`ComparisonReport::winners`]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Winner {
+    A,
+    B,
+    /// Both have the same error (usually the same color)
+    Tie,
+}
+
+/// How close one remapped image is to the original
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ResultScore {
+    /// Mean square error, in the same units as [`QuantizationResult::remapping_error`]
+    pub mse: f64,
+    /// Mean structural similarity of luma, 1 for identical images, lower is worse
+    pub ssim: f64,
+}
+
+/// Result of [`compare_on`]
+#[derive(Debug, Clone)]
+pub struct ComparisonReport {
+    pub width: usize,
+    pub height: usize,
+    pub a: ResultScore,
+    pub b: ResultScore,
+    /// Better result of every pixel, `width * height` of them, row by row
+    pub winners: Vec<Winner>,
+}
+
+impl ComparisonReport {
+    /// Numbers of pixels won by `a` and by `b`. Ties aren't counted.
+    #[must_use]
+    pub fn wins(&self) -> (usize, usize) {
+        let a = self.winners.iter().filter(|&&w| w == Winner::A).count();
+        let b = self.winners.iter().filter(|&&w| w == Winner::B).count();
+        (a, b)
+    }
+}
+
+/// Remaps the image with both results, and reports how close each of them got to the original, overall and for every pixel.
+///
+/// Useful for tuning speed and quality settings, or for comparing palettes from different sources.
+/// Both results are remapped with their own settings, so their palettes get refined for this image, like in `remapped()`.
+pub fn compare_on(image: &mut Image<'_, '_>, a: &mut QuantizationResult, b: &mut QuantizationResult) -> Result<ComparisonReport, liq_error> {
+    let (width, height) = (image.width(), image.height());
+    let len = width * height;
+    let mut remapped = |res: &mut QuantizationResult| -> Result<Vec<RGBA>, liq_error> {
+        let mut buf: Vec<RGBA> = FallibleVec::try_with_capacity(len).map_err(|_| LIQ_OUT_OF_MEMORY)?;
+        buf.resize(len, RGBA::default());
+        res.remap_to_rgba_into(image, &mut buf)?;
+        Ok(buf)
+    };
+    let (out_a, out_b) = (remapped(a)?, remapped(b)?);
+
+    // pixels as they were remapped, which works even if the RGBA source has been freed after quantization
+    let gamma = image.gamma();
+    let mut original: Vec<f_pixel> = FallibleVec::try_with_capacity(len).map_err(|_| LIQ_OUT_OF_MEMORY)?;
+    let mut temp_row = temp_buf(width);
+    let mut rows = image.px.rows_iter(&mut temp_row)?;
+    for row in 0..height {
+        original.extend_from_slice(rows.row_f(&mut temp_row, row).get(..width).ok_or(LIQ_BUFFER_TOO_SMALL)?);
+    }
+
+    // palettes may have been converted to a different output gamma
+    let (lut_a, lut_b) = (gamma_lut(a.output_gamma()), gamma_lut(b.output_gamma()));
+    let mut winners: Vec<Winner> = FallibleVec::try_with_capacity(len).map_err(|_| LIQ_OUT_OF_MEMORY)?;
+    let (mut total_a, mut total_b) = (0., 0.);
+    for ((px, &pa), &pb) in original.iter().zip(&out_a).zip(&out_b) {
+        let error_a = px.diff(&f_pixel::from_rgba(&lut_a, pa));
+        let error_b = px.diff(&f_pixel::from_rgba(&lut_b, pb));
+        total_a += f64::from(error_a);
+        total_b += f64::from(error_b);
+        winners.push(if error_a < error_b { Winner::A } else if error_b < error_a { Winner::B } else { Winner::Tie });
+    }
+
+    let original: Vec<RGBA> = original.iter().map(|px| px.to_rgb(gamma)).collect();
+    let len = len.max(1) as f64;
+    Ok(ComparisonReport {
+        width,
+        height,
+        a: ResultScore { mse: mse_to_standard_mse(total_a / len), ssim: ssim(&original, &out_a, width) },
+        b: ResultScore { mse: mse_to_standard_mse(total_b / len), ssim: ssim(&original, &out_b, width) },
+        winners,
+    })
+}
+
+/// Premultiplied luma, 0-255
+#[inline]
+fn luma(px: RGBA) -> f64 {
+    (0.2126 * f64::from(px.r) + 0.7152 * f64::from(px.g) + 0.0722 * f64::from(px.b)) * f64::from(px.a) / 255.
+}
+
+/// Mean SSIM of non-overlapping blocks
+fn ssim(original: &[RGBA], remapped: &[RGBA], width: usize) -> f64 {
+    const C1: f64 = (0.01 * 255.) * (0.01 * 255.);
+    const C2: f64 = (0.03 * 255.) * (0.03 * 255.);
+    if width == 0 {
+        return 1.;
+    }
+    let height = original.len() / width;
+    let mut total = 0.;
+    let mut blocks = 0;
+    for by in (0..height).step_by(SSIM_BLOCK) {
+        for bx in (0..width).step_by(SSIM_BLOCK) {
+            let (mut sum_x, mut sum_y, mut sum_xx, mut sum_yy, mut sum_xy) = (0., 0., 0., 0., 0.);
+            let mut n = 0.;
+            for y in by..(by + SSIM_BLOCK).min(height) {
+                for x in bx..(bx + SSIM_BLOCK).min(width) {
+                    let (lx, ly) = (luma(original[y * width + x]), luma(remapped[y * width + x]));
+                    sum_x += lx;
+                    sum_y += ly;
+                    sum_xx += lx * lx;
+                    sum_yy += ly * ly;
+                    sum_xy += lx * ly;
+                    n += 1.;
+                }
+            }
+            let (mean_x, mean_y) = (sum_x / n, sum_y / n);
+            let var_x = sum_xx / n - mean_x * mean_x;
+            let var_y = sum_yy / n - mean_y * mean_y;
+            let cov = sum_xy / n - mean_x * mean_y;
+            total += ((2. * mean_x * mean_y + C1) * (2. * cov + C2)) / ((mean_x * mean_x + mean_y * mean_y + C1) * (var_x + var_y + C2));
+            blocks += 1;
+        }
+    }
+    if blocks == 0 { 1. } else { total / f64::from(blocks) }
+}
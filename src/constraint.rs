@@ -0,0 +1,166 @@
+//! Limits on colors of generated palettes (see `Attributes::set_palette_constraint`)
+
+use crate::cvd::ConfusionWeighting;
+use crate::pal::{f_pixel, gamma_lut, PalF, ARGBF, LIQ_WEIGHT_A, MIN_OPAQUE_A, RGBA};
+use std::hash::{Hash, Hasher};
+
+/// Colors that generated palettes are limited to, see [`Attributes::set_palette_constraint`](crate::Attributes::set_palette_constraint)
+#[derive(Debug, Clone, PartialEq)]
+pub enum PaletteConstraint {
+    /// Colors lie on ramps, which are straight lines between pairs of colors (e.g. black to sepia and sepia to white).
+    /// Translucent palette colors are on the ramps too, with their own alpha.
+    Ramps(Vec<(RGBA, RGBA)>),
+    /// Hues are within `width` degrees around `hue` (0 is red, 120 is green, 240 is blue). Grays are always allowed.
+    HueRange { hue: f32, width: f32 },
+}
+
+impl PaletteConstraint {
+    pub(crate) fn is_valid(&self) -> bool {
+        match self {
+            Self::Ramps(ramps) => !ramps.is_empty(),
+            Self::HueRange { hue, width } => hue.is_finite() && (0. ..=360.).contains(width),
+        }
+    }
+}
+
+impl Hash for PaletteConstraint {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Self::Ramps(ramps) => {
+                0u8.hash(state);
+                ramps.hash(state);
+            },
+            Self::HueRange { hue, width } => {
+                1u8.hash(state);
+                hue.to_bits().hash(state);
+                width.to_bits().hash(state);
+            },
+        }
+    }
+}
+
+/// Constraint converted to the gamma of the histogram, for moving palette colors to the closest allowed colors
+pub(crate) struct ConstraintProjection {
+    shape: Shape,
+    /// Palette is chosen in a different color space for `set_color_vision_deficiency`
+    weighting: Option<ConfusionWeighting>,
+}
+
+enum Shape {
+    /// Start and end of each ramp, opaque
+    Ramps(Vec<(ARGBF, ARGBF)>),
+    HueRange { min_hue: f32, width: f32, gamma: f64 },
+}
+
+impl ConstraintProjection {
+    pub(crate) fn new(constraint: &PaletteConstraint, gamma: f64, weighting: Option<ConfusionWeighting>) -> Self {
+        let shape = match *constraint {
+            PaletteConstraint::Ramps(ref ramps) => {
+                let lut = gamma_lut(gamma);
+                let opaque = |c: RGBA| f_pixel::from_rgba(&lut, RGBA { a: 255, ..c }).0;
+                Shape::Ramps(ramps.iter().map(|&(start, end)| (opaque(start), opaque(end))).collect())
+            },
+            PaletteConstraint::HueRange { hue, width } => Shape::HueRange {
+                min_hue: (hue - width / 2.).rem_euclid(360.),
+                width,
+                gamma,
+            },
+        };
+        Self { shape, weighting }
+    }
+
+    /// Moves colors of the palette that aren't fixed
+    pub(crate) fn project_palette(&self, palette: &mut PalF) {
+        for (color, _) in palette.iter_mut().filter(|(_, pop)| !pop.is_fixed()) {
+            if color.a >= MIN_OPAQUE_A {
+                *color = match &self.weighting {
+                    Some(w) => w.weigh(self.project(w.unweigh(*color))),
+                    None => self.project(*color),
+                };
+            }
+        }
+    }
+
+    fn project(&self, px: f_pixel) -> f_pixel {
+        match &self.shape {
+            Shape::Ramps(ramps) => {
+                let alpha = px.a / LIQ_WEIGHT_A;
+                let rgb = |c: ARGBF| [c.r, c.g, c.b];
+                let p = rgb(px.0);
+                let mut best = (f32::MAX, p);
+                for &(start, end) in ramps {
+                    // colors are premultiplied, so the ramp is scaled by alpha of the color
+                    let a = rgb(start).map(|c| c * alpha);
+                    let ab = [(end.r - start.r) * alpha, (end.g - start.g) * alpha, (end.b - start.b) * alpha];
+                    let len_sq = dot(ab, ab);
+                    let t = if len_sq > 0. { (dot([p[0] - a[0], p[1] - a[1], p[2] - a[2]], ab) / len_sq).clamp(0., 1.) } else { 0. };
+                    let q = [a[0] + ab[0] * t, a[1] + ab[1] * t, a[2] + ab[2] * t];
+                    let d = [p[0] - q[0], p[1] - q[1], p[2] - q[2]];
+                    let dist = dot(d, d);
+                    if dist < best.0 {
+                        best = (dist, q);
+                    }
+                }
+                let [r, g, b] = best.1;
+                f_pixel(ARGBF { a: px.a, r, g, b })
+            },
+            &Shape::HueRange { min_hue, width, gamma } => {
+                let rgba = px.to_rgb(gamma);
+                let hue = match hue(rgba) {
+                    Some(hue) => hue,
+                    None => return px,
+                };
+                let from_min = (hue - min_hue).rem_euclid(360.);
+                if from_min <= width {
+                    return px;
+                }
+                // closer of the two ends of the range
+                let new_hue = if from_min - width < 360. - from_min { min_hue + width } else { min_hue };
+                f_pixel::from_rgba(&gamma_lut(gamma), with_hue(rgba, new_hue))
+            },
+        }
+    }
+}
+
+#[inline]
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+/// Hue in degrees, `None` for grays
+fn hue(px: RGBA) -> Option<f32> {
+    let (r, g, b) = (f32::from(px.r), f32::from(px.g), f32::from(px.b));
+    let max = r.max(g).max(b);
+    let chroma = max - r.min(g).min(b);
+    if chroma <= 0. {
+        return None;
+    }
+    let sector = if max == r {
+        ((g - b) / chroma).rem_euclid(6.)
+    } else if max == g {
+        (b - r) / chroma + 2.
+    } else {
+        (r - g) / chroma + 4.
+    };
+    Some(sector * 60.)
+}
+
+/// Same value and saturation (HSV) with a different hue
+fn with_hue(px: RGBA, hue: f32) -> RGBA {
+    let (r, g, b) = (f32::from(px.r), f32::from(px.g), f32::from(px.b));
+    let max = r.max(g).max(b);
+    let chroma = max - r.min(g).min(b);
+    let min = max - chroma;
+    let sector = hue.rem_euclid(360.) / 60.;
+    let x = chroma * (1. - (sector.rem_euclid(2.) - 1.).abs());
+    let (r, g, b) = match sector as u8 {
+        0 => (chroma, x, 0.),
+        1 => (x, chroma, 0.),
+        2 => (0., chroma, x),
+        3 => (0., x, chroma),
+        4 => (x, 0., chroma),
+        _ => (chroma, 0., x),
+    };
+    let ch = |c: f32| (c + min).round().clamp(0., 255.) as u8;
+    RGBA::new(ch(r), ch(g), ch(b), px.a)
+}
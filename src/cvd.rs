@@ -0,0 +1,99 @@
+//! Color difference as seen with color vision deficiency (see `Attributes::set_color_vision_deficiency`)
+
+use crate::hist::{FixedColorsSet, HashColor, HistogramInternal, RgbaHasher};
+use crate::pal::{f_pixel, PalF, LIQ_WEIGHT_B, LIQ_WEIGHT_G, LIQ_WEIGHT_R};
+use std::collections::HashMap;
+
+/// Type of color vision deficiency that palettes can be made for
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ColorVisionDeficiency {
+    /// Reduced sensitivity to red (protanopia and protanomaly)
+    Protan,
+    /// Reduced sensitivity to green (deuteranopia and deuteranomaly), the most common kind
+    Deutan,
+    /// Reduced sensitivity to blue (tritanopia and tritanomaly)
+    Tritan,
+}
+
+/// Fraction of a difference along a confusion line that still counts
+const CONFUSION_WEIGHT: f32 = 0.25;
+
+impl ColorVisionDeficiency {
+    /// Change of linear RGB that only changes response of the affected cone, i.e. direction of its confusion lines.
+    ///
+    /// These are columns of LMS to RGB matrix from Viénot, Brettel & Mollon (1999).
+    fn confusion_line(self) -> [f32; 3] {
+        match self {
+            Self::Protan => [0.080_944, -0.010_249, -0.000_365],
+            Self::Deutan => [-0.130_504, 0.054_019, -0.004_122],
+            Self::Tritan => [0.116_721, -0.113_615, 0.693_511],
+        }
+    }
+}
+
+/// Linear transform of colors that shrinks differences along confusion lines, so that palette colors
+/// aren't spent on differences that can't be seen.
+///
+/// The transform is invertible, so colors chosen in the weighed space are mapped back to the real colors.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct ConfusionWeighting {
+    /// Unit vector of confusion lines in the internal color space
+    dir: [f32; 3],
+}
+
+impl ConfusionWeighting {
+    pub(crate) fn new(cvd: ColorVisionDeficiency) -> Self {
+        // the internal color space isn't linear, but channel weights matter more than the gamma
+        let [r, g, b] = cvd.confusion_line();
+        let dir = [r * LIQ_WEIGHT_R, g * LIQ_WEIGHT_G, b * LIQ_WEIGHT_B];
+        let len = dir.iter().map(|c| c * c).sum::<f32>().sqrt();
+        Self { dir: dir.map(|c| c / len) }
+    }
+
+    /// Adds `scale` times the color's component along the confusion line
+    #[inline]
+    fn scaled(&self, mut px: f_pixel, scale: f32) -> f_pixel {
+        let [dr, dg, db] = self.dir;
+        let t = (px.r * dr + px.g * dg + px.b * db) * scale;
+        px.r += t * dr;
+        px.g += t * dg;
+        px.b += t * db;
+        px
+    }
+
+    #[inline]
+    pub(crate) fn weigh(&self, px: f_pixel) -> f_pixel {
+        self.scaled(px, CONFUSION_WEIGHT - 1.)
+    }
+
+    /// Inverse of `weigh`
+    #[inline]
+    pub(crate) fn unweigh(&self, px: f_pixel) -> f_pixel {
+        self.scaled(px, 1. / CONFUSION_WEIGHT - 1.)
+    }
+
+    pub(crate) fn weigh_histogram(&self, hist: &mut HistogramInternal) {
+        for item in hist.items.iter_mut() {
+            item.color = self.weigh(item.color);
+        }
+    }
+
+    pub(crate) fn weigh_fixed_colors(&self, fixed_colors: &FixedColorsSet) -> FixedColorsSet {
+        let mut weighed = HashMap::with_capacity_and_hasher(fixed_colors.len(), RgbaHasher(0));
+        weighed.extend(fixed_colors.iter().map(|(HashColor(c), &fixed)| (HashColor(self.weigh(*c)), fixed)));
+        weighed
+    }
+
+    /// Maps palette made for the weighed colors back to the real colors. Fixed colors are restored exactly.
+    pub(crate) fn unweigh_palette(&self, palette: &mut PalF, fixed_colors: &FixedColorsSet) {
+        let fixed: Vec<(f_pixel, f_pixel)> = fixed_colors.keys().map(|&HashColor(c)| (self.weigh(c), c)).collect();
+        for (color, pop) in palette.iter_mut() {
+            let original = if pop.is_fixed() { fixed.iter().find(|(weighed, _)| weighed == color) } else { None };
+            *color = match original {
+                Some(&(_, original)) => original,
+                None => self.unweigh(*color),
+            };
+        }
+    }
+}
+
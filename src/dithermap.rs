@@ -0,0 +1,246 @@
+//! Maps of edges, noise and texture of images, for adaptive dithering and for weighing the histogram (the `dither-maps` feature)
+//!
+//! Builds without the feature dither uniformly, and don't weigh colors by noise.
+
+use crate::blur::{liq_blur, liq_max3, liq_min3};
+use crate::error::*;
+use crate::image::{DitherMaps, Image};
+use crate::pal::{f_pixel, PalF, PalIndex, MIN_OPAQUE_A, ARGBF};
+use crate::remap::RowProgress;
+use crate::seacow::{RowBitmap, SeaCow};
+use crate::LIQ_HIGH_MEMORY_LIMIT;
+use rayon::iter::{IndexedParallelIterator, ParallelIterator};
+use rayon::slice::ParallelSliceMut;
+use rgb::ComponentMap;
+
+impl<'pixels, 'rows> Image<'pixels, 'rows> {
+    ///
+    /// `progress` gets the fraction of rows done so far, and aborts if it returns `true`
+    pub(crate) fn update_dither_map(&mut self, remapped_image: &RowBitmap<'_, PalIndex>, palette: &mut PalF, progress: &(dyn Fn(f32) -> bool + Sync)) -> Result<(), liq_error> {
+        let width = self.width();
+        let has_background = self.background.is_some();
+        let edges = match self.maps.edges.as_deref_mut() {
+            Some(e) => e,
+            None => return Ok(()),
+        };
+        let colors = palette.as_slice();
+
+        let rows: Vec<&[PalIndex]> = remapped_image.rows().collect();
+        let row_progress = RowProgress::new(rows.len(), progress);
+        edges.par_chunks_exact_mut(width).enumerate().for_each(|(row, edges)| {
+            if !row_progress.next_row() {
+                return;
+            }
+            let this_row = rows[row];
+            let prev_row = row.checked_sub(1).map(|r| rows[r]);
+            let next_row = rows.get(row + 1);
+            let mut lastpixel = this_row[0];
+            let mut lastcol = 0;
+            for (col, px) in this_row.iter().copied().enumerate().skip(1) {
+                if has_background && (colors[px as usize]).a < MIN_OPAQUE_A {
+                    // Transparency may or may not create an edge. When there's an explicit background set, assume no edge.
+                    continue;
+                }
+                if px != lastpixel || col == width - 1 {
+                    let mut neighbor_count = 10 * (col - lastcol);
+                    let mut i = lastcol;
+                    while i < col {
+                        if let Some(prev_row) = prev_row {
+                            let pixelabove = prev_row[i];
+                            if pixelabove == lastpixel { neighbor_count += 15; };
+                        }
+                        if let Some(next_row) = next_row {
+                            let pixelbelow = next_row[i];
+                            if pixelbelow == lastpixel { neighbor_count += 15; };
+                        }
+                        i += 1;
+                    }
+                    while lastcol <= col {
+                        let e = edges[lastcol];
+                        edges[lastcol] = ((e as u16 + 128) as f32
+                            * (255. / (255 + 128) as f32)
+                            * (1. - 20. / (20 + neighbor_count) as f32))
+                            as u8;
+                        lastcol += 1;
+                    }
+                    lastpixel = px;
+                }
+            }
+        });
+        row_progress.finish()?;
+        self.maps.dither_map = self.maps.edges.take();
+        Ok(())
+    }
+
+    /// Builds three maps:
+    ///    importance_map - approximation of areas with high-frequency noise, except straight edges. 1=flat, 0=noisy.
+    ///    edges - noise map including all edges
+    ///    variance_map - smoothness of colors around each pixel, for `DitherLevel::Auto`. 255=smooth, 0=textured.
+    ///
+    /// Rows are processed in parallel. `progress` gets the fraction of rows done so far, and aborts if it returns `true`
+    pub(crate) fn contrast_maps(&mut self, progress: &(dyn Fn(f32) -> bool + Sync)) -> Result<(), liq_error> {
+        let width = self.width();
+        let height = self.height();
+        if width < 4 || height < 4 || (3 * width * height) > LIQ_HIGH_MEMORY_LIMIT {
+            return Ok(()); // shrug
+        }
+
+        let len = width * height;
+        let DitherMaps { edges, variance_map, spare, .. } = &mut self.maps;
+        let noise = &mut self.importance_map.get_or_insert_with(|| SeaCow::boxed(DitherMaps::new_map(spare, len))).as_mut_slice()[..len];
+        let edges = &mut edges.get_or_insert_with(|| DitherMaps::new_map(spare, len))[..len];
+        let variance = &mut variance_map.get_or_insert_with(|| DitherMaps::new_map(spare, len))[..len];
+
+        let mut tmp = DitherMaps::new_map(spare, len);
+
+        let pixels = self.px.all_rows_f()?;
+        let row_progress = RowProgress::new(height, progress);
+
+        noise.par_chunks_exact_mut(width).zip(edges.par_chunks_exact_mut(width)).zip(variance.par_chunks_exact_mut(width)).enumerate().for_each(|(row, ((noise_row, edges_row), variance_row))| {
+            if !row_progress.next_row() {
+                return;
+            }
+            let prev_row = &pixels[row.saturating_sub(1) * width..][..width];
+            let curr_row = &pixels[row * width..][..width];
+            let next_row = &pixels[(row + 1).min(height - 1) * width..][..width];
+            let mut prev;
+            let mut curr = curr_row[0].0;
+            let mut next = curr;
+            for i in 0..width {
+                prev = curr;
+                curr = next;
+                next = curr_row[(i + 1).min(width - 1)].0;
+                // contrast is difference between pixels neighbouring horizontally and vertically
+                let horiz = (prev + next - curr * 2.).map(|c| c.abs()); // noise is amplified
+                let prevl = prev_row[i].0;
+                let nextl = next_row[i].0;
+                let vert = (prevl + nextl - curr * 2.).map(|c| c.abs());
+                let horiz = horiz.a.max(horiz.r).max(horiz.g.max(horiz.b));
+                let vert = vert.a.max(vert.r).max(vert.g.max(vert.b));
+                let edge = horiz.max(vert);
+                let mut z = edge - (horiz - vert).abs() * 0.5;
+                z = 1. - z.max(horiz.min(vert));
+                z *= z;
+                z *= z;
+                // 85 is about 1/3rd of weight (not 0, because noisy pixels still need to be included, just not as precisely).
+                noise_row[i] = (80. + z * 176.) as u8;
+                edges_row[i] = ((1. - edge) * 256.) as u8;
+                // standard deviation of the pixel and its 4 neighbors, in the channel that varies most
+                let mean = (prev + next + prevl + nextl + curr) * 0.2;
+                let var = [prev, next, prevl, nextl, curr].iter()
+                    .map(|&c| (c - mean).map(|c| c * c))
+                    .fold(ARGBF::default(), |sum, sq| sum + sq) * 0.2;
+                let deviation = var.a.max(var.r).max(var.g.max(var.b)).sqrt();
+                // gradients change by a few levels per pixel, and textures by tens of levels
+                variance_row[i] = (255. - (deviation * 10.).min(1.) * 255.) as u8;
+            }
+        });
+        row_progress.finish()?;
+        // noise areas are shrunk and then expanded to remove thin edges from the map
+        liq_max3(noise, &mut tmp, width, height);
+        liq_max3(&tmp, noise, width, height);
+        liq_blur(noise, &mut tmp, width, height, 3);
+        liq_max3(noise, &mut tmp, width, height);
+        liq_min3(&tmp, noise, width, height);
+        liq_min3(noise, &mut tmp, width, height);
+        liq_min3(&tmp, noise, width, height);
+        liq_min3(edges, &mut tmp, width, height);
+        liq_max3(&tmp, edges, width, height);
+        for (edges, noise) in edges.iter_mut().zip(noise) {
+            *edges = (*noise).min(*edges);
+        }
+        // textured areas are expanded a bit, so that dithering doesn't leak into them from their edges
+        liq_min3(variance, &mut tmp, width, height);
+        variance.copy_from_slice(&tmp);
+        liq_blur(variance, &mut tmp, width, height, 2);
+        spare.push(tmp);
+        Ok(())
+    }
+
+    /// Builds contrast maps, and then adjusts the importance map for text on flat backgrounds (see `Attributes::set_screenshot_mode`).
+    ///
+    /// Pixels that have the same color as a neighbor are solid text or background, and get full weight.
+    /// Pixels at edges that are next to solid ones, but don't match them, are anti-aliasing, and get very little weight.
+    pub(crate) fn text_maps(&mut self, progress: &(dyn Fn(f32) -> bool + Sync)) -> Result<(), liq_error> {
+        /// Anti-aliasing still needs some colors for smooth edges, so it's not 0
+        const ANTI_ALIASING_WEIGHT: u8 = 4;
+        /// Pixels in `edges` map below this are at edges
+        const EDGE_THRESHOLD: u8 = 224;
+
+        self.contrast_maps(progress)?;
+        let width = self.width();
+        let height = self.height();
+        let (importance_map, edges) = match (self.importance_map.as_mut(), self.maps.edges.as_deref()) {
+            (Some(importance_map), Some(edges)) => (&mut importance_map.as_mut_slice()[..width * height], &edges[..width * height]),
+            _ => return Ok(()),
+        };
+        let solid = solid_pixels(&self.px.all_rows_f()?[..width * height], width);
+
+        for (i, importance) in importance_map.iter_mut().enumerate() {
+            if solid[i] {
+                *importance = 255;
+                continue;
+            }
+            if edges[i] >= EDGE_THRESHOLD {
+                continue;
+            }
+            let x = i % width;
+            let y = i / width;
+            let next_to_solid = (y.saturating_sub(1)..(y + 2).min(height))
+                .any(|y| (x.saturating_sub(1)..(x + 2).min(width)).any(|x| solid[y * width + x]));
+            if next_to_solid {
+                *importance = (*importance).min(ANTI_ALIASING_WEIGHT);
+            }
+        }
+        Ok(())
+    }
+
+    /// Builds contrast maps, and then scales the importance map by smoothness from the variance map (see `Attributes::set_gradient_priority`).
+    ///
+    /// Textured pixels keep some weight, so that detailed areas still get colors, just fewer of them.
+    pub(crate) fn gradient_maps(&mut self, progress: &(dyn Fn(f32) -> bool + Sync)) -> Result<(), liq_error> {
+        /// Fraction of importance (out of 255) that the most textured pixels keep
+        const MIN_WEIGHT: u32 = 48;
+
+        self.contrast_maps(progress)?;
+        let len = self.width() * self.height();
+        let (importance_map, variance) = match (self.importance_map.as_mut(), self.maps.variance_map.as_deref()) {
+            (Some(importance_map), Some(variance)) => (&mut importance_map.as_mut_slice()[..len], &variance[..len]),
+            _ => return Ok(()),
+        };
+        for (importance, &smooth) in importance_map.iter_mut().zip(variance) {
+            let scale = MIN_WEIGHT + u32::from(smooth) * (255 - MIN_WEIGHT) / 255;
+            *importance = (u32::from(*importance) * scale / 255) as u8;
+        }
+        Ok(())
+    }
+
+    /// Replaces the dither map with one that disables dithering of pixels that have the same color as a neighbor
+    /// (see `Attributes::set_undithered_flat_areas`). Other pixels are dithered as much as edges allow.
+    pub(crate) fn flat_areas_dither_map(&mut self) -> Result<(), liq_error> {
+        let width = self.width();
+        let len = width * self.height();
+        let solid = solid_pixels(&self.px.all_rows_f()?[..len], width);
+        let mut dither_map = match self.maps.dither_map.take() {
+            Some(map) if map.len() == len => map,
+            _ => DitherMaps::new_map(&mut self.maps.spare, len),
+        };
+        let edges = self.maps.edges.as_deref().filter(|e| e.len() >= len);
+        for (i, (d, &solid)) in dither_map.iter_mut().zip(&solid).enumerate() {
+            *d = if solid { 0 } else { edges.map_or(255, |e| e[i]) };
+        }
+        self.maps.dither_map = Some(dither_map);
+        Ok(())
+    }
+}
+
+/// Pixels that have the same color as one of their 4 neighbors
+fn solid_pixels(pixels: &[f_pixel], width: usize) -> Vec<bool> {
+    pixels.iter().enumerate().map(|(i, px)| {
+        let x = i % width;
+        (x > 0 && pixels[i - 1] == *px)
+            || (x + 1 < width && pixels[i + 1] == *px)
+            || (i >= width && pixels[i - width] == *px)
+            || (i + width < pixels.len() && pixels[i + width] == *px)
+    }).collect()
+}
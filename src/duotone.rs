@@ -0,0 +1,161 @@
+//! Palettes of just a few colors, and duotones, chosen by splitting colors by brightness.
+//!
+//! Median cut only ever splits boxes in halves along their widest channel, which is fine for hundreds of colors,
+//! but for 2-4 colors it's better to search all splits of a single axis, which here is luma.
+
+use crate::hist::HistogramInternal;
+use crate::pal::{f_pixel, PalF, PalLen, PalPop, ARGBF, LIQ_WEIGHT_B, LIQ_WEIGHT_G, LIQ_WEIGHT_R};
+use rgb::alt::ARGB;
+use rgb::ComponentMap;
+
+/// Palettes up to this size are also tried with [`small_palette`]
+pub(crate) const SMALL_PALETTE_MAX_COLORS: PalLen = 4;
+
+/// Duotones get at most this many tones. The search gets slow for more, and they'd be barely distinguishable anyway.
+const MAX_DUOTONE_COLORS: PalLen = 64;
+
+/// Positions on the axis are quantized to this many levels for the exhaustive search
+const LEVELS: usize = 512;
+
+/// Brightness in 0-1 range (premultiplied, so transparent colors are dark)
+#[inline]
+fn luma(px: &f_pixel) -> f32 {
+    0.299 / LIQ_WEIGHT_R * px.r + 0.587 / LIQ_WEIGHT_G * px.g + 0.114 / LIQ_WEIGHT_B * px.b
+}
+
+#[derive(Copy, Clone, Default)]
+struct Sums {
+    weight: f64,
+    pos: f64,
+    pos_sq: f64,
+    color: ARGB<f64>,
+}
+
+impl Sums {
+    fn add(&mut self, other: &Sums) {
+        self.weight += other.weight;
+        self.pos += other.pos;
+        self.pos_sq += other.pos_sq;
+        self.color += other.color;
+    }
+
+    fn sub(&self, other: &Sums) -> Sums {
+        Sums {
+            weight: self.weight - other.weight,
+            pos: self.pos - other.pos,
+            pos_sq: self.pos_sq - other.pos_sq,
+            color: self.color - other.color,
+        }
+    }
+
+    /// Weighted squared error of all positions to `rep`, or to their mean if `rep` is `None`
+    fn error(&self, rep: Option<f64>) -> f64 {
+        if self.weight <= 0. {
+            return 0.;
+        }
+        match rep {
+            Some(c) => (self.pos_sq - 2. * c * self.pos + c * c * self.weight).max(0.),
+            None => (self.pos_sq - self.pos * self.pos / self.weight).max(0.),
+        }
+    }
+}
+
+/// Histogram items on a 0-1 axis, with cumulative sums for quick error of any range of levels
+struct Axis {
+    /// `cumulative[i]` is sum of levels `0..i`
+    cumulative: Vec<Sums>,
+}
+
+impl Axis {
+    fn new(hist: &HistogramInternal, position: impl Fn(&f_pixel) -> f32) -> Self {
+        let mut levels = vec![Sums::default(); LEVELS];
+        for item in hist.items.iter() {
+            let pos = position(&item.color).clamp(0., 1.);
+            let w = item.perceptual_weight as f64;
+            let level = &mut levels[((pos * LEVELS as f32) as usize).min(LEVELS - 1)];
+            level.weight += w;
+            level.pos += w * pos as f64;
+            level.pos_sq += w * (pos * pos) as f64;
+            level.color += item.color.0.map(|c| c as f64 * w);
+        }
+        let mut cumulative = Vec::with_capacity(LEVELS + 1);
+        let mut total = Sums::default();
+        cumulative.push(total);
+        for level in &levels {
+            total.add(level);
+            cumulative.push(total);
+        }
+        Self { cumulative }
+    }
+
+    #[inline]
+    fn range(&self, start: usize, end: usize) -> Sums {
+        self.cumulative[end].sub(&self.cumulative[start])
+    }
+
+    /// Splits levels into `reps.len()` consecutive ranges with the lowest total error.
+    /// Each range is represented by its `reps` value, or its mean if that's `None`.
+    fn optimal_split(&self, reps: &[Option<f64>]) -> Vec<Sums> {
+        let n = reps.len();
+        debug_assert!(n > 0 && n <= LEVELS);
+        // error[s][end] is the lowest error of ranges 0..=s covering levels 0..end
+        let mut error = vec![vec![f64::INFINITY; LEVELS + 1]; n];
+        let mut start_of = vec![vec![0; LEVELS + 1]; n];
+        for (end, e) in error[0].iter_mut().enumerate().skip(1) {
+            *e = self.range(0, end).error(reps[0]);
+        }
+        for s in 1..n {
+            for end in s + 1..=LEVELS {
+                let (best_start, best_error) = (s..end)
+                    .map(|start| (start, error[s - 1][start] + self.range(start, end).error(reps[s])))
+                    .fold((s, f64::INFINITY), |best, x| if x.1 < best.1 { x } else { best });
+                error[s][end] = best_error;
+                start_of[s][end] = best_start;
+            }
+        }
+
+        let mut ranges = vec![Sums::default(); n];
+        let mut end = LEVELS;
+        for s in (0..n).rev() {
+            let start = if s > 0 { start_of[s][end] } else { 0 };
+            ranges[s] = self.range(start, end);
+            end = start;
+        }
+        ranges
+    }
+}
+
+/// Palette of up to `max_colors` averages of colors with similar brightness. Empty ranges are left out.
+pub(crate) fn small_palette(hist: &HistogramInternal, max_colors: PalLen) -> PalF {
+    let axis = Axis::new(hist, luma);
+    let reps = vec![None; max_colors.into()];
+    let mut palette = PalF::new();
+    for range in axis.optimal_split(&reps).iter().filter(|r| r.weight > 0.) {
+        let color = range.color.map(|c| (c / range.weight) as f32);
+        palette.push(f_pixel(color), PalPop::new(range.weight as f32));
+    }
+    palette
+}
+
+/// Palette of `dark`, `light`, and up to `max_colors - 2` mixes of them in between, at brightness levels that fit the histogram best.
+///
+/// The colors are fixed, so that they're not moved off the line between `dark` and `light`.
+pub(crate) fn duotone_palette(hist: &HistogramInternal, max_colors: PalLen, dark: f_pixel, light: f_pixel) -> PalF {
+    let dark_luma = luma(&dark);
+    let luma_range = (luma(&light) - dark_luma).max(f32::EPSILON);
+    let axis = Axis::new(hist, |px| (luma(px) - dark_luma) / luma_range);
+
+    let n = usize::from(max_colors.clamp(2, MAX_DUOTONE_COLORS));
+    let reps: Vec<_> = (0..n).map(|i| if i == 0 { Some(0.) } else if i == n - 1 { Some(1.) } else { None }).collect();
+    let mut palette = PalF::new();
+    for (range, rep) in axis.optimal_split(&reps).iter().zip(reps) {
+        let t = match rep {
+            Some(t) => t as f32,
+            None if range.weight > 0. => (range.pos / range.weight) as f32,
+            None => continue,
+        };
+        let color: ARGBF = dark.0 + (light.0 - dark.0) * t;
+        palette.push(f_pixel(color), PalPop::new(range.weight as f32).to_fixed());
+    }
+    palette
+}
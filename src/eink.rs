@@ -0,0 +1,133 @@
+//! Remapping for color e-paper displays, which have a few fixed inks and no transparency
+
+use crate::error::*;
+use crate::image::Image;
+use crate::pal::RGBA;
+use crate::remap::{remap_to_device_palette, DitherKernel, DitherLevel, RemapOptions};
+use fallible_collections::FallibleVec;
+use std::mem::MaybeUninit;
+
+/// Common e-paper panels
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+#[non_exhaustive]
+pub enum EinkPanel {
+    /// Black and white
+    BlackWhite,
+    /// Black, white and red (BWR)
+    BlackWhiteRed,
+    /// Black, white and yellow (BWY)
+    BlackWhiteYellow,
+    /// 7-color ACeP (Advanced Color ePaper): black, white, green, blue, red, yellow and orange
+    Acep7,
+}
+
+impl EinkPanel {
+    /// Colors of the inks as they look on the panel, in order of the panel's color indices.
+    ///
+    /// They're approximate, and much less saturated than the colors the inks are named after, so that dithering can reproduce
+    /// colors of the image with what the panel can actually show.
+    #[must_use]
+    pub fn palette(self) -> &'static [RGBA] {
+        const BLACK: RGBA = RGBA { r: 40, g: 36, b: 40, a: 255 };
+        const WHITE: RGBA = RGBA { r: 255, g: 255, b: 255, a: 255 };
+        match self {
+            Self::BlackWhite => &[BLACK, WHITE],
+            Self::BlackWhiteRed => &[BLACK, WHITE, RGBA { r: 170, g: 40, b: 40, a: 255 }],
+            Self::BlackWhiteYellow => &[BLACK, WHITE, RGBA { r: 215, g: 190, b: 40, a: 255 }],
+            Self::Acep7 => &[
+                RGBA { r: 57, g: 48, b: 57, a: 255 },
+                WHITE,
+                RGBA { r: 58, g: 91, b: 70, a: 255 },
+                RGBA { r: 61, g: 59, b: 94, a: 255 },
+                RGBA { r: 156, g: 72, b: 75, a: 255 },
+                RGBA { r: 208, g: 190, b: 71, a: 255 },
+                RGBA { r: 177, g: 106, b: 73, a: 255 },
+            ],
+        }
+    }
+
+    /// Largest dithering error passed to neighboring pixels, as a fraction of each channel's range.
+    ///
+    /// Colors outside of the panel's gamut have errors that never go away, and unclamped they'd smear across large flat areas.
+    /// The fewer colors the panel has, the more error is needed to dither them into shades.
+    fn max_dither_error(self) -> f32 {
+        match self {
+            Self::BlackWhite => 0.6,
+            Self::BlackWhiteRed | Self::BlackWhiteYellow => 0.4,
+            Self::Acep7 => 0.25,
+        }
+    }
+}
+
+/// Remaps images to the fixed palette of an e-paper panel.
+///
+/// Transparent pixels are flattened onto the paper color, and the output indices are the panel's own color indices.
+#[derive(Clone)]
+pub struct EinkRemapper {
+    panel: EinkPanel,
+    background: RGBA,
+    dither_level: f32,
+    kernel: DitherKernel,
+}
+
+impl EinkRemapper {
+    /// Full Floyd-Steinberg dithering, on white background
+    #[must_use]
+    pub fn new(panel: EinkPanel) -> Self {
+        Self {
+            panel,
+            background: RGBA { r: 255, g: 255, b: 255, a: 255 },
+            dither_level: 1.,
+            kernel: DitherKernel::FloydSteinberg,
+        }
+    }
+
+    /// Colors of the panel, in order of the remapped indices. See [`EinkPanel::palette`].
+    #[inline]
+    #[must_use]
+    pub fn palette(&self) -> &'static [RGBA] {
+        self.panel.palette()
+    }
+
+    /// Color that semi-transparent pixels are blended with. Default is white. Its alpha is ignored.
+    #[inline]
+    pub fn set_background(&mut self, color: RGBA) {
+        self.background = color;
+    }
+
+    /// 0-1. Default is 1 (full dithering).
+    pub fn set_dithering_level(&mut self, value: f32) -> liq_error {
+        if !(0. ..=1.).contains(&value) {
+            return LIQ_VALUE_OUT_OF_RANGE;
+        }
+        self.dither_level = value;
+        LIQ_OK
+    }
+
+    /// Error diffusion pattern. Default is Floyd-Steinberg.
+    #[inline]
+    pub fn set_dither_kernel(&mut self, kernel: DitherKernel) {
+        self.kernel = kernel;
+    }
+
+    /// Remap image into a new `Vec` of the panel's color indices
+    pub fn remapped(&self, image: &mut Image<'_, '_>) -> Result<Vec<u8>, liq_error> {
+        let len = image.width() * image.height();
+        // Capacity is essential here, as it creates uninitialized buffer
+        unsafe {
+            let mut buf: Vec<u8> = FallibleVec::try_with_capacity(len).map_err(|_| LIQ_OUT_OF_MEMORY)?;
+            let uninit_slice = std::slice::from_raw_parts_mut(buf.as_mut_ptr().cast::<MaybeUninit<u8>>(), buf.capacity());
+            self.remap_into(image, uninit_slice)?;
+            buf.set_len(len);
+            Ok(buf)
+        }
+    }
+
+    /// Remap image into `output_buf` of the panel's color indices. The buffer must be `width * height` large.
+    pub fn remap_into(&self, image: &mut Image<'_, '_>, output_buf: &mut [MaybeUninit<u8>]) -> Result<(), liq_error> {
+        let mut options = RemapOptions::default();
+        options.set_dither_level(DitherLevel::Fixed(self.dither_level)).ok()?;
+        options.set_kernel(self.kernel);
+        remap_to_device_palette(image, output_buf, self.panel.palette(), 0, self.background, &options, self.panel.max_dither_error())
+    }
+}
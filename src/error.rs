@@ -1,4 +1,5 @@
 pub use liq_error::*;
+use crate::quant::QuantizationResult;
 use std::fmt;
 
 #[repr(C)]
@@ -72,3 +73,93 @@ impl liq_error {
         assert!(self.is_ok(), "{}", msg);
     }
 }
+
+/// Error of [`Attributes::quantize_detailed`](crate::Attributes::quantize_detailed), which keeps the palette if quality was too low
+#[derive(Debug)]
+pub enum QuantizeError {
+    /// Quality was below the minimum set with `set_quality` or `set_max_pixel_error` (`LIQ_QUALITY_TOO_LOW`).
+    ///
+    /// It has the best palette that was found anyway. Its [`QuantizationResult::quantization_error`] and
+    /// [`QuantizationResult::quantization_quality`] tell how close it got, and it can be used for remapping like any other result.
+    QualityTooLow(Box<QuantizationResult>),
+    /// Any other error
+    Other(liq_error),
+}
+
+impl From<liq_error> for QuantizeError {
+    #[inline]
+    fn from(err: liq_error) -> Self {
+        Self::Other(err)
+    }
+}
+
+impl From<QuantizeError> for liq_error {
+    #[inline]
+    fn from(err: QuantizeError) -> Self {
+        match err {
+            QuantizeError::QualityTooLow(_) => LIQ_QUALITY_TOO_LOW,
+            QuantizeError::Other(err) => err,
+        }
+    }
+}
+
+impl std::error::Error for QuantizeError {}
+
+impl fmt::Display for QuantizeError {
+    #[cold]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::QualityTooLow(result) => write!(f, "QUALITY_TOO_LOW (q={})", result.quantization_quality().unwrap_or(0)),
+            Self::Other(err) => err.fmt(f),
+        }
+    }
+}
+
+/// Problem with an image found by [`Image::validate`](crate::Image::validate)
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ImageError {
+    /// Pixels have been freed after the image was added to a histogram, so it can't be used again (`LIQ_UNSUPPORTED`)
+    PixelsNotAvailable,
+    /// There are fewer row pointers than the height of the image (`LIQ_BUFFER_TOO_SMALL`)
+    MissingRows { rows: usize, height: usize },
+    /// Row pointer at this index is null (`LIQ_INVALID_POINTER`)
+    NullRow(usize),
+    /// Importance map is smaller than `width * height` (`LIQ_BUFFER_TOO_SMALL`)
+    ImportanceMapTooSmall { len: usize, required: usize },
+    /// Background image has a different size than the image (`LIQ_BUFFER_TOO_SMALL`)
+    BackgroundSizeMismatch,
+    /// Background image itself is invalid
+    Background(Box<ImageError>),
+    /// Callback that provides rows panicked when asked for the first row (`LIQ_BITMAP_NOT_AVAILABLE`)
+    RowCallbackFailed,
+}
+
+impl From<ImageError> for liq_error {
+    #[inline]
+    fn from(err: ImageError) -> Self {
+        match err {
+            ImageError::PixelsNotAvailable => LIQ_UNSUPPORTED,
+            ImageError::MissingRows { .. } | ImageError::ImportanceMapTooSmall { .. } | ImageError::BackgroundSizeMismatch => LIQ_BUFFER_TOO_SMALL,
+            ImageError::NullRow(_) => LIQ_INVALID_POINTER,
+            ImageError::Background(err) => (*err).into(),
+            ImageError::RowCallbackFailed => LIQ_BITMAP_NOT_AVAILABLE,
+        }
+    }
+}
+
+impl std::error::Error for ImageError {}
+
+impl fmt::Display for ImageError {
+    #[cold]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PixelsNotAvailable => f.write_str("pixels are no longer available"),
+            Self::MissingRows { rows, height } => write!(f, "{} rows for image height {}", rows, height),
+            Self::NullRow(row) => write!(f, "row {} is null", row),
+            Self::ImportanceMapTooSmall { len, required } => write!(f, "importance map has {} pixels, needs {}", len, required),
+            Self::BackgroundSizeMismatch => f.write_str("background has a different size"),
+            Self::Background(err) => write!(f, "background: {}", err),
+            Self::RowCallbackFailed => f.write_str("row callback failed"),
+        }
+    }
+}
@@ -0,0 +1,204 @@
+//! Palettes larger than 256 colors, with 16-bit indices
+
+use crate::error::*;
+use crate::hist::{Cluster, HistItem, Histogram, HistogramInternal, RgbaHasher, LIQ_MAXCLUSTER};
+use crate::image::Image;
+use crate::mediancut::mediancut;
+use crate::nearest::Nearest;
+use crate::pal::{f_pixel, AlphaEncoding, PalF, PalLen, MAX_COLORS, RGBA};
+use crate::quant::{find_best_palette, quality_to_mse};
+use crate::remap::{get_dithered_pixel, Remapped};
+use crate::rows::temp_buf;
+use crate::Attributes;
+use fallible_collections::FallibleVec;
+use std::collections::HashMap;
+use std::mem::MaybeUninit;
+
+/// Largest palette supported by [`Attributes::quantize_extended`]
+pub const MAX_EXTENDED_COLORS: u32 = 4096;
+
+/// Palette of up to 4096 colors made by [`Attributes::quantize_extended`].
+///
+/// Colors are split into groups of up to 256 similar colors. Each pixel is remapped to the best color
+/// in the group closest to it, so the result can be slightly worse than a search of the whole palette.
+#[derive(Clone)]
+pub struct ExtendedQuantizationResult {
+    /// Representative color of each group
+    group_centers: PalF,
+    /// Colors of each group, matching a range of `palette`
+    groups: Vec<PalF>,
+    palette: Vec<RGBA>,
+    gamma: f64,
+    dither_level: f32,
+}
+
+pub(crate) fn quantize_extended(attr: &Attributes, image: &mut Image<'_, '_>, max_colors: u32) -> Result<ExtendedQuantizationResult, liq_error> {
+    if !(2..=MAX_EXTENDED_COLORS).contains(&max_colors) {
+        return Err(LIQ_VALUE_OUT_OF_RANGE);
+    }
+    if !image.fixed_colors.is_empty() || image.alpha_encoding() != AlphaEncoding::Linear {
+        return Err(LIQ_UNSUPPORTED);
+    }
+
+    let mut hist = Histogram::new(attr);
+    hist.add_image(attr, image)?;
+    let (mut hist, _, gamma) = hist.finalize(attr)?;
+
+    // Split histogram into groups that fit in a regular palette
+    let num_groups = (max_colors as usize).div_ceil(MAX_COLORS) as PalLen;
+    let colors_per_group = (max_colors as usize / num_groups as usize) as PalLen;
+    let group_centers = if num_groups > 1 {
+        mediancut(&mut hist, num_groups, 0., quality_to_mse(1), false)
+    } else {
+        let mut c = PalF::new();
+        c.push(f_pixel::default(), crate::pal::PalPop::new(1.));
+        c
+    };
+    let mut grouped_items = vec![Vec::new(); group_centers.len()];
+    {
+        let n = Nearest::new(&group_centers);
+        for item in hist.items.iter() {
+            let (group, _) = n.search(&item.color, 0);
+            grouped_items[group as usize].push(item.clone());
+        }
+    }
+
+    let mut group_attr = attr.clone();
+    group_attr.set_max_colors(colors_per_group.into()).ok()?;
+    let posterize = attr.min_posterization();
+
+    let mut groups = Vec::with_capacity(grouped_items.len());
+    let mut palette = Vec::with_capacity(max_colors as usize);
+    for items in grouped_items {
+        let mut group_palette = if items.is_empty() {
+            PalF::new()
+        } else {
+            let hist = histogram_of_items(items);
+            let (max_mse, target_mse, target_mse_is_zero) = group_attr.target_mse(hist.items.len());
+            let (group_palette, _, pixel_error_too_high) = find_best_palette(&group_attr, target_mse, target_mse_is_zero, max_mse, hist, &HashMap::with_hasher(RgbaHasher(0)), None)?;
+            if pixel_error_too_high {
+                return Err(LIQ_QUALITY_TOO_LOW);
+            }
+            group_palette
+        };
+        if !group_palette.as_slice().is_empty() {
+            let int_palette = Remapped::make_int_palette(&mut group_palette, gamma, AlphaEncoding::Linear, posterize);
+            palette.extend_from_slice(int_palette.as_slice());
+        }
+        groups.push(group_palette);
+    }
+
+    Ok(ExtendedQuantizationResult {
+        group_centers,
+        groups,
+        palette,
+        gamma,
+        dither_level: 1.,
+    })
+}
+
+fn histogram_of_items(items: Vec<HistItem>) -> HistogramInternal {
+    let len = items.len() as u32;
+    let mut clusters = [Cluster { begin: len, end: len }; LIQ_MAXCLUSTER];
+    clusters[0].begin = 0;
+    HistogramInternal {
+        total_perceptual_weight: items.iter().map(|i| i.perceptual_weight as f64).sum(),
+        items: items.into_boxed_slice(),
+        clusters,
+        alpha_weighted: false,
+    }
+}
+
+impl ExtendedQuantizationResult {
+    /// Palette for indices of remapped images
+    #[inline]
+    #[must_use]
+    pub fn palette(&self) -> &[RGBA] {
+        &self.palette
+    }
+
+    /// Gamma of the palette and images it has been made for
+    #[inline]
+    #[must_use]
+    pub fn output_gamma(&self) -> f64 {
+        self.gamma
+    }
+
+    /// 0-1. Default is 1 (full Floyd-Steinberg dithering).
+    pub fn set_dithering_level(&mut self, value: f32) -> liq_error {
+        if !(0. ..=1.).contains(&value) {
+            return LIQ_VALUE_OUT_OF_RANGE;
+        }
+        self.dither_level = value;
+        LIQ_OK
+    }
+
+    /// Remap image into a new `Vec` of 16-bit palette indices
+    pub fn remapped_u16(&self, image: &mut Image<'_, '_>) -> Result<Vec<u16>, liq_error> {
+        let len = image.width() * image.height();
+        // Capacity is essential here, as it creates uninitialized buffer
+        unsafe {
+            let mut buf: Vec<u16> = FallibleVec::try_with_capacity(len).map_err(|_| LIQ_OUT_OF_MEMORY)?;
+            let uninit_slice = std::slice::from_raw_parts_mut(buf.as_mut_ptr().cast::<MaybeUninit<u16>>(), buf.capacity());
+            self.remap_into_u16(image, uninit_slice)?;
+            buf.set_len(len);
+            Ok(buf)
+        }
+    }
+
+    /// Remap image into `output_buf` of 16-bit palette indices. The buffer must be `width * height` large.
+    pub fn remap_into_u16(&self, image: &mut Image<'_, '_>, output_buf: &mut [MaybeUninit<u16>]) -> Result<(), liq_error> {
+        let width = image.width();
+        let height = image.height();
+        let output_buf = output_buf.get_mut(0..width * height).ok_or(LIQ_BUFFER_TOO_SMALL)?;
+        if image.px.gamma != self.gamma {
+            return Err(LIQ_UNSUPPORTED);
+        }
+
+        let centers = Nearest::new(&self.group_centers);
+        let groups: Vec<_> = self.groups.iter().map(|g| (!g.as_slice().is_empty()).then(|| Nearest::new(g))).collect();
+        let mut offsets = Vec::with_capacity(self.groups.len());
+        let mut offset = 0;
+        for g in &self.groups {
+            offsets.push(offset);
+            offset += g.len();
+        }
+
+        // finds color in the closest non-empty group
+        let search = |px: &f_pixel, last_group: usize| -> (usize, usize, f_pixel) {
+            let (g, _) = centers.search(px, last_group as _);
+            let g = if groups[g as usize].is_some() { g as usize } else { last_group };
+            let (idx, _) = groups[g].as_ref().unwrap().search(px, 0);
+            (g, offsets[g] + idx as usize, self.groups[g].as_slice()[idx as usize])
+        };
+        let first_group = groups.iter().position(|g| g.is_some()).ok_or(LIQ_UNSUPPORTED)?;
+
+        let mut temp_row = temp_buf(width);
+        let mut rows = image.px.rows_iter(&mut temp_row)?;
+        let dither_level = self.dither_level * 15. / 16.;
+        let max_dither_error = quality_to_mse(35) as f32;
+        let mut thiserr = vec![f_pixel::default(); width + 2];
+        let mut nexterr = vec![f_pixel::default(); width + 2];
+        let mut last_group = first_group;
+
+        for (row, output_row) in output_buf.chunks_exact_mut(width).enumerate() {
+            let row_pixels = rows.row_f(&mut temp_row, row);
+            nexterr.fill_with(f_pixel::default);
+            for (col, (px, out)) in row_pixels.iter().zip(output_row).enumerate() {
+                let spx = if dither_level > 0. { get_dithered_pixel(dither_level, max_dither_error, thiserr[col + 1], *px) } else { *px };
+                let (group, idx, color) = search(&spx, last_group);
+                last_group = group;
+                out.write(idx as u16);
+                if dither_level > 0. {
+                    let err = spx.0 - color.0;
+                    thiserr[col + 2].0 += err * (7. / 16.);
+                    nexterr[col + 2].0 = err * (1. / 16.);
+                    nexterr[col + 1].0 += err * (5. / 16.);
+                    nexterr[col].0 += err * (3. / 16.);
+                }
+            }
+            std::mem::swap(&mut thiserr, &mut nexterr);
+        }
+        Ok(())
+    }
+}
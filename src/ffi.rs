@@ -6,13 +6,16 @@ use crate::hist::*;
 use crate::image::*;
 use crate::pal::*;
 use crate::quant::*;
-use crate::rows::PixelsSource;
+use crate::remap::RemapScratch;
+use crate::rows::{PixelsSource, RowCallback};
 use crate::seacow::*;
 use std::ffi::CString;
 use std::mem::MaybeUninit;
 use std::os::raw::c_char;
 use std::os::raw::{c_int, c_uint, c_void};
 use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 pub type liq_attr = crate::Attributes;
 pub type liq_palette = crate::Palette;
@@ -25,6 +28,7 @@ pub type liq_log_callback_function = unsafe extern "C" fn(liq: &liq_attr, messag
 pub type liq_log_flush_callback_function = unsafe extern "C" fn(liq: &liq_attr, user_info: *mut c_void);
 pub type liq_progress_callback_function = unsafe extern "C" fn(progress_percent: f32, user_info: *mut c_void) -> c_int;
 pub type liq_image_get_rgba_row_callback = unsafe extern "C" fn(row_out: *mut MaybeUninit<RGBA>, row: c_int, width: c_int, user_info: *mut c_void);
+pub type liq_image_user_info_free_callback = unsafe extern "C" fn(user_info: *mut c_void);
 
 #[repr(transparent)]
 #[derive(PartialEq, Debug, Copy, Clone)]
@@ -168,6 +172,72 @@ pub extern "C" fn liq_get_min_posterization(attr: &liq_attr) -> c_uint {
     attr.min_posterization().into()
 }
 
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn liq_set_max_pixel_error(attr: &mut liq_attr, max_error: f64) -> liq_error {
+    if bad_object!(attr, LIQ_ATTR_MAGIC) { return LIQ_INVALID_POINTER; }
+    attr.set_max_pixel_error(max_error)
+}
+
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn liq_get_max_pixel_error(attr: &liq_attr) -> f64 {
+    if bad_object!(attr, LIQ_ATTR_MAGIC) { return -1.; }
+    attr.max_pixel_error().unwrap_or(-1.)
+}
+
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn liq_set_duotone(attr: &mut liq_attr, dark: liq_color, light: liq_color) -> liq_error {
+    if bad_object!(attr, LIQ_ATTR_MAGIC) { return LIQ_INVALID_POINTER; }
+    attr.set_duotone(dark, light)
+}
+
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn liq_set_screenshot_mode(attr: &mut liq_attr, enabled: c_int) -> liq_error {
+    if bad_object!(attr, LIQ_ATTR_MAGIC) { return LIQ_INVALID_POINTER; }
+    #[cfg(feature = "dither-maps")]
+    {
+        attr.set_screenshot_mode(enabled != 0);
+        LIQ_OK
+    }
+    #[cfg(not(feature = "dither-maps"))]
+    {
+        let _ = enabled;
+        LIQ_UNSUPPORTED
+    }
+}
+
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn liq_set_preserve_extremes(attr: &mut liq_attr, enabled: c_int) {
+    if bad_object!(attr, LIQ_ATTR_MAGIC) { return };
+    attr.set_preserve_extremes(enabled != 0);
+}
+
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn liq_set_alpha_levels(attr: &mut liq_attr, levels: c_uint) -> liq_error {
+    if bad_object!(attr, LIQ_ATTR_MAGIC) { return LIQ_INVALID_POINTER; }
+    attr.set_alpha_levels(levels)
+}
+
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn liq_set_histogram_precision(attr: &mut liq_attr, bits: c_int) -> liq_error {
+    if bad_object!(attr, LIQ_ATTR_MAGIC) { return LIQ_INVALID_POINTER; }
+    if !(0..=8).contains(&bits) { return LIQ_VALUE_OUT_OF_RANGE; }
+    attr.set_histogram_precision(bits as u8)
+}
+
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn liq_set_auto_levels(attr: &mut liq_attr, enabled: c_int) {
+    if bad_object!(attr, LIQ_ATTR_MAGIC) { return };
+    attr.set_auto_levels(enabled != 0);
+}
+
 #[no_mangle]
 #[inline(never)]
 pub extern "C" fn liq_set_speed(attr: &mut liq_attr, speed: c_int) -> liq_error {
@@ -215,7 +285,7 @@ pub extern "C" fn liq_quantize_image(attr: &mut liq_attr, img: &mut Image) -> Op
 
 #[no_mangle]
 #[inline(never)]
-pub unsafe extern "C" fn liq_write_remapped_image(result: &mut liq_result, input_image: &mut Image, buffer_bytes: *mut MaybeUninit<u8>, buffer_size: usize) -> liq_error {
+pub unsafe extern "C" fn liq_write_remapped_image(result: &mut liq_result, input_image: &mut Image, buffer_bytes: *mut MaybeUninit<PalIndex>, buffer_size: usize) -> liq_error {
     if bad_object!(result, LIQ_RESULT_MAGIC) ||
        bad_object!(input_image, LIQ_IMAGE_MAGIC) { return LIQ_INVALID_POINTER; }
 
@@ -225,22 +295,22 @@ pub unsafe extern "C" fn liq_write_remapped_image(result: &mut liq_result, input
     if buffer_size < required_size { return LIQ_BUFFER_TOO_SMALL; }
     let buffer_bytes = std::slice::from_raw_parts_mut(buffer_bytes, required_size);
 
-    let rows = RowBitmapMut::new_contiguous(buffer_bytes, input_image.width());
-    result.write_remapped_image_rows_internal(input_image, rows).err().unwrap_or(LIQ_OK)
+    let mut rows = RowBitmapMut::new_contiguous(buffer_bytes, input_image.width());
+    result.write_remapped_image_rows_internal(input_image, &mut rows, &mut RemapScratch::new()).err().unwrap_or(LIQ_OK)
 }
 
 #[no_mangle]
 #[inline(never)]
-pub unsafe extern "C" fn liq_write_remapped_image_rows(result: &mut liq_result, input_image: &mut Image, row_pointers: *mut *mut MaybeUninit<u8>) -> liq_error {
+pub unsafe extern "C" fn liq_write_remapped_image_rows(result: &mut liq_result, input_image: &mut Image, row_pointers: *mut *mut MaybeUninit<PalIndex>) -> liq_error {
     if bad_object!(result, LIQ_RESULT_MAGIC) ||
        bad_object!(input_image, LIQ_IMAGE_MAGIC) { return LIQ_INVALID_POINTER; }
 
     if liq_received_invalid_pointer(row_pointers.cast()) { return LIQ_INVALID_POINTER; }
 
     let rows = std::slice::from_raw_parts_mut(row_pointers, input_image.height());
-    let rows = RowBitmapMut::new(rows, input_image.width());
+    let mut rows = RowBitmapMut::new(rows, input_image.width());
 
-    result.write_remapped_image_rows_internal(input_image, rows).err().unwrap_or(LIQ_OK)
+    result.write_remapped_image_rows_internal(input_image, &mut rows, &mut RemapScratch::new()).err().unwrap_or(LIQ_OK)
 }
 
 #[no_mangle]
@@ -406,6 +476,13 @@ pub extern "C" fn liq_get_remapping_quality(result: &liq_result) -> c_int {
     result.remapping_quality().map(c_int::from).unwrap_or(-1)
 }
 
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn liq_get_transparent_index(result: &liq_result) -> c_int {
+    if bad_object!(result, LIQ_RESULT_MAGIC) { return -1; }
+    result.transparent_index().map(c_int::from).unwrap_or(-1)
+}
+
 #[no_mangle]
 #[inline(never)]
 pub fn liq_image_quantize(img: &mut Image, attr: &liq_attr, write_only_output: &mut MaybeUninit<Option<Box<liq_result>>>) -> liq_error {
@@ -414,7 +491,7 @@ pub fn liq_image_quantize(img: &mut Image, attr: &liq_attr, write_only_output: &
 
     let mut hist = Histogram::new(attr);
     if let Err(err) = hist.add_image(attr, img) { return err; }
-    store_boxed_result(hist.quantize_internal(attr, false), write_only_output)
+    store_boxed_result(hist.quantize_internal(attr, false).map_err(liq_error::from), write_only_output)
 }
 
 #[no_mangle]
@@ -423,7 +500,7 @@ pub fn liq_histogram_quantize(hist: &mut Histogram, attr: &liq_attr, write_only_
     if bad_object!(attr, LIQ_ATTR_MAGIC) ||
        bad_object!(hist, LIQ_HISTOGRAM_MAGIC) { return LIQ_INVALID_POINTER; }
 
-    store_boxed_result(hist.quantize_internal(attr, true), write_only_output)
+    store_boxed_result(hist.quantize_internal(attr, true).map_err(liq_error::from), write_only_output)
 }
 
 #[inline]
@@ -434,21 +511,9 @@ fn store_boxed_result<T>(res: Result<T, liq_error>, out: &mut MaybeUninit<Option
     }
 }
 
-pub(crate) fn check_image_size(attr: &liq_attr, width: u32, height: u32) -> bool {
+fn check_image_size(attr: &liq_attr, width: u32, height: u32) -> bool {
     if bad_object!(attr, LIQ_ATTR_MAGIC) { return false; }
-
-    if width == 0 || height == 0 {
-        attr.verbose_print("  error: width and height must be > 0");
-        return false;
-    }
-    if width as usize > c_int::MAX as usize / std::mem::size_of::<liq_color>() / height as usize
-        || width as usize > c_int::MAX as usize / 16 / std::mem::size_of::<f_pixel>()
-        || height as usize > c_int::MAX as usize / std::mem::size_of::<usize>()
-    {
-        attr.verbose_print("  error: image too large");
-        return false;
-    }
-    true
+    crate::image::check_image_size(attr, width, height)
 }
 
 #[no_mangle]
@@ -459,6 +524,67 @@ pub unsafe extern "C" fn liq_image_create_custom(attr: &liq_attr, row_callback:
     liq_image::new_internal(attr, PixelsSource::Callback(db), width, height, gamma).ok().map(Box::new)
 }
 
+/// User data of a row callback, freed with the C destructor when the image drops the callback
+struct RowCallbackUserInfo {
+    /// One for the whole image, or one per row
+    user_info: Box<[AnySyncSendPtr]>,
+    free_callback: Option<liq_image_user_info_free_callback>,
+    /// Set once the image has been created. Until then the caller owns the user data, and it's not freed.
+    owned: Arc<AtomicBool>,
+}
+
+impl RowCallbackUserInfo {
+    #[inline]
+    fn for_row(&self, row: usize) -> *mut c_void {
+        self.user_info.get(row).unwrap_or(&self.user_info[0]).0
+    }
+}
+
+impl Drop for RowCallbackUserInfo {
+    fn drop(&mut self) {
+        if !self.owned.load(Ordering::Acquire) {
+            return;
+        }
+        if let Some(free_callback) = self.free_callback {
+            for user_info in self.user_info.iter() {
+                unsafe { free_callback(user_info.0) };
+            }
+        }
+    }
+}
+
+/// The image takes ownership of the user data only if it's created. Otherwise it's left for the caller to free.
+unsafe fn image_from_row_callback(attr: &liq_attr, row_callback: liq_image_get_rgba_row_callback, user_info: Box<[AnySyncSendPtr]>, free_callback: Option<liq_image_user_info_free_callback>, width: c_uint, height: c_uint, gamma: f64) -> Option<Box<liq_image<'static, 'static>>> {
+    let owned = Arc::new(AtomicBool::new(false));
+    let user_info = RowCallbackUserInfo { user_info, free_callback, owned: owned.clone() };
+    let db: Box<RowCallback> = Box::new(move |row, y| row_callback(row.as_mut_ptr(), y as _, row.len() as _, user_info.for_row(y)));
+    let img = liq_image::new_internal(attr, PixelsSource::Callback(db), width, height, gamma).ok()?;
+    owned.store(true, Ordering::Release);
+    Some(Box::new(img))
+}
+
+/// Like `liq_image_create_custom`, but `free_callback` (if not NULL) is called with `user_info` once the image doesn't need it any more,
+/// at the latest when the image is destroyed. If the image can't be created, the caller keeps ownership of `user_info`.
+#[no_mangle]
+#[inline(never)]
+pub unsafe extern "C" fn liq_image_create_custom_with_free(attr: &liq_attr, row_callback: liq_image_get_rgba_row_callback, user_info: AnySyncSendPtr, free_callback: Option<liq_image_user_info_free_callback>, width: c_uint, height: c_uint, gamma: f64)
+ -> Option<Box<Image<'static, 'static>>> {
+    if bad_object!(attr, LIQ_ATTR_MAGIC) { return None; }
+    image_from_row_callback(attr, row_callback, Box::new([user_info]), free_callback, width, height, gamma)
+}
+
+/// Like `liq_image_create_custom`, but the callback gets its own user data for each row, from the `row_user_info` array of `height` pointers
+/// (e.g. rows of a streaming decoder). The array is copied. `free_callback` (if not NULL) is called for each of them once the image doesn't need them any more.
+#[no_mangle]
+#[inline(never)]
+pub unsafe extern "C" fn liq_image_create_custom_rows(attr: &liq_attr, row_callback: liq_image_get_rgba_row_callback, row_user_info: *const *mut c_void, free_callback: Option<liq_image_user_info_free_callback>, width: c_uint, height: c_uint, gamma: f64)
+ -> Option<Box<Image<'static, 'static>>> {
+    if bad_object!(attr, LIQ_ATTR_MAGIC) || row_user_info.is_null() { return None; }
+    if !check_image_size(attr, width, height) { return None; }
+    let user_info = std::slice::from_raw_parts(row_user_info, height as usize).iter().map(|&p| AnySyncSendPtr(p)).collect();
+    image_from_row_callback(attr, row_callback, user_info, free_callback, width, height, gamma)
+}
+
 #[no_mangle]
 #[inline(never)]
 pub unsafe extern "C" fn liq_image_create_rgba_rows<'rows>(attr: &liq_attr, rows: *const *const u8, width: c_uint, height: c_uint, gamma: f64) -> Option<Box<Image<'rows, 'static>>> {
@@ -510,6 +636,16 @@ pub extern "C" fn liq_histogram_add_image(input_hist: &mut Histogram, attr: &liq
     input_hist.add_image(attr, input_image).err().unwrap_or(LIQ_OK)
 }
 
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn liq_histogram_add_image_weighted(input_hist: &mut Histogram, attr: &liq_attr, input_image: &mut Image, weight: f32) -> liq_error {
+    if bad_object!(attr, LIQ_ATTR_MAGIC) ||
+       bad_object!(input_hist, LIQ_HISTOGRAM_MAGIC) ||
+       bad_object!(input_image, LIQ_IMAGE_MAGIC) { return LIQ_INVALID_POINTER; }
+
+    input_hist.add_image_weighted(attr, input_image, weight).err().unwrap_or(LIQ_OK)
+}
+
 #[no_mangle]
 #[inline(never)]
 pub unsafe fn liq_executing_user_callback(callback: liq_image_get_rgba_row_callback, temp_row: &mut [MaybeUninit<liq_color>], row: usize, user_info: *mut std::os::raw::c_void) {
@@ -557,6 +693,14 @@ fn link_every_symbol() {
         + liq_get_min_quality as *const c_void as usize
         + liq_get_max_quality as *const c_void as usize
         + liq_set_last_index_transparent as *const c_void as usize
+        + liq_set_max_pixel_error as *const c_void as usize
+        + liq_get_max_pixel_error as *const c_void as usize
+        + liq_set_duotone as *const c_void as usize
+        + liq_set_screenshot_mode as *const c_void as usize
+        + liq_set_preserve_extremes as *const c_void as usize
+        + liq_set_auto_levels as *const c_void as usize
+        + liq_set_alpha_levels as *const c_void as usize
+        + liq_set_histogram_precision as *const c_void as usize
         + liq_image_create_rgba_rows as *const c_void as usize
         + liq_image_create_rgba as *const c_void as usize
         + liq_image_set_memory_ownership as *const c_void as usize
@@ -565,6 +709,8 @@ fn link_every_symbol() {
         + liq_attr_set_progress_callback as *const c_void as usize
         + liq_result_set_progress_callback as *const c_void as usize
         + liq_image_create_custom as *const c_void as usize
+        + liq_image_create_custom_with_free as *const c_void as usize
+        + liq_image_create_custom_rows as *const c_void as usize
         + liq_image_set_background as *const c_void as usize
         + liq_image_set_importance_map as *const c_void as usize
         + liq_image_add_fixed_color as *const c_void as usize
@@ -573,6 +719,7 @@ fn link_every_symbol() {
         + liq_image_destroy as *const c_void as usize
         + liq_histogram_create as *const c_void as usize
         + liq_histogram_add_image as *const c_void as usize
+        + liq_histogram_add_image_weighted as *const c_void as usize
         + liq_histogram_add_colors as *const c_void as usize
         + liq_histogram_add_fixed_color as *const c_void as usize
         + liq_histogram_destroy as *const c_void as usize
@@ -590,6 +737,7 @@ fn link_every_symbol() {
         + liq_result_destroy as *const c_void as usize
         + liq_get_remapping_error as *const c_void as usize
         + liq_get_remapping_quality as *const c_void as usize
+        + liq_get_transparent_index as *const c_void as usize
         + liq_version as *const c_void as usize;
     assert_ne!(!0, x);
 }
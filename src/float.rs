@@ -0,0 +1,55 @@
+//! Images of floating-point pixels, such as renders or decoded EXR images, with a policy for pixels that aren't finite numbers
+
+use crate::error::*;
+use crate::image::{check_image_size, image_size, Image};
+use crate::pal::RGBA;
+use crate::Attributes;
+
+/// What [`Image::new_f32`] does with pixels that have NaN or infinite channels, which would otherwise poison averages of colors
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum NonFinitePolicy {
+    /// NaN channels become 0, and infinities are clamped to 0 or 1 like other values out of range
+    #[default]
+    Clamp,
+    /// Pixels with any channel that isn't finite become fully transparent
+    Transparent,
+    /// The image is rejected with `LIQ_VALUE_OUT_OF_RANGE`
+    Error,
+}
+
+impl NonFinitePolicy {
+    #[inline]
+    fn to_rgba(self, px: [f32; 4]) -> RGBA {
+        if self == Self::Transparent && px.iter().any(|c| !c.is_finite()) {
+            return RGBA::new(0, 0, 0, 0);
+        }
+        let [r, g, b, a] = px.map(|c| if c.is_nan() { 0 } else { (c.clamp(0., 1.) * 255. + 0.5) as u8 });
+        RGBA::new(r, g, b, a)
+    }
+}
+
+impl Image<'static, 'static> {
+    /// Image of `width * height` RGBA pixels with channels from 0 to 1, which are encoded with `gamma` the same way as 8-bit pixels of [`Image::new`].
+    ///
+    /// Channels are clamped to 0-1 and rounded to 8 bits a row at a time, and pixels that aren't finite numbers are handled as `policy` says.
+    /// The pixels are copied.
+    pub fn new_f32(attr: &Attributes, pixels: &[[f32; 4]], width: usize, height: usize, gamma: f64, policy: NonFinitePolicy) -> Result<Self, liq_error> {
+        let (width_u32, height_u32) = image_size(attr, width, height)?;
+        if !check_image_size(attr, width_u32, height_u32) {
+            return Err(LIQ_BUFFER_TOO_SMALL);
+        }
+        let pixels = pixels.get(..width * height).ok_or(LIQ_BUFFER_TOO_SMALL)?;
+        if policy == NonFinitePolicy::Error && pixels.iter().flatten().any(|c| !c.is_finite()) {
+            attr.verbose_print("  error: pixels have NaN or infinite values");
+            return Err(LIQ_VALUE_OUT_OF_RANGE);
+        }
+        let pixels = pixels.to_vec();
+        unsafe {
+            Image::new_fn(attr, move |row, y| {
+                for (px, &f) in row.iter_mut().zip(&pixels[y * width..(y + 1) * width]) {
+                    px.write(policy.to_rgba(f));
+                }
+            }, width, height, gamma)
+        }
+    }
+}
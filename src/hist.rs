@@ -1,19 +1,21 @@
 use crate::error::*;
-use crate::ffi::MagicTag;
-use crate::ffi::{LIQ_FREED_MAGIC, LIQ_HISTOGRAM_MAGIC};
+#[cfg(feature = "capi")]
+use crate::ffi::{MagicTag, LIQ_FREED_MAGIC, LIQ_HISTOGRAM_MAGIC};
 use crate::image::Image;
+use crate::labels::label_palette;
+use crate::levels::Levels;
 use crate::pal::PalIndex;
 use crate::pal::ARGBF;
-use crate::pal::{f_pixel, gamma_lut, RGBA};
+use crate::pal::{f_pixel, gamma_lut, AlphaEncoding, MAX_COLORS, RGBA};
 use crate::quant::QuantizationResult;
+use crate::remap::{alpha_to_level, standard_mse_to_mse, RemapScratch};
 use crate::rows::temp_buf;
 use crate::rows::DynamicRows;
 use crate::Attributes;
 use rgb::ComponentSlice;
-use std::collections::{HashMap, HashSet};
-use std::convert::TryInto;
+use std::collections::HashMap;
 use std::fmt;
-use std::hash::Hash;
+use std::hash::{Hash, Hasher};
 use std::os::raw::c_uint;
 
 /// Number of pixels in a given color
@@ -26,22 +28,106 @@ pub struct HistogramEntry {
     pub count: c_uint,
 }
 
+/// Summary of colors collected in a [`Histogram`], see [`Histogram::stats`]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct HistogramStats {
+    /// Number of distinct colors. All fully transparent colors count as one.
+    pub colors: usize,
+    /// Estimated entropy of the colors, in bits per pixel.
+    ///
+    /// Pixel counts are weighted by their visual importance, so this is only an approximation of the true entropy.
+    pub entropy: f64,
+    /// Number of distinct colors that are fully transparent (0 or 1)
+    pub transparent_colors: usize,
+    /// Number of distinct colors that are neither fully opaque nor fully transparent
+    pub translucent_colors: usize,
+}
+
 /// Generate one shared palette for multiple images.
 pub struct Histogram {
+    #[cfg(feature = "capi")]
     pub(crate) magic_header: MagicTag,
     gamma: Option<f64>,
+    alpha_encoding: AlphaEncoding,
     fixed_colors: FixedColorsSet,
 
     /// maps RGBA as u32 to (boosted) count
-    hashmap: HashMap<u32, (u32, RGBA), RgbaHasher>,
+    hashmap: ColorHash,
     /// how many pixels were counted
     total_area: usize,
 
     posterize_bits: u8,
     max_histogram_entries: u32,
+    /// See `set_estimate_unique_colors`
+    estimate_unique_colors: bool,
+    /// Counts colors before posterization, since the hashmap can't once it's posterized
+    unposterized_colors: Option<Box<ColorCountSketch>>,
+}
+
+/// Colors of a histogram, see `Histogram::hashmap`
+pub(crate) type ColorHash = HashMap<u32, (u32, RGBA), RgbaHasher>;
+
+/// Fixed colors mapped to their tolerance and tag
+pub(crate) type FixedColorsSet = HashMap<HashColor, FixedColor, RgbaHasher>;
+
+#[derive(Debug, Copy, Clone, Default)]
+pub(crate) struct FixedColor {
+    /// 0 if the color only has to be in the palette
+    pub tolerance: f32,
+    /// Set with `add_fixed_color_tagged`, to find the color's palette index
+    pub tag: Option<u32>,
+}
+
+/// HyperLogLog estimate of the number of distinct colors, in constant memory
+#[derive(Clone)]
+struct ColorCountSketch {
+    registers: [u8; ColorCountSketch::REGISTERS],
+}
+
+impl Default for ColorCountSketch {
+    fn default() -> Self {
+        Self { registers: [0; Self::REGISTERS] }
+    }
+}
+
+impl ColorCountSketch {
+    /// Bits of the hash used to choose the register. 2^10 registers give about 3% error.
+    const INDEX_BITS: u32 = 10;
+    const REGISTERS: usize = 1 << Self::INDEX_BITS;
+
+    #[inline(always)]
+    fn insert(&mut self, px_int: u32) {
+        // HyperLogLog needs well-mixed bits
+        let h = mix64(u64::from(px_int));
+        let index = (h >> (64 - Self::INDEX_BITS)) as usize;
+        let rank = ((h << Self::INDEX_BITS).leading_zeros() + 1).min(64 - Self::INDEX_BITS + 1) as u8;
+        let r = &mut self.registers[index];
+        *r = (*r).max(rank);
+    }
+
+    fn estimate(&self) -> usize {
+        let m = Self::REGISTERS as f64;
+        let alpha = 0.7213 / (1. + 1.079 / m);
+        let sum: f64 = self.registers.iter().map(|&r| (-f64::from(r)).exp2()).sum();
+        let estimate = alpha * m * m / sum;
+        let empty = self.registers.iter().filter(|&&r| r == 0).count();
+        // linear counting is more accurate for small counts
+        if estimate <= 2.5 * m && empty > 0 {
+            (m * (m / empty as f64).ln()).round() as usize
+        } else {
+            estimate.round() as usize
+        }
+    }
 }
 
-pub(crate) type FixedColorsSet = HashSet<HashColor, RgbaHasher>;
+/// splitmix64 finalizer
+#[inline(always)]
+fn mix64(x: u64) -> u64 {
+    let mut h = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    h = (h ^ (h >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    h ^ (h >> 31)
+}
 
 #[derive(Clone)]
 pub(crate) struct HistItem {
@@ -87,31 +173,86 @@ impl Histogram {
     pub fn new(attr: &Attributes) -> Self {
         Self {
             posterize_bits: attr.posterize_bits(),
-            max_histogram_entries: attr.max_histogram_entries,
-            fixed_colors: HashSet::with_hasher(RgbaHasher(0)),
+            max_histogram_entries: attr.histogram_entries_limit(),
+            estimate_unique_colors: false,
+            unposterized_colors: None,
+            fixed_colors: HashMap::with_hasher(RgbaHasher(0)),
             hashmap: HashMap::with_hasher(RgbaHasher(0)),
+            #[cfg(feature = "capi")]
             magic_header: LIQ_HISTOGRAM_MAGIC,
             gamma: None,
+            alpha_encoding: AlphaEncoding::Linear,
             total_area: 0,
         }
     }
 
+    /// Uses the color hash of a histogram given to [`RemapScratch::reclaim_histogram`], so that collecting colors doesn't allocate it again.
+    ///
+    /// It only has an effect before any colors have been added.
+    pub fn reuse_buffers(&mut self, scratch: &mut RemapScratch) {
+        if self.hashmap.is_empty() {
+            if let Some(hashmap) = scratch.take_color_hash() {
+                self.hashmap = hashmap;
+            }
+        }
+    }
+
+    /// The emptied color hash, keeping its capacity
+    pub(crate) fn take_color_hash(&mut self) -> ColorHash {
+        let mut hashmap = std::mem::replace(&mut self.hashmap, HashMap::with_hasher(RgbaHasher(0)));
+        hashmap.clear();
+        hashmap
+    }
+
     /// "Learns" colors from the image, which will be later used to generate the palette.
     ///
     /// Fixed colors added to the image are also added to the histogram. If the total number of fixed colors exceeds 256,
     /// this function will fail with `LIQ_BUFFER_TOO_SMALL`.
-    #[inline(never)]
+    #[inline]
     pub fn add_image(&mut self, attr: &Attributes, image: &mut Image) -> Result<(), liq_error> {
+        self.add_image_weighted(attr, image, 1.)
+    }
+
+    /// Like `add_image()`, but each pixel of the image counts `weight` times as much as usual.
+    ///
+    /// Useful when images are of different importance, e.g. levels of a mip chain that should share a palette,
+    /// or frames of an animation where a long-lasting title card would otherwise get more than its share of colors.
+    /// A frame shown for a fraction of the animation's duration can be weighed by that fraction.
+    /// The weight must be between 0 and 256.
+    #[inline(never)]
+    pub fn add_image_weighted(&mut self, attr: &Attributes, image: &mut Image, weight: f32) -> Result<(), liq_error> {
+        if !(0. ..=256.).contains(&weight) {
+            return Err(LIQ_VALUE_OUT_OF_RANGE);
+        }
         let width = image.width();
         let height = image.height();
-        if image.importance_map.is_none() && attr.use_contrast_maps {
-            image.contrast_maps()?;
+        // labels are ranked by their plain counts
+        #[cfg(feature = "dither-maps")]
+        if image.importance_map.is_none() && attr.label_mode.is_none() {
+            let progress = |done: f32| attr.progress(attr.progress_stage1 as f32 * 0.40 * done);
+            if attr.screenshot_mode() {
+                image.text_maps(&progress)?;
+            } else if attr.gradient_priority() {
+                image.gradient_maps(&progress)?;
+            } else if attr.use_contrast_maps {
+                image.contrast_maps(&progress)?;
+            }
+        }
+        #[cfg(feature = "dither-maps")]
+        if attr.undithered_flat_areas() {
+            image.flat_areas_dither_map()?;
         }
 
         self.gamma = Some(image.gamma());
+        self.alpha_encoding = image.alpha_encoding();
 
-        for c in image.fixed_colors.iter().copied() {
-            self.fixed_colors.insert(HashColor(c));
+        for (c, fixed) in image.fixed_colors.iter().copied() {
+            self.insert_fixed_color(c, fixed);
+        }
+        if attr.preserve_extremes() {
+            for c in image.extreme_colors()? {
+                self.insert_fixed_color(c, FixedColor::default());
+            }
         }
 
         if attr.progress(attr.progress_stage1 as f32 * 0.40) {
@@ -125,7 +266,7 @@ impl Histogram {
 
         let importance_map = image.importance_map.as_ref().map(|m| m.as_slice());
 
-        self.add_pixel_rows(&mut image.px, importance_map, posterize_bits)?;
+        self.add_pixel_rows(&mut image.px, importance_map, posterize_bits, weight)?;
         image.free_histogram_inputs();
 
         Ok(())
@@ -134,6 +275,7 @@ impl Histogram {
     /// Alternative to `add_image()`. Intead of counting colors in an image, it directly takes an array of colors and their counts.
     ///
     /// This function is only useful if you already have a histogram of the image from another source.
+    /// Counts are capped at 65535, and aren't weighed the same as pixels of images. See `add_color_counts()` for exact weights.
     #[inline(never)]
     pub fn add_colors(&mut self, entries: &[HistogramEntry], gamma: f64) -> Result<(), liq_error> {
         if entries.is_empty() || entries.len() > 1 << 24 {
@@ -149,62 +291,256 @@ impl Histogram {
 
         self.total_area += entries.len();
         for e in entries {
-            self.add_color(e.color, e.count.try_into().unwrap_or(u16::MAX));
+            self.add_color(e.color, e.count.min(u16::MAX.into()));
+        }
+
+        Ok(())
+    }
+
+    /// Like `add_colors()`, but each count is weighed exactly like that many pixels of an image added with `add_image()`.
+    ///
+    /// Useful for exact color frequencies computed elsewhere, e.g. from an indexed image or a database of swatches,
+    /// especially when they're mixed with images in the same histogram. Colors with a count of 0 are ignored.
+    #[inline(never)]
+    pub fn add_color_counts(&mut self, colors: &[(RGBA, u32)], gamma: f64) -> Result<(), liq_error> {
+        if colors.is_empty() || colors.len() > 1 << 24 {
+            return Err(LIQ_VALUE_OUT_OF_RANGE);
+        }
+
+        if !(0. ..1.).contains(&gamma) {
+            return Err(LIQ_VALUE_OUT_OF_RANGE);
+        }
+
+        self.gamma = Some(if gamma > 0. { gamma } else { 0.45455 });
+        self.reserve(colors.len());
+
+        for &(color, count) in colors.iter().filter(|&&(_, count)| count > 0) {
+            self.total_area = self.total_area.saturating_add(count as usize);
+            // pixels of images have importance of 255 each
+            self.add_color(color, count.saturating_mul(255));
         }
 
         Ok(())
     }
 
     /// Add a color guaranteed to be in the final palette
+    #[inline]
     pub fn add_fixed_color(&mut self, color: RGBA, gamma: f64) -> liq_error {
+        self.add_fixed_color_with_tolerance(color, 0., gamma)
+    }
+
+    /// Add a color guaranteed to be in the final palette, and protect it from being mixed with similar colors.
+    ///
+    /// Colors within `tolerance` of the fixed color won't be used for other palette entries, and will be remapped
+    /// exactly to the fixed color, without dithering. The tolerance is in the same units as [`QuantizationResult::quantization_error`].
+    pub fn add_fixed_color_with_tolerance(&mut self, color: RGBA, tolerance: f64, gamma: f64) -> liq_error {
+        if tolerance.is_nan() || tolerance < 0. {
+            return LIQ_VALUE_OUT_OF_RANGE;
+        }
+        self.add_fixed_color_internal(color, FixedColor { tolerance: standard_mse_to_mse(tolerance) as f32, tag: None }, gamma)
+    }
+
+    /// Like `add_fixed_color`, but with a tag that identifies the color in the palette.
+    ///
+    /// After quantization, [`QuantizationResult::tagged_index`] gives palette index of the color,
+    /// regardless of how the palette has been sorted.
+    pub fn add_fixed_color_tagged(&mut self, color: RGBA, tag: u32, gamma: f64) -> liq_error {
+        self.add_fixed_color_internal(color, FixedColor { tolerance: 0., tag: Some(tag) }, gamma)
+    }
+
+    fn add_fixed_color_internal(&mut self, color: RGBA, fixed: FixedColor, gamma: f64) -> liq_error {
         let lut = gamma_lut(if gamma > 0. { gamma } else { 0.45455 });
         let px = f_pixel::from_rgba(&lut, RGBA{r: color.r, g: color.g, b: color.b, a: color.a,});
 
-        if self.fixed_colors.len() > 255 {
+        if self.fixed_colors.len() >= MAX_COLORS {
             return LIQ_UNSUPPORTED;
         }
-        self.fixed_colors.insert(HashColor(px));
+        self.insert_fixed_color(px, fixed);
         LIQ_OK
     }
 
+    fn insert_fixed_color(&mut self, px: f_pixel, fixed: FixedColor) {
+        self.fixed_colors.entry(HashColor(px))
+            .and_modify(|f| {
+                f.tolerance = f.tolerance.max(fixed.tolerance);
+                f.tag = fixed.tag.or(f.tag);
+            })
+            .or_insert(fixed);
+    }
+
     /// Generate palette for all images/colors added to the histogram.
     ///
     /// Palette generated using this function won't be improved during remapping.
     /// If you're generating palette for only one image, it's better not to use the `Histogram`.
     #[inline]
     pub fn quantize(&mut self, attr: &Attributes) -> Result<QuantizationResult, liq_error> {
+        Ok(self.quantize_internal(attr, true)?)
+    }
+
+    /// Like `quantize()`, but if quality is too low, the error has the palette that was found. See [`QuantizeError`].
+    #[inline]
+    pub fn quantize_detailed(&mut self, attr: &Attributes) -> Result<QuantizationResult, QuantizeError> {
         self.quantize_internal(attr, true)
     }
 
-    #[inline(never)]
-    pub(crate) fn quantize_internal(&mut self, attr: &Attributes, freeze_result_colors: bool) -> Result<QuantizationResult, liq_error> {
+    /// Tries each of the `Attributes` in order, until one of them gives a palette of good enough quality.
+    /// They should be increasingly relaxed, e.g. allow more colors or lower quality.
+    ///
+    /// Colors are added to the histogram only once, and reused for every attempt. Settings that affect how images
+    /// are added to the histogram (such as posterization) are used from `Attributes` that the histogram has been created with.
+    ///
+    /// If none of them is good enough, the error has the palette from the last attempt.
+    pub fn quantize_with_fallback(&mut self, attr_list: &[Attributes]) -> Result<QuantizationResult, QuantizeError> {
+        self.quantize_first_acceptable(attr_list, true)
+    }
+
+    pub(crate) fn quantize_first_acceptable<'a>(&mut self, attr_list: impl IntoIterator<Item = &'a Attributes>, freeze_result_colors: bool) -> Result<QuantizationResult, QuantizeError> {
+        let mut last_err = QuantizeError::Other(LIQ_VALUE_OUT_OF_RANGE);
+        for attr in attr_list {
+            match self.quantize_internal(attr, freeze_result_colors) {
+                Err(err @ QuantizeError::QualityTooLow(_)) => {
+                    attr.verbose_print("  quality too low, trying next settings");
+                    last_err = err;
+                },
+                res => return res,
+            }
+        }
+        Err(last_err)
+    }
+
+    /// Makes [`Histogram::unique_colors_estimate`] count colors before posterization. It hashes every pixel added, so it's off by default.
+    ///
+    /// It must be enabled before any colors are added, otherwise it fails with `LIQ_UNSUPPORTED`.
+    pub fn set_estimate_unique_colors(&mut self, enabled: bool) -> liq_error {
+        if !self.hashmap.is_empty() {
+            return LIQ_UNSUPPORTED;
+        }
+        self.estimate_unique_colors = enabled;
+        self.unposterized_colors = if enabled && self.posterize_bits > 0 { Some(Box::default()) } else { None };
+        LIQ_OK
+    }
+
+    /// Number of distinct colors added so far, before posterization.
+    ///
+    /// It's exact, unless the histogram has been posterized (because of `Attributes::set_min_posterization`, `set_histogram_precision`, or too many colors).
+    /// Then it's estimated within a few percent if `set_estimate_unique_colors` has been enabled, and otherwise it's the number of posterized colors.
+    /// Fully transparent colors count as one.
+    #[must_use]
+    pub fn unique_colors_estimate(&self) -> usize {
+        match &self.unposterized_colors {
+            Some(sketch) => sketch.estimate().max(self.hashmap.len()),
+            None => self.hashmap.len(),
+        }
+    }
+
+    /// Statistics about colors added so far, e.g. to skip quantization of images that already have few colors.
+    ///
+    /// Colors are counted after posterization (see `Attributes::set_min_posterization`), and fixed colors are not included.
+    #[must_use]
+    pub fn stats(&self) -> HistogramStats {
+        let total: f64 = self.hashmap.values().map(|&(count, _)| count as f64).sum();
+        let entropy = if total > 0. {
+            -self.hashmap.values().map(|&(count, _)| {
+                let p = count as f64 / total;
+                if p > 0. { p * p.log2() } else { 0. }
+            }).sum::<f64>()
+        } else { 0. };
+        HistogramStats {
+            colors: self.hashmap.len(),
+            entropy,
+            transparent_colors: self.hashmap.values().filter(|(_, c)| c.a == 0).count(),
+            translucent_colors: self.hashmap.values().filter(|(_, c)| c.a != 0 && c.a != 255).count(),
+        }
+    }
+
+    /// Histogram for palette generation, with a copy of the fixed colors and gamma of the images
+    pub(crate) fn finalize(&mut self, attr: &Attributes) -> Result<(HistogramInternal, FixedColorsSet, f64), liq_error> {
         if self.hashmap.is_empty() && self.fixed_colors.is_empty() {
             return Err(LIQ_UNSUPPORTED);
         }
+        let gamma = self.gamma.unwrap_or(0.45455);
+        let (_, target_mse, _) = attr.target_mse(self.hashmap.len());
+        Ok((self.finalize_builder(gamma, target_mse, None, attr.alpha_levels, attr.alpha_weighted_colors()), self.fixed_colors.clone(), gamma))
+    }
+
+    #[inline(always)]
+    pub(crate) fn alpha_encoding(&self) -> AlphaEncoding {
+        self.alpha_encoding
+    }
+
+    #[inline(never)]
+    /// Hashes everything that decides the palette, regardless of the order in which the colors have been added
+    pub(crate) fn hash_contents<H: Hasher>(&self, state: &mut H) {
+        // sums don't depend on iteration order of the hashmaps
+        let colors = self.hashmap.iter().fold(0u64, |sum, (&px_int, &(count, rgba))| {
+            let rgba_int = unsafe { RGBAInt { rgba }.int };
+            sum.wrapping_add(mix64(mix64(u64::from(px_int) | u64::from(count) << 32) ^ u64::from(rgba_int)))
+        });
+        let fixed_colors = self.fixed_colors.iter().fold(0u64, |sum, (HashColor(px), fixed)| {
+            let tag = fixed.tag.map_or(0, |tag| u64::from(tag) << 32 | 1);
+            let h = [px.a, px.r, px.g, px.b].iter().fold(mix64(tag) ^ u64::from(fixed.tolerance.to_bits()), |h, c| mix64(h ^ u64::from(c.to_bits())));
+            sum.wrapping_add(h)
+        });
+        self.gamma.map(f64::to_bits).hash(state);
+        (self.alpha_encoding as u8).hash(state);
+        self.posterize_bits.hash(state);
+        self.max_histogram_entries.hash(state);
+        self.hashmap.len().hash(state);
+        colors.hash(state);
+        self.fixed_colors.len().hash(state);
+        fixed_colors.hash(state);
+    }
+
+    pub(crate) fn quantize_internal(&mut self, attr: &Attributes, freeze_result_colors: bool) -> Result<QuantizationResult, QuantizeError> {
+        if self.hashmap.is_empty() && self.fixed_colors.is_empty() {
+            return Err(LIQ_UNSUPPORTED.into());
+        }
 
-        if attr.progress(0.) { return Err(LIQ_ABORTED); }
+        if attr.progress(0.) { return Err(LIQ_ABORTED.into()); }
         if attr.progress(attr.progress_stage1 as f32 * 0.89) {
-            return Err(LIQ_ABORTED);
+            return Err(LIQ_ABORTED.into());
         }
 
         let gamma = self.gamma.unwrap_or(0.45455);
+        if let Some(fallback) = attr.label_mode {
+            let mut palette = label_palette(self.hashmap.values().map(|&(count, rgba)| (rgba, count)), attr.max_colors, &self.fixed_colors, gamma, self.alpha_encoding);
+            attr.verbose_print(format!("  kept {} of {} label colors", palette.len(), self.hashmap.len()));
+            palette.set_label_fallback(Some(fallback));
+            return Ok(QuantizationResult::with_palette(attr, palette, None, &self.fixed_colors, gamma, self.alpha_encoding));
+        }
         let (_, target_mse, _) = attr.target_mse(self.hashmap.len());
-        let hist = self.finalize_builder(gamma, target_mse);
+        let levels = self.auto_levels(attr);
+        let hist = self.finalize_builder(gamma, target_mse, levels.as_ref(), attr.alpha_levels, attr.alpha_weighted_colors());
 
         attr.verbose_print(format!("  made histogram...{} colors found", hist.items.len()));
 
-        QuantizationResult::new(attr, hist, freeze_result_colors, &self.fixed_colors, gamma)
+        QuantizationResult::new(attr, hist, freeze_result_colors, &self.fixed_colors, gamma, self.alpha_encoding, levels)
+    }
+
+    /// Fixed colors must stay exact, and duotone colors are given by the user, so stretching can't be used with them
+    fn auto_levels(&self, attr: &Attributes) -> Option<Levels> {
+        if !attr.auto_levels() || !self.fixed_colors.is_empty() || attr.duotone.is_some() {
+            return None;
+        }
+        let levels = Levels::from_histogram(self.hashmap.values().map(|&(count, rgba)| (rgba, count)))?;
+        let (black, white) = levels.black_and_white_points();
+        attr.verbose_print(format!("  stretching levels from {:?} - {:?}", black.rgb(), white.rgb()));
+        Some(levels)
     }
 
     #[inline(always)]
-    fn add_color(&mut self, rgba: RGBA, boost: u16) {
+    fn add_color(&mut self, rgba: RGBA, boost: u32) {
         let px_int = if rgba.a != 0 {
-            self.posterize_mask() & unsafe { RGBAInt { rgba }.int }
+            unsafe { RGBAInt { rgba }.int }
         } else { 0 };
+        if let Some(sketch) = &mut self.unposterized_colors {
+            sketch.insert(px_int);
+        }
+        let px_int = self.posterize_mask() & px_int;
 
         self.hashmap.entry(px_int)
-            .and_modify(move |e| e.0 += boost as u32)
-            .or_insert((boost as u32, rgba));
+            .and_modify(move |e| e.0 = e.0.saturating_add(boost))
+            .or_insert((boost, rgba));
     }
 
     fn reserve(&mut self, entries: usize) {
@@ -223,6 +559,13 @@ impl Histogram {
         if self.posterize_bits >= posterize_bits {
             return;
         }
+        if self.posterize_bits == 0 && self.estimate_unique_colors {
+            let mut sketch = Box::<ColorCountSketch>::default();
+            for &px_int in self.hashmap.keys() {
+                sketch.insert(px_int);
+            }
+            self.unposterized_colors = Some(sketch);
+        }
         self.posterize_bits = posterize_bits;
         let new_posterize_mask = self.posterize_mask();
 
@@ -233,10 +576,12 @@ impl Histogram {
         }));
     }
 
-    pub(crate) fn add_pixel_rows(&mut self, image: &mut DynamicRows<'_, '_>, importance_map: Option<&[u8]>, posterize_bits: u8) -> Result<(), liq_error> {
+    pub(crate) fn add_pixel_rows(&mut self, image: &mut DynamicRows<'_, '_>, importance_map: Option<&[u8]>, posterize_bits: u8, weight: f32) -> Result<(), liq_error> {
         let width = image.width as usize;
         let height = image.height as usize;
-        self.total_area += width * height;
+        self.total_area += ((width * height) as f32 * weight) as usize;
+        // weight of 1 is common, and must not change counts because of rounding
+        let boost = |importance: u8| if weight == 1. { u32::from(importance) } else { (f32::from(importance) * weight).round() as u32 };
 
         let mut importance_map = importance_map.unwrap_or(&[]).chunks_exact(width).fuse();
         let image_iter = image.rgba_rows_iter()?;
@@ -246,7 +591,7 @@ impl Histogram {
             let pixels_row = &image_iter.row_rgba(&mut temp_row, row)[..width];
             let importance_map = importance_map.next().map(move |m| &m[..width]);
             for (col, px) in pixels_row.iter().copied().enumerate() {
-                self.add_color(px, importance_map.map(move |map| map[col]).unwrap_or(255) as u16);
+                self.add_color(px, boost(importance_map.map(move |map| map[col]).unwrap_or(255)));
             }
         }
         self.init_posterize_bits(posterize_bits);
@@ -257,7 +602,7 @@ impl Histogram {
         Ok(())
     }
 
-    pub(crate) fn finalize_builder(&mut self, gamma: f64, target_mse: f64) -> HistogramInternal {
+    pub(crate) fn finalize_builder(&mut self, gamma: f64, target_mse: f64, levels: Option<&Levels>, alpha_levels: u8, alpha_weighted: bool) -> HistogramInternal {
         debug_assert!(gamma > 0.);
 
         let mut counts = [0; LIQ_MAXCLUSTER];
@@ -269,6 +614,7 @@ impl Histogram {
         let max_fixed_color_difference = (target_mse / 2.).max(2. / 256. / 256.) as f32;
 
         let lut = gamma_lut(gamma);
+        let alpha_lut = self.alpha_encoding.lut(gamma);
 
         let total_perceptual_weight = self.hashmap.values().map(|&(boost, color)| {
             if boost == 0 && !temp.is_empty() {
@@ -276,17 +622,23 @@ impl Histogram {
             }
             let cluster_index = (((color.r >> 7) << 3) | ((color.g >> 7) << 2) | ((color.b >> 7) << 1) | (color.a >> 7)) as u8;
 
-            let weight = (boost as f32 / 170.).min(max_perceptual_weight);
+            let mut weight = (boost as f32 / 170.).min(max_perceptual_weight);
             if weight == 0. {
                 return 0.;
             }
 
-            let color = f_pixel::from_rgba(&lut, color);
+            // fully transparent colors keep their weight, since they need their own palette entry
+            if alpha_weighted && color.a != 0 {
+                weight *= f32::from(color.a) / 255.;
+            }
+
+            let color = RGBA { a: alpha_to_level(color.a, alpha_levels), ..color };
+            let color = f_pixel::from_rgba_alpha_lut(&lut, alpha_lut.as_ref(), levels.map_or(color, |l| l.stretch(color)));
 
             // fixed colors are always included in the palette, so it would be wasteful to duplicate them in palette from histogram
             // FIXME: removes fixed colors from histogram (could be done better by marking them as max importance instead)
-            for HashColor(fixed) in &self.fixed_colors {
-                if color.diff(fixed) < max_fixed_color_difference {
+            for (HashColor(fixed), f) in &self.fixed_colors {
+                if color.diff(fixed) < max_fixed_color_difference.max(f.tolerance) {
                     return 0.;
                 }
             }
@@ -329,6 +681,7 @@ impl Histogram {
             items,
             clusters,
             total_perceptual_weight,
+            alpha_weighted,
         }
     }
 }
@@ -345,6 +698,7 @@ union RGBAInt {
     int: u32,
 }
 
+#[cfg(feature = "capi")]
 impl Drop for Histogram {
     fn drop(&mut self) {
         self.magic_header = LIQ_FREED_MAGIC;
@@ -354,10 +708,13 @@ impl Drop for Histogram {
 /// Clusters form initial boxes for quantization, to ensure extreme colors are better represented
 pub const LIQ_MAXCLUSTER: usize = 16;
 
+#[derive(Clone)]
 pub(crate) struct HistogramInternal {
     pub items: Box<[HistItem]>,
     pub total_perceptual_weight: f64,
     pub clusters: [Cluster; LIQ_MAXCLUSTER],
+    /// Fully transparent items don't move visible palette entries in K-means, see `Attributes::set_alpha_weighted_colors`
+    pub alpha_weighted: bool,
 }
 
 // Pre-grouped colors
@@ -376,6 +733,7 @@ impl std::hash::BuildHasher for RgbaHasher {
     }
 }
 
+#[derive(Clone)]
 pub(crate) struct RgbaHasher(pub u32);
 impl std::hash::Hasher for RgbaHasher {
     // magic constant from fxhash. For a single 32-bit key that's all it needs!
@@ -400,7 +758,7 @@ impl std::hash::Hasher for RgbaHasher {
 
 /// libstd's HashSet is afraid of NaN
 #[repr(transparent)]
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub(crate) struct HashColor(pub f_pixel);
 
 #[allow(clippy::derive_hash_xor_eq)]
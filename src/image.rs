@@ -1,35 +1,133 @@
-use crate::attr::Attributes;
-use crate::blur::{liq_blur, liq_max3, liq_min3};
+use crate::attr::{Attributes, LogCallback};
 use crate::error::*;
-use crate::ffi::MagicTag;
-use crate::ffi::LIQ_FREED_MAGIC;
-use crate::ffi::LIQ_IMAGE_MAGIC;
-use crate::pal::{f_pixel, gamma_lut, PalF, MIN_OPAQUE_A, RGBA};
-use crate::remap::DitherMapMode;
-use crate::rows::{DynamicRows, PixelsSource};
-use crate::seacow::RowBitmap;
+#[cfg(feature = "capi")]
+use crate::ffi::{MagicTag, LIQ_FREED_MAGIC, LIQ_IMAGE_MAGIC};
+use crate::hist::FixedColor;
+use crate::levels::Levels;
+use crate::pal::{f_pixel, gamma_lut, AlphaEncoding, MAX_COLORS, RGBA};
+#[cfg(feature = "dither-maps")]
+use crate::remap::UnditheredPrepass;
+use crate::remap::{standard_mse_to_mse, DitherMapMode, RemapScratch};
+use crate::resize::{downscale, ResizeFilter};
+use crate::rows::{temp_buf, DynamicRows, PixelsSource};
 use crate::seacow::SeaCow;
 use crate::LIQ_HIGH_MEMORY_LIMIT;
-use rgb::ComponentMap;
+#[cfg(feature = "dither-maps")]
+use fallible_collections::FallibleVec;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::mem::MaybeUninit;
+use std::os::raw::c_int;
+use std::sync::Arc;
+
+/// Maps made from the image for adaptive dithering. Empty without the `dither-maps` feature, and then dithering is uniform.
+#[derive(Default)]
+pub(crate) struct DitherMaps {
+    #[cfg(feature = "dither-maps")]
+    pub(crate) edges: Option<Box<[u8]>>,
+    #[cfg(feature = "dither-maps")]
+    pub(crate) dither_map: Option<Box<[u8]>>,
+    /// Remapping that `dither_map` has been made from, reused when only the dithering level changes
+    #[cfg(feature = "dither-maps")]
+    pub(crate) undithered_prepass: Option<Box<UnditheredPrepass>>,
+    /// Dithering strength for `DitherLevel::Auto`, 255 = smooth
+    #[cfg(feature = "dither-maps")]
+    pub(crate) variance_map: Option<Box<[u8]>>,
+    /// Buffers for new maps, from `RemapScratch` or from maps that aren't needed any more
+    #[cfg(feature = "dither-maps")]
+    pub(crate) spare: Vec<Box<[u8]>>,
+}
+
+impl DitherMaps {
+    /// Dithering strength of each pixel (0-255), or empty if dithering is uniform.
+    ///
+    /// If there's no dither map made by remapping, `edges` can be used instead.
+    #[inline]
+    pub(crate) fn dither_strength(&self, or_edges: bool) -> &[u8] {
+        #[cfg(feature = "dither-maps")]
+        if let Some(map) = self.dither_map.as_deref().or(self.edges.as_deref().filter(|_| or_edges)) {
+            return map;
+        }
+        let _ = or_edges;
+        &[]
+    }
+
+    /// Buffer for a map of `len` bytes, a spare one if it fits. Its contents are left over from previous use.
+    #[cfg(feature = "dither-maps")]
+    pub(crate) fn new_map(spare: &mut Vec<Box<[u8]>>, len: usize) -> Box<[u8]> {
+        match spare.iter().position(|m| m.len() == len) {
+            Some(i) => spare.swap_remove(i),
+            None => vec![0; len].into_boxed_slice(),
+        }
+    }
+
+    /// Smoothness of each pixel for `DitherLevel::Auto` (255 = smooth), or empty if it hasn't been made
+    #[inline]
+    pub(crate) fn smoothness(&self) -> &[u8] {
+        #[cfg(feature = "dither-maps")]
+        if let Some(map) = self.variance_map.as_deref() {
+            return map;
+        }
+        &[]
+    }
+}
 
 /// Describes image dimensions for the library.
 pub struct Image<'pixels, 'rows> {
+    #[cfg(feature = "capi")]
     pub(crate) magic_header: MagicTag,
     pub(crate) px: DynamicRows<'pixels, 'rows>,
     pub(crate) importance_map: Option<SeaCow<'static, u8>>,
-    pub(crate) edges: Option<Box<[u8]>>,
-    pub(crate) dither_map: Option<Box<[u8]>>,
+    pub(crate) maps: DitherMaps,
     pub(crate) background: Option<Box<Image<'pixels, 'rows>>>,
-    pub(crate) fixed_colors: Vec<f_pixel>,
+    /// Fixed colors with their tolerance and tag
+    pub(crate) fixed_colors: Vec<(f_pixel, FixedColor)>,
+    /// Replaces the log callback of `Attributes` when quantizing this image
+    pub(crate) log_callback: Option<Arc<LogCallback>>,
 }
 
 impl<'pixels, 'rows> Image<'pixels, 'rows> {
     pub(crate) fn free_histogram_inputs(&mut self) {
+        #[cfg(feature = "dither-maps")]
+        if let Some(map) = self.importance_map.as_mut().and_then(|m| m.take_boxed()) {
+            self.maps.spare.push(map);
+        }
         self.importance_map = None;
         self.px.free_histogram_inputs();
     }
 
+    /// Moves buffers of all maps to `out`, for `RemapScratch::reclaim_image`
+    pub(crate) fn take_map_buffers(&mut self, out: &mut Vec<Box<[u8]>>) {
+        self.free_histogram_inputs();
+        #[cfg(feature = "dither-maps")]
+        {
+            let maps = &mut self.maps;
+            out.extend(maps.edges.take());
+            out.extend(maps.dither_map.take());
+            out.extend(maps.variance_map.take());
+            out.append(&mut maps.spare);
+        }
+        let _ = out;
+    }
+
+    /// Takes buffers kept by [`RemapScratch::reclaim_image`] that fit this image, and uses them for its maps instead of allocating new ones.
+    ///
+    /// Maps are made when the image is quantized or added to a histogram, and when it's remapped with dithering.
+    /// Without the `dither-maps` feature there are no maps, and this does nothing.
+    pub fn reuse_buffers(&mut self, scratch: &mut RemapScratch) {
+        #[cfg(feature = "dither-maps")]
+        self.maps.spare.extend(scratch.take_map_buffers(self.width() * self.height()));
+        let _ = scratch;
+    }
+
+    /// Stretch of the input that a palette has been made for, see `Attributes::set_auto_levels`
+    pub(crate) fn set_levels(&mut self, levels: Option<Levels>) {
+        self.px.set_levels(levels);
+        if let Some(bg) = self.background.as_mut() {
+            bg.px.set_levels(levels);
+        }
+    }
+
     pub(crate) fn new_internal(
         attr: &Attributes,
         pixels: PixelsSource<'pixels, 'rows>,
@@ -37,7 +135,7 @@ impl<'pixels, 'rows> Image<'pixels, 'rows> {
         height: u32,
         gamma: f64,
     ) -> Result<Self, liq_error> {
-        if !crate::ffi::check_image_size(attr, width, height) {
+        if !check_image_size(attr, width, height) {
             return Err(LIQ_BUFFER_TOO_SMALL);
         }
 
@@ -46,6 +144,7 @@ impl<'pixels, 'rows> Image<'pixels, 'rows> {
             return Err(LIQ_VALUE_OUT_OF_RANGE);
         }
         let img = Image {
+            #[cfg(feature = "capi")]
             magic_header: LIQ_IMAGE_MAGIC,
             px: DynamicRows::new(
                 width,
@@ -54,10 +153,10 @@ impl<'pixels, 'rows> Image<'pixels, 'rows> {
                 if gamma > 0. { gamma } else { 0.45455 },
             ),
             importance_map: None,
-            edges: None,
-            dither_map: None,
+            maps: DitherMaps::default(),
             background: None,
             fixed_colors: Vec::new(),
+            log_callback: None,
         };
         // if image is huge or converted pixels are not likely to be reused then don't cache converted pixels
         let low_memory_hint = !attr.use_contrast_maps && attr.use_dither_map == DitherMapMode::None;
@@ -68,54 +167,6 @@ impl<'pixels, 'rows> Image<'pixels, 'rows> {
         Ok(img)
     }
 
-    pub(crate) fn update_dither_map(&mut self, remapped_image: &RowBitmap<'_, u8>, palette: &mut PalF) {
-        let width = self.width();
-        let edges = match self.edges.as_deref_mut() {
-            Some(e) => e,
-            None => return,
-        };
-        let colors = palette.as_slice();
-
-        let mut prev_row: Option<&[_]> = None;
-        let mut rows = remapped_image.rows().zip(edges.chunks_exact_mut(width)).peekable();
-        while let Some((this_row, edges)) = rows.next() {
-            let mut lastpixel = this_row[0];
-            let mut lastcol = 0;
-            for (col, px) in this_row.iter().copied().enumerate().skip(1) {
-                if self.background.is_some() && (colors[px as usize]).a < MIN_OPAQUE_A {
-                    // Transparency may or may not create an edge. When there's an explicit background set, assume no edge.
-                    continue;
-                }
-                if px != lastpixel || col == width - 1 {
-                    let mut neighbor_count = 10 * (col - lastcol);
-                    let mut i = lastcol;
-                    while i < col {
-                        if let Some(prev_row) = prev_row {
-                            let pixelabove = prev_row[i];
-                            if pixelabove == lastpixel { neighbor_count += 15; };
-                        }
-                        if let Some((next_row, _)) = rows.peek() {
-                            let pixelbelow = next_row[i];
-                            if pixelbelow == lastpixel { neighbor_count += 15; };
-                        }
-                        i += 1;
-                    }
-                    while lastcol <= col {
-                        let e = edges[lastcol];
-                        edges[lastcol] = ((e as u16 + 128) as f32
-                            * (255. / (255 + 128) as f32)
-                            * (1. - 20. / (20 + neighbor_count) as f32))
-                            as u8;
-                        lastcol += 1;
-                    }
-                    lastpixel = px;
-                }
-            }
-            prev_row = Some(this_row);
-        }
-        self.dither_map = self.edges.take();
-    }
-
     /// Remap pixels assuming they will be displayed on this background.
     ///
     /// Pixels that match the background color will be made transparent if there's a fully transparent color available in the palette.
@@ -129,7 +180,71 @@ impl<'pixels, 'rows> Image<'pixels, 'rows> {
             return Err(LIQ_BUFFER_TOO_SMALL);
         }
         self.background = Some(Box::new(background));
-        self.dither_map = None;
+        #[cfg(feature = "dither-maps")]
+        {
+            self.maps.dither_map = None;
+            self.maps.undithered_prepass = None;
+        }
+        Ok(())
+    }
+
+    /// Guesses color of the image's background from pixels along its edges, e.g. as a default for flattening transparency
+    /// (see `EinkRemapper::set_background`).
+    ///
+    /// Similar colors are averaged, so noise and JPEG artifacts don't matter. Returns `None` if no color covers
+    /// more than half of the edges, or if edges are mostly transparent. Fails if the image has been quantized already.
+    pub fn detect_background_color(&self) -> Result<Option<RGBA>, liq_error> {
+        let (width, height) = (self.width(), self.height());
+        let rows = self.px.rgba_rows_iter()?;
+        let mut temp_row = temp_buf(width);
+        // colors with 4 bits per channel, and sums of their exact values
+        let mut buckets = HashMap::<u32, (u32, [u64; 4])>::new();
+        let mut total = 0;
+        for row in 0..height {
+            let edge_row = row == 0 || row == height - 1;
+            let border = rows.row_rgba(&mut temp_row, row).iter().enumerate()
+                .filter(|&(x, _)| edge_row || x == 0 || x == width - 1);
+            for (_, px) in border {
+                let key = if px.a == 0 { 0 } else {
+                    u32::from_be_bytes([px.r >> 4, px.g >> 4, px.b >> 4, (px.a >> 4) | 0x10])
+                };
+                let (count, sums) = buckets.entry(key).or_insert((0, [0; 4]));
+                *count += 1;
+                for (sum, c) in sums.iter_mut().zip([px.r, px.g, px.b, px.a]) {
+                    *sum += u64::from(c);
+                }
+                total += 1;
+            }
+        }
+
+        let (key, (count, sums)) = match buckets.into_iter().max_by_key(|&(_, (count, _))| count) {
+            Some(best) => best,
+            None => return Ok(None),
+        };
+        if key == 0 || count * 2 <= total {
+            return Ok(None);
+        }
+        let avg = sums.map(|sum| ((sum + u64::from(count) / 2) / u64::from(count)) as u8);
+        Ok(Some(RGBA::new(avg[0], avg[1], avg[2], avg[3])))
+    }
+
+    /// Checks that pixels of the image (and its background) can be read, so that problems are found before quantization or remapping starts.
+    ///
+    /// Images that get rows from a callback are asked for the first row. Errors can be converted to `liq_error`.
+    pub fn validate(&self) -> Result<(), ImageError> {
+        self.px.validate()?;
+        if let Some(map) = &self.importance_map {
+            let required = self.width() * self.height();
+            if map.as_slice().len() < required {
+                return Err(ImageError::ImportanceMapTooSmall { len: map.as_slice().len(), required });
+            }
+        }
+        if let Some(background) = &self.background {
+            if background.width() != self.width() || background.height() != self.height() {
+                return Err(ImageError::BackgroundSizeMismatch);
+            }
+            background.validate().map_err(|err| ImageError::Background(Box::new(err)))?;
+        }
         Ok(())
     }
 
@@ -142,6 +257,7 @@ impl<'pixels, 'rows> Image<'pixels, 'rows> {
     }
 
     #[inline]
+    #[cfg(feature = "capi")]
     pub(crate) fn set_importance_map_raw(&mut self, map: Option<SeaCow<'static, u8>>) {
         self.importance_map = map;
     }
@@ -167,81 +283,266 @@ impl<'pixels, 'rows> Image<'pixels, 'rows> {
     /// It must be called before the image is quantized.
     ///
     /// Returns error if more than 256 colors are added. If image is quantized to fewer colors than the number of fixed colors added, then excess fixed colors will be ignored.
+    #[inline]
     pub fn add_fixed_color(&mut self, color: RGBA) -> liq_error {
-        if self.fixed_colors.len() > 255 { return LIQ_UNSUPPORTED; }
+        self.add_fixed_color_with_tolerance(color, 0.)
+    }
+
+    /// Like [`Image::add_fixed_color`], but also protects the color from being mixed with similar colors.
+    ///
+    /// Pixels within `tolerance` of the fixed color won't contribute to other palette entries, and will be remapped exactly
+    /// to the fixed color, and won't be dithered. This keeps areas of brand colors clean. The tolerance is in the same units as
+    /// [`QuantizationResult::quantization_error`](crate::QuantizationResult::quantization_error).
+    pub fn add_fixed_color_with_tolerance(&mut self, color: RGBA, tolerance: f64) -> liq_error {
+        if tolerance.is_nan() || tolerance < 0. { return LIQ_VALUE_OUT_OF_RANGE; }
+        self.add_fixed_color_internal(color, FixedColor { tolerance: standard_mse_to_mse(tolerance) as f32, tag: None })
+    }
+
+    /// Like [`Image::add_fixed_color`], with a tag that identifies the color in the palette.
+    ///
+    /// After quantization, [`QuantizationResult::tagged_index`](crate::QuantizationResult::tagged_index) gives palette index of the color,
+    /// so there's no need to search the palette for it.
+    pub fn add_fixed_color_tagged(&mut self, color: RGBA, tag: u32) -> liq_error {
+        self.add_fixed_color_internal(color, FixedColor { tolerance: 0., tag: Some(tag) })
+    }
+
+    fn add_fixed_color_internal(&mut self, color: RGBA, fixed: FixedColor) -> liq_error {
+        if self.fixed_colors.len() >= MAX_COLORS { return LIQ_UNSUPPORTED; }
         let lut = gamma_lut(self.px.gamma);
-        self.fixed_colors.push(f_pixel::from_rgba(&lut, RGBA {r: color.r, g: color.g, b: color.b, a: color.a}));
+        let alpha_lut = self.px.alpha_encoding.lut(self.px.gamma);
+        let px = f_pixel::from_rgba_alpha_lut(&lut, alpha_lut.as_ref(), RGBA {r: color.r, g: color.g, b: color.b, a: color.a});
+        self.fixed_colors.push((px, fixed));
         LIQ_OK
     }
 
+    /// Pure black, pure white and fully transparent, whichever cover at least 1/1000th of the image (see `Attributes::set_preserve_extremes`)
+    pub(crate) fn extreme_colors(&self) -> Result<Vec<f_pixel>, liq_error> {
+        const EXTREMES: [RGBA; 3] = [
+            RGBA { r: 0, g: 0, b: 0, a: 255 },
+            RGBA { r: 255, g: 255, b: 255, a: 255 },
+            RGBA { r: 0, g: 0, b: 0, a: 0 },
+        ];
+        let (width, height) = (self.width(), self.height());
+        let rows = self.px.rgba_rows_iter()?;
+        let mut temp_row = temp_buf(width);
+        let mut counts = [0; 3];
+        for row in 0..height {
+            for px in rows.row_rgba(&mut temp_row, row) {
+                match (px.r, px.g, px.b, px.a) {
+                    (0, 0, 0, 255) => counts[0] += 1,
+                    (255, 255, 255, 255) => counts[1] += 1,
+                    (_, _, _, 0) => counts[2] += 1,
+                    _ => {},
+                }
+            }
+        }
+
+        let min_count = (width * height / 1000).max(1);
+        let lut = gamma_lut(self.px.gamma);
+        Ok(EXTREMES.iter().zip(counts)
+            .filter(|&(_, count)| count >= min_count)
+            .map(|(&color, _)| f_pixel::from_rgba(&lut, color))
+            .collect())
+    }
+
+    /// Makes a copy of the image downscaled to fit in `max_width`×`max_height`, keeping the aspect ratio. Smaller images are just copied.
+    ///
+    /// Pixels are averaged in linear light, so the colors of the copy match the original. A palette made from the small copy
+    /// can be used to remap the original at full size, which is much faster than quantizing a large image:
+    ///
+    /// ```rust,ignore
+    /// let mut thumb = img.resized(512, 512, ResizeFilter::Triangle)?;
+    /// let mut res = attr.quantize(&mut thumb)?;
+    /// let (palette, pixels) = res.remapped(&mut img)?;
+    /// ```
+    ///
+    /// Fixed colors and alpha encoding are copied too, but not the importance map or background.
+    /// It must be called before the image is quantized.
+    pub fn resized(&self, max_width: usize, max_height: usize, filter: ResizeFilter) -> Result<Image<'static, 'static>, liq_error> {
+        if max_width == 0 || max_height == 0 {
+            return Err(LIQ_VALUE_OUT_OF_RANGE);
+        }
+        let (width, height) = (self.width(), self.height());
+        let scale = (max_width as f64 / width as f64).min(max_height as f64 / height as f64).min(1.);
+        let new_width = ((width as f64 * scale).round() as usize).clamp(1, max_width);
+        let new_height = ((height as f64 * scale).round() as usize).clamp(1, max_height);
+
+        let gamma = self.px.gamma;
+        let mut to_linear = [0.; 256];
+        for (i, t) in to_linear.iter_mut().enumerate() {
+            *t = (i as f32 / 255.).powf((1. / gamma) as f32);
+        }
+        let alpha_to_linear = self.px.alpha_encoding.lut(gamma);
+
+        let rows = self.px.rgba_rows_iter()?;
+        let mut temp_row = temp_buf(width);
+        let small = downscale(width, height, new_width, new_height, filter, |y, linear| {
+            for (out, px) in linear.iter_mut().zip(rows.row_rgba(&mut temp_row, y)) {
+                let a = alpha_to_linear.as_ref().map_or(px.a as f32 / 255., |lut| lut[px.a as usize]);
+                *out = [to_linear[px.r as usize] * a, to_linear[px.g as usize] * a, to_linear[px.b as usize] * a, a];
+            }
+        });
+        let encode = |c: f32| (c.max(0.).powf(gamma as f32) * 255.).round().min(255.) as u8;
+        let pixels: Box<[RGBA]> = small.iter().map(|&[r, g, b, a]| {
+            if a <= 0. {
+                return RGBA::new(0, 0, 0, 0);
+            }
+            let alpha = if alpha_to_linear.is_some() { encode(a) } else { (a * 255.).round().min(255.) as u8 };
+            RGBA::new(encode(r / a), encode(g / a), encode(b / a), alpha)
+        }).collect();
+
+        let mut img = Image::new_stride_internal(&Attributes::new(), SeaCow::boxed(pixels), new_width, new_height, new_width, gamma)?;
+        img.px.alpha_encoding = self.px.alpha_encoding;
+        img.fixed_colors = self.fixed_colors.clone();
+        Ok(img)
+    }
+
+    /// Makes a sharpened copy of the image, to compensate for softening caused by posterization and dithering,
+    /// which is most noticeable on downscaled photos.
+    ///
+    /// `amount` is 0-1. It's a mild unsharp mask, applied only at edges, so that noise and smooth gradients aren't made worse.
+    /// Images smaller than 4×4 pixels, or too large for edge detection, are copied unchanged.
+    ///
+    /// Fixed colors and alpha encoding are copied too, but not the importance map or background.
+    /// It must be called before the image is quantized.
+    ///
+    /// Edges are found with the same maps as adaptive dithering, so it needs the `dither-maps` feature.
+    #[cfg(feature = "dither-maps")]
+    pub fn sharpened(&mut self, amount: f32) -> Result<Image<'static, 'static>, liq_error> {
+        if !(0. ..=1.).contains(&amount) {
+            return Err(LIQ_VALUE_OUT_OF_RANGE);
+        }
+        let (width, height) = (self.width(), self.height());
+        if self.maps.edges.is_none() {
+            self.contrast_maps(&|_| false)?;
+        }
+
+        let rows = self.px.rgba_rows_iter()?;
+        let mut temp_row = temp_buf(width);
+        let mut pixels: Vec<RGBA> = FallibleVec::try_with_capacity(width * height).map_err(|_| LIQ_OUT_OF_MEMORY)?;
+        for row in 0..height {
+            pixels.extend_from_slice(rows.row_rgba(&mut temp_row, row));
+        }
+
+        let mut sharpened = pixels.clone();
+        if let Some(edges) = self.maps.edges.as_deref().filter(|_| amount > 0.) {
+            for (y, (out_row, edges_row)) in sharpened.chunks_exact_mut(width).zip(edges.chunks_exact(width)).enumerate() {
+                let neighbor_rows = [y.saturating_sub(1), y, (y + 1).min(height - 1)];
+                for (x, (out, &edge)) in out_row.iter_mut().zip(edges_row).enumerate() {
+                    // edges map is 255 in flat areas
+                    let strength = amount * f32::from(255 - edge) / 255.;
+                    if strength <= 0. || out.a == 0 {
+                        continue;
+                    }
+                    // 3×3 box blur, weighted by alpha, because colors of transparent pixels don't matter
+                    let mut sum = [0.; 3];
+                    let mut total_alpha = 0.;
+                    for &ny in &neighbor_rows {
+                        for nx in [x.saturating_sub(1), x, (x + 1).min(width - 1)].iter().copied() {
+                            let px = pixels[ny * width + nx];
+                            let a = f32::from(px.a);
+                            sum[0] += f32::from(px.r) * a;
+                            sum[1] += f32::from(px.g) * a;
+                            sum[2] += f32::from(px.b) * a;
+                            total_alpha += a;
+                        }
+                    }
+                    let sharpen = |c: u8, blurred: f32| {
+                        let c = f32::from(c);
+                        (c + (c - blurred / total_alpha) * strength).round().clamp(0., 255.) as u8
+                    };
+                    out.r = sharpen(out.r, sum[0]);
+                    out.g = sharpen(out.g, sum[1]);
+                    out.b = sharpen(out.b, sum[2]);
+                }
+            }
+        }
+
+        let mut img = Image::new_stride_internal(&Attributes::new(), SeaCow::boxed(sharpened.into_boxed_slice()), width, height, width, self.px.gamma)?;
+        img.px.alpha_encoding = self.px.alpha_encoding;
+        img.fixed_colors = self.fixed_colors.clone();
+        Ok(img)
+    }
+
+    /// Part of the image, `width`×`height` pixels from (`x`, `y`), that uses pixels of this image without copying them.
+    ///
+    /// Useful for quantizing or remapping tiles or cropped regions of a large frame. The view has the same gamma, alpha encoding,
+    /// chroma key and fixed colors as this image, but not its importance map nor background.
+    ///
+    /// Only images made from RGBA pixels can be viewed (not callbacks nor planes), and only until they're quantized,
+    /// which frees their pixels. Otherwise it returns `LIQ_UNSUPPORTED`.
+    pub fn view(&self, x: usize, y: usize, width: usize, height: usize) -> Result<Image<'_, 'static>, liq_error> {
+        if x > self.width() || width > self.width() - x || y > self.height() || height > self.height() - y {
+            return Err(LIQ_VALUE_OUT_OF_RANGE);
+        }
+        let rows = self.px.view_rows(x, y, height).ok_or(LIQ_UNSUPPORTED)?;
+        let (width, height) = image_size(&Attributes::new(), width, height)?;
+        // the rows point to pixels borrowed from this image
+        let mut img = Image::new_internal(&Attributes::new(), PixelsSource::Pixels { rows: SeaCow::boxed(rows), pixels: None }, width, height, self.px.gamma)?;
+        img.px.alpha_encoding = self.px.alpha_encoding;
+        img.px.set_chroma_key(self.px.chroma_key())?;
+        img.fixed_colors = self.fixed_colors.clone();
+        Ok(img)
+    }
+
     #[inline(always)]
     pub(crate) fn gamma(&self) -> f64 {
         self.px.gamma
     }
 
-    /// Builds two maps:
-    ///    importance_map - approximation of areas with high-frequency noise, except straight edges. 1=flat, 0=noisy.
-    ///    edges - noise map including all edges
-    pub(crate) fn contrast_maps(&mut self) -> Result<(), liq_error> {
+    /// Sets whether alpha of the pixels is linear (default), or gamma-encoded like the color channels.
+    ///
+    /// Wrong encoding causes dark or light fringes around soft edges. Alpha of the output palette is encoded the same way.
+    ///
+    /// It must be called before the image is quantized, and before fixed colors are added.
+    /// All images added to the same `Histogram` should use the same encoding.
+    #[inline]
+    pub fn set_alpha_encoding(&mut self, encoding: AlphaEncoding) {
+        self.px.alpha_encoding = encoding;
+    }
+
+    /// Makes pixels of this color transparent, like sprites exported with a magenta or green background instead of alpha.
+    ///
+    /// Pixels whose red, green and blue each differ from the key by at most `tolerance` are quantized and remapped as fully transparent,
+    /// so they're remapped to the transparent palette entry. Alpha of the pixels isn't compared.
+    ///
+    /// It must be called before the image is quantized, otherwise it returns `LIQ_UNSUPPORTED`.
+    pub fn set_chroma_key(&mut self, key: RGBA, tolerance: u8) -> Result<(), liq_error> {
+        self.px.set_chroma_key(Some((key, tolerance)))
+    }
+
+    #[inline(always)]
+    pub(crate) fn alpha_encoding(&self) -> AlphaEncoding {
+        self.px.alpha_encoding
+    }
+
+    /// Guesses if the image is a screenshot, UI or a diagram, rather than a photo, because most of its pixels have the same color
+    /// as their neighbor. See `Attributes::set_screenshot_preset`.
+    ///
+    /// Fails if the image has been quantized already.
+    pub fn looks_like_screenshot(&self) -> Result<bool, liq_error> {
+        /// Fraction of pixels that must be the same as the next one. Noise of cameras makes that rare in photos.
+        const MIN_REPEATED: f64 = 0.5;
+
         let width = self.width();
-        let height = self.height();
-        if width < 4 || height < 4 || (3 * width * height) > LIQ_HIGH_MEMORY_LIMIT {
-            return Ok(()); // shrug
+        let rows = self.px.rgba_rows_iter()?;
+        let mut temp_row = temp_buf(width);
+        let mut repeated = 0;
+        for row in 0..self.height() {
+            let row = rows.row_rgba(&mut temp_row, row);
+            repeated += row.windows(2).filter(|px| px[0] == px[1]).count();
         }
+        Ok(repeated as f64 >= (self.width() * self.height()) as f64 * MIN_REPEATED)
+    }
 
-        let noise = &mut self.importance_map.get_or_insert_with(move || SeaCow::boxed(vec![0; height * width].into_boxed_slice())).as_mut_slice()[..width * height];
-        let edges = &mut self.edges.get_or_insert_with(move || vec![0; width * height].into_boxed_slice())[..width * height];
-
-        let mut tmp = vec![0; width * height];
-
-        let mut rows_iter = self.px.all_rows_f()?.chunks_exact(width);
-
-        let mut next_row = rows_iter.next().unwrap();
-        let mut curr_row = next_row;
-        let mut prev_row;
-
-        for (noise_row, edges_row) in noise.chunks_exact_mut(width).zip(edges.chunks_exact_mut(width)) {
-            prev_row = curr_row;
-            curr_row = next_row;
-            next_row = rows_iter.next().unwrap_or(next_row);
-            let mut prev;
-            let mut curr = curr_row[0].0;
-            let mut next = curr;
-            for i in 0..width {
-                prev = curr;
-                curr = next;
-                next = curr_row[(i + 1).min(width - 1)].0;
-                // contrast is difference between pixels neighbouring horizontally and vertically
-                let horiz = (prev + next - curr * 2.).map(|c| c.abs()); // noise is amplified
-                let prevl = prev_row[i].0;
-                let nextl = next_row[i].0;
-                let vert = (prevl + nextl - curr * 2.).map(|c| c.abs());
-                let horiz = horiz.a.max(horiz.r).max(horiz.g.max(horiz.b));
-                let vert = vert.a.max(vert.r).max(vert.g.max(vert.b));
-                let edge = horiz.max(vert);
-                let mut z = edge - (horiz - vert).abs() * 0.5;
-                z = 1. - z.max(horiz.min(vert));
-                z *= z;
-                z *= z;
-                // 85 is about 1/3rd of weight (not 0, because noisy pixels still need to be included, just not as precisely).
-                noise_row[i] = (80. + z * 176.) as u8;
-                edges_row[i] = ((1. - edge) * 256.) as u8;
-            }
-        }
-        // noise areas are shrunk and then expanded to remove thin edges from the map
-        liq_max3(noise, &mut tmp, width, height);
-        liq_max3(&tmp, noise, width, height);
-        liq_blur(noise, &mut tmp, width, height, 3);
-        liq_max3(noise, &mut tmp, width, height);
-        liq_min3(&tmp, noise, width, height);
-        liq_min3(noise, &mut tmp, width, height);
-        liq_min3(&tmp, noise, width, height);
-        liq_min3(edges, &mut tmp, width, height);
-        liq_max3(&tmp, edges, width, height);
-        for (edges, noise) in edges.iter_mut().zip(noise) {
-            *edges = (*noise).min(*edges);
-        }
-        Ok(())
+    /// Sends messages about quantization of this image to this callback, instead of the one set with `Attributes::set_log_callback`.
+    ///
+    /// Useful when images are quantized at the same time with shared `Attributes`, and each job needs its own log.
+    /// It's used by `Attributes::quantize`, `quantize_detailed`, `quantize_with_fallback`, `quantize_extended`,
+    /// and `quantize_mip_chain` (if it's set on the first level).
+    #[inline]
+    pub fn set_log_callback<F: Fn(&Attributes, &str) + Send + Sync + 'static>(&mut self, callback: F) {
+        self.log_callback = Some(Arc::new(callback));
     }
 
     /// Describe dimensions of a slice of RGBA pixels.
@@ -265,7 +566,24 @@ impl<'pixels, 'rows> Image<'pixels, 'rows> {
     /// This function is marked as unsafe, because the callback function MUST initialize the entire row (call `write` on every `MaybeUninit` pixel).
     ///
     pub unsafe fn new_fn<F: 'static + Fn(&mut [MaybeUninit<RGBA>], usize) + Send + Sync>(attr: &Attributes, convert_row_fn: F, width: usize, height: usize, gamma: f64) -> Result<Self, liq_error> {
-        Image::new_internal(attr, PixelsSource::Callback(Box::new(convert_row_fn)), width as u32, height as u32, gamma)
+        let (width, height) = image_size(attr, width, height)?;
+        Image::new_internal(attr, PixelsSource::Callback(Box::new(convert_row_fn)), width, height, gamma)
+    }
+
+    /// Separate planes of red, green, blue, and optionally alpha (the image is opaque if it's `None`), each `width * height` bytes.
+    ///
+    /// Channels are interleaved a row at a time when they're needed, so large frames from planar video pipelines don't have to be converted to RGBA first.
+    ///
+    /// Use `0.` for gamma if the image is sRGB (most images are).
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_planar(attr: &Attributes, r: &'pixels [u8], g: &'pixels [u8], b: &'pixels [u8], a: Option<&'pixels [u8]>, width: usize, height: usize, gamma: f64) -> Result<Self, liq_error> {
+        let (width_u32, height_u32) = image_size(attr, width, height)?;
+        let len = width.checked_mul(height).ok_or(LIQ_BUFFER_TOO_SMALL)?;
+        if [r, g, b].iter().chain(a.as_ref()).any(|plane| plane.len() < len) {
+            attr.verbose_print(format!("Planes are too small for {}×{} pixels", width, height));
+            return Err(LIQ_BUFFER_TOO_SMALL);
+        }
+        Image::new_internal(attr, PixelsSource::Planar { r, g, b, a }, width_u32, height_u32, gamma)
     }
 
     /// Stride is in pixels. Allows defining regions of larger images or images with padding without copying.
@@ -286,19 +604,54 @@ impl<'pixels, 'rows> Image<'pixels, 'rows> {
 
     fn new_stride_internal<'a>(attr: &Attributes, pixels: SeaCow<'a, RGBA>, width: usize, height: usize, stride: usize, gamma: f64) -> Result<Image<'a, 'static>, liq_error> {
         let slice = pixels.as_slice();
-        if slice.len() < (stride * height + width - stride) {
+        let (width_u32, height_u32) = image_size(attr, width, height)?;
+        if !check_image_size(attr, width_u32, height_u32) {
+            return Err(LIQ_BUFFER_TOO_SMALL);
+        }
+        let required_len = stride.checked_mul(height.saturating_sub(1)).and_then(|len| len.checked_add(width)).ok_or(LIQ_BUFFER_TOO_SMALL)?;
+        if slice.len() < required_len {
             attr.verbose_print(format!("Buffer length is {} bytes, which is not enough for {}×{}×4 RGBA bytes", slice.len()*4, stride, height));
             return Err(LIQ_BUFFER_TOO_SMALL);
         }
 
         let rows = SeaCow::boxed(slice.chunks(stride).map(|row| row.as_ptr()).collect());
-        Image::new_internal(attr, PixelsSource::Pixels { rows, pixels: Some(pixels) }, width as u32, height as u32, gamma)
+        Image::new_internal(attr, PixelsSource::Pixels { rows, pixels: Some(pixels) }, width_u32, height_u32, gamma)
+    }
+}
+
+pub(crate) fn check_image_size(attr: &Attributes, width: u32, height: u32) -> bool {
+    if width == 0 || height == 0 {
+        attr.verbose_print("  error: width and height must be > 0");
+        return false;
+    }
+    // on 64-bit platforms the total size is limited only by the address space
+    let image_bytes = (width as usize).checked_mul(height as usize)
+        .and_then(|area| area.checked_mul(std::mem::size_of::<RGBA>()))
+        .filter(|&bytes| bytes <= isize::MAX as usize);
+    if image_bytes.is_none()
+        || width as usize > c_int::MAX as usize / 16 / std::mem::size_of::<f_pixel>()
+        || height as usize > c_int::MAX as usize / std::mem::size_of::<usize>()
+    {
+        attr.verbose_print("  error: image too large");
+        return false;
     }
+    true
 }
 
+/// Dimensions that don't fit in `u32` are rejected, rather than truncated
+pub(crate) fn image_size(attr: &Attributes, width: usize, height: usize) -> Result<(u32, u32), liq_error> {
+    match (u32::try_from(width), u32::try_from(height)) {
+        (Ok(width), Ok(height)) => Ok((width, height)),
+        _ => {
+            attr.verbose_print("  error: image too large");
+            Err(LIQ_BUFFER_TOO_SMALL)
+        },
+    }
+}
+
+#[cfg(feature = "capi")]
 impl<'pixels, 'rows> Drop for Image<'pixels, 'rows> {
     fn drop(&mut self) {
         self.magic_header = LIQ_FREED_MAGIC;
     }
 }
-
@@ -0,0 +1,78 @@
+//! Requantization of images that already have a palette, such as PNG8 with a `tRNS` chunk (see `Attributes::quantize_indexed`)
+
+use crate::error::*;
+use crate::hist::Histogram;
+use crate::image::{check_image_size, image_size, Image};
+use crate::nearest::Nearest;
+use crate::pal::{f_pixel, gamma_lut, PalF, PalIndex, PalPop, RGBA};
+use crate::quant::QuantizationResult;
+use crate::Attributes;
+
+/// Indices of the image's pixels. Fails if the size is invalid, there are more than 256 or no palette colors,
+/// too few indices, or indices of colors that aren't in the palette.
+fn checked_indices<'a>(attr: &Attributes, palette: &[RGBA], indices: &'a [u8], width: usize, height: usize) -> Result<&'a [u8], liq_error> {
+    let (width_u32, height_u32) = image_size(attr, width, height)?;
+    if !check_image_size(attr, width_u32, height_u32) {
+        return Err(LIQ_BUFFER_TOO_SMALL);
+    }
+    if palette.is_empty() || palette.len() > 256 {
+        return Err(LIQ_VALUE_OUT_OF_RANGE);
+    }
+    let indices = indices.get(..width * height).ok_or(LIQ_BUFFER_TOO_SMALL)?;
+    if indices.iter().any(|&i| usize::from(i) >= palette.len()) {
+        return Err(LIQ_VALUE_OUT_OF_RANGE);
+    }
+    Ok(indices)
+}
+
+impl Image<'static, 'static> {
+    /// Image of `width * height` palette `indices`, such as a PNG8 image. Palette colors may have alpha, as in a `tRNS` chunk.
+    ///
+    /// Pixels are converted to RGBA a row at a time, so that the image doesn't take 4 times more memory.
+    /// The palette and indices are copied. See [`Attributes::quantize_indexed`] for a faster way that doesn't dither.
+    pub fn new_indexed(attr: &Attributes, palette: &[RGBA], indices: &[u8], width: usize, height: usize, gamma: f64) -> Result<Self, liq_error> {
+        let indices = checked_indices(attr, palette, indices, width, height)?.to_vec();
+        let palette = palette.to_vec();
+        unsafe {
+            Image::new_fn(attr, move |row, y| {
+                for (px, &i) in row.iter_mut().zip(&indices[y * width..(y + 1) * width]) {
+                    px.write(palette[usize::from(i)]);
+                }
+            }, width, height, gamma)
+        }
+    }
+}
+
+impl Attributes {
+    /// Reduces colors of an image that already has a palette (see [`Image::new_indexed`]), working only with the palette and counts of its indices,
+    /// without converting pixels to RGBA.
+    ///
+    /// Every color of the source palette is replaced by one color of the new palette, so there's no dithering.
+    /// Alpha of palette entries is quantized along with their colors, the same way as alpha of pixels.
+    /// Colors are weighed by how many pixels use them, so the result is similar to quantizing the expanded image.
+    ///
+    /// Returns the new result, and its indices for every pixel. Colors of the result are frozen, as with [`Histogram::quantize`],
+    /// so the indices stay valid for its palette even after the result is used to remap other images.
+    pub fn quantize_indexed(&self, palette: &[RGBA], indices: &[u8], width: usize, height: usize, gamma: f64) -> Result<(QuantizationResult, Vec<PalIndex>), liq_error> {
+        let indices = checked_indices(self, palette, indices, width, height)?;
+
+        let mut counts = vec![0u32; palette.len()];
+        for &i in indices {
+            counts[usize::from(i)] = counts[usize::from(i)].saturating_add(1);
+        }
+        let colors: Vec<_> = palette.iter().copied().zip(counts).collect();
+        let mut hist = Histogram::new(self);
+        hist.add_color_counts(&colors, gamma)?;
+        // frozen, so that remapping with the result doesn't move the colors the indices point to
+        let mut res = hist.quantize_internal(self, true)?;
+
+        let lut = gamma_lut(res.output_gamma());
+        let mut palf = PalF::new();
+        for &c in res.palette() {
+            palf.push(f_pixel::from_rgba(&lut, c), PalPop::new(0.));
+        }
+        let n = Nearest::new(&palf);
+        let mapping: Vec<_> = palette.iter().map(|&c| n.search(&f_pixel::from_rgba(&lut, c), 0).0).collect();
+        Ok((res, indices.iter().map(|&i| mapping[usize::from(i)]).collect()))
+    }
+}
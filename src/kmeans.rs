@@ -5,8 +5,9 @@ use rayon::iter::ParallelIterator;
 use rayon::slice::ParallelSliceMut;
 use rgb::alt::ARGB;
 use rgb::ComponentMap;
-use std::cell::RefCell;
-use thread_local::ThreadLocal;
+
+/// Histogram items per K-means batch. Each batch has its own sums of all palette entries.
+const ITEMS_PER_CHUNK: usize = 1024;
 
 pub(crate) struct Kmeans {
     averages: Vec<ColorAvg>,
@@ -47,8 +48,20 @@ impl Kmeans {
         self.weighed_diff_sum
     }
 
-    #[inline(never)]
+    #[inline]
     pub(crate) fn iteration(hist: &mut HistogramInternal, palette: &mut PalF, adjust_weight: bool) -> f64 {
+        Self::iteration_with_search(hist, palette, adjust_weight, false)
+    }
+
+    /// Like `iteration`, but every histogram color is matched with its true nearest palette entry,
+    /// without heuristics of the nearest search (see `Attributes::set_exact_final_iteration`)
+    #[inline]
+    pub(crate) fn exact_iteration(hist: &mut HistogramInternal, palette: &mut PalF) -> f64 {
+        Self::iteration_with_search(hist, palette, false, true)
+    }
+
+    #[inline(never)]
+    fn iteration_with_search(hist: &mut HistogramInternal, palette: &mut PalF, adjust_weight: bool, exhaustive: bool) -> f64 {
         if hist.items.is_empty() {
             return 0.;
         }
@@ -57,21 +70,22 @@ impl Kmeans {
         let colors = palette.as_slice();
         let len = colors.len();
 
-        let tls = ThreadLocal::new();
         let total = hist.total_perceptual_weight;
+        let alpha_weighted = hist.alpha_weighted;
 
-        // chunk size is a trade-off between parallelization and overhead
-        hist.items.par_chunks_mut(256).for_each(|batch| {
-            let kmeans = tls.get_or(move || RefCell::new(Kmeans::new(len)));
-            kmeans.borrow_mut().iterate_batch(batch, &n, colors, adjust_weight);
-        });
-
-        let diff = tls.into_iter()
-            .map(RefCell::into_inner)
-            .reduce(Kmeans::merge)
-            .map(|kmeans| {
-                kmeans.finalize(palette) / total
-            }).unwrap_or(0.);
+        // chunk size is a trade-off between parallelization and overhead.
+        // Chunks have a fixed size and are merged in order, so that the result doesn't depend on scheduling of threads.
+        let diff = hist.items.par_chunks_mut(ITEMS_PER_CHUNK).map(|batch| {
+            let mut kmeans = Kmeans::new(len);
+            kmeans.iterate_batch(batch, &n, colors, adjust_weight, alpha_weighted, exhaustive);
+            kmeans
+        })
+        .collect::<Vec<_>>()
+        .into_iter()
+        .reduce(Kmeans::merge)
+        .map(|kmeans| {
+            kmeans.finalize(palette) / total
+        }).unwrap_or(0.);
 
         // kmeans may have obsoleted some palette entries. Replace them with any entry from the histogram
         // (it happens so rarely that there's no point doing something smarter)
@@ -81,10 +95,10 @@ impl Kmeans {
         diff
     }
 
-    fn iterate_batch(&mut self, batch: &mut [HistItem], n: &Nearest, colors: &[f_pixel], adjust_weight: bool) {
+    fn iterate_batch(&mut self, batch: &mut [HistItem], n: &Nearest, colors: &[f_pixel], adjust_weight: bool, alpha_weighted: bool, exhaustive: bool) {
         self.weighed_diff_sum += batch.iter_mut().map(|item| {
             let px = item.color;
-            let (matched, mut diff) = n.search(&px, unsafe { item.tmp.likely_palette_index });
+            let (matched, mut diff) = if exhaustive { n.search_exhaustive(&px) } else { n.search(&px, unsafe { item.tmp.likely_palette_index }) };
             item.tmp.likely_palette_index = matched;
             if adjust_weight {
                 let remapped = colors[matched as usize];
@@ -93,18 +107,26 @@ impl Kmeans {
                 item.adjusted_weight = (item.perceptual_weight + 2. * item.adjusted_weight) * (0.5 + diff);
             }
             debug_assert!((diff as f64) < 1e20);
-            self.update_color(px, item.adjusted_weight, matched);
+            // garbage under transparent pixels would only fade visible colors
+            if !(alpha_weighted && px.a <= 0. && colors[matched as usize].a > 0.) {
+                self.update_color(px, item.adjusted_weight, matched);
+            }
             (diff * item.perceptual_weight) as f64
         }).sum::<f64>();
     }
 
     #[inline]
     pub fn merge(mut self, new: Kmeans) -> Kmeans {
-        self.weighed_diff_sum += new.weighed_diff_sum;
-        self.averages.iter_mut().zip(new.averages).for_each(|(p, n)| {
+        self.add(&new);
+        self
+    }
+
+    #[inline]
+    pub fn add(&mut self, other: &Kmeans) {
+        self.weighed_diff_sum += other.weighed_diff_sum;
+        self.averages.iter_mut().zip(&other.averages).for_each(|(p, n)| {
             p.sum += n.sum;
             p.total += n.total;
         });
-        self
     }
 }
@@ -0,0 +1,38 @@
+//! Quantization of images whose colors are categories, such as segmentation maps (see `Attributes::set_label_mode`)
+
+use crate::hist::FixedColorsSet;
+use crate::pal::{f_pixel, gamma_lut, AlphaEncoding, PalF, PalLen, PalPop, RGBA};
+
+/// Where pixels of colors that didn't get into the palette go, see [`Attributes::set_label_mode`](crate::Attributes::set_label_mode)
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum LabelFallback {
+    /// The most similar palette color
+    Nearest,
+    /// The palette color nearest to this one, e.g. of the background or an "unlabeled" class.
+    /// Add it with `Image::add_fixed_color` to make sure it's in the palette.
+    Color(RGBA),
+}
+
+/// The most frequent colors, exactly as they are, and fixed colors. All of them are fixed, so that K-means never averages them.
+///
+/// `counts` are colors with their weights from the histogram.
+pub(crate) fn label_palette(counts: impl Iterator<Item = (RGBA, u32)>, max_colors: PalLen, fixed_colors: &FixedColorsSet, gamma: f64, alpha_encoding: AlphaEncoding) -> PalF {
+    let lut = gamma_lut(gamma);
+    let alpha_lut = alpha_encoding.lut(gamma);
+    let mut counts: Vec<_> = counts.filter(|&(_, count)| count > 0).collect();
+    // ties are broken by color, so that the palette doesn't depend on order of the hashmap
+    counts.sort_unstable_by_key(|&(c, count)| (std::cmp::Reverse(count), [c.r, c.g, c.b, c.a]));
+
+    let mut palette = PalF::new();
+    for (color, count) in counts {
+        if palette.len() + fixed_colors.len() >= max_colors as usize {
+            break;
+        }
+        let color = f_pixel::from_rgba_alpha_lut(&lut, alpha_lut.as_ref(), color);
+        if fixed_colors.keys().any(|fixed| fixed.0 == color) {
+            continue;
+        }
+        palette.push(color, PalPop::new(count as f32).to_fixed());
+    }
+    palette.with_fixed_colors(max_colors, fixed_colors)
+}
@@ -0,0 +1,119 @@
+//! Stretching levels of low-contrast images before quantization (see `Attributes::set_auto_levels`)
+
+use crate::pal::RGBA;
+use std::num::NonZeroU8;
+
+/// Fraction of pixels at each end of a channel that is allowed to be clipped, so that a few specks of dust don't decide the range
+const CLIPPED_FRACTION: f64 = 0.001;
+
+/// Channels that already span this much are left as-is, because they have little to gain
+const FULL_RANGE: u8 = 224;
+
+/// Channels with a narrower range are left as-is, because stretching them would mostly amplify noise
+const MIN_RANGE: u8 = 32;
+
+/// Linear stretch of each of RGB channels from `black..=white` to the full `0..=255` range. Alpha is unchanged.
+///
+/// Quantization happens on stretched colors, and the final palette is mapped back with [`Levels::unstretch`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct Levels {
+    black: [u8; 3],
+    /// Always above black, so it's never 0, which also keeps `Option<Levels>` small
+    white: [NonZeroU8; 3],
+}
+
+impl Levels {
+    /// Black and white points of colors weighed by their counts. Fully transparent colors are ignored.
+    ///
+    /// `None` if the colors already use (nearly) the full range, and there's nothing to stretch.
+    pub(crate) fn from_histogram(colors: impl Iterator<Item = (RGBA, u32)>) -> Option<Self> {
+        let mut counts = [[0u64; 256]; 3];
+        let mut total = 0u64;
+        for (px, count) in colors {
+            if px.a == 0 || count == 0 {
+                continue;
+            }
+            counts[0][px.r as usize] += u64::from(count);
+            counts[1][px.g as usize] += u64::from(count);
+            counts[2][px.b as usize] += u64::from(count);
+            total += u64::from(count);
+        }
+        if total == 0 {
+            return None;
+        }
+
+        let clipped = (total as f64 * CLIPPED_FRACTION) as u64;
+        let mut black_points = [0; 3];
+        let mut white_points = [255; 3];
+        for (ch, counts) in counts.iter().enumerate() {
+            let black = percentile(counts.iter().enumerate(), clipped).unwrap_or(0);
+            let white = percentile(counts.iter().enumerate().rev(), clipped).unwrap_or(255);
+            let range = white.saturating_sub(black);
+            if (MIN_RANGE..FULL_RANGE).contains(&range) {
+                black_points[ch] = black;
+                white_points[ch] = white;
+            }
+        }
+
+        if black_points == [0; 3] && white_points == [255; 3] {
+            return None;
+        }
+        let [r, g, b] = white_points.map(NonZeroU8::new);
+        Some(Self { black: black_points, white: [r?, g?, b?] })
+    }
+
+    /// Maps a color of the image to the stretched range. Colors outside of the range are clipped.
+    #[inline]
+    pub(crate) fn stretch(&self, px: RGBA) -> RGBA {
+        let ch = |c: u8, i: usize| {
+            let black = u32::from(self.black[i]);
+            let range = u32::from(self.white[i].get()) - black;
+            let c = u32::from(c).clamp(black, black + range) - black;
+            ((c * 255 + range / 2) / range) as u8
+        };
+        RGBA::new(ch(px.r, 0), ch(px.g, 1), ch(px.b, 2), px.a)
+    }
+
+    /// Inverse of `stretch`, for colors of the palette
+    #[inline]
+    pub(crate) fn unstretch(&self, px: RGBA) -> RGBA {
+        let ch = |c: u8, i: usize| {
+            let black = u32::from(self.black[i]);
+            let range = u32::from(self.white[i].get()) - black;
+            (black + (u32::from(c) * range + 127) / 255) as u8
+        };
+        RGBA::new(ch(px.r, 0), ch(px.g, 1), ch(px.b, 2), px.a)
+    }
+
+    /// Inverse of `black_and_white_points`. `None` if white isn't above black in every channel.
+    pub(crate) fn from_black_and_white_points(black: RGBA, white: RGBA) -> Option<Self> {
+        let black = [black.r, black.g, black.b];
+        let white = [white.r, white.g, white.b];
+        if black.iter().zip(&white).any(|(b, w)| b >= w) {
+            return None;
+        }
+        let [r, g, b] = white.map(NonZeroU8::new);
+        Some(Self { black, white: [r?, g?, b?] })
+    }
+
+    /// Colors that are stretched to black and white
+    #[must_use]
+    pub(crate) fn black_and_white_points(&self) -> (RGBA, RGBA) {
+        let [r, g, b] = self.black;
+        let black = RGBA::new(r, g, b, 255);
+        let [r, g, b] = self.white;
+        (black, RGBA::new(r.get(), g.get(), b.get(), 255))
+    }
+}
+
+/// The first level past `clipped` pixels
+fn percentile<'a>(counts: impl Iterator<Item = (usize, &'a u64)>, clipped: u64) -> Option<u8> {
+    let mut sum = 0;
+    for (level, &count) in counts {
+        sum += count;
+        if sum > clipped {
+            return Some(level as u8);
+        }
+    }
+    None
+}
@@ -6,31 +6,110 @@
 #![doc(html_logo_url = "https://pngquant.org/pngquant-logo.png")]
 #![allow(non_camel_case_types)]
 
+#[cfg(all(feature = "capi", feature = "large-palettes"))]
+compile_error!("the C API uses 8-bit palette indices, so it can't be built with the `large-palettes` feature (use `default-features = false` to disable `capi`)");
+
+#[cfg(feature = "capi")]
 pub mod ffi;
+#[cfg(feature = "palette-io")]
+pub mod palette_io;
+#[cfg(feature = "yuv")]
+pub mod yuv;
 
 mod attr;
+#[cfg(feature = "dither-maps")]
 mod blur;
+mod cache;
+mod checkpoint;
+mod compare;
+mod constraint;
+mod cvd;
+#[cfg(feature = "dither-maps")]
+mod dithermap;
+mod duotone;
+mod eink;
 mod error;
+mod extended;
+mod float;
 mod hist;
 mod image;
+mod indexed;
 mod kmeans;
+mod labels;
+mod levels;
 mod mediancut;
+mod monitor;
 mod nearest;
+mod ordered;
 mod pal;
+mod pipeline;
 mod quant;
+mod reduce;
 mod remap;
+mod resize;
 mod rows;
+mod scenes;
 mod seacow;
+mod terminal;
 
 pub use attr::Attributes;
 pub use attr::ControlFlow;
+pub use attr::EffectiveSettings;
+pub use attr::LogCallback;
+pub use attr::LogEvent;
+pub use attr::OvershootStrategy;
+pub use attr::Refinement;
+pub use attr::TransparentPlacement;
+pub use cache::QuantCache;
+pub use cache::QuantCacheKey;
+pub use compare::compare_on;
+pub use compare::ComparisonReport;
+pub use compare::ResultScore;
+pub use compare::Winner;
+pub use constraint::PaletteConstraint;
+pub use cvd::ColorVisionDeficiency;
+pub use eink::EinkPanel;
+pub use eink::EinkRemapper;
 pub use error::liq_error;
+pub use error::ImageError;
+pub use error::QuantizeError;
+pub use extended::ExtendedQuantizationResult;
+pub use extended::MAX_EXTENDED_COLORS;
+pub use float::NonFinitePolicy;
 pub use hist::Histogram;
 pub use hist::HistogramEntry;
+pub use hist::HistogramStats;
 pub type Image<'pixels> = image::Image<'pixels, 'static>;
+pub use labels::LabelFallback;
+pub use monitor::PaletteMonitor;
+pub use pal::AlphaEncoding;
+pub use pal::PalIndex;
+pub use pal::MAX_COLORS;
 pub use pal::Palette;
+pub use pal::PaletteDiff;
 pub use pal::RGBA;
+pub use pipeline::HistogramBuilder;
+pub use pipeline::KmeansStep;
+pub use pipeline::PaletteOptimizer;
+pub use pipeline::Remapper;
+pub use pipeline::TileErrors;
+pub use quant::FrozenRemapper;
+pub use quant::IndexRun;
+#[cfg(feature = "debug-stages")]
+pub use quant::PaletteStage;
 pub use quant::QuantizationResult;
+pub use quant::RemappedImage;
+pub use reduce::reduce_palette;
+pub use reduce::ReducedPalette;
+pub use remap::DitherKernel;
+pub use remap::DitherLevel;
+pub use remap::DitherState;
+pub use remap::RemapOptions;
+pub use remap::RemapScratch;
+pub use scenes::SceneDetector;
+pub use resize::ResizeFilter;
+pub use terminal::TerminalPalette;
+pub use terminal::TerminalRemapper;
 
 const LIQ_HIGH_MEMORY_LIMIT: usize = 1 << 26;
 pub const LIQ_VERSION: u32 = 40000;
@@ -91,6 +170,364 @@ fn histogram() {
     assert_eq!(3, pal.len());
 }
 
+#[test]
+#[cfg(target_pointer_width = "64")]
+fn gigapixel_image() {
+    let width = 1 << 17;
+    let height = (1 << 15) + 3;
+    let attr = new();
+    let img = unsafe {
+        Image::new_fn(&attr, move |row, y| {
+            for (x, px) in row.iter_mut().enumerate() {
+                px.write(RGBA::new(x as u8, y as u8, (y >> 8) as u8, 255));
+            }
+        }, width, height, 0.)
+    }.unwrap();
+    assert_eq!(width * height, img.width() * img.height());
+    assert!(img.width() * img.height() > u32::MAX as usize);
+
+    let rows = img.px.rgba_rows_iter().unwrap();
+    let mut temp_row = rows::temp_buf(width);
+    let last_row = rows.row_rgba(&mut temp_row, height - 1);
+    assert_eq!(RGBA::new(255, (height - 1) as u8, ((height - 1) >> 8) as u8, 255), last_row[width - 1]);
+
+    // offsets of rows in dither maps would wrap around to row 2 in 32 bits
+    let map = vec![0u8; 4 * width];
+    assert_eq!(width, remap::map_row(&map, 3, width).len());
+    assert!(remap::map_row(&map, height - 1, width).is_empty());
+    assert!(remap::map_row(&map, usize::MAX / 2, width).is_empty());
+
+    let too_wide = unsafe { Image::new_fn(&attr, |_, _| {}, u32::MAX as usize + 2, 1, 0.) };
+    assert_eq!(liq_error::LIQ_BUFFER_TOO_SMALL, too_wide.err().unwrap());
+    assert!(Image::new_stride(&attr, &[RGBA::default(); 4], 2, usize::MAX / 2, usize::MAX / 2, 0.).is_err());
+}
+
+#[test]
+fn planar_image() {
+    let (width, height) = (37, 23);
+    let pixels: Vec<_> = (0..width * height).map(|i| RGBA::new(i as u8, (i / 7) as u8, (i * 3) as u8, if i % 5 == 0 { 128 } else { 255 })).collect();
+    let plane = |f: fn(&RGBA) -> u8| pixels.iter().map(f).collect::<Vec<u8>>();
+    let (r, g, b, a) = (plane(|p| p.r), plane(|p| p.g), plane(|p| p.b), plane(|p| p.a));
+
+    let attr = new();
+    let mut interleaved = attr.new_image(&pixels[..], width, height, 0.).unwrap();
+    let mut planar = Image::new_planar(&attr, &r, &g, &b, Some(&a), width, height, 0.).unwrap();
+    let mut res = attr.quantize(&mut interleaved).unwrap();
+    let mut res2 = attr.quantize(&mut planar).unwrap();
+    assert_eq!(res.palette_vec().unwrap(), res2.palette_vec().unwrap());
+    let mut interleaved = attr.new_image(&pixels[..], width, height, 0.).unwrap();
+    let mut planar = Image::new_planar(&attr, &r, &g, &b, Some(&a), width, height, 0.).unwrap();
+    assert_eq!(res.remapped(&mut interleaved).unwrap(), res2.remapped(&mut planar).unwrap());
+
+    let mut opaque = Image::new_planar(&attr, &r, &g, &b, None, width, height, 0.).unwrap();
+    let mut res = attr.quantize(&mut opaque).unwrap();
+    assert!(res.palette().iter().all(|c| c.a == 255));
+    assert_eq!(liq_error::LIQ_BUFFER_TOO_SMALL, Image::new_planar(&attr, &r, &g, &b[1..], None, width, height, 0.).err().unwrap());
+}
+
+#[test]
+fn chroma_key() {
+    let (width, height) = (32, 32);
+    // red sprite on a magenta key, which has been slightly altered by lossy compression
+    let bitmap: Vec<_> = (0..width * height).map(|i| {
+        let (x, y) = (i % width, i / width);
+        if (8..24).contains(&x) && (8..24).contains(&y) { RGBA::new(200, (x * 4) as u8, 10, 255) } else { RGBA::new(255 - (i % 5) as u8, (i % 3) as u8, 250, 255) }
+    }).collect();
+    let liq = new();
+    let mut img = liq.new_image(&bitmap[..], width, height, 0.).unwrap();
+    img.set_chroma_key(RGBA::new(255, 0, 255, 255), 6).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    let (palette, indices) = res.remapped(&mut img).unwrap();
+    assert!(palette.iter().all(|c| c.a == 0 || c.b < 100), "{:?}", palette);
+    let transparent = res.transparent_index().unwrap();
+    for (px, &idx) in bitmap.iter().zip(&indices) {
+        assert_eq!(px.b == 250, idx == transparent);
+    }
+    assert_eq!(Err(liq_error::LIQ_UNSUPPORTED), img.set_chroma_key(RGBA::new(0, 255, 0, 255), 0));
+}
+
+#[test]
+fn image_view() {
+    let (width, height) = (40, 30);
+    let bitmap: Vec<_> = (0..width * height).map(|i| {
+        let (x, y) = (i % width, i / width);
+        RGBA::new((x * 6) as u8, (y * 8) as u8, ((x ^ y) * 4) as u8, if x > 30 { 128 } else { 255 })
+    }).collect();
+    let (x, y, w, h) = (5, 7, 30, 20);
+    let crop: Vec<_> = bitmap.chunks(width).skip(y).take(h).flat_map(|row| row[x..x + w].iter().copied()).collect();
+
+    let liq = new();
+    let full = liq.new_image(&bitmap[..], width, height, 0.).unwrap();
+    let mut view = full.view(x, y, w, h).unwrap();
+    assert_eq!((w, h), (view.width(), view.height()));
+    let mut copy = liq.new_image(&crop[..], w, h, 0.).unwrap();
+
+    let mut res = liq.quantize(&mut view).unwrap();
+    let mut res2 = liq.quantize(&mut copy).unwrap();
+    assert_eq!(res.palette_vec().unwrap(), res2.palette_vec().unwrap());
+    let remapped = res.remapped(&mut full.view(x, y, w, h).unwrap()).unwrap();
+    assert_eq!(remapped, res2.remapped(&mut liq.new_image(&crop[..], w, h, 0.).unwrap()).unwrap());
+
+    assert_eq!(liq_error::LIQ_VALUE_OUT_OF_RANGE, full.view(x, y, w, h + 4).err().unwrap());
+    assert_eq!(liq_error::LIQ_VALUE_OUT_OF_RANGE, full.view(width, 0, 1, 1).err().unwrap());
+}
+
+#[test]
+fn indexed_input() {
+    // gradient of translucent reds, and transparent corners
+    let palette: Vec<_> = (0..64u8).map(|i| RGBA::new(255, i * 2, 0, 3 + i * 4)).chain([RGBA::new(0, 0, 0, 0), RGBA::new(9, 9, 9, 0)]).collect();
+    let (width, height) = (40, 30);
+    let indices: Vec<u8> = (0..width * height).map(|i| if i % width < 2 { 64 + (i / width % 2) as u8 } else { (i % width * 63 / width) as u8 }).collect();
+
+    let mut liq = new();
+    liq.set_max_colors(16).unwrap();
+    assert_eq!(Err(liq_error::LIQ_VALUE_OUT_OF_RANGE), liq.quantize_indexed(&palette[..10], &indices, width, height, 0.).map(|_| ()));
+    assert_eq!(Err(liq_error::LIQ_BUFFER_TOO_SMALL), liq.quantize_indexed(&palette, &indices[1..], width, height, 0.).map(|_| ()));
+
+    let (mut res, new_indices) = liq.quantize_indexed(&palette, &indices, width, height, 0.).unwrap();
+    let new_palette = res.palette_vec().unwrap();
+    assert!(new_palette.len() <= 16);
+    assert_eq!(width * height, new_indices.len());
+    for (&old, &new) in indices.iter().zip(&new_indices) {
+        let (old, new) = (palette[old as usize], new_palette[new as usize]);
+        if old.a == 0 {
+            assert_eq!(0, new.a);
+        } else {
+            assert!((i16::from(old.a) - i16::from(new.a)).abs() < 24, "{:?} {:?}", old, new);
+        }
+    }
+    // remapping another image doesn't move the colors the indices point to
+    let mut other = Image::new_indexed(&liq, &palette, &indices, width, height, 0.).unwrap();
+    res.remapped(&mut other).unwrap();
+    assert_eq!(new_palette, res.palette_vec().unwrap());
+
+    // the same pixels, expanded for the usual quantization and remapping
+    let mut img = Image::new_indexed(&liq, &palette, &indices, width, height, 0.).unwrap();
+    let mut res2 = liq.quantize(&mut img).unwrap();
+    let (expanded_palette, remapped) = res2.remapped(&mut img).unwrap();
+    assert!(expanded_palette.len() <= 16);
+    for (&old, &new) in indices.iter().zip(&remapped) {
+        assert_eq!(palette[old as usize].a == 0, expanded_palette[new as usize].a == 0);
+    }
+}
+
+#[test]
+fn f32_image_non_finite() {
+    let liq = new();
+    let (width, height) = (4, 4);
+    let pixels: Vec<[f32; 4]> = (0..width * height).map(|i| match i % 4 {
+        0 => [0.5, 0.25, 1., 1.],
+        1 => [f32::NAN, 0., 2., 1.],
+        2 => [f32::INFINITY, f32::NEG_INFINITY, 0.5, 1.],
+        _ => [1., 1., 1., 1.],
+    }).collect();
+    let remapped = |policy| {
+        let mut img = Image::new_f32(&liq, &pixels, width, height, 0., policy)?;
+        let mut res = liq.quantize(&mut img)?;
+        res.remapped(&mut img)
+    };
+    let expanded = |sanitized: [RGBA; 2]| {
+        let bitmap: Vec<_> = (0..width * height).map(|i| match i % 4 {
+            0 => RGBA::new(128, 64, 255, 255),
+            3 => RGBA::new(255, 255, 255, 255),
+            n => sanitized[n - 1],
+        }).collect();
+        let mut img = liq.new_image(&bitmap[..], width, height, 0.).unwrap();
+        let mut res = liq.quantize(&mut img).unwrap();
+        res.remapped(&mut img).unwrap()
+    };
+
+    assert_eq!(expanded([RGBA::new(0, 0, 255, 255), RGBA::new(255, 0, 128, 255)]), remapped(NonFinitePolicy::Clamp).unwrap());
+    assert_eq!(expanded([RGBA::new(0, 0, 0, 0); 2]), remapped(NonFinitePolicy::Transparent).unwrap());
+    assert_eq!(Err(liq_error::LIQ_VALUE_OUT_OF_RANGE), remapped(NonFinitePolicy::Error));
+    assert_eq!(liq_error::LIQ_BUFFER_TOO_SMALL, Image::new_f32(&liq, &pixels[1..], width, height, 0., NonFinitePolicy::Clamp).err().unwrap());
+}
+
+#[test]
+#[cfg(feature = "yuv")]
+fn yuv_image() {
+    use crate::yuv::{YuvMatrix, YuvPlanes, YuvRange};
+    let (width, height) = (5, 3);
+    // gray, red, and blue 2×2 blocks
+    let y = [126, 126, 82, 82, 41, 126, 126, 82, 82, 41, 235, 235, 16, 16, 16];
+    let u = [128, 90, 240, 128, 128, 128];
+    let v = [128, 240, 110, 128, 128, 128];
+    let uv: Vec<u8> = u.iter().zip(&v).flat_map(|(&u, &v)| [u, v]).collect();
+
+    let attr = new();
+    let nv12 = Image::new_yuv(&attr, YuvPlanes::Nv12 { y: &y, uv: &uv }, YuvMatrix::Bt601, YuvRange::Limited, width, height).unwrap();
+    let i420 = Image::new_yuv(&attr, YuvPlanes::I420 { y: &y, u: &u, v: &v }, YuvMatrix::Bt601, YuvRange::Limited, width, height).unwrap();
+    let mut nv12_row = rows::temp_buf(width);
+    let mut i420_row = rows::temp_buf(width);
+    let (nv12_rows, i420_rows) = (nv12.px.rgba_rows_iter().unwrap(), i420.px.rgba_rows_iter().unwrap());
+    for row in 0..height {
+        assert_eq!(nv12_rows.row_rgba(&mut nv12_row, row), i420_rows.row_rgba(&mut i420_row, row));
+    }
+    let top = i420_rows.row_rgba(&mut i420_row, 0).to_vec();
+    let bottom = i420_rows.row_rgba(&mut i420_row, 2).to_vec();
+    let close = |a: RGBA, b: RGBA| a.iter().zip(b.iter()).all(|(a, b)| (i16::from(a) - i16::from(b)).abs() <= 2);
+    assert!(close(RGBA::new(128, 128, 128, 255), top[0]), "{:?}", top[0]);
+    assert!(close(RGBA::new(255, 0, 0, 255), top[2]), "{:?}", top[2]);
+    assert!(close(RGBA::new(0, 0, 255, 255), top[4]), "{:?}", top[4]);
+    assert_eq!(RGBA::new(255, 255, 255, 255), bottom[0]);
+    assert_eq!(RGBA::new(0, 0, 0, 255), bottom[2]);
+
+    let mut img = Image::new_yuv(&attr, YuvPlanes::I420 { y: &y, u: &u, v: &v }, YuvMatrix::Bt709, YuvRange::Full, width, height).unwrap();
+    attr.quantize(&mut img).unwrap();
+    assert_eq!(liq_error::LIQ_BUFFER_TOO_SMALL, Image::new_yuv(&attr, YuvPlanes::I420 { y: &y, u: &u[1..], v: &v }, YuvMatrix::Bt601, YuvRange::Limited, width, height).err().unwrap());
+}
+
+#[test]
+fn gif_interlaced_rows() {
+    let (width, height) = (7, 21);
+    let pixels: Vec<_> = (0..width * height).map(|i| RGBA::new((i * 5) as u8, (i / 3) as u8, (i * 11) as u8, 255)).collect();
+    let attr = new();
+    let mut res = attr.quantize(&mut attr.new_image(&pixels[..], width, height, 0.).unwrap()).unwrap();
+    let options = RemapOptions::default();
+    let (_, sequential) = res.remapped_with(&mut attr.new_image(&pixels[..], width, height, 0.).unwrap(), &options).unwrap();
+    res.set_gif_interlaced(true);
+    let (_, interlaced) = res.remapped_with(&mut attr.new_image(&pixels[..], width, height, 0.).unwrap(), &options).unwrap();
+
+    let order = [0, 8, 16, 4, 12, 20, 2, 6, 10, 14, 18, 1, 3, 5, 7, 9, 11, 13, 15, 17, 19];
+    for (stored, &y) in interlaced.chunks_exact(width).zip(&order) {
+        assert_eq!(stored, &sequential[y * width..(y + 1) * width]);
+    }
+}
+
+#[test]
+#[cfg(feature = "dither-maps")]
+fn sharpened_image() {
+    let (width, height) = (16, 16);
+    // soft vertical edge between dark and light halves
+    let pixels: Vec<_> = (0..width * height).map(|i| {
+        let v = match i % width { 0..=6 => 60, 7 => 100, 8 => 160, _ => 200 };
+        RGBA::new(v, v, v, 255)
+    }).collect();
+    let attr = new();
+    let mut img = attr.new_image(&pixels[..], width, height, 0.).unwrap();
+    assert!(img.sharpened(1.5).is_err());
+
+    let unchanged = img.sharpened(0.).unwrap();
+    let sharp = img.sharpened(1.).unwrap();
+    let (unchanged, sharp) = (unchanged.px.rgba_rows_iter().unwrap(), sharp.px.rgba_rows_iter().unwrap());
+    let (mut temp_row1, mut temp_row2) = (rows::temp_buf(width), rows::temp_buf(width));
+    let unchanged_row = unchanged.row_rgba(&mut temp_row1, 8);
+    let sharp_row = sharp.row_rgba(&mut temp_row2, 8);
+    assert_eq!(&pixels[8 * width..9 * width], unchanged_row);
+    // more contrast across the edge, and flat areas are left alone
+    assert!(sharp_row[7].r < 100 && sharp_row[8].r > 160, "{:?}", sharp_row);
+    assert_eq!(sharp_row[0], pixels[0]);
+    assert_eq!(sharp_row[15], pixels[15]);
+}
+
+#[test]
+fn quant_cache() {
+    let attr = new();
+    let entries: Vec<_> = (0..200u8).map(|i| HistogramEntry { color: RGBA::new(i, 255 - i, i / 2, 255), count: u32::from(i) + 1 }).collect();
+    let mut hist = Histogram::new(&attr);
+    hist.add_colors(&entries, 0.).unwrap();
+    let mut reversed = Histogram::new(&attr);
+    for e in entries.iter().rev() {
+        reversed.add_colors(&[*e], 0.).unwrap();
+    }
+    let key = QuantCacheKey::new(&hist, &attr);
+    assert_eq!(key, QuantCacheKey::new(&reversed, &attr));
+
+    let mut fewer_colors = new();
+    fewer_colors.set_max_colors(16).unwrap();
+    assert_ne!(key, QuantCacheKey::new(&hist, &fewer_colors));
+    let mut other = Histogram::new(&attr);
+    other.add_colors(&entries[1..], 0.).unwrap();
+    assert_ne!(key, QuantCacheKey::new(&other, &attr));
+
+    let mut cache = QuantCache::new(2).unwrap();
+    assert!(cache.lookup(key).is_none());
+    let mut res = hist.quantize(&attr).unwrap();
+    cache.insert(key, &res);
+    let mut cached = cache.lookup(key).unwrap();
+    assert_eq!(res.palette(), cached.palette());
+    assert_eq!(res.quantization_error(), cached.quantization_error());
+
+    // least recently used is dropped
+    let other_key = QuantCacheKey::new(&other, &attr);
+    cache.insert(other_key, &other.quantize(&attr).unwrap());
+    let fewer_key = QuantCacheKey::new(&hist, &fewer_colors);
+    cache.insert(fewer_key, &hist.quantize(&fewer_colors).unwrap());
+    assert_eq!(2, cache.len());
+    assert!(cache.lookup(key).is_none());
+    assert_eq!(16, cache.lookup(fewer_key).unwrap().palette().len());
+    assert!(cache.remove(other_key));
+    assert_eq!(1, cache.len());
+}
+
+#[test]
+fn degenerate_inputs_are_errors() {
+    let mut attr = new();
+    assert_eq!(liq_error::LIQ_BUFFER_TOO_SMALL, attr.new_image_stride(&[], 0, 0, 0, 0.).err().unwrap());
+    assert_eq!(liq_error::LIQ_BUFFER_TOO_SMALL, attr.new_image_stride(&[], 0, 5, 0, 0.).err().unwrap());
+
+    // more fixed colors than the palette can hold
+    attr.set_max_colors(4).unwrap();
+    let mut hist = Histogram::new(&attr);
+    for i in 0..10u8 {
+        hist.add_fixed_color(RGBA::new(i * 20, 0, 0, 255), 0.).unwrap();
+    }
+    let entries: Vec<_> = (0..100u8).map(|i| HistogramEntry { color: RGBA::new(i, i * 2, 255 - i, 255), count: 1 }).collect();
+    hist.add_colors(&entries, 0.).unwrap();
+    let mut res = hist.quantize(&attr).unwrap();
+    assert_eq!(4, res.palette_vec().unwrap().len());
+}
+
+#[test]
+fn histogram_stats() {
+    let attr = Attributes::new();
+    let mut hist = Histogram::new(&attr);
+    assert_eq!(0, hist.stats().colors);
+
+    hist.add_colors(&[
+        HistogramEntry { color: RGBA::new(0, 0, 0, 0), count: 5 },
+        HistogramEntry { color: RGBA::new(9, 9, 9, 0), count: 5 },
+        HistogramEntry { color: RGBA::new(255, 0, 0, 255), count: 10 },
+        HistogramEntry { color: RGBA::new(0, 0, 255, 128), count: 10 },
+        HistogramEntry { color: RGBA::new(0, 255, 0, 255), count: 10 },
+    ], 0.0).unwrap();
+
+    let stats = hist.stats();
+    assert_eq!(4, stats.colors);
+    assert_eq!(1, stats.transparent_colors);
+    assert_eq!(1, stats.translucent_colors);
+    assert!((stats.entropy - 2.0).abs() < 0.001, "{}", stats.entropy);
+}
+
+#[test]
+fn unique_colors_estimate() {
+    let bitmap: Vec<_> = (0..300 * 300u32).map(|i| RGBA::new(i as u8, (i >> 8) as u8, (i >> 16) as u8 ^ (i as u8 & 0xF0), 255)).collect();
+
+    let attr = Attributes::new();
+    let mut hist = Histogram::new(&attr);
+    assert_eq!(0, hist.unique_colors_estimate());
+    let mut img = attr.new_image(&bitmap[..32], 8, 4, 0.).unwrap();
+    hist.add_image(&attr, &mut img).unwrap();
+    assert_eq!(32, hist.unique_colors_estimate());
+
+    let mut attr = Attributes::new();
+    attr.set_min_posterization(4).unwrap();
+    let mut hist = Histogram::new(&attr);
+    let mut img = attr.new_image(&bitmap[..], 300, 300, 0.).unwrap();
+    hist.add_image(&attr, &mut img).unwrap();
+    // not counted unless enabled
+    assert_eq!(hist.stats().colors, hist.unique_colors_estimate());
+    assert_eq!(liq_error::LIQ_UNSUPPORTED, hist.set_estimate_unique_colors(true));
+
+    let mut hist = Histogram::new(&attr);
+    hist.set_estimate_unique_colors(true).unwrap();
+    let mut img = attr.new_image(&bitmap[..], 300, 300, 0.).unwrap();
+    hist.add_image(&attr, &mut img).unwrap();
+    assert!(hist.stats().colors < 90000 / 4);
+    let estimate = hist.unique_colors_estimate();
+    assert!((estimate as f64 / 90000. - 1.).abs() < 0.06, "{}", estimate);
+}
+
 #[test]
 fn poke_it() {
     let width = 10usize;
@@ -164,112 +601,2501 @@ fn set_importance_map() {
 }
 
 #[test]
-fn thread() {
-    let liq = Attributes::new();
-    std::thread::spawn(move || {
-        let b = vec![RGBA::new(0, 0, 0, 0); 1];
-        liq.new_image(&b, 1, 1, 0.).unwrap();
-    }).join().unwrap();
+fn max_pixel_error() {
+    let bitmap = [RGBA::new(0, 0, 0, 255), RGBA::new(255, 255, 255, 255), RGBA::new(255, 0, 0, 255), RGBA::new(0, 0, 255, 255)];
+
+    let mut liq = new();
+    liq.set_max_colors(2);
+    liq.set_max_pixel_error(1.);
+    assert!(liq.max_pixel_error().unwrap() > 0.99);
+    let mut img = liq.new_image(&bitmap[..], 2, 2, 0.).unwrap();
+    assert_eq!(liq_error::LIQ_QUALITY_TOO_LOW, liq.quantize(&mut img).unwrap_err());
+
+    liq.set_max_colors(4);
+    let mut img = liq.new_image(&bitmap[..], 2, 2, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    assert_eq!(4, res.palette().len());
 }
 
 #[test]
-fn c_callback_test_c() {
-    use crate::ffi::*;
-    use std::mem::MaybeUninit;
+fn quality_too_low_result() {
+    let bitmap: Vec<_> = (0..64 * 64).map(|i| RGBA::new((i % 64 * 4) as u8, (i / 64 * 4) as u8, 128, 255)).collect();
 
-    use rgb::RGBA8 as RGBA;
-    use std::os::raw::*;
+    let mut liq = new();
+    liq.set_max_colors(4);
+    liq.set_quality(90, 100).unwrap();
+    let mut img = liq.new_image(&bitmap[..], 64, 64, 0.).unwrap();
+    assert_eq!(liq_error::LIQ_QUALITY_TOO_LOW, liq.quantize(&mut img).unwrap_err());
 
-    let mut called = 0;
-    let mut res = unsafe {
-        let mut a = liq_attr_create().unwrap();
-        unsafe extern "C" fn get_row(output_row: *mut MaybeUninit<RGBA>, y: c_int, width: c_int, user_data: *mut c_void) {
-            assert!((0..5).contains(&y));
-            assert_eq!(123, width);
-            for i in 0..width as isize {
-                let n = i as u8;
-                (*output_row.offset(i as isize)).write(RGBA::new(n, n, n, n));
-            }
-            let user_data = user_data as *mut i32;
-            *user_data += 1;
-        }
-        let mut img = liq_image_create_custom(&a, get_row, AnySyncSendPtr((&mut called) as *mut _ as *mut c_void), 123, 5, 0.).unwrap();
-        liq_quantize_image(&mut a, &mut img).unwrap()
+    let mut img = liq.new_image(&bitmap[..], 64, 64, 0.).unwrap();
+    let mut res = match liq.quantize_detailed(&mut img) {
+        Err(QuantizeError::QualityTooLow(res)) => res,
+        other => panic!("{:?}", other.map(|_| ())),
     };
-    assert!(called > 5 && called < 50);
-    let pal = liq_get_palette(&mut res).unwrap();
-    assert_eq!(123, pal.count);
+    assert!(res.quantization_quality().unwrap() < 90);
+    assert!(res.quantization_error().unwrap() > 0.);
+    assert_eq!(4, res.palette().len());
+    let (_, pixels) = res.remapped(&mut img).unwrap();
+    assert_eq!(64 * 64, pixels.len());
+
+    liq.set_max_colors(4);
+    liq.set_max_pixel_error(1.);
+    let mut img = liq.new_image(&bitmap[..], 64, 64, 0.).unwrap();
+    assert!(matches!(liq.quantize_detailed(&mut img), Err(QuantizeError::QualityTooLow(_))));
 }
 
 #[test]
-fn r_callback_test() {
-    use std::mem::MaybeUninit;
-    use std::sync::atomic::AtomicU16;
-    use std::sync::atomic::Ordering::SeqCst;
-    use std::sync::Arc;
+fn quantize_with_fallback() {
+    let bitmap: Vec<_> = (0..64 * 64).map(|i| RGBA::new((i % 64 * 4) as u8, (i / 64 * 4) as u8, 128, 255)).collect();
 
-    let called = Arc::new(AtomicU16::new(0));
-    let called2 = called.clone();
-    let mut res = {
-        let mut a = new();
-        let get_row = move |output_row: &mut [MaybeUninit<RGBA>], y: usize| {
-            assert!((0..5).contains(&y));
-            assert_eq!(123, output_row.len());
-            for (n, out) in output_row.iter_mut().enumerate() {
-                let n = n as u8;
-                out.write(RGBA::new(n, n, n, n));
-            }
-            called2.fetch_add(1, SeqCst);
-        };
-        let mut img = unsafe { Image::new_fn(&a, get_row, 123, 5, 0.).unwrap() };
-        a.quantize(&mut img).unwrap()
+    let mut strict = new();
+    strict.set_max_colors(4);
+    strict.set_quality(90, 100).unwrap();
+    let mut more_colors = strict.clone();
+    more_colors.set_max_colors(8);
+    let mut relaxed = strict.clone();
+    relaxed.set_quality(0, 100).unwrap();
+
+    let mut img = strict.new_image(&bitmap[..], 64, 64, 0.).unwrap();
+    let mut res = strict.quantize_with_fallback(&mut img, &[more_colors.clone(), relaxed.clone()]).unwrap();
+    assert_eq!(4, res.palette().len());
+
+    let mut hist = Histogram::new(&strict);
+    let mut img = strict.new_image(&bitmap[..], 64, 64, 0.).unwrap();
+    hist.add_image(&strict, &mut img).unwrap();
+    let mut res = match hist.quantize_with_fallback(&[strict.clone(), more_colors]) {
+        Err(QuantizeError::QualityTooLow(res)) => res,
+        other => panic!("{:?}", other.map(|_| ())),
     };
-    let called = called.load(SeqCst);
-    assert!(called > 5 && called < 50);
-    assert_eq!(123, res.palette().len());
+    assert_eq!(8, res.palette().len());
+    let mut res = hist.quantize_with_fallback(&[strict, relaxed]).unwrap();
+    assert_eq!(4, res.palette().len());
 }
 
 #[test]
-fn sizes() {
-    use pal::PalF;
-    use pal::Palette;
-    assert!(std::mem::size_of::<PalF>() < 256*(8*4)+32, "{}", std::mem::size_of::<PalF>());
-    assert!(std::mem::size_of::<QuantizationResult>() < std::mem::size_of::<PalF>() + std::mem::size_of::<Palette>() + 100, "{}", std::mem::size_of::<QuantizationResult>());
-    assert!(std::mem::size_of::<Attributes>() < 200);
-    assert!(std::mem::size_of::<Image>() < 300);
-    assert!(std::mem::size_of::<Histogram>() < 200);
-    assert!(std::mem::size_of::<crate::hist::HistItem>() <= 32);
+fn fixed_color_tolerance() {
+    let brand = RGBA::new(200, 0, 0, 255);
+    let shades = [brand, RGBA::new(198, 2, 0, 255), RGBA::new(202, 0, 3, 255)];
+    let bitmap: Vec<_> = (0..16 * 16).map(|i| {
+        let (x, y) = (i % 16, i / 16);
+        if x < 8 { shades[(x + y) % 3] } else { let g = (x * 16 + y) as u8; RGBA::new(g, g, g, 255) }
+    }).collect();
+
+    let mut liq = new();
+    liq.set_max_colors(8);
+    let mut img = liq.new_image(&bitmap[..], 16, 16, 0.).unwrap();
+    img.add_fixed_color_with_tolerance(brand, 20.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    res.set_dithering_level(1.);
+    let (palette, pixels) = res.remapped(&mut img).unwrap();
+    let brand_index = palette.iter().position(|&c| c == brand).unwrap();
+    for (i, &px) in pixels.iter().enumerate() {
+        if i % 16 < 8 {
+            assert_eq!(brand_index, px as usize);
+        }
+    }
 }
 
 #[test]
-fn ownership_bitflags() {
-    use seacow::liq_ownership;
-    assert_eq!(4+16, (liq_ownership::LIQ_OWN_ROWS | liq_ownership::LIQ_COPY_PIXELS).bits());
+fn undithered_color() {
+    let background = RGBA::new(250, 250, 240, 255);
+    let bitmap: Vec<_> = (0..32 * 32).map(|i| {
+        let (x, y) = (i % 32, i / 32);
+        if y < 16 { background } else { RGBA::new((x * 8) as u8, (y * 4) as u8, 128, 255) }
+    }).collect();
+
+    let mut liq = new();
+    liq.set_max_colors(4);
+    let mut img = liq.new_image(&bitmap[..], 32, 32, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    res.set_dithering_level(1.);
+    res.add_undithered_color(background).unwrap();
+    let (_, pixels) = res.remapped(&mut img).unwrap();
+    let first = pixels[0];
+    assert!(pixels[..32 * 16].iter().all(|&px| px == first));
 }
 
-#[doc(hidden)]
-pub fn _unstable_internal_kmeans_bench() -> impl FnMut() {
-    use crate::pal::PalF;
-    use crate::pal::PalPop;
+#[test]
+fn palette_mapping() {
+    let mut a = Palette { count: 3, entries: [RGBA::default(); MAX_COLORS] };
+    a.entries[..3].copy_from_slice(&[RGBA::new(255, 0, 0, 255), RGBA::new(0, 0, 250, 255), RGBA::new(0, 0, 0, 0)]);
+    let mut b = Palette { count: 4, entries: [RGBA::default(); MAX_COLORS] };
+    b.entries[..4].copy_from_slice(&[RGBA::new(0, 0, 0, 0), RGBA::new(0, 0, 255, 255), RGBA::new(0, 255, 0, 255), RGBA::new(250, 5, 0, 255)]);
+    let table = a.mapping_to(&b);
+    assert_eq!([3, 1, 0], table[..3]);
+}
 
-    let attr = new();
-    let mut h = hist::Histogram::new(&attr);
+#[test]
+fn palette_type() {
+    let colors = [RGBA::new(255, 0, 0, 255), RGBA::new(0, 0, 0, 0), RGBA::new(0, 0, 255, 128)];
+    let pal = Palette::new(&colors).unwrap();
+    assert_eq!(3, pal.len());
+    assert_eq!(RGBA::new(0, 0, 255, 128), pal[2]);
+    assert_eq!(&colors[..], &pal.to_rgba_vec()[..]);
+    assert_eq!(colors.iter().collect::<Vec<_>>(), (&pal).into_iter().collect::<Vec<_>>());
+    assert_eq!(vec![1, 2], pal.transparent_entries().collect::<Vec<_>>());
 
-    let e = (0..10000u32).map(|i| HistogramEntry {
-        count: i.wrapping_mul(17) % 12345,
-        color: RGBA::new(i as u8, (i.wrapping_mul(7) >> 2) as u8, (i.wrapping_mul(11) >> 11) as u8, 255),
-    }).collect::<Vec<_>>();
+    // unused entries don't matter
+    let mut other = pal.clone();
+    other.entries[200] = RGBA::new(1, 2, 3, 4);
+    assert_eq!(pal, other);
+    assert_eq!(format!("{:?}", &colors[..]), format!("{:?}", pal));
+    assert!(Palette::new(&[RGBA::default(); MAX_COLORS + 1]).is_err());
+}
 
-    h.add_colors(&e, 0.).unwrap();
-    let mut hist = h.finalize_builder(0.45455, 0.);
+#[test]
+fn palette_diff() {
+    let mut a = Palette { count: 4, entries: [RGBA::default(); MAX_COLORS] };
+    a.entries[..4].copy_from_slice(&[RGBA::new(255, 0, 0, 255), RGBA::new(0, 0, 250, 255), RGBA::new(0, 0, 0, 0), RGBA::new(0, 255, 0, 255)]);
+    let mut b = Palette { count: 4, entries: [RGBA::default(); MAX_COLORS] };
+    b.entries[..4].copy_from_slice(&[RGBA::new(0, 0, 0, 0), RGBA::new(0, 0, 255, 255), RGBA::new(255, 255, 255, 255), RGBA::new(255, 0, 0, 255)]);
+    let diff = a.diff(&b);
+    assert_eq!(vec![(0, 3), (2, 0)], diff.matched);
+    assert_eq!(1, diff.moved.len());
+    assert_eq!((1, 1), (diff.moved[0].0, diff.moved[0].1));
+    assert!(diff.moved[0].2 > 0. && diff.moved[0].2 < PaletteDiff::MAX_MOVE_DISTANCE);
+    assert_eq!(vec![2], diff.added);
+    assert_eq!(vec![3], diff.removed);
+    assert!(!diff.is_same());
 
-    let lut = pal::gamma_lut(0.45455);
-    let mut p = PalF::new();
-    for i in 0..=255 {
-        p.push(pal::f_pixel::from_rgba(&lut, RGBA::new(i|7, i, i, 255)), PalPop::new(1.));
+    let diff = b.diff(&b);
+    assert!(diff.is_same());
+    assert_eq!(4, diff.matched.len());
+}
+
+#[test]
+fn palette_perceptual_hash() {
+    let colors: Vec<_> = (0..16u8).map(|i| RGBA::new(i * 16, 255 - i * 16, 100, 255)).collect();
+    let pal = Palette::new(&colors).unwrap();
+    assert_ne!(0, pal.perceptual_hash());
+
+    let mut reversed = colors.clone();
+    reversed.reverse();
+    assert_eq!(pal.perceptual_hash(), Palette::new(&reversed).unwrap().perceptual_hash());
+
+    // a slightly different palette is near, an unrelated one is far
+    let mut similar = colors.clone();
+    similar[3].r += 8;
+    similar.push(RGBA::new(0, 0, 0, 0));
+    let similar = Palette::new(&similar).unwrap();
+    let other: Vec<_> = (0..16u8).map(|i| RGBA::new(200, i * 8, 255 - i * 8, 255)).collect();
+    let other = Palette::new(&other).unwrap();
+    let distance = |a: &Palette, b: &Palette| (a.perceptual_hash() ^ b.perceptual_hash()).count_ones();
+    assert!(distance(&pal, &similar) <= 2);
+    assert!(distance(&pal, &other) > distance(&pal, &similar) + 4);
+
+    // full palettes have colors in every cube, but are told apart by where most of their colors are
+    let full = |warm: bool| -> Palette {
+        let colors: Vec<_> = (0..=255u8).map(|i| if i < 64 {
+            RGBA::new(i % 4 * 64 + 32, i / 4 % 4 * 64 + 32, i / 16 * 64 + 32, 255)
+        } else if warm {
+            RGBA::new(200 + i % 50, i, 40, 255)
+        } else {
+            RGBA::new(40, i, 200 + i % 50, 255)
+        }).collect();
+        Palette::new(&colors).unwrap()
+    };
+    let (warm, cool) = (full(true), full(false));
+    assert_ne!(u64::MAX, warm.perceptual_hash());
+    assert_ne!(0, warm.perceptual_hash());
+    assert!(distance(&warm, &cool) > 4);
+}
+
+#[test]
+fn frozen_remapper() {
+    let bitmap: Vec<_> = (0..64 * 64u32).map(|i| RGBA::new(i as u8, (i >> 4) as u8, (i >> 6) as u8, 255)).collect();
+    let mut liq = new();
+    liq.set_max_colors(16);
+    let mut img = liq.new_image(&bitmap[..], 64, 64, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    res.set_dithering_level(1.);
+    let frozen = std::sync::Arc::new(res.freeze());
+    let expected = frozen.remapped(&mut liq.new_image(&bitmap[..], 64, 64, 0.).unwrap()).unwrap();
+
+    let threads: Vec<_> = (0..4).map(|_| {
+        let frozen = frozen.clone();
+        let bitmap = bitmap.clone();
+        std::thread::spawn(move || {
+            let liq = new();
+            let mut img = liq.new_image(&bitmap[..], 64, 64, 0.).unwrap();
+            frozen.remapped(&mut img).unwrap()
+        })
+    }).collect();
+    for t in threads {
+        assert_eq!(expected, t.join().unwrap());
     }
+    assert_eq!(16, frozen.palette().len());
+}
 
-    move || {
-        kmeans::Kmeans::iteration(&mut hist, &mut p, false);
+#[test]
+fn clone_result() {
+    let bitmap: Vec<_> = (0..32 * 32u32).map(|i| RGBA::new(i as u8, (i >> 2) as u8, 100, 255)).collect();
+    let mut liq = new();
+    liq.set_max_colors(8);
+    let mut img = liq.new_image(&bitmap[..], 32, 32, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    res.set_progress_callback(|_| ControlFlow::Break);
+
+    let mut copy = res.clone();
+    assert_eq!(res.palette(), copy.palette());
+    copy.set_dithering_level(0.5);
+    // the callback would have aborted remapping
+    copy.remapped(&mut img).unwrap();
+    assert!(res.remapped(&mut img).is_err());
+}
+
+#[test]
+fn checkpoint_round_trip() {
+    let (width, height) = (32, 32);
+    let bitmap: Vec<_> = (0..width * height).map(|i| RGBA::new((i % width * 8) as u8, (i / width * 8) as u8, 90, if i % 7 == 0 { 0 } else { 255 })).collect();
+    let mut liq = new();
+    liq.set_max_colors(20).unwrap();
+    let mut hist = Histogram::new(&liq);
+    let mut img = liq.new_image(&bitmap[..], width, height, 0.).unwrap();
+    img.add_fixed_color_tagged(RGBA::new(255, 255, 255, 255), 7).unwrap();
+    hist.add_image(&liq, &mut img).unwrap();
+    let key = QuantCacheKey::new(&hist, &liq);
+    let mut res = hist.quantize(&liq).unwrap();
+    res.set_dithering_level(0.8).unwrap();
+    res.add_undithered_color(RGBA::new(0, 0, 90, 255)).unwrap();
+
+    let mut saved = Vec::new();
+    res.write_checkpoint(Some(key), &mut saved).unwrap();
+    let (mut restored, digest) = QuantizationResult::read_checkpoint(&saved[..]).unwrap();
+    assert_eq!(Some(key), digest);
+    assert!(res.tagged_index(7).is_some());
+    assert_eq!(res.tagged_index(7), restored.tagged_index(7));
+    assert_eq!(res.quantization_error(), restored.quantization_error());
+
+    let remapped = res.remapped(&mut liq.new_image(&bitmap[..], width, height, 0.).unwrap()).unwrap();
+    assert_eq!(remapped, restored.remapped(&mut liq.new_image(&bitmap[..], width, height, 0.).unwrap()).unwrap());
+
+    // truncated or corrupted checkpoints are rejected
+    assert!(QuantizationResult::read_checkpoint(&saved[..saved.len() - 1]).is_err());
+    saved[8] = 99;
+    assert!(QuantizationResult::read_checkpoint(&saved[..]).is_err());
+}
+
+#[test]
+fn remapped_image() {
+    let bitmap: Vec<_> = (0..40 * 30u32).map(|i| if i % 40 < 10 { RGBA::new(0, 0, 0, 0) } else { RGBA::new(i as u8, 100, 200, 255) }).collect();
+    let liq = new();
+    let mut img = liq.new_image(&bitmap[..], 40, 30, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    let out = res.remapped_image(&mut img).unwrap();
+    assert_eq!((40, 30), (out.width, out.height));
+    assert_eq!(40 * 30, out.indices.len());
+    let transparent = out.transparent_index.unwrap();
+    assert_eq!(0, out.palette[transparent as usize].a);
+    assert_eq!(transparent, out.indices[0]);
+    assert!(out.error.is_some());
+
+    let opaque: Vec<_> = bitmap.iter().map(|c| RGBA { a: 255, ..*c }).collect();
+    let mut img = liq.new_image(&opaque[..], 40, 30, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    assert_eq!(None, res.remapped_image(&mut img).unwrap().transparent_index);
+}
+
+#[test]
+fn palette_monitor() {
+    assert!(PaletteMonitor::new(0, 50).is_err());
+    let mut monitor = PaletteMonitor::new(3, 40).unwrap();
+    assert_eq!(None, monitor.average_quality());
+
+    let frame = |shift: u8| -> Vec<RGBA> {
+        (0..32 * 32u32).map(|i| RGBA::new((i % 32 * 8) as u8, shift, (i / 32 * 8) as u8, 255)).collect()
+    };
+    let mut liq = new();
+    liq.set_max_colors(64).unwrap();
+    let pixels = frame(0);
+    let mut img = liq.new_image(&pixels[..], 32, 32, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    res.set_dithering_level(0.).unwrap();
+
+    for _ in 0..3 {
+        let mut img = liq.new_image(&pixels[..], 32, 32, 0.).unwrap();
+        res.remapped(&mut img).unwrap();
+        assert!(!monitor.add_frame(&res));
+    }
+    assert!(monitor.average_quality().unwrap() >= 40);
+
+    // content drifts away from the palette
+    monitor.reset();
+    assert_eq!(None, monitor.average_error());
+    let pixels = frame(255);
+    for i in 0..3 {
+        let mut img = liq.new_image(&pixels[..], 32, 32, 0.).unwrap();
+        res.remapped(&mut img).unwrap();
+        assert_eq!(i == 2, monitor.add_frame(&res), "needs 3 frames");
+    }
+    assert!(monitor.average_quality().unwrap() < 40);
+    assert!(monitor.needs_new_palette());
+}
+
+#[test]
+fn transparent_index() {
+    let bitmap: Vec<_> = (0..40 * 30u32).map(|i| if i % 40 < 10 { RGBA::new(0, 0, 0, 0) } else { RGBA::new(i as u8, 100, 200, 255) }).collect();
+    let liq = new();
+    let mut img = liq.new_image(&bitmap[..], 40, 30, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    assert_eq!(None, res.transparent_index());
+    let (palette, pixels) = res.remapped(&mut img).unwrap();
+    let transparent = res.transparent_index().unwrap();
+    assert_eq!(0, palette[transparent as usize].a);
+    assert_eq!(transparent, pixels[0]);
+
+    // next frame is the same, so it's left to the previous frame in the background
+    let opaque: Vec<_> = bitmap.iter().map(|c| RGBA { a: 255, ..*c }).collect();
+    let mut img = liq.new_image(&opaque[..], 40, 30, 0.).unwrap();
+    img.set_background(liq.new_image(&opaque[..], 40, 30, 0.).unwrap()).unwrap();
+    let (palette, pixels) = res.remapped(&mut img).unwrap();
+    let transparent = res.transparent_index().unwrap();
+    assert_eq!(0, palette[transparent as usize].a);
+    assert!(pixels.iter().filter(|&&idx| idx == transparent).count() > 40 * 30 / 2);
+}
+
+#[test]
+fn transparent_placement() {
+    // 40% and 30% of opaque colors, 20% transparent, 10% of another opaque color
+    let bitmap: Vec<_> = (0..40 * 30u32).map(|i| match i % 10 {
+        0..=3 => RGBA::new(200, 200, 40, 255),
+        4..=6 => RGBA::new(20, 40, 200, 255),
+        7 | 8 => RGBA::new(0, 0, 0, 0),
+        _ => RGBA::new(30, 200, 30, 255),
+    }).collect();
+    let opaque: Vec<_> = bitmap.iter().map(|c| RGBA { a: 255, ..*c }).collect();
+    let red = RGBA::new(255, 0, 0, 255);
+
+    let mut liq = new();
+    assert_eq!(TransparentPlacement::First, liq.transparent_placement());
+    liq.set_last_index_transparent(true);
+    assert_eq!(TransparentPlacement::Last, liq.transparent_placement());
+
+    let mut opaque_order = Vec::new();
+    for &placement in &[TransparentPlacement::First, TransparentPlacement::Last, TransparentPlacement::Keep] {
+        liq.set_transparent_placement(placement);
+        let mut img = liq.new_image(&bitmap[..], 40, 30, 0.).unwrap();
+        img.add_fixed_color_tagged(red, 1).unwrap();
+        let mut res = liq.quantize(&mut img).unwrap();
+        let palette = res.palette_vec().unwrap();
+        assert_eq!(5, palette.len());
+        assert_eq!(red, palette[res.tagged_index(1).unwrap() as usize]);
+        let transparent = palette.iter().position(|c| c.a == 0).unwrap();
+        match placement {
+            TransparentPlacement::First => assert_eq!(0, transparent),
+            TransparentPlacement::Last => assert_eq!(4, transparent),
+            TransparentPlacement::Keep => {},
+        }
+        // only the transparent color is moved
+        opaque_order.push(palette.iter().copied().filter(|c| c.a != 0).collect::<Vec<_>>());
+        assert_eq!(opaque_order[0], *opaque_order.last().unwrap());
+
+        // pixels left to the background use the same entry
+        let mut img = liq.new_image(&opaque[..], 40, 30, 0.).unwrap();
+        img.set_background(liq.new_image(&opaque[..], 40, 30, 0.).unwrap()).unwrap();
+        let (remapped_palette, _) = res.remapped(&mut img).unwrap();
+        assert_eq!(Some(transparent as PalIndex), res.transparent_index());
+        assert_eq!(red, remapped_palette[res.tagged_index(1).unwrap() as usize]);
+    }
+}
+
+#[test]
+fn remap_with_scratch() {
+    let frame = |w: usize, h: usize, t: usize| -> Vec<RGBA> {
+        (0..w * h).map(|i| RGBA::new((i % w * 255 / w) as u8, (i / w * 255 / h) as u8, (t * 40) as u8, 255)).collect()
+    };
+    let liq = new();
+    let pixels = frame(40, 30, 0);
+    let mut img = liq.new_image(&pixels[..], 40, 30, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+
+    let mut scratch = RemapScratch::new();
+    for (t, &(w, h)) in [(40, 30), (64, 20), (10, 50)].iter().enumerate() {
+        let pixels = frame(w, h, t);
+        for &level in &[0., 1.] {
+            res.set_dithering_level(level).unwrap();
+            let mut expected = res.clone();
+            let mut img = liq.new_image(&pixels[..], w, h, 0.).unwrap();
+            let (_, expected) = expected.remapped(&mut img).unwrap();
+
+            let mut img = liq.new_image(&pixels[..], w, h, 0.).unwrap();
+            let mut buf = vec![std::mem::MaybeUninit::uninit(); w * h];
+            let mut scratch_res = res.clone();
+            scratch_res.remap_into_with_scratch(&mut img, &mut buf, &mut scratch).unwrap();
+            let out: Vec<PalIndex> = buf.into_iter().map(|px| unsafe { px.assume_init() }).collect();
+            assert_eq!(expected, out);
+        }
+    }
+}
+
+#[test]
+fn remap_cache() {
+    let (width, height) = (64, 64);
+    // flat areas like a screenshot, and noise like a photo
+    let flat: Vec<_> = (0..width * height).map(|i| RGBA::new(((i % width) / 8 * 30) as u8, ((i / width) / 8 * 30) as u8, 100, 255)).collect();
+    let noise: Vec<_> = (0..(width * height) as u32).map(|i| {
+        let h = i.wrapping_mul(2654435761);
+        RGBA::new(h as u8, (h >> 8) as u8, (h >> 16) as u8, 255)
+    }).collect();
+    for bitmap in &[flat, noise] {
+        let liq = new();
+        let mut res = liq.quantize(&mut liq.new_image(&bitmap[..], width, height, 0.).unwrap()).unwrap();
+        let uncached = res.remapped(&mut liq.new_image(&bitmap[..], width, height, 0.).unwrap()).unwrap();
+        assert!(!res.remap_cache());
+        res.set_remap_cache(true);
+        let cached = res.remapped(&mut liq.new_image(&bitmap[..], width, height, 0.).unwrap()).unwrap();
+        assert_eq!(uncached, cached);
+        assert!(res.clone().remap_cache());
+    }
+}
+
+#[test]
+fn undithered_remap_progress() {
+    use std::sync::atomic::AtomicU32;
+    use std::sync::atomic::Ordering::SeqCst;
+    use std::sync::Arc;
+
+    let bitmap: Vec<_> = (0..64 * 1024u32).map(|i| RGBA::new(i as u8, (i >> 8) as u8, 100, 255)).collect();
+    let mut liq = new();
+    liq.set_max_colors(16).unwrap();
+    let mut img = liq.new_image(&bitmap[..], 64, 1024, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    res.set_dithering_level(0.).unwrap();
+
+    let calls = Arc::new(AtomicU32::new(0));
+    let calls2 = calls.clone();
+    res.set_progress_callback(move |percent| {
+        if percent > 0. && percent < 100. {
+            calls2.fetch_add(1, SeqCst);
+        }
+        ControlFlow::Continue
+    });
+    res.remapped(&mut img).unwrap();
+    assert!(calls.load(SeqCst) >= 10, "{}", calls.load(SeqCst));
+
+    res.set_progress_callback(|percent| if percent > 50. { ControlFlow::Break } else { ControlFlow::Continue });
+    assert_eq!(liq_error::LIQ_ABORTED, res.remapped(&mut img).unwrap_err());
+}
+
+#[test]
+#[cfg(feature = "dither-maps")]
+fn dither_map_progress() {
+    use std::sync::atomic::AtomicU32;
+    use std::sync::atomic::Ordering::SeqCst;
+    use std::sync::Arc;
+
+    let bitmap: Vec<_> = (0..64 * 1024u32).map(|i| RGBA::new(i as u8, (i >> 8) as u8, (i * 7) as u8, 255)).collect();
+    let mut liq = new();
+    liq.set_max_colors(16).unwrap();
+    let mut img = liq.new_image(&bitmap[..], 64, 1024, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+
+    // stage of building contrast maps and the dither map, before dithering starts
+    let calls = Arc::new(AtomicU32::new(0));
+    let calls2 = calls.clone();
+    res.set_progress_callback(move |percent| {
+        if percent > 0. && percent < 15. && percent != 5. {
+            calls2.fetch_add(1, SeqCst);
+        }
+        ControlFlow::Continue
+    });
+    let mut img = liq.new_image(&bitmap[..], 64, 1024, 0.).unwrap();
+    res.remapped(&mut img).unwrap();
+    assert!(calls.load(SeqCst) >= 10, "{}", calls.load(SeqCst));
+
+    res.set_progress_callback(|percent| if percent > 0. && percent < 5. { ControlFlow::Break } else { ControlFlow::Continue });
+    let mut img = liq.new_image(&bitmap[..], 64, 1024, 0.).unwrap();
+    assert_eq!(liq_error::LIQ_ABORTED, res.remapped(&mut img).unwrap_err());
+}
+
+#[test]
+fn effective_settings() {
+    let mut liq = new();
+    let default = liq.effective_settings();
+    assert_eq!((4, 0, 100), (default.speed, default.min_quality, default.target_quality));
+    assert_eq!((20, 12, 1_703_936, 0), (default.feedback_loop_trials, default.kmeans_iterations, default.max_histogram_entries, default.input_posterization));
+    assert!(default.dither_map && !default.dither_map_on_huge_images && default.contrast_maps);
+
+    liq.set_speed(1).unwrap();
+    liq.set_quality(50, 80).unwrap();
+    let slow = liq.effective_settings();
+    assert_eq!((50, 80), (slow.min_quality, slow.target_quality));
+    assert_eq!((47, 31, 2_490_368), (slow.feedback_loop_trials, slow.kmeans_iterations, slow.max_histogram_entries));
+    assert!(slow.dither_map && slow.dither_map_on_huge_images);
+    assert!(slow.kmeans_iteration_limit < default.kmeans_iteration_limit);
+
+    liq.set_speed(10).unwrap();
+    let fast = liq.effective_settings();
+    assert_eq!((0, 0, 131_072, 1), (fast.feedback_loop_trials, fast.kmeans_iterations, fast.max_histogram_entries, fast.input_posterization));
+    assert!(!fast.dither_map && !fast.contrast_maps);
+}
+
+#[test]
+fn auto_speed() {
+    use std::sync::{Arc, Mutex};
+
+    let pixels = |width: usize, height: usize| -> Vec<_> {
+        (0..width * height).map(|i| RGBA::new((i % width * 255 / width) as u8, (i / width * 255 / height) as u8, (i % 7 * 30) as u8, 255)).collect()
+    };
+    let mut auto = new();
+    assert!(!auto.auto_speed());
+    auto.set_auto_speed(true);
+    assert!(auto.auto_speed());
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let sink = log.clone();
+    auto.set_log_callback(move |_, msg| sink.lock().unwrap().push(msg.to_string()));
+
+    // icons get the best speed
+    let icon = pixels(64, 64);
+    let mut slowest = new();
+    slowest.set_speed(1).unwrap();
+    let palette = |attr: &Attributes| attr.quantize(&mut attr.new_image(&icon[..], 64, 64, 0.).unwrap()).unwrap().palette_vec().unwrap();
+    assert_eq!(palette(&slowest), palette(&auto));
+    assert_eq!(new().speed(), auto.speed());
+
+    let photo = pixels(300, 200);
+    auto.quantize(&mut auto.new_image(&photo[..], 300, 200, 0.).unwrap()).unwrap();
+    let log = log.lock().unwrap();
+    assert!(log.iter().any(|msg| msg.contains("speed 1 for 64×64")), "{:?}", log);
+    assert!(log.iter().any(|msg| msg.contains("speed 3 for 300×200")), "{:?}", log);
+}
+
+#[test]
+fn histogram_precision() {
+    // smooth gradient with a unique color in every pixel
+    let (width, height) = (512, 512);
+    let bitmap: Vec<_> = (0..width * height).map(|i| {
+        let (x, y) = (i % width, i / width);
+        RGBA::new((x / 2) as u8, (y / 2) as u8, ((x % 2) * 2 + (y % 2) + 100) as u8, 255)
+    }).collect();
+    let colors = |liq: &Attributes| {
+        let mut img = liq.new_image(&bitmap[..], width, height, 0.).unwrap();
+        let mut hist = Histogram::new(liq);
+        hist.add_image(liq, &mut img).unwrap();
+        hist.stats().colors
+    };
+
+    let mut liq = new();
+    liq.set_speed(10).unwrap();
+    assert_eq!(0, liq.histogram_precision());
+    // automatically, the fastest speed drops a bit of every channel
+    assert_eq!(128 * 128 * 2, colors(&liq));
+
+    assert_eq!(liq_error::LIQ_VALUE_OUT_OF_RANGE, liq.set_histogram_precision(4));
+    assert_eq!(liq_error::LIQ_VALUE_OUT_OF_RANGE, liq.set_histogram_precision(9));
+    liq.set_histogram_precision(8).unwrap();
+    assert_eq!(8, liq.histogram_precision());
+    assert_eq!((u32::MAX, 0), (liq.effective_settings().max_histogram_entries, liq.effective_settings().input_posterization));
+    assert_eq!(width * height, colors(&liq));
+
+    liq.set_histogram_precision(5).unwrap();
+    assert_eq!(3, liq.effective_settings().input_posterization);
+    assert_eq!(32 * 32, colors(&liq));
+
+    let mut img = liq.new_image(&bitmap[..], width, height, 0.).unwrap();
+    assert!(liq.quantize(&mut img).is_ok());
+}
+
+#[test]
+fn validate_image() {
+    let liq = new();
+    let bitmap = vec![RGBA::new(1, 2, 3, 255); 8 * 8];
+    let mut img = liq.new_image(&bitmap[..], 8, 8, 0.).unwrap();
+    img.validate().unwrap();
+
+    img.set_importance_map(&[255; 10]).unwrap();
+    let err = img.validate().unwrap_err();
+    assert_eq!(ImageError::ImportanceMapTooSmall { len: 10, required: 64 }, err);
+    assert_eq!(liq_error::LIQ_BUFFER_TOO_SMALL, liq_error::from(err));
+
+    let failing = unsafe { Image::new_fn(&liq, |_, row| assert!(row > 0), 8, 8, 0.).unwrap() };
+    assert_eq!(Err(ImageError::RowCallbackFailed), failing.validate());
+    let mut img = liq.new_image(&bitmap[..], 8, 8, 0.).unwrap();
+    img.set_background(failing).unwrap();
+    assert_eq!(Err(ImageError::Background(Box::new(ImageError::RowCallbackFailed))), img.validate());
+}
+
+#[test]
+fn remap_to_rgba() {
+    let bitmap: Vec<_> = (0..32 * 16u32).map(|i| RGBA::new(i as u8, (i >> 3) as u8, 50, if i % 7 == 0 { 0 } else { 255 })).collect();
+    let mut liq = new();
+    liq.set_max_colors(10).unwrap();
+    let mut img = liq.new_image(&bitmap[..], 32, 16, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    res.set_gif_interlaced(true);
+    let mut rgba = vec![RGBA::default(); 32 * 16];
+    res.remap_to_rgba_into(&mut img, &mut rgba).unwrap();
+
+    res.set_gif_interlaced(false);
+    let (pal, indices) = res.remapped(&mut img).unwrap();
+    assert!(indices.iter().zip(&rgba).all(|(&i, &px)| pal[i as usize] == px));
+    assert_eq!(liq_error::LIQ_BUFFER_TOO_SMALL, res.remap_to_rgba_into(&mut img, &mut rgba[1..]).unwrap_err());
+}
+
+#[test]
+fn remap_to_rgba_in_bands() {
+    // more pixels than fit in one band of the RGBA and run outputs
+    let (width, height) = (300, 260);
+    let bitmap: Vec<_> = (0..width * height).map(|i| RGBA::new((i % width) as u8, (i / width) as u8, ((i % width) ^ (i / width)) as u8, 255)).collect();
+    let liq = new();
+    let mut res = liq.quantize(&mut liq.new_image(&bitmap[..], width, height, 0.).unwrap()).unwrap();
+    for &dithering in &[0., 1.] {
+        res.set_dithering_level(dithering).unwrap();
+        let (pal, indices) = res.remapped(&mut liq.new_image(&bitmap[..], width, height, 0.).unwrap()).unwrap();
+
+        let mut rgba = vec![RGBA::default(); width * height];
+        res.remap_to_rgba_into(&mut liq.new_image(&bitmap[..], width, height, 0.).unwrap(), &mut rgba).unwrap();
+        assert!(indices.iter().zip(&rgba).all(|(&i, &px)| pal[i as usize] == px));
+
+        let (_, runs) = res.remapped_runs(&mut liq.new_image(&bitmap[..], width, height, 0.).unwrap(), false).unwrap();
+        let expanded: Vec<_> = runs.iter().flat_map(|r| std::iter::repeat_n(r.index, r.len)).collect();
+        assert_eq!(indices, expanded);
+    }
+}
+
+#[test]
+fn remapped_runs() {
+    let (width, height) = (20, 12);
+    let bitmap: Vec<_> = (0..width * height).map(|i| if (i % width) < 7 || i / width == 5 { RGBA::new(250, 0, 0, 255) } else { RGBA::new(0, 0, 250, 255) }).collect();
+    let liq = new();
+    let mut res = liq.quantize(&mut liq.new_image(&bitmap[..], width, height, 0.).unwrap()).unwrap();
+    let (_, indices) = res.remapped(&mut liq.new_image(&bitmap[..], width, height, 0.).unwrap()).unwrap();
+
+    for &per_row in &[true, false] {
+        let (_, runs) = res.remapped_runs(&mut liq.new_image(&bitmap[..], width, height, 0.).unwrap(), per_row).unwrap();
+        let expanded: Vec<_> = runs.iter().flat_map(|r| std::iter::repeat_n(r.index, r.len)).collect();
+        assert_eq!(indices, expanded);
+        assert!(runs.windows(2).all(|w| w[0].index != w[1].index || w[1].x == 0));
+        if per_row {
+            assert_eq!(height * 2 - 1, runs.len());
+            assert!(runs.iter().all(|r| r.x + r.len <= width));
+        } else {
+            // the solid row continues into the start of the next row
+            assert_eq!(height * 2 - 2, runs.len());
+            assert_eq!(IndexRun { x: 0, y: 5, len: 20 + 7, index: indices[5 * width] }, runs[10]);
+        }
+    }
+
+    res.set_gif_interlaced(true);
+    let (_, runs) = res.remapped_runs(&mut liq.new_image(&bitmap[..], width, height, 0.).unwrap(), true).unwrap();
+    let rows: Vec<_> = runs.iter().filter(|r| r.x == 0).map(|r| r.y).collect();
+    assert_eq!(vec![0, 8, 4, 2, 6, 10, 1, 3, 5, 7, 9, 11], rows);
+}
+
+#[test]
+fn compare_results() {
+    let (width, height) = (48, 32);
+    let bitmap: Vec<_> = (0..width * height).map(|i| {
+        let (x, y) = (i % width, i / width);
+        RGBA::new((x * 5) as u8, (y * 7) as u8, ((x + y) * 3) as u8, 255)
+    }).collect();
+    let mut liq = new();
+    liq.set_max_colors(64).unwrap();
+    let mut many = liq.quantize(&mut liq.new_image(&bitmap[..], width, height, 0.).unwrap()).unwrap();
+    liq.set_max_colors(4).unwrap();
+    let mut img = liq.new_image(&bitmap[..], width, height, 0.).unwrap();
+    let mut few = liq.quantize(&mut img).unwrap();
+    many.set_dithering_level(0.).unwrap();
+    few.set_dithering_level(0.).unwrap();
+
+    let report = compare_on(&mut img, &mut many, &mut few).unwrap();
+    assert_eq!(width * height, report.winners.len());
+    assert!(report.a.mse < report.b.mse);
+    assert!(report.a.ssim > report.b.ssim && report.a.ssim <= 1.);
+    let (wins_a, wins_b) = report.wins();
+    assert!(wins_a > wins_b * 4, "{} {}", wins_a, wins_b);
+
+    let report = compare_on(&mut img, &mut few.clone(), &mut few).unwrap();
+    assert_eq!(report.a, report.b);
+    assert!(report.winners.iter().all(|&w| w == Winner::Tie));
+}
+
+#[test]
+fn error_heatmap() {
+    let liq = new();
+    let bitmap = [RGBA::new(0, 0, 0, 255), RGBA::new(128, 128, 128, 255), RGBA::new(250, 250, 250, 255), RGBA::new(0, 0, 0, 255)];
+    let mut img = liq.new_image(&bitmap[..], 2, 2, 0.).unwrap();
+    let mut res = Remapper::new(&liq, &[RGBA::new(0, 0, 0, 255), RGBA::new(255, 255, 255, 255)], 0.).unwrap().into_result();
+    res.set_dithering_level(0.).unwrap();
+    let (_, indices) = res.remapped(&mut img).unwrap();
+    let heatmap = res.error_heatmap(&img, &indices, 50.).unwrap();
+    assert_eq!(4, heatmap.len());
+    assert_eq!(RGBA::new(0, 0, 255, 255), heatmap[0]);
+    assert_eq!(RGBA::new(255, 0, 0, 255), heatmap[1]);
+    assert!(heatmap[2].b > 0 && heatmap[2].r == 0, "{:?}", heatmap[2]);
+    assert!(res.error_heatmap(&img, &indices[..3], 50.).is_err());
+    assert!(res.error_heatmap(&img, &indices, 0.).is_err());
+}
+
+#[test]
+fn remap_with_options() {
+    let bitmap: Vec<_> = (0..32 * 32u32).map(|i| RGBA::new(i as u8, (i >> 2) as u8, 100, 255)).collect();
+    let mut liq = new();
+    liq.set_max_colors(8);
+    let mut img = liq.new_image(&bitmap[..], 32, 32, 0.).unwrap();
+    let res = liq.quantize(&mut img).unwrap();
+
+    let mut options = RemapOptions::default();
+    options.set_dither_level(DitherLevel::Fixed(0.)).unwrap();
+    let (_, undithered) = res.remapped_with(&mut img, &options).unwrap();
+    let (_, fs) = res.remapped_with(&mut img, &RemapOptions::default()).unwrap();
+    let mut options = RemapOptions::default();
+    options.set_kernel(DitherKernel::SierraLite);
+    let (pal, sierra) = res.remapped_with(&mut img, &options).unwrap();
+    assert_ne!(undithered, fs);
+    assert_ne!(fs, sierra);
+    assert!(sierra.iter().all(|&i| (i as usize) < pal.len()));
+    assert_eq!(liq_error::LIQ_VALUE_OUT_OF_RANGE, options.set_dither_level(DitherLevel::Fixed(2.)));
+    options.dither_level = DitherLevel::Fixed(2.);
+    assert!(res.remapped_with(&mut img, &options).is_err());
+}
+
+#[test]
+fn max_mix_distance() {
+    let (width, height) = (64, 16);
+    let bitmap: Vec<_> = (0..width * height).map(|i| match i % width {
+        0..=23 => RGBA::new(255, 0, 0, 255),
+        24..=39 => RGBA::new(128, 0, 128, 255),
+        _ => RGBA::new(0, 0, 255, 255),
+    }).collect();
+    let mut liq = new();
+    liq.set_max_colors(2).unwrap();
+    let mut img = liq.new_image(&bitmap[..], width, height, 0.).unwrap();
+    let res = liq.quantize(&mut img).unwrap();
+
+    let middle_colors = |pixels: &[PalIndex]| (0..height).flat_map(|y| (28..36).map(move |x| pixels[y * width + x])).collect::<std::collections::HashSet<_>>().len();
+    for &kernel in &[DitherKernel::FloydSteinberg, DitherKernel::Ordered] {
+        let mut options = RemapOptions::default();
+        options.set_kernel(kernel);
+        let (_, mixed) = res.remapped_with(&mut img, &options).unwrap();
+        assert_eq!(2, middle_colors(&mixed));
+        options.set_max_mix_distance(Some(100.)).unwrap();
+        let (_, limited) = res.remapped_with(&mut img, &options).unwrap();
+        assert_eq!(1, middle_colors(&limited));
+    }
+    let mut options = RemapOptions::default();
+    assert_eq!(liq_error::LIQ_VALUE_OUT_OF_RANGE, options.set_max_mix_distance(Some(-1.)));
+    options.max_mix_distance = Some(-1.);
+    assert!(res.remapped_with(&mut img, &options).is_err());
+}
+
+#[test]
+fn remap_with_metric() {
+    let liq = new();
+    let palette = [RGBA::new(190, 255, 255, 255), RGBA::new(0, 0, 0, 255)];
+    let bitmap: Vec<_> = (0..16 * 16u32).map(|i| RGBA::new(200, 0, (i % 3) as u8, 255)).collect();
+
+    let mut remapper = Remapper::new(&liq, &palette, 0.).unwrap();
+    remapper.set_dithering_level(0.);
+    let (pal, pixels) = remapper.remapped(&mut liq.new_image(&bitmap[..], 16, 16, 0.).unwrap()).unwrap();
+    assert!(pixels.iter().all(|&i| pal[i as usize] == palette[1]));
+
+    // only red matters
+    let mut remapper = Remapper::new(&liq, &palette, 0.).unwrap()
+        .with_metric(|px, color| (f32::from(px.r) - f32::from(color.r)).abs());
+    let (pal, pixels) = remapper.remapped(&mut liq.new_image(&bitmap[..], 16, 16, 0.).unwrap()).unwrap();
+    assert!(pixels.iter().all(|&i| pal[i as usize] == palette[0]));
+
+    let mut tile = vec![std::mem::MaybeUninit::uninit(); 8 * 4];
+    remapper.remap_tile(8, 4, &mut liq.new_image(&bitmap[..8 * 4], 8, 4, 0.).unwrap(), &mut tile, None).unwrap();
+    assert!(tile.into_iter().all(|i| unsafe { i.assume_init() } == pixels[0]));
+}
+
+#[test]
+fn noise_shaped_dithering() {
+    // smooth sky-like gradient
+    let (width, height) = (128, 64);
+    let bitmap: Vec<_> = (0..width * height).map(|i| {
+        let (x, y) = (i % width, i / width);
+        RGBA::new((60 + y) as u8, (100 + y + x / 4) as u8, (200 + x / 4) as u8, 255)
+    }).collect();
+    let mut liq = new();
+    liq.set_max_colors(16).unwrap();
+    let mut img = liq.new_image(&bitmap[..], width, height, 0.).unwrap();
+    let res = liq.quantize(&mut img).unwrap();
+
+    // error of averages of 8x8 blocks, i.e. low-frequency error that is visible as patterns
+    let block_error = |pal: &[RGBA], pixels: &[PalIndex]| {
+        let mut total = 0.;
+        for by in (0..height).step_by(8) {
+            for bx in (0..width).step_by(8) {
+                let mut diff = [0i32; 3];
+                for y in by..by + 8 {
+                    for x in bx..bx + 8 {
+                        let (out, inp) = (pal[pixels[y * width + x] as usize], bitmap[y * width + x]);
+                        diff[0] += out.r as i32 - inp.r as i32;
+                        diff[1] += out.g as i32 - inp.g as i32;
+                        diff[2] += out.b as i32 - inp.b as i32;
+                    }
+                }
+                total += diff.iter().map(|&d| (d as f64 / 64.).powi(2)).sum::<f64>();
+            }
+        }
+        total
+    };
+    let (pal, fs) = res.remapped_with(&mut img, &RemapOptions::default()).unwrap();
+    let mut options = RemapOptions::default();
+    options.set_kernel(DitherKernel::NoiseShaped);
+    let (shaped_pal, shaped) = res.remapped_with(&mut img, &options).unwrap();
+    assert_ne!(fs, shaped);
+    assert!(block_error(&shaped_pal, &shaped) < block_error(&pal, &fs));
+}
+
+#[test]
+fn ordered_dithering() {
+    let (width, height) = (64, 32);
+    let frame = |right: u8| -> Vec<RGBA> {
+        (0..width * height).map(|i| {
+            let x = i % width;
+            if x < 32 { RGBA::new((x * 8) as u8, (x * 8) as u8, 80, 255) } else { RGBA::new(right, 40, 200, 255) }
+        }).collect()
+    };
+    let (first, second) = (frame(20), frame(240));
+    let mut liq = new();
+    liq.set_max_colors(6).unwrap();
+    let mut img = liq.new_image(&first[..], width, height, 0.).unwrap();
+    let res = liq.quantize(&mut img).unwrap();
+    let mut options = RemapOptions::default();
+    options.set_kernel(DitherKernel::Ordered);
+
+    let (pal, ordered) = res.remapped_with(&mut img, &options).unwrap();
+    assert!(ordered.iter().all(|&i| (i as usize) < pal.len()));
+    // the gradient is dithered, and average of each 8x8 block stays close to the input
+    assert!(ordered[..32].iter().collect::<std::collections::HashSet<_>>().len() > 2);
+    for bx in (0..32).step_by(8) {
+        let (mut out, mut inp) = (0i32, 0i32);
+        for y in 0..8 {
+            for x in bx..bx + 8 {
+                out += pal[ordered[y * width + x] as usize].r as i32;
+                inp += first[y * width + x].r as i32;
+            }
+        }
+        assert!((out - inp).abs() / 64 < 24, "{} {}", out / 64, inp / 64);
+    }
+
+    // the same colors at the same positions are remapped the same way, regardless of changes elsewhere
+    let mut img2 = liq.new_image(&second[..], width, height, 0.).unwrap();
+    let (_, ordered2) = res.remapped_with(&mut img2, &options).unwrap();
+    for y in 0..height {
+        assert_eq!(ordered[y * width..y * width + 32], ordered2[y * width..y * width + 32]);
+    }
+}
+
+#[test]
+fn ordered_dither_frame_index() {
+    let bitmap = vec![RGBA::new(128, 128, 128, 255); 16 * 16];
+    let mut liq = new();
+    liq.set_max_colors(2).unwrap();
+    let image = |liq: &Attributes| {
+        let mut img = liq.new_image(&bitmap[..], 16, 16, 0.).unwrap();
+        img.add_fixed_color(RGBA::new(0, 0, 0, 255)).unwrap();
+        img.add_fixed_color(RGBA::new(255, 255, 255, 255)).unwrap();
+        img
+    };
+    let res = liq.quantize(&mut image(&liq)).unwrap();
+    let remap = |frame_index| {
+        let mut options = RemapOptions::default();
+        options.set_kernel(DitherKernel::Ordered);
+        options.set_frame_index(frame_index);
+        let (pal, indices) = res.remapped_with(&mut image(&liq), &options).unwrap();
+        indices.iter().map(|&i| pal[i as usize].r == 255).collect::<Vec<_>>()
+    };
+
+    let fixed = remap(None);
+    assert_eq!(fixed, remap(Some(0)));
+    assert_ne!(fixed, remap(Some(1)));
+    // every pixel flips between black and white over time, instead of the pattern standing still
+    let mut white_frames = vec![0; 16 * 16];
+    for frame in 0..64 {
+        let white = remap(Some(frame));
+        let count = white.iter().filter(|&&w| w).count();
+        assert!((96..160).contains(&count), "{}", count);
+        for (n, w) in white_frames.iter_mut().zip(white) {
+            *n += w as u32;
+        }
+    }
+    assert!(white_frames.iter().all(|&n| (16..48).contains(&n)), "{:?}", white_frames);
+}
+
+#[test]
+#[cfg(feature = "dither-maps")]
+fn auto_dither_level() {
+    // smooth gradient on the left, noisy texture on the right
+    let (width, height) = (64, 64);
+    let bitmap: Vec<_> = (0..width * height).map(|i| {
+        let (x, y) = (i % width, i / width);
+        if x < 32 {
+            RGBA::new((40 + y * 2) as u8, (60 + x) as u8, 150, 255)
+        } else {
+            let n = (i as u32).wrapping_mul(2_654_435_761) >> 24;
+            RGBA::new((40 + n / 2) as u8, (60 + n / 3) as u8, 150, 255)
+        }
+    }).collect();
+    let mut liq = new();
+    liq.set_speed(8).unwrap();
+    liq.set_max_colors(12).unwrap();
+    let mut img = liq.new_image(&bitmap[..], width, height, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    assert_eq!(liq_error::LIQ_VALUE_OUT_OF_RANGE, res.set_dither_level(DitherLevel::Fixed(-1.)));
+
+    let mut options = RemapOptions::default();
+    options.set_dither_level(DitherLevel::Fixed(0.)).unwrap();
+    let (_, undithered) = res.remapped_with(&mut img, &options).unwrap();
+    let (_, full) = res.remapped_with(&mut img, &RemapOptions::default()).unwrap();
+    res.set_dither_level(DitherLevel::Auto).unwrap();
+    let (_, auto) = res.remapped(&mut img).unwrap();
+    let changed = |a: &[PalIndex], b: &[PalIndex], left: bool| a.iter().zip(b).enumerate()
+        .filter(|&(i, (a, b))| (i % width < 32) == left && a != b).count();
+    // dithered like with the full level in the gradient, but barely at all in the texture
+    assert!(changed(&undithered, &auto, true) > changed(&undithered, &full, true) / 2);
+    assert!(changed(&undithered, &auto, false) < changed(&undithered, &full, false) / 4);
+}
+
+#[test]
+#[cfg(feature = "dither-maps")]
+fn dither_level_change_reuses_prepass() {
+    let (width, height) = (48, 48);
+    let bitmap: Vec<_> = (0..width * height).map(|i| {
+        let (x, y) = (i % width, i / width);
+        if (x / 12 + y / 12) % 2 == 0 { RGBA::new((x * 5) as u8, (y * 5) as u8, 90, 255) } else { RGBA::new(250, 240 - (x * 3) as u8, 20, 255) }
+    }).collect();
+    let liq = new();
+    let mut img = liq.new_image(&bitmap[..], width, height, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    res.set_dithering_level(1.).unwrap();
+    let full = res.remapped(&mut img).unwrap();
+    assert!(img.maps.dither_map.is_some() && img.maps.undithered_prepass.is_some());
+
+    res.set_dithering_level(0.5).unwrap();
+    let half = res.remapped(&mut img).unwrap();
+    assert_ne!(full.1, half.1);
+    res.set_dithering_level(1.).unwrap();
+    assert_eq!(full, res.remapped(&mut img).unwrap());
+
+    // same as remapping from scratch
+    let mut fresh = liq.new_image(&bitmap[..], width, height, 0.).unwrap();
+    liq.quantize(&mut fresh).unwrap();
+    res.set_dithering_level(0.5).unwrap();
+    assert_eq!(half, res.remapped(&mut fresh).unwrap());
+}
+
+#[test]
+fn thread() {
+    let liq = Attributes::new();
+    std::thread::spawn(move || {
+        let b = vec![RGBA::new(0, 0, 0, 0); 1];
+        liq.new_image(&b, 1, 1, 0.).unwrap();
+    }).join().unwrap();
+}
+
+#[test]
+fn deterministic() {
+    let liq = new();
+    let bitmap: Vec<_> = (0..96 * 64u32).map(|i| {
+        let h = i.wrapping_mul(2_654_435_761);
+        RGBA::new((i % 96) as u8, (h >> 24) as u8, (i / 96) as u8, 255)
+    }).collect();
+    let quantize = || {
+        let mut img = liq.new_image(&bitmap[..], 96, 64, 0.).unwrap();
+        let mut res = liq.quantize(&mut img).unwrap();
+        res.set_dithering_level(0.).unwrap();
+        res.remapped(&mut img).unwrap()
+    };
+    let first = quantize();
+    for _ in 0..3 {
+        assert_eq!(first, quantize());
+    }
+}
+
+#[test]
+fn remap_reentrant_in_rayon_pool() {
+    use rayon::prelude::*;
+    use std::mem::MaybeUninit;
+
+    let (width, height) = (64, 512);
+    let pixel = |x: usize, y: usize| RGBA::new((x * 4) as u8, y as u8, (x ^ y) as u8, 255);
+    let pixels: Vec<_> = (0..width * height).map(|i| pixel(i % width, i / width)).collect();
+    let liq = new();
+    let mut res = liq.quantize(&mut liq.new_image(&pixels[..], width, height, 0.).unwrap()).unwrap();
+    res.set_dithering_level(0.).unwrap();
+
+    let mut scratch = RemapScratch::new();
+    let mut expected = vec![MaybeUninit::uninit(); width * height];
+    res.remap_into_with_scratch(&mut liq.new_image(&pixels[..], width, height, 0.).unwrap(), &mut expected, &mut scratch).unwrap();
+
+    // rows made with rayon, so that threads waiting for them pick up other rows of the same remapping
+    let mut img = unsafe {
+        Image::new_fn(&liq, move |row, y| {
+            let (left, right) = row.split_at_mut(width / 2);
+            rayon::join(|| std::thread::sleep(std::time::Duration::from_micros(200)), || {
+                right.par_iter_mut().enumerate().for_each(|(x, px)| { px.write(pixel(x + width / 2, y)); });
+            });
+            left.iter_mut().enumerate().for_each(|(x, px)| { px.write(pixel(x, y)); });
+        }, width, height, 0.)
+    }.unwrap();
+    let mut out = vec![MaybeUninit::uninit(); width * height];
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+    pool.install(|| res.remap_into_with_scratch(&mut img, &mut out, &mut scratch)).unwrap();
+    let (expected, out): (Vec<_>, Vec<_>) = expected.iter().zip(&out).map(|(a, b)| unsafe { (a.assume_init(), b.assume_init()) }).unzip();
+    assert_eq!(expected, out);
+}
+
+#[test]
+fn input_fits_palette() {
+    let liq = new();
+    let bitmap: Vec<_> = (0..64 * 64u32).map(|i| {
+        let c = (i * 7 % 200) as u8;
+        RGBA::new(c, 255 - c, c / 2, if c.is_multiple_of(3) { 128 } else { 255 })
+    }).collect();
+    for dither in [0., 1.] {
+        let mut img = liq.new_image(&bitmap[..], 64, 64, 0.).unwrap();
+        let mut res = liq.quantize(&mut img).unwrap();
+        res.set_dithering_level(dither).unwrap();
+        let (pal, pixels) = res.remapped(&mut img).unwrap();
+        assert!(pixels.iter().zip(&bitmap).all(|(&i, px)| pal[i as usize] == *px));
+        assert_eq!(Some(0.), res.remapping_error());
+    }
+}
+
+#[test]
+fn pipeline_stages() {
+    let liq = new();
+    let bitmap: Vec<_> = (0..32 * 32u32).map(|i| RGBA::new((i * 7) as u8, (i / 4) as u8, (i * 3 / 5) as u8, 255)).collect();
+    let mut img = liq.new_image(&bitmap[..], 32, 32, 0.).unwrap();
+    let mut hist = HistogramBuilder::new(&liq);
+    hist.add_image(&liq, &mut img).unwrap();
+
+    let mut opt = PaletteOptimizer::new(&liq, &mut hist).unwrap();
+    opt.median_cut();
+    let first_error = opt.kmeans_iteration();
+    opt.kmeans_iteration();
+    assert!(opt.quantization_error().unwrap() <= first_error);
+
+    // custom post-processing: snap to 4 bits per channel
+    let snapped: Vec<_> = opt.palette().iter().map(|c| RGBA::new(c.r & 0xF0, c.g & 0xF0, c.b & 0xF0, 255)).collect();
+    let mut remapper = Remapper::new(&liq, &snapped, 0.).unwrap();
+    remapper.set_dithering_level(0.5).unwrap();
+    let (pal, pixels) = remapper.remapped(&mut img).unwrap();
+    assert!(pal.iter().all(|c| snapped.contains(c)));
+    assert!(pixels.iter().all(|&i| (i as usize) < pal.len()));
+
+    let mut remapper = opt.into_remapper();
+    assert!(remapper.remapped(&mut img).is_ok());
+}
+
+#[test]
+fn pipeline_custom_kmeans() {
+    let liq = new();
+    let bitmap: Vec<_> = (0..32 * 32u32).map(|i| RGBA::new((i * 7) as u8, (i / 4) as u8, (i * 3 / 5) as u8, 255)).collect();
+    let optimizer = || {
+        let mut img = liq.new_image(&bitmap[..], 32, 32, 0.).unwrap();
+        let mut hist = HistogramBuilder::new(&liq);
+        hist.add_image(&liq, &mut img).unwrap();
+        hist.add_fixed_color(RGBA::new(1, 2, 3, 255), 0.).unwrap();
+        PaletteOptimizer::new(&liq, &mut hist).unwrap()
+    };
+
+    let mut opt = optimizer();
+    assert_eq!(liq_error::LIQ_VALUE_OUT_OF_RANGE, opt.set_frozen(0, true));
+    assert!(opt.kmeans_iteration_with(&KmeansStep { step: 0., ..Default::default() }).is_err());
+    opt.median_cut();
+    let seed = opt.palette();
+    assert_eq!(RGBA::new(1, 2, 3, 255), seed[0]);
+    assert!(opt.is_frozen(0));
+    assert_eq!(liq_error::LIQ_UNSUPPORTED, opt.set_frozen(0, false));
+
+    // half a step goes half way (colors that weren't used are replaced in both)
+    let mut full = optimizer();
+    full.median_cut();
+    full.kmeans_iteration();
+    opt.kmeans_iteration_with(&KmeansStep { step: 0.5, adjust_weights: false }).unwrap();
+    let half = opt.palette();
+    assert_ne!(full.palette(), half);
+    for ((s, f), h) in seed.iter().zip(full.palette()).zip(half).filter(|((_, f), h)| f != h) {
+        let mid = |a: u8, b: u8| (i32::from(a) + i32::from(b)) / 2;
+        assert!((mid(s.r, f.r) - i32::from(h.r)).abs() <= 2 && (mid(s.g, f.g) - i32::from(h.g)).abs() <= 2, "{:?} {:?} {:?}", s, f, h);
+    }
+
+    // frozen colors stay, others keep moving
+    assert!(!opt.is_frozen(1));
+    opt.set_frozen(1, true).unwrap();
+    let before = opt.palette();
+    for _ in 0..3 {
+        opt.kmeans_iteration_with(&KmeansStep { step: 1., adjust_weights: true }).unwrap();
+    }
+    let after = opt.palette();
+    assert_eq!(before[..2], after[..2]);
+    assert_ne!(before[2..], after[2..]);
+    opt.set_frozen(1, false).unwrap();
+    assert!(!opt.is_frozen(1));
+}
+
+#[test]
+fn remap_tiles() {
+    let liq = new();
+    let gray = RGBA::new(128, 128, 128, 255);
+    let bitmap = vec![gray; 16 * 8];
+    let mut remapper = Remapper::new(&liq, &[RGBA::new(0, 0, 0, 255), RGBA::new(255, 255, 255, 255)], 0.).unwrap();
+    remapper.set_dithering_level(1.);
+    let mut remap_rows = |edge_errors: &mut Option<TileErrors>| {
+        let mut rows = Vec::new();
+        for y in 0..8 {
+            let mut tile = liq.new_image(&bitmap[..16], 16, 1, 0.).unwrap();
+            let mut row = vec![std::mem::MaybeUninit::uninit(); 16];
+            remapper.remap_tile(0, y, &mut tile, &mut row, edge_errors.as_mut()).unwrap();
+            rows.push(row.into_iter().map(|i| unsafe { i.assume_init() }).collect::<Vec<PalIndex>>());
+        }
+        rows
+    };
+    let separate = remap_rows(&mut None);
+    assert!(separate.iter().all(|row| row == &separate[0]));
+    let joined = remap_rows(&mut Some(TileErrors::new(16)));
+    assert!(joined.iter().any(|row| row != &joined[0]));
+    let white = joined.iter().flatten().filter(|&&i| i == 1).count();
+    assert!((48..=80).contains(&white), "{}", white);
+
+    let mut tile = liq.new_image(&bitmap[..16], 16, 1, 0.).unwrap();
+    let mut row = vec![std::mem::MaybeUninit::uninit(); 16];
+    assert!(remapper.remap_tile(8, 0, &mut tile, &mut row, Some(&mut TileErrors::new(16))).is_err());
+    assert_eq!(2, remapper.palette().len());
+}
+
+#[test]
+fn remap_strips() {
+    let mut liq = new();
+    // edges for the dither map would be different at the ends of strips
+    liq.set_speed(10).unwrap();
+    let bitmap: Vec<_> = (0..32 * 24u32).map(|i| RGBA::new((i % 32 * 8) as u8, (i / 32 * 10) as u8, 100, 255)).collect();
+    let palette = [RGBA::new(0, 0, 0, 255), RGBA::new(255, 255, 255, 255), RGBA::new(255, 0, 100, 255), RGBA::new(0, 255, 100, 255)];
+    let mut remapper = Remapper::new(&liq, &palette, 0.).unwrap();
+    remapper.set_dithering_level(1.);
+    let whole = remapper.remapped(&mut liq.new_image(&bitmap[..], 32, 24, 0.).unwrap()).unwrap().1;
+
+    let mut state = DitherState::new();
+    let mut strips = vec![std::mem::MaybeUninit::uninit(); 32 * 24];
+    // odd heights change direction of the serpentine scan
+    for (pixels, output) in bitmap.chunks(32 * 5).zip(strips.chunks_mut(32 * 5)) {
+        let mut strip = liq.new_image(pixels, 32, pixels.len() / 32, 0.).unwrap();
+        remapper.remap_strip_into(&mut strip, output, &mut state).unwrap();
+    }
+    let strips: Vec<PalIndex> = strips.into_iter().map(|i| unsafe { i.assume_init() }).collect();
+    assert_eq!(whole, strips);
+
+    // the state is only for strips of the same width
+    let mut narrow = liq.new_image(&bitmap[..16 * 24], 16, 24, 0.).unwrap();
+    let mut output = vec![std::mem::MaybeUninit::uninit(); 16 * 24];
+    assert_eq!(Err(liq_error::LIQ_VALUE_OUT_OF_RANGE), remapper.remap_strip_into(&mut narrow, &mut output, &mut state));
+    remapper.remap_strip_into(&mut narrow, &mut output, &mut DitherState::new()).unwrap();
+}
+
+#[test]
+fn gamma_encoded_alpha() {
+    let liq = new();
+    let bitmap: Vec<_> = (0..16u8).map(|i| RGBA::new(200, 100, 50, i * 13 + 60)).collect();
+    for &encoding in &[AlphaEncoding::Linear, AlphaEncoding::Gamma] {
+        let mut img = liq.new_image(&bitmap[..], 4, 4, 0.).unwrap();
+        img.set_alpha_encoding(encoding);
+        let mut res = liq.quantize(&mut img).unwrap();
+        let (pal, pixels) = res.remapped(&mut img).unwrap();
+        for (px, &i) in bitmap.iter().zip(&pixels) {
+            assert!((i32::from(px.a) - i32::from(pal[i as usize].a)).abs() <= 1, "{:?} {:?} {:?}", encoding, px, pal[i as usize]);
+        }
+    }
+}
+
+#[test]
+fn linear_palette() {
+    let bitmap = [RGBA::new(0, 0, 0, 255), RGBA::new(255, 255, 255, 255), RGBA::new(128, 128, 128, 255), RGBA::new(255, 0, 0, 0)];
+    let liq = new();
+    let mut res = liq.quantize(&mut liq.new_image(&bitmap[..], 2, 2, 0.).unwrap()).unwrap();
+    let srgb = res.palette_vec().unwrap();
+    let linear = res.palette_linear();
+    assert_eq!(srgb.len(), linear.len());
+    for (s, l) in srgb.iter().zip(&linear) {
+        assert_eq!(f32::from(s.a) / 255., l.a);
+        if s.a == 0 {
+            continue;
+        }
+        match s.r {
+            0 => assert_eq!(0., l.r),
+            255 => assert_eq!(1., l.r),
+            // mid-gray of sRGB is about a fifth of the light
+            128 => assert!((0.2..0.23).contains(&l.r), "{:?}", l),
+            _ => panic!("{:?}", s),
+        }
+    }
+
+    // output gamma 1 is linear already
+    res.set_output_gamma(0.99999).unwrap();
+    for (s, l) in res.palette_vec().unwrap().iter().zip(res.palette_linear()) {
+        assert!((f32::from(s.g) / 255. - l.g).abs() < 1. / 255., "{:?} {:?}", s, l);
+    }
+}
+
+#[test]
+fn extended_palette() {
+    let liq = new();
+    let bitmap: Vec<_> = (0..64 * 64u32).map(|i| RGBA::new((i % 64 * 4) as u8, (i / 64 * 4) as u8, (i % 7 * 30) as u8, 255)).collect();
+    let mut img = liq.new_image(&bitmap[..], 64, 64, 0.).unwrap();
+    assert!(liq.quantize_extended(&mut img, 5000).is_err());
+
+    let mut res = liq.quantize_extended(&mut img, 1000).unwrap();
+    let pal = res.palette().to_vec();
+    assert!(pal.len() > 256 && pal.len() <= 1000, "{}", pal.len());
+
+    for &dither in &[0., 1.] {
+        res.set_dithering_level(dither).unwrap();
+        let pixels = res.remapped_u16(&mut img).unwrap();
+        let mut total_diff = 0;
+        for (px, &i) in bitmap.iter().zip(&pixels) {
+            let c = pal[i as usize];
+            total_diff += (i32::from(px.r) - i32::from(c.r)).abs() + (i32::from(px.g) - i32::from(c.g)).abs() + (i32::from(px.b) - i32::from(c.b)).abs();
+        }
+        // 256 colors give average difference of 28
+        assert!(total_diff / (bitmap.len() as i32) < 14, "{}", total_diff / (bitmap.len() as i32));
+    }
+}
+
+#[test]
+#[cfg(feature = "large-palettes")]
+fn large_palette() {
+    let mut liq = new();
+    liq.set_max_colors(1000).unwrap();
+    assert!(liq.set_max_colors(MAX_COLORS as u32 + 1).is_err());
+    let bitmap: Vec<_> = (0..64 * 64u32).map(|i| RGBA::new((i % 64 * 4) as u8, (i / 64 * 4) as u8, (i % 7 * 30) as u8, 255)).collect();
+    let mut img = liq.new_image(&bitmap[..], 64, 64, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    res.set_dithering_level(0.).unwrap();
+    let (pal, pixels) = res.remapped(&mut img).unwrap();
+    assert!(pal.len() > 256 && pal.len() <= 1000, "{}", pal.len());
+    assert!(pixels.iter().any(|&i| i > 255));
+    assert!(pixels.iter().all(|&i| usize::from(i) < pal.len()));
+}
+
+#[test]
+fn small_palette() {
+    let mut liq = new();
+    liq.set_max_colors(2).unwrap();
+    let bitmap: Vec<_> = (0..64 * 16u32).map(|i| RGBA::new((i % 64 * 4) as u8, (i % 64 * 4) as u8, (i % 64 * 4) as u8, 255)).collect();
+    let mut img = liq.new_image(&bitmap[..], 64, 16, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    res.set_dithering_level(0.).unwrap();
+    let (pal, pixels) = res.remapped(&mut img).unwrap();
+    assert_eq!(2, pal.len());
+    assert!(pal.iter().all(|c| c.r == c.g && c.g == c.b), "{:?}", pal);
+    // gradient is split once, somewhere in the middle
+    let row = &pixels[..64];
+    let split = row.iter().position(|&i| i != row[0]).unwrap();
+    assert!(split > 16 && split < 48, "{}", split);
+    assert!(row[split..].iter().all(|&i| i == row[63]));
+}
+
+#[test]
+fn duotone() {
+    let dark = RGBA::new(20, 30, 90, 255);
+    let light = RGBA::new(250, 240, 200, 255);
+    let mut liq = new();
+    assert!(liq.set_duotone(light, dark).is_err());
+    liq.set_duotone(dark, light).unwrap();
+    liq.set_max_colors(4).unwrap();
+    let bitmap: Vec<_> = (0..64 * 64u32).map(|i| RGBA::new((i % 64 * 4) as u8, (i / 64 * 4) as u8, 128, 255)).collect();
+    let mut img = liq.new_image(&bitmap[..], 64, 64, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    let (pal, _) = res.remapped(&mut img).unwrap();
+    assert_eq!(4, pal.len());
+
+    let close = |a: u8, b: u8| (i16::from(a) - i16::from(b)).abs() <= 1;
+    let same = |a: RGBA, b: RGBA| close(a.r, b.r) && close(a.g, b.g) && close(a.b, b.b) && a.a == b.a;
+    assert!(pal.iter().any(|&c| same(c, dark)), "{:?}", pal);
+    assert!(pal.iter().any(|&c| same(c, light)), "{:?}", pal);
+    for c in &pal {
+        // mixes of the two colors are between them in every channel
+        assert!(c.r + 1 >= dark.r && c.r <= light.r + 1 && c.g + 1 >= dark.g && c.g <= light.g + 1 && c.b + 1 >= dark.b && c.b <= light.b + 1, "{:?}", c);
+    }
+
+    // colors off the line between the two inks can't meet quality limits
+    liq.set_max_pixel_error(1.).unwrap();
+    assert!(matches!(liq.quantize_detailed(&mut liq.new_image(&bitmap[..], 64, 64, 0.).unwrap()), Err(QuantizeError::QualityTooLow(_))));
+    let mut liq = new();
+    liq.set_duotone(dark, light).unwrap();
+    liq.set_max_colors(4).unwrap();
+    liq.set_quality(90, 100).unwrap();
+    assert!(matches!(liq.quantize_detailed(&mut liq.new_image(&bitmap[..], 64, 64, 0.).unwrap()), Err(QuantizeError::QualityTooLow(_))));
+}
+
+#[test]
+fn color_vision_deficiency() {
+    let mut liq = new();
+    liq.set_max_colors(3).unwrap();
+    // red and green that deutans confuse, and two blues that anyone can tell apart
+    let swatches = [RGBA::new(210, 80, 40, 255), RGBA::new(110, 130, 40, 255), RGBA::new(20, 40, 120, 255), RGBA::new(40, 75, 170, 255)];
+    let bitmap: Vec<_> = swatches.iter().flat_map(|&c| std::iter::repeat_n(c, 64)).collect();
+    let palette = |liq: &Attributes| {
+        let mut img = liq.new_image(&bitmap[..], 16, 16, 0.).unwrap();
+        liq.quantize(&mut img).unwrap().palette().to_vec()
+    };
+    let normal = palette(&liq);
+    assert!(normal.contains(&swatches[0]) && normal.contains(&swatches[1]));
+    liq.set_color_vision_deficiency(Some(ColorVisionDeficiency::Deutan));
+    let deutan = palette(&liq);
+    assert!(deutan.contains(&swatches[2]) && deutan.contains(&swatches[3]));
+
+    // colors are mapped back exactly
+    liq.set_max_colors(5).unwrap();
+    let mut img = liq.new_image(&bitmap[..], 16, 16, 0.).unwrap();
+    img.add_fixed_color(RGBA::new(1, 2, 3, 255)).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    let all = res.palette();
+    assert!(swatches.iter().chain(&[RGBA::new(1, 2, 3, 255)]).all(|c| all.contains(c)), "{:?}", all);
+}
+
+#[test]
+fn palette_constraint() {
+    let mut liq = new();
+    liq.set_max_colors(16).unwrap();
+    let bitmap: Vec<_> = (0..64 * 64u32).map(|i| RGBA::new((i % 64 * 4) as u8, (i / 64 * 4) as u8, (255 - i % 64 * 4) as u8, 255)).collect();
+    assert_eq!(liq_error::LIQ_VALUE_OUT_OF_RANGE, liq.set_palette_constraint(Some(PaletteConstraint::Ramps(vec![]))));
+
+    liq.set_palette_constraint(Some(PaletteConstraint::Ramps(vec![(RGBA::new(0, 0, 0, 255), RGBA::new(255, 255, 255, 255))]))).unwrap();
+    let mut img = liq.new_image(&bitmap[..], 64, 64, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    let grays = res.remapped(&mut img).unwrap().0;
+    assert!(grays.len() > 4);
+    assert!(grays.iter().all(|c| c.r.max(c.g).max(c.b) - c.r.min(c.g).min(c.b) <= 1), "{:?}", grays);
+
+    liq.set_palette_constraint(Some(PaletteConstraint::HueRange { hue: 200., width: 30. })).unwrap();
+    let mut img = liq.new_image(&bitmap[..], 64, 64, 0.).unwrap();
+    let palette = liq.quantize(&mut img).unwrap().palette().to_vec();
+    for c in palette {
+        let (r, g, b) = (f32::from(c.r), f32::from(c.g), f32::from(c.b));
+        let (max, min) = (r.max(g).max(b), r.min(g).min(b));
+        if max - min > 8. {
+            // cyan-blue: blue is the largest or close to green, and red is the smallest
+            assert!(r == min && b >= g - 2. && b - g < (max - min) * 0.6, "{:?}", c);
+        }
+    }
+}
+
+#[test]
+fn eink() {
+    let liq = new();
+    let mut bitmap = vec![RGBA::new(128, 128, 128, 255); 32 * 32];
+    bitmap[..32 * 8].fill(RGBA::new(0, 0, 0, 0));
+    bitmap[32 * 24..].fill(RGBA::new(200, 20, 20, 255));
+    let mut img = liq.new_image(&bitmap[..], 32, 32, 0.).unwrap();
+
+    let bw = EinkRemapper::new(EinkPanel::BlackWhite);
+    let pixels = bw.remapped(&mut img).unwrap();
+    assert!(pixels.iter().all(|&i| i < 2));
+    // transparent is flattened onto white paper
+    assert!(pixels[..32 * 8].iter().all(|&i| i == 1));
+    // gray is dithered
+    let gray = &pixels[32 * 8..32 * 24];
+    let white = gray.iter().filter(|&&i| i == 1).count();
+    assert!(white > gray.len() / 4 && white < gray.len() * 3 / 4, "{}", white);
+
+    let mut bwr = EinkRemapper::new(EinkPanel::BlackWhiteRed);
+    bwr.set_dithering_level(0.).unwrap();
+    let pixels = bwr.remapped(&mut img).unwrap();
+    assert_eq!(&RGBA::new(170, 40, 40, 255), &bwr.palette()[2]);
+    assert!(pixels[32 * 24..].iter().all(|&i| i == 2));
+
+    let acep = EinkRemapper::new(EinkPanel::Acep7);
+    assert_eq!(7, acep.palette().len());
+    assert!(acep.remapped(&mut img).unwrap().iter().all(|&i| i < 7));
+}
+
+#[test]
+fn terminal() {
+    let liq = new();
+    let bitmap: Vec<_> = (0..16 * 16u32).map(|i| RGBA::new((i % 16 * 16) as u8, (i / 16 * 16) as u8, 0, if i < 16 { 0 } else { 255 })).collect();
+    let mut img = liq.new_image(&bitmap[..], 16, 16, 0.).unwrap();
+
+    let xterm = TerminalRemapper::new(TerminalPalette::Xterm256);
+    assert_eq!(256, xterm.palette().len());
+    assert_eq!(RGBA::new(95, 135, 255, 255), xterm.palette()[16 + 36 + 2 * 6 + 5]);
+    assert_eq!(RGBA::new(238, 238, 238, 255), xterm.palette()[255]);
+    let codes = xterm.remapped(&mut img).unwrap();
+    assert!(codes.iter().all(|&c| c >= 16));
+    // transparent row is on black background
+    assert!(codes[..16].iter().all(|&c| c == 16));
+
+    let mut ansi = TerminalRemapper::new(TerminalPalette::Ansi16);
+    ansi.set_dithering_level(0.).unwrap();
+    ansi.set_background(RGBA::new(255, 255, 255, 255));
+    let codes = ansi.remapped(&mut img).unwrap();
+    assert!(codes.iter().all(|&c| c < 16));
+    assert!(codes[..16].iter().all(|&c| c == 15));
+    assert_eq!(10, codes[16 * 15]); // green
+}
+
+#[test]
+fn resized() {
+    let liq = new();
+    // checkerboard of black and white above red
+    let bitmap: Vec<_> = (0..100 * 60u32).map(|i| {
+        let (x, y) = (i % 100, i / 100);
+        if y >= 30 { RGBA::new(255, 0, 0, 255) } else if (x + y) % 2 == 0 { RGBA::new(255, 255, 255, 255) } else { RGBA::new(0, 0, 0, 255) }
+    }).collect();
+    let mut img = liq.new_image(&bitmap[..], 100, 60, 0.).unwrap();
+    assert!(img.resized(0, 10, ResizeFilter::Box).is_err());
+
+    for &filter in &[ResizeFilter::Box, ResizeFilter::Triangle] {
+        let mut small = img.resized(50, 50, filter).unwrap();
+        assert_eq!((50, 30), (small.width(), small.height()));
+        let mut res = liq.quantize(&mut small).unwrap();
+        res.set_dithering_level(0.).unwrap();
+        let (pal, _) = res.remapped(&mut small).unwrap();
+        // average of black and white in linear light, not 128
+        assert!(pal.iter().any(|c| c.r > 180 && c.r < 192 && c.r == c.g && c.g == c.b), "{:?}", pal);
+    }
+
+    let mut small = img.resized(10, 10, ResizeFilter::Triangle).unwrap();
+    let mut res = liq.quantize(&mut small).unwrap();
+    let (pal, pixels) = res.remapped(&mut img).unwrap();
+    assert_eq!(100 * 60, pixels.len());
+    assert_eq!(RGBA::new(255, 0, 0, 255), pal[pixels[100 * 59] as usize]);
+}
+
+#[test]
+fn mip_chain() {
+    let mut liq = new();
+    liq.set_speed(10).unwrap();
+    let level = |size: u32| -> Vec<RGBA> { (0..size * size).map(|i| RGBA::new((i % size * 255 / size) as u8, (i / size * 255 / size) as u8, 128, 255)).collect() };
+    let (l0, l1, l2) = (level(32), level(16), level(4));
+
+    let mut once = Histogram::new(&liq);
+    once.add_image_weighted(&liq, &mut liq.new_image(&l1[..], 16, 16, 0.).unwrap(), 2.).unwrap();
+    let mut twice = Histogram::new(&liq);
+    twice.add_image(&liq, &mut liq.new_image(&l1[..], 16, 16, 0.).unwrap()).unwrap();
+    twice.add_image(&liq, &mut liq.new_image(&l1[..], 16, 16, 0.).unwrap()).unwrap();
+    assert_eq!(QuantCacheKey::new(&twice, &liq), QuantCacheKey::new(&once, &liq));
+    assert!(once.add_image_weighted(&liq, &mut liq.new_image(&l1[..], 16, 16, 0.).unwrap(), -1.).is_err());
+
+    let mut levels = [liq.new_image(&l0[..], 32, 32, 0.).unwrap(), liq.new_image(&l1[..], 16, 16, 0.).unwrap(), liq.new_image(&l2[..], 4, 4, 0.).unwrap()];
+    assert!(liq.quantize_mip_chain(&mut levels, &[1.]).is_err());
+    let (mut res, indices) = liq.quantize_mip_chain(&mut levels, &[1., 4., 16.]).unwrap();
+    assert_eq!(vec![32 * 32, 16 * 16, 4 * 4], indices.iter().map(|i| i.len()).collect::<Vec<_>>());
+    let palette = res.palette_vec().unwrap();
+    for (level, indices) in levels.iter_mut().zip(&indices) {
+        assert!(indices.iter().all(|&i| (i as usize) < palette.len()));
+        assert_eq!(palette, res.remapped(level).unwrap().0);
+    }
+}
+
+#[test]
+#[cfg(feature = "dither-maps")]
+fn screenshot_mode() {
+    let background = RGBA::new(245, 245, 240, 255);
+    let text = RGBA::new(20, 40, 120, 255);
+    let mix = |t: u32| {
+        let m = |a: u8, b: u8| ((a as u32 * (255 - t) + b as u32 * t) / 255) as u8;
+        RGBA::new(m(background.r, text.r), m(background.g, text.g), m(background.b, text.b), 255)
+    };
+    // 2px-wide strokes with anti-aliasing of varying strength on both sides, next to a gradient
+    let bitmap: Vec<_> = (0..96 * 64u32).map(|i| {
+        let (x, y) = (i % 96, i / 96);
+        if x >= 64 { return RGBA::new((x * 4 - 128) as u8, (y * 2 + 64) as u8, 40, 255); }
+        match x % 8 {
+            3 | 4 => text,
+            2 => mix(40 + y * 3),
+            5 => mix(220 - y * 3),
+            _ => background,
+        }
+    }).collect();
+
+    let mut liq = new();
+    liq.set_max_colors(6).unwrap();
+    assert!(!liq.screenshot_mode());
+    liq.set_screenshot_mode(true);
+    let mut img = liq.new_image(&bitmap[..], 96, 64, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    let pal = res.palette();
+    assert!(pal.contains(&text), "{:?}", pal);
+    assert!(pal.contains(&background), "{:?}", pal);
+}
+
+#[test]
+#[cfg(feature = "dither-maps")]
+fn screenshot_preset() {
+    // dark and light areas, and a narrow stripe of a color between them that doesn't get its own palette entry
+    let (width, height) = (64, 48);
+    let bitmap: Vec<_> = (0..width * height).map(|i| match i % width {
+        0..=27 => RGBA::new(100, 100, 100, 255),
+        28..=35 => RGBA::new(128, 128, 128, 255),
+        _ => RGBA::new(156, 156, 156, 255),
+    }).collect();
+    let stripe_is_flat = |pixels: &[PalIndex]| (0..height).all(|y| (28..36).all(|x| pixels[y * width + x] == pixels[28]));
+
+    let mut liq = new();
+    liq.set_max_colors(2).unwrap();
+    assert!(liq.new_image(&bitmap[..], width, height, 0.).unwrap().looks_like_screenshot().unwrap());
+    let noise: Vec<_> = (0..64 * 64u32).map(|i| RGBA::new((i.wrapping_mul(2_654_435_761) >> 24) as u8, 0, 0, 255)).collect();
+    assert!(!liq.new_image(&noise[..], 64, 64, 0.).unwrap().looks_like_screenshot().unwrap());
+
+    // without the dither map, the stripe is dithered between the fixed dark and light colors
+    liq.set_speed(10).unwrap();
+    let image = |liq: &Attributes| {
+        let mut img = liq.new_image(&bitmap[..], width, height, 0.).unwrap();
+        img.add_fixed_color(RGBA::new(100, 100, 100, 255)).unwrap();
+        img.add_fixed_color(RGBA::new(156, 156, 156, 255)).unwrap();
+        img
+    };
+    let mut img = image(&liq);
+    let mut res = liq.quantize(&mut img).unwrap();
+    res.set_dithering_level(1.).unwrap();
+    let (_, pixels) = res.remapped(&mut img).unwrap();
+    assert!(!stripe_is_flat(&pixels));
+
+    liq.set_undithered_flat_areas(true);
+    let mut img = image(&liq);
+    let mut res = liq.quantize(&mut img).unwrap();
+    res.set_dithering_level(1.).unwrap();
+    let (_, pixels) = res.remapped(&mut img).unwrap();
+    assert!(stripe_is_flat(&pixels));
+
+    // palette of a mostly black and white image is refined for an image of grays, but exact colors are kept if asked
+    let source: Vec<_> = bitmap.iter().map(|&c| match c.r {
+        100 => RGBA::new(0, 0, 0, 255),
+        128 => RGBA::new(128, 128, 128, 255),
+        _ => RGBA::new(255, 255, 255, 255),
+    }).collect();
+    let grays: Vec<_> = bitmap.iter().map(|&c| if c.r == 100 { RGBA::new(40, 40, 40, 255) } else { RGBA::new(200, 200, 200, 255) }).collect();
+    let refined_palette = |liq: &Attributes| {
+        let mut img = liq.new_image(&source[..], width, height, 0.).unwrap();
+        let mut res = liq.quantize(&mut img).unwrap();
+        let exact: Vec<_> = res.palette().iter().copied().filter(|c| source.contains(c)).collect();
+        res.set_dithering_level(1.).unwrap();
+        let mut img = liq.new_image(&grays[..], width, height, 0.).unwrap();
+        (exact, res.remapped(&mut img).unwrap().0)
+    };
+    let mut liq = new();
+    liq.set_max_colors(2).unwrap();
+    let (exact, pal) = refined_palette(&liq);
+    assert!(!exact.iter().all(|c| pal.contains(c)), "{:?} {:?}", exact, pal);
+    assert!(!liq.keep_exact_colors());
+    liq.set_keep_exact_colors(true);
+    let (exact, pal) = refined_palette(&liq);
+    assert!(!exact.is_empty());
+    assert!(exact.iter().all(|c| pal.contains(c)), "{:?} {:?}", exact, pal);
+
+    let mut liq = new();
+    liq.set_screenshot_preset();
+    assert!(liq.screenshot_mode() && liq.keep_exact_colors() && liq.undithered_flat_areas());
+    assert_eq!(6, liq.speed());
+}
+
+#[test]
+#[cfg(feature = "dither-maps")]
+fn gradient_priority() {
+    // sky: a vertical gradient over most of the image, and a patch of colorful noise at the bottom
+    let (width, height) = (128, 128);
+    let bitmap: Vec<_> = (0..width * height).map(|i| {
+        let y = i / width;
+        if y < 96 {
+            let v = (y * 80 / 96) as u8;
+            RGBA::new(60 + v / 2, 100 + v, 200, 255)
+        } else {
+            let n = (i as u32).wrapping_mul(2_654_435_761);
+            RGBA::new((n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, 255)
+        }
+    }).collect();
+    let sky_colors = |liq: &Attributes| {
+        let mut img = liq.new_image(&bitmap[..], width, height, 0.).unwrap();
+        let mut res = liq.quantize(&mut img).unwrap();
+        let mut img = liq.new_image(&bitmap[..], width, height, 0.).unwrap();
+        let (_, pixels) = res.remapped(&mut img).unwrap();
+        let mut used = pixels[..96 * width].to_vec();
+        used.sort_unstable();
+        used.dedup();
+        used.len()
+    };
+
+    let mut liq = new();
+    liq.set_max_colors(16).unwrap();
+    let without = sky_colors(&liq);
+    assert!(!liq.gradient_priority());
+    liq.set_gradient_priority(true);
+    let with = sky_colors(&liq);
+    assert!(with > without, "{} {}", with, without);
+
+    liq.set_screenshot_preset();
+    liq.set_photo_preset();
+    assert!(liq.gradient_priority());
+    assert!(!liq.screenshot_mode() && !liq.keep_exact_colors() && !liq.undithered_flat_areas());
+    assert_eq!(3, liq.speed());
+}
+
+#[test]
+fn overshoot_strategy() {
+    let bitmap: Vec<_> = (0..128 * 128u32).map(|i| {
+        let (x, y) = (i % 128, i / 128);
+        RGBA::new((x * 2) as u8, (y * 2) as u8, ((x + y) / 2) as u8, 255)
+    }).collect();
+    let palette_len = |strategy| {
+        let mut liq = new();
+        liq.set_quality(0, 70).unwrap();
+        liq.set_overshoot_strategy(strategy);
+        let mut img = liq.new_image(&bitmap[..], 128, 128, 0.).unwrap();
+        liq.quantize(&mut img).unwrap().palette().len()
+    };
+
+    assert_eq!(OvershootStrategy::Balanced, new().overshoot_strategy());
+    let off = palette_len(OvershootStrategy::Off);
+    let aggressive = palette_len(OvershootStrategy::Aggressive);
+    assert!(aggressive < off, "{} {}", aggressive, off);
+    assert!(palette_len(OvershootStrategy::Balanced) <= off);
+}
+
+#[test]
+fn refinement() {
+    let bitmap: Vec<_> = (0..64 * 64u32).map(|i| {
+        let (x, y) = (i % 64, i / 64);
+        RGBA::new((x * 4) as u8, (y * 4) as u8, ((x * y) / 16) as u8, 255)
+    }).collect();
+    let quantize = |refinement| {
+        let mut liq = new();
+        liq.set_max_colors(16).unwrap();
+        liq.set_refinement(refinement);
+        let mut img = liq.new_image(&bitmap[..], 64, 64, 0.).unwrap();
+        let mut res = liq.quantize(&mut img).unwrap();
+        let palette = res.palette().to_vec();
+        let mut img = liq.new_image(&bitmap[..], 64, 64, 0.).unwrap();
+        res.remapped(&mut img).unwrap();
+        (palette, res.remapping_error().unwrap())
+    };
+
+    assert_eq!(Refinement::Full, new().refinement());
+    let (raw, raw_error) = quantize(Refinement::None);
+    let (_, quick_error) = quantize(Refinement::Quick);
+    let (full, full_error) = quantize(Refinement::Full);
+    assert_ne!(raw, full);
+    assert!(full_error < raw_error && quick_error < raw_error, "{} {} {}", full_error, quick_error, raw_error);
+    // raw median cut is deterministic
+    assert_eq!(raw, quantize(Refinement::None).0);
+
+    let mut liq = new();
+    liq.set_refinement(Refinement::None);
+    let settings = liq.effective_settings();
+    assert_eq!((0, 0), (settings.feedback_loop_trials, settings.kmeans_iterations));
+    liq.set_refinement(Refinement::Quick);
+    assert_eq!(1, liq.effective_settings().kmeans_iterations);
+    // quality limits still work without K-means
+    liq.set_refinement(Refinement::None);
+    liq.set_quality(99, 100).unwrap();
+    let mut img = liq.new_image(&bitmap[..], 64, 64, 0.).unwrap();
+    assert!(liq.quantize(&mut img).is_err());
+}
+
+#[test]
+fn exact_final_iteration() {
+    use crate::pal::{PalF, PalPop};
+
+    let attr = new();
+    let mut h = hist::Histogram::new(&attr);
+    let e = (0..5000u32).map(|i| HistogramEntry {
+        count: 1 + i % 7,
+        color: RGBA::new(i as u8, (i.wrapping_mul(7) >> 2) as u8, (i.wrapping_mul(11) >> 6) as u8, 255),
+    }).collect::<Vec<_>>();
+    h.add_colors(&e, 0.).unwrap();
+    let mut hist = h.finalize_builder(0.45455, 0., None, 0, false);
+    let lut = pal::gamma_lut(0.45455);
+    let mut p = PalF::new();
+    for i in 0..64u8 {
+        p.push(pal::f_pixel::from_rgba(&lut, RGBA::new(i * 4, i.wrapping_mul(37), 255 - i * 4, 255)), PalPop::new(1.));
+    }
+
+    let n = nearest::Nearest::new(&p);
+    let expected = hist.items.iter().map(|item| {
+        let best = p.as_slice().iter().map(|c| item.color.diff(c)).fold(f32::INFINITY, f32::min);
+        assert_eq!(best, n.search_exhaustive(&item.color).1);
+        assert!(best <= n.search(&item.color, 0).1 * 1.0001);
+        f64::from(best * item.perceptual_weight)
+    }).sum::<f64>() / hist.total_perceptual_weight;
+    drop(n);
+    let error = kmeans::Kmeans::exact_iteration(&mut hist, &mut p.clone());
+    assert!((error - expected).abs() < expected * 1e-4, "{} {}", error, expected);
+
+    let pixels: Vec<_> = e.iter().map(|e| e.color).collect();
+    let mut liq = new();
+    assert!(!liq.exact_final_iteration());
+    liq.set_exact_final_iteration(true);
+    assert!(liq.exact_final_iteration());
+    let res = liq.quantize(&mut liq.new_image(&pixels[..], 100, 50, 0.).unwrap()).unwrap();
+    assert!(res.quantization_error().is_some());
+}
+
+#[test]
+fn label_mode() {
+    let labels = [RGBA::new(0, 0, 0, 255), RGBA::new(200, 30, 30, 255), RGBA::new(30, 200, 30, 255), RGBA::new(30, 30, 200, 255), RGBA::new(210, 40, 40, 255)];
+    // labels cover 40%, 25%, 20%, 10% and 5% of the image, and a few pixels are blends of two labels
+    let bitmap: Vec<_> = (0..100 * 100u32).map(|i| match i % 100 {
+        0 => RGBA::new(100, 100, 30, 255),
+        1..=40 => labels[0],
+        41..=65 => labels[1],
+        66..=85 => labels[2],
+        86..=95 => labels[3],
+        _ => labels[4],
+    }).collect();
+
+    let mut liq = new();
+    liq.set_max_colors(4).unwrap();
+    assert_eq!(None, liq.label_mode());
+    liq.set_label_mode(Some(LabelFallback::Nearest));
+    let mut img = liq.new_image(&bitmap[..], 100, 100, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    let mut pal = res.palette().to_vec();
+    pal.sort_by_key(|c| labels.iter().position(|l| l == c));
+    assert_eq!(&labels[..4], &pal[..]);
+
+    res.set_dithering_level(1.).unwrap();
+    let mut img = liq.new_image(&bitmap[..], 100, 100, 0.).unwrap();
+    let (pal, pixels) = res.remapped(&mut img).unwrap();
+    for (px, &idx) in bitmap.iter().zip(&pixels) {
+        let expected = if *px == labels[4] { labels[1] } else if labels.contains(px) { *px } else { pal[idx as usize] };
+        assert_eq!(expected, pal[idx as usize]);
+    }
+
+    liq.set_label_mode(Some(LabelFallback::Color(labels[0])));
+    let mut img = liq.new_image(&bitmap[..], 100, 100, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    let mut img = liq.new_image(&bitmap[..], 100, 100, 0.).unwrap();
+    let (pal, pixels) = res.remapped(&mut img).unwrap();
+    for (px, &idx) in bitmap.iter().zip(&pixels) {
+        let expected = if labels[..4].contains(px) { *px } else { labels[0] };
+        assert_eq!(expected, pal[idx as usize]);
+    }
+}
+
+#[test]
+fn image_log_callback() {
+    use std::sync::{Arc, Mutex};
+
+    let attr_log = Arc::new(Mutex::new(Vec::new()));
+    let mut liq = new();
+    let sink = attr_log.clone();
+    liq.set_log_callback(move |_, msg| sink.lock().unwrap().push(msg.to_string()));
+
+    let bitmap: Vec<_> = (0..64 * 64u32).map(|i| RGBA::new((i % 64 * 4) as u8, (i / 64 * 4) as u8, 90, 255)).collect();
+    let logs: Vec<_> = (0..2).map(|_| Arc::new(Mutex::new(Vec::new()))).collect();
+    let threads: Vec<_> = logs.iter().map(|log| {
+        let (liq, bitmap, sink) = (liq.clone(), bitmap.clone(), log.clone());
+        std::thread::spawn(move || {
+            let mut img = liq.new_image(&bitmap[..], 64, 64, 0.).unwrap();
+            img.set_log_callback(move |_, msg| sink.lock().unwrap().push(msg.to_string()));
+            liq.quantize(&mut img).unwrap();
+        })
+    }).collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    for log in &logs {
+        let log = log.lock().unwrap();
+        assert_eq!(1, log.iter().filter(|msg| msg.contains("made histogram")).count(), "{:?}", log);
+    }
+    assert!(attr_log.lock().unwrap().is_empty());
+
+    let mut img = liq.new_image(&bitmap[..], 64, 64, 0.).unwrap();
+    liq.quantize(&mut img).unwrap();
+    assert!(attr_log.lock().unwrap().iter().any(|msg| msg.contains("made histogram")));
+}
+
+#[test]
+fn log_events() {
+    use std::sync::{Arc, Mutex};
+
+    let pixels: Vec<_> = (0..64 * 64u32).map(|i| {
+        if i % 64 < 4 { RGBA::new(0, 0, 0, 0) } else { RGBA::new((i * 7) as u8, (i / 64 * 4) as u8, ((i * 13) >> 3) as u8, 255) }
+    }).collect();
+    let mut liq = new();
+    liq.set_speed(10).unwrap();
+    liq.set_max_colors(8).unwrap();
+    liq.set_quality(95, 99).unwrap();
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let (events_sink, log_sink) = (events.clone(), log.clone());
+    liq.set_event_callback(move |_, event| events_sink.lock().unwrap().push(*event));
+    liq.set_log_callback(move |_, msg| log_sink.lock().unwrap().push(msg.to_string()));
+
+    let mut img = liq.new_image(&pixels[..], 64, 64, 0.).unwrap();
+    assert!(liq.quantize(&mut img).is_err());
+    let events = events.lock().unwrap();
+    let log = log.lock().unwrap();
+    assert!(events.iter().any(|e| matches!(e, LogEvent::ColorsSelected { .. })), "{:?}", events);
+    let (mse, limit) = events.iter().find_map(|e| match *e { LogEvent::MseExceeded { mse, limit } => Some((mse, limit)), _ => None }).unwrap();
+    assert!(mse > limit);
+    for event in events.iter() {
+        assert!(log.contains(&event.to_string()), "{} {:?}", event, log);
+    }
+
+    // the transparent color is first, so tRNS needs only one entry
+    let mut liq = new();
+    let trns = Arc::new(Mutex::new(None));
+    let sink = trns.clone();
+    liq.set_event_callback(move |_, event| if let LogEvent::TrnsEliminated { count } = *event { *sink.lock().unwrap() = Some(count); });
+    liq.quantize(&mut liq.new_image(&pixels[..], 64, 64, 0.).unwrap()).unwrap();
+    assert_eq!(Some(1), *trns.lock().unwrap());
+    assert_eq!("  eliminated opaque tRNS-chunk entries...1 entry transparent", LogEvent::TrnsEliminated { count: 1 }.to_string());
+}
+
+#[test]
+fn base_palette() {
+    // grays that the base palette has, and a red area that it doesn't
+    let bitmap: Vec<_> = (0..64 * 64u32).map(|i| match i % 64 {
+        0..=47 => RGBA::new((i % 4 * 80) as u8, (i % 4 * 80) as u8, (i % 4 * 80) as u8, 255),
+        _ => RGBA::new(220, 20, 30, 255),
+    }).collect();
+    let base = [RGBA::new(240, 240, 240, 255), RGBA::new(0, 0, 0, 255), RGBA::new(0, 0, 255, 255), RGBA::new(160, 160, 160, 255), RGBA::new(80, 80, 80, 255)];
+    let liq = new();
+
+    let mut img = liq.new_image(&bitmap[..], 64, 64, 0.).unwrap();
+    assert_eq!(liq_error::LIQ_VALUE_OUT_OF_RANGE, liq.quantize_with_base_palette(&mut img, &[base[0], base[1], base[0]], 2).unwrap_err());
+    assert_eq!(liq_error::LIQ_VALUE_OUT_OF_RANGE, liq.quantize_with_base_palette(&mut img, &base, MAX_COLORS as u32).unwrap_err());
+
+    let mut res = liq.quantize_with_base_palette(&mut img, &base, 3).unwrap();
+    res.set_dithering_level(0.).unwrap();
+    let mut img = liq.new_image(&bitmap[..], 64, 64, 0.).unwrap();
+    let (pal, pixels) = res.remapped(&mut img).unwrap();
+    assert!(pal.len() > base.len() && pal.len() <= base.len() + 3, "{:?}", pal);
+    assert_eq!(&base[..], &pal[..base.len()]);
+    for (px, &idx) in bitmap.iter().zip(&pixels) {
+        if px.r == px.g {
+            assert_eq!(*px, pal[idx as usize]);
+        } else if px.r == 220 {
+            assert!(idx as usize >= base.len());
+            let c = pal[idx as usize];
+            assert!(c.r > 200 && c.g < 40, "{:?}", c);
+        }
+    }
+}
+
+#[test]
+#[cfg(feature = "debug-stages")]
+fn debug_stage_palettes() {
+    let bitmap: Vec<_> = (0..64 * 64u32).map(|i| RGBA::new((i % 64 * 4) as u8, (i / 64 * 4) as u8, (i % 7 * 30) as u8, 255)).collect();
+    let mut liq = new();
+    liq.set_max_colors(16).unwrap();
+    let mut img = liq.new_image(&bitmap[..], 64, 64, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    let median_cut = res.stage_palette(PaletteStage::MedianCut).unwrap();
+    let feedback = res.stage_palette(PaletteStage::FeedbackLoop).unwrap();
+    let kmeans = res.stage_palette(PaletteStage::Kmeans).unwrap();
+    assert!(median_cut.len() <= 16 && feedback.len() <= 16);
+    assert_ne!(median_cut, kmeans);
+    // the final palette is the last stage, sorted (it hasn't been remapped, so it's not refined further)
+    let (mut last, mut fin) = (kmeans.clone(), res.palette().to_vec());
+    last.sort_by_key(|c| (c.r, c.g, c.b, c.a));
+    fin.sort_by_key(|c| (c.r, c.g, c.b, c.a));
+    assert_eq!(last, fin);
+
+    liq.set_refinement(Refinement::None);
+    let mut img = liq.new_image(&bitmap[..], 64, 64, 0.).unwrap();
+    let res = liq.quantize(&mut img).unwrap();
+    assert!(res.stage_palette(PaletteStage::MedianCut).is_some());
+    assert!(res.stage_palette(PaletteStage::Kmeans).is_none());
+}
+
+#[test]
+fn palette_reduction() {
+    assert_eq!(liq_error::LIQ_VALUE_OUT_OF_RANGE, crate::reduce_palette(&[RGBA::new(1, 2, 3, 255)], &[], 4).unwrap_err());
+    assert_eq!(liq_error::LIQ_VALUE_OUT_OF_RANGE, crate::reduce_palette(&[RGBA::new(1, 2, 3, 255)], &[0.], 4).unwrap_err());
+
+    // palettes of two frames: reds and blues, with the same black in both
+    let frame1: Vec<_> = (0..8).map(|i| RGBA::new(128 + i * 16, 10, 10, 255)).chain(Some(RGBA::new(0, 0, 0, 255))).collect();
+    let frame2: Vec<_> = (0..8).map(|i| RGBA::new(10, 10, 128 + i * 16, 255)).chain(Some(RGBA::new(0, 0, 0, 255))).collect();
+    let colors: Vec<_> = frame1.iter().chain(&frame2).copied().collect();
+    let mut weights = vec![1.; colors.len()];
+    weights[8] = 100.;
+
+    let exact = crate::reduce_palette(&colors, &weights, 17).unwrap();
+    assert_eq!(17, exact.palette.len());
+    for (c, &i) in colors.iter().zip(&exact.mapping) {
+        assert_eq!(*c, exact.palette[i as usize]);
+    }
+
+    let reduced = crate::reduce_palette(&colors, &weights, 5).unwrap();
+    assert!(reduced.palette.len() <= 5);
+    assert_eq!(colors.len(), reduced.mapping.len());
+    assert_eq!(reduced.mapping[8], reduced.mapping[17]);
+    assert_eq!(RGBA::new(0, 0, 0, 255), reduced.palette[reduced.mapping[8] as usize]);
+    for (c, &i) in colors.iter().zip(&reduced.mapping) {
+        let p = reduced.palette[i as usize];
+        // reds stay red, and blues stay blue
+        assert_eq!(c.r > c.b, p.r > p.b, "{:?} {:?}", c, p);
+    }
+}
+
+#[test]
+fn rgba5551_preset() {
+    // colorful gradient, with alpha fading out from left to right
+    let bitmap: Vec<_> = (0..128 * 64u32).map(|i| {
+        let (x, y) = (i % 128, i / 128);
+        RGBA::new((y * 4) as u8, (x * 2) as u8, 200, (255 - x * 2) as u8)
+    }).collect();
+    let mut liq = new();
+    liq.set_rgba5551_preset();
+    assert_eq!(3, liq.min_posterization());
+    assert_eq!(2, liq.alpha_levels());
+
+    let mut img = liq.new_image(&bitmap[..], 128, 64, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    let (pal, pixels) = res.remapped(&mut img).unwrap();
+    let in_5_bits = |c: u8| c == ((c >> 3) << 3 | c >> 5);
+    for c in &pal {
+        assert!(c.a == 0 || c.a == 255, "{:?}", c);
+        assert!(in_5_bits(c.r) && in_5_bits(c.g) && in_5_bits(c.b), "{:?}", c);
+    }
+    // without dithering, alpha is thresholded
+    for (px, &idx) in bitmap.iter().zip(&pixels) {
+        assert_eq!(px.a >= 128, pal[idx as usize].a == 255, "{:?}", px);
+    }
+
+    res.set_dithering_level(1.).unwrap();
+    let mut img = liq.new_image(&bitmap[..], 128, 64, 0.).unwrap();
+    let (pal, pixels) = res.remapped(&mut img).unwrap();
+    // 3/4 transparent columns get some opaque pixels
+    let opaque = (0..64).filter(|y| pal[pixels[y * 128 + 96] as usize].a == 255).count();
+    assert!(opaque > 4 && opaque < 32, "{}", opaque);
+}
+
+#[test]
+fn preserve_extremes() {
+    // dark and light grays, with some pure black, white and transparent pixels mixed in
+    let bitmap: Vec<_> = (0..100 * 100u32).map(|i| match i % 50 {
+        0 => RGBA::new(0, 0, 0, 255),
+        1 => RGBA::new(255, 255, 255, 255),
+        2 => RGBA::new(1, 2, 3, 0),
+        _ => { let v = (i / 100 % 2 * 200 + 20 + i % 7) as u8; RGBA::new(v, v, v, 255) },
+    }).collect();
+
+    let mut liq = new();
+    liq.set_max_colors(5).unwrap();
+    assert!(!liq.preserve_extremes());
+    liq.set_preserve_extremes(true);
+    let mut img = liq.new_image(&bitmap[..], 100, 100, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    let pal = res.palette();
+    assert!(pal.contains(&RGBA::new(0, 0, 0, 255)), "{:?}", pal);
+    assert!(pal.contains(&RGBA::new(255, 255, 255, 255)), "{:?}", pal);
+    assert!(pal.iter().any(|c| c.a == 0), "{:?}", pal);
+}
+
+#[test]
+fn min_entry_population() {
+    // two large areas, and a few stray pixels of other colors
+    let (width, height) = (64, 64);
+    let mut pixels: Vec<_> = (0..width * height).map(|i| {
+        let v = (i % 5) as u8;
+        if i % width < 32 { RGBA::new(20 + v, 40, 200, 255) } else { RGBA::new(220, 200 + v, 20, 255) }
+    }).collect();
+    for (i, &c) in [RGBA::new(255, 0, 0, 255), RGBA::new(0, 255, 0, 255), RGBA::new(255, 0, 255, 255)].iter().enumerate() {
+        pixels[i * 700 + 5] = c;
+    }
+
+    let mut liq = new();
+    liq.set_max_colors(8).unwrap();
+    assert_eq!(0., liq.min_entry_population());
+    assert!(liq.set_min_entry_population(1.5).is_err());
+    let mut all = liq.quantize(&mut liq.new_image(&pixels[..], width, height, 0.).unwrap()).unwrap();
+    assert_eq!(8, all.palette().len());
+    assert!(all.palette().iter().any(|c| c.g == 255 || c.b == 255), "{:?}", all.palette());
+
+    liq.set_min_entry_population(0.01).unwrap();
+    assert_eq!(0.01, liq.min_entry_population());
+    let mut img = liq.new_image(&pixels[..], width, height, 0.).unwrap();
+    img.add_fixed_color(RGBA::new(0, 0, 0, 255)).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    let palette = res.palette();
+    assert!(palette.len() < 8 && palette.contains(&RGBA::new(0, 0, 0, 255)), "{:?}", palette);
+    // no entry is spent on the stray pixels
+    assert!(palette.iter().all(|c| c.g < 250 && c.b < 250 && (c.r == 0 || c.r > 10)), "{:?}", palette);
+}
+
+#[test]
+fn preserve_rare_colors() {
+    // a gray gradient, and a tiny red icon
+    let (width, height) = (128, 64);
+    let mut pixels: Vec<_> = (0..width * height).map(|i| { let v = (i % width * 2) as u8; RGBA::new(v, v, v, 255) }).collect();
+    for &(x, y) in &[(100, 10), (101, 10), (100, 11), (101, 11)] {
+        pixels[y * width + x] = RGBA::new(230, 10, 10, 255);
+    }
+    let is_red = |c: &RGBA| c.r > 150 && c.g < 80 && c.b < 80;
+
+    let mut liq = new();
+    liq.set_max_colors(8).unwrap();
+    let mut res = liq.quantize(&mut liq.new_image(&pixels[..], width, height, 0.).unwrap()).unwrap();
+    assert!(!res.palette().iter().any(is_red), "{:?}", res.palette());
+
+    assert!(!liq.preserve_rare_colors());
+    liq.set_preserve_rare_colors(true);
+    assert!(liq.preserve_rare_colors());
+    assert!(liq.set_rare_color_distinctness(0.).is_err());
+    assert!((liq.rare_color_distinctness() - Attributes::DEFAULT_RARE_COLOR_DISTINCTNESS).abs() < 0.01);
+    let mut img = liq.new_image(&pixels[..], width, height, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    res.set_dithering_level(0.).unwrap();
+    let (palette, indices) = res.remapped(&mut img).unwrap();
+    assert!(palette.len() <= 8);
+    assert!(is_red(&palette[indices[10 * width + 100] as usize]), "{:?}", palette);
+    // the gradient still has most of the palette
+    assert!(palette.iter().filter(|c| c.r == c.g && c.g == c.b).count() >= 6, "{:?}", palette);
+}
+
+#[test]
+fn auto_levels() {
+    // faded scan: a smooth gradient in a narrow range of grays
+    let bitmap: Vec<_> = (0..64 * 64u32).map(|i| {
+        let v = (100 + (i % 64) * 60 / 63) as u8;
+        RGBA::new(v, v, v + 10, 255)
+    }).collect();
+
+    let mut liq = new();
+    liq.set_max_colors(8).unwrap();
+    assert!(!liq.auto_levels());
+    liq.set_auto_levels(true);
+    let mut img = liq.new_image(&bitmap[..], 64, 64, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    let (black, white) = res.auto_levels().unwrap();
+    assert_eq!(RGBA::new(100, 100, 110, 255), black);
+    assert_eq!(RGBA::new(160, 160, 170, 255), white);
+
+    let mut img = liq.new_image(&bitmap[..], 64, 64, 0.).unwrap();
+    let (pal, idx) = res.remapped(&mut img).unwrap();
+    assert!(pal.iter().all(|c| c.g >= 100 && c.g <= 160), "{:?}", pal);
+    let max_diff = bitmap.iter().zip(&idx).map(|(px, &i)| (i32::from(px.g) - i32::from(pal[i as usize].g)).abs()).max().unwrap();
+    assert!(max_diff <= 8, "{}", max_diff);
+
+    // already uses the full range
+    let full: Vec<_> = (0..64 * 64u32).map(|i| { let v = (i % 64 * 4) as u8; RGBA::new(v, v, v, 255) }).collect();
+    let mut img = liq.new_image(&full[..], 64, 64, 0.).unwrap();
+    assert_eq!(None, liq.quantize(&mut img).unwrap().auto_levels());
+}
+
+#[test]
+fn alpha_levels() {
+    // colorful, with smoothly varying alpha
+    let bitmap: Vec<_> = (0..64 * 64u32).map(|i| {
+        let (x, y) = (i % 64, i / 64);
+        RGBA::new((x * 4) as u8, (y * 4) as u8, 128, (x * 2 + y * 2) as u8)
+    }).collect();
+
+    let mut liq = new();
+    assert_eq!(liq_error::LIQ_VALUE_OUT_OF_RANGE, liq.set_alpha_levels(1));
+    assert_eq!(liq_error::LIQ_VALUE_OUT_OF_RANGE, liq.set_alpha_levels(256));
+    assert_eq!(0, liq.alpha_levels());
+    liq.set_alpha_levels(4).unwrap();
+    assert_eq!(4, liq.alpha_levels());
+
+    let mut img = liq.new_image(&bitmap[..], 64, 64, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    assert!(res.palette().iter().all(|c| [0, 85, 170, 255].contains(&c.a)), "{:?}", res.palette());
+    assert!(res.palette().iter().filter(|c| c.a != 0).map(|c| c.a).collect::<std::collections::HashSet<_>>().len() > 1);
+
+    let mut img = liq.new_image(&bitmap[..], 64, 64, 0.).unwrap();
+    let (pal, _) = res.remapped(&mut img).unwrap();
+    assert!(pal.iter().all(|c| [0, 85, 170, 255].contains(&c.a)), "{:?}", pal);
+}
+
+#[test]
+fn alpha_weighted_colors() {
+    let bitmap: Vec<_> = (0..64 * 64u32).map(|i| match i % 10 {
+        // a smooth gradient that needs many palette entries
+        0..=5 => RGBA::new((i / 16) as u8, 100, 255 - (i / 16) as u8, 255),
+        // garbage left by an encoder under (nearly) invisible pixels
+        6..=7 => RGBA::new((i * 97) as u8, (i * 31) as u8, 255 - (i * 7) as u8, 0),
+        _ => RGBA::new(255 - (i * 13) as u8, (i * 53) as u8, (i * 5) as u8, 12),
+    }).collect();
+    let visible_entries = |liq: &Attributes| {
+        let mut res = liq.quantize(&mut liq.new_image(&bitmap[..], 64, 64, 0.).unwrap()).unwrap();
+        let remapped = res.remapped(&mut liq.new_image(&bitmap[..], 64, 64, 0.).unwrap()).unwrap();
+        remapped.0.iter().filter(|p| p.a == 255).count()
+    };
+
+    let mut liq = new();
+    liq.set_max_colors(16).unwrap();
+    assert!(!liq.alpha_weighted_colors());
+    let plain = visible_entries(&liq);
+    liq.set_alpha_weighted_colors(true);
+    assert!(liq.alpha_weighted_colors());
+    let weighted = visible_entries(&liq);
+    assert!(weighted > plain, "{} {}", weighted, plain);
+}
+
+#[test]
+fn tagged_fixed_colors() {
+    let bitmap: Vec<_> = (0..64 * 64u32).map(|i| RGBA::new((i % 64 * 4) as u8, (i / 64 * 4) as u8, 90, 255)).collect();
+    let accent = RGBA::new(255, 0, 128, 255);
+    let text = RGBA::new(10, 10, 10, 255);
+
+    let mut liq = new();
+    liq.set_max_colors(16).unwrap();
+    let mut img = liq.new_image(&bitmap[..], 64, 64, 0.).unwrap();
+    img.add_fixed_color_tagged(accent, 7).unwrap();
+    img.add_fixed_color_tagged(text, 1).unwrap();
+    img.add_fixed_color(RGBA::new(0, 255, 0, 255)).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    let accent_idx = res.tagged_index(7).unwrap();
+    let text_idx = res.tagged_index(1).unwrap();
+    assert_eq!(accent, res.palette()[accent_idx as usize]);
+    assert_eq!(text, res.palette()[text_idx as usize]);
+    assert_eq!(Some(7), res.palette_tag(accent_idx));
+    assert_eq!(None, res.tagged_index(2));
+    assert_eq!(1, (0..16).filter_map(|i| res.palette_tag(i)).filter(|&t| t == 1).count());
+
+    let mut hist = Histogram::new(&liq);
+    let mut img = liq.new_image(&bitmap[..], 64, 64, 0.).unwrap();
+    hist.add_image(&liq, &mut img).unwrap();
+    hist.add_fixed_color_tagged(accent, 3, 0.).unwrap();
+    let mut res = hist.quantize(&liq).unwrap();
+    let idx = res.tagged_index(3).unwrap();
+    assert_eq!(accent, res.palette()[idx as usize]);
+}
+
+#[test]
+fn histogram_color_counts() {
+    let mut liq = new();
+    liq.set_speed(10).unwrap();
+    let counts = [(RGBA::new(10, 20, 30, 255), 300), (RGBA::new(200, 100, 0, 255), 50), (RGBA::new(0, 0, 0, 0), 2)];
+    let bitmap: Vec<_> = counts.iter().flat_map(|&(c, n)| std::iter::repeat_n(c, n as usize)).collect();
+    let mut img = liq.new_image(&bitmap[..], 352, 1, 0.).unwrap();
+    let mut from_image = Histogram::new(&liq);
+    from_image.add_image(&liq, &mut img).unwrap();
+
+    let mut from_counts = Histogram::new(&liq);
+    from_counts.add_color_counts(&counts[..2], 0.).unwrap();
+    from_counts.add_color_counts(&[counts[2], (RGBA::new(1, 2, 3, 255), 0)], 0.).unwrap();
+    assert_eq!(QuantCacheKey::new(&from_image, &liq), QuantCacheKey::new(&from_counts, &liq));
+    assert_eq!(2, from_counts.quantize(&liq).unwrap().palette().iter().filter(|c| c.a == 255).count());
+
+    let mut huge = Histogram::new(&liq);
+    huge.add_color_counts(&[(RGBA::new(1, 2, 3, 255), u32::MAX), (RGBA::new(200, 2, 3, 255), 1 << 20)], 0.).unwrap();
+    assert_eq!(2, huge.quantize(&liq).unwrap().palette().len());
+    assert_eq!(Err(liq_error::LIQ_VALUE_OUT_OF_RANGE), huge.add_color_counts(&[], 0.));
+}
+
+#[test]
+fn weighted_animation_frames() {
+    let (width, height) = (32, 32);
+    // title card in shades of blue, repeated for many frames, and one frame of content in shades of red
+    let title: Vec<_> = (0..width * height).map(|i| RGBA::new(10, 20, (i % 64 * 4) as u8, 255)).collect();
+    let content: Vec<_> = (0..width * height).map(|i| RGBA::new((i % 64 * 4) as u8, 20, 10, 255)).collect();
+    let mut liq = new();
+    liq.set_max_colors(16).unwrap();
+    let reds = |title_weight: f32| {
+        let mut hist = Histogram::new(&liq);
+        for _ in 0..9 {
+            hist.add_image_weighted(&liq, &mut liq.new_image(&title[..], width, height, 0.).unwrap(), title_weight).unwrap();
+        }
+        hist.add_image(&liq, &mut liq.new_image(&content[..], width, height, 0.).unwrap()).unwrap();
+        hist.quantize(&liq).unwrap().palette().iter().filter(|c| c.r > c.b).count()
+    };
+    let unweighted = reds(1.);
+    let weighted = reds(1. / 9.);
+    assert!(weighted > unweighted, "{} {}", weighted, unweighted);
+    assert!(weighted >= 6, "{}", weighted);
+}
+
+#[test]
+fn scene_cuts() {
+    assert!(SceneDetector::new(0.).is_err());
+    assert!(SceneDetector::new(1.5).is_err());
+    let mut scenes = SceneDetector::new(0.3).unwrap();
+    assert_eq!(0, scenes.scenes().count());
+
+    // a gradient moving sideways, then a cut to a different one
+    let frame = |shift: usize, blue: bool| -> Vec<RGBA> {
+        (0..64 * 32).map(|i| {
+            let v = ((i % 64 + shift) * 4) as u8;
+            if blue { RGBA::new(0, v / 2, 128 + v / 2, 255) } else { RGBA::new(v, 64, 0, 255) }
+        }).collect()
+    };
+    let liq = new();
+    let frames = [frame(0, false), frame(1, false), frame(2, false), frame(0, true), frame(2, true)];
+    let starts: Vec<_> = frames.iter().map(|pixels| {
+        let img = liq.new_image(&pixels[..], 64, 32, 0.).unwrap();
+        scenes.add_frame(&img).unwrap()
+    }).collect();
+    assert_eq!(starts, [true, false, false, true, false]);
+    assert_eq!(scenes.cuts(), [0, 3]);
+    assert_eq!(scenes.scenes().collect::<Vec<_>>(), [0..3, 3..5]);
+    assert_eq!(5, scenes.frame_count());
+    assert!(scenes.last_distance().unwrap() < 0.3);
+
+    scenes.reset();
+    assert!(scenes.cuts().is_empty());
+    assert_eq!(None, scenes.last_distance());
+}
+
+#[test]
+fn detect_background_color() {
+    let liq = new();
+    // slightly noisy off-white around a black square
+    let bitmap: Vec<_> = (0..50 * 40u32).map(|i| {
+        let (x, y) = (i % 50, i / 50);
+        if x > 10 && x < 40 && y > 10 && y < 30 { return RGBA::new(0, 0, 0, 255); }
+        let v = 249 + (i % 3) as u8;
+        RGBA::new(v, v, v - 10, 255)
+    }).collect();
+    let img = liq.new_image(&bitmap[..], 50, 40, 0.).unwrap();
+    assert_eq!(Some(RGBA::new(250, 250, 240, 255)), img.detect_background_color().unwrap());
+
+    let transparent: Vec<_> = bitmap.iter().map(|&c| if c.r == 0 { c } else { RGBA::new(0, 0, 0, 0) }).collect();
+    let img = liq.new_image(&transparent[..], 50, 40, 0.).unwrap();
+    assert_eq!(None, img.detect_background_color().unwrap());
+
+    let stripes: Vec<_> = (0..50 * 40u32).map(|i| if i % 2 == 0 { RGBA::new(255, 0, 0, 255) } else { RGBA::new(0, 0, 255, 255) }).collect();
+    let img = liq.new_image(&stripes[..], 50, 40, 0.).unwrap();
+    assert_eq!(None, img.detect_background_color().unwrap());
+}
+
+#[test]
+fn shared_attributes() {
+    use std::sync::atomic::AtomicU32;
+    use std::sync::atomic::Ordering::SeqCst;
+    use std::sync::Arc;
+
+    fn is_sync<T: Send + Sync>() {}
+    is_sync::<Attributes>();
+
+    let calls = Arc::new(AtomicU32::new(0));
+    let calls2 = calls.clone();
+    let mut liq = new();
+    liq.set_progress_callback_arc(Arc::new(move |_| {
+        calls2.fetch_add(1, SeqCst);
+        ControlFlow::Continue
+    }));
+    let liq = Arc::new(liq);
+
+    let threads: Vec<_> = (0..4u8).map(|n| {
+        let liq = liq.clone();
+        std::thread::spawn(move || {
+            let bitmap = [RGBA::new(n, 2 * n, 3 * n, 255); 16];
+            let mut img = liq.new_image(&bitmap[..], 4, 4, 0.).unwrap();
+            let mut res = liq.quantize(&mut img).unwrap();
+            assert_eq!(1, res.palette().len());
+        })
+    }).collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    assert!(calls.load(SeqCst) >= 4);
+}
+
+#[test]
+#[cfg(feature = "capi")]
+fn c_callback_test_c() {
+    use crate::ffi::*;
+    use std::mem::MaybeUninit;
+
+    use rgb::RGBA8 as RGBA;
+    use std::os::raw::*;
+
+    let mut called = 0;
+    let mut res = unsafe {
+        let mut a = liq_attr_create().unwrap();
+        unsafe extern "C" fn get_row(output_row: *mut MaybeUninit<RGBA>, y: c_int, width: c_int, user_data: *mut c_void) {
+            assert!((0..5).contains(&y));
+            assert_eq!(123, width);
+            for i in 0..width as isize {
+                let n = i as u8;
+                (*output_row.offset(i as isize)).write(RGBA::new(n, n, n, n));
+            }
+            let user_data = user_data as *mut i32;
+            *user_data += 1;
+        }
+        let mut img = liq_image_create_custom(&a, get_row, AnySyncSendPtr((&mut called) as *mut _ as *mut c_void), 123, 5, 0.).unwrap();
+        liq_quantize_image(&mut a, &mut img).unwrap()
+    };
+    // without contrast maps, every row is read just once
+    let min_calls = if cfg!(feature = "dither-maps") { 6 } else { 5 };
+    assert!(called >= min_calls && called < 50);
+    let pal = liq_get_palette(&mut res).unwrap();
+    assert_eq!(123, pal.count);
+}
+
+#[test]
+#[cfg(feature = "capi")]
+fn c_callback_user_info_free() {
+    use crate::ffi::*;
+    use std::mem::MaybeUninit;
+    use std::os::raw::*;
+    use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+
+    static FREED: AtomicUsize = AtomicUsize::new(0);
+    unsafe extern "C" fn get_row(output_row: *mut MaybeUninit<RGBA>, _y: c_int, width: c_int, user_data: *mut c_void) {
+        let shade = *(user_data as *const u8);
+        for i in 0..width as usize {
+            (*output_row.add(i)).write(RGBA::new(shade, shade, shade, 255));
+        }
+    }
+    unsafe extern "C" fn free_user_data(_: *mut c_void) {
+        FREED.fetch_add(1, SeqCst);
+    }
+
+    let shades = [10u8, 60, 110, 160, 210];
+    let row_user_info: Vec<_> = shades.iter().map(|s| s as *const u8 as *mut c_void).collect();
+    unsafe {
+        let mut a = liq_attr_create().unwrap();
+        let mut img = liq_image_create_custom_rows(&a, get_row, row_user_info.as_ptr(), Some(free_user_data), 7, 5, 0.).unwrap();
+        let mut res = liq_quantize_image(&mut a, &mut img).unwrap();
+        let pal = liq_get_palette(&mut res).unwrap();
+        assert_eq!(5, pal.count);
+        for shade in shades {
+            assert!(pal.entries[..5].iter().any(|c| c.g == shade), "{}", shade);
+        }
+        liq_image_destroy(Some(img));
+        assert_eq!(5, FREED.load(SeqCst));
+
+        // user data stays with the caller if the image isn't created
+        assert!(liq_image_create_custom_with_free(&a, get_row, AnySyncSendPtr(row_user_info[0]), Some(free_user_data), 7, 5, 2.).is_none());
+        assert_eq!(5, FREED.load(SeqCst));
+        let img = liq_image_create_custom_with_free(&a, get_row, AnySyncSendPtr(row_user_info[0]), Some(free_user_data), 7, 5, 0.).unwrap();
+        liq_image_destroy(Some(img));
+        assert_eq!(6, FREED.load(SeqCst));
+    }
+}
+
+#[test]
+fn r_callback_test() {
+    use std::mem::MaybeUninit;
+    use std::sync::atomic::AtomicU16;
+    use std::sync::atomic::Ordering::SeqCst;
+    use std::sync::Arc;
+
+    let called = Arc::new(AtomicU16::new(0));
+    let called2 = called.clone();
+    let mut res = {
+        let mut a = new();
+        let get_row = move |output_row: &mut [MaybeUninit<RGBA>], y: usize| {
+            assert!((0..5).contains(&y));
+            assert_eq!(123, output_row.len());
+            for (n, out) in output_row.iter_mut().enumerate() {
+                let n = n as u8;
+                out.write(RGBA::new(n, n, n, n));
+            }
+            called2.fetch_add(1, SeqCst);
+        };
+        let mut img = unsafe { Image::new_fn(&a, get_row, 123, 5, 0.).unwrap() };
+        a.quantize(&mut img).unwrap()
+    };
+    let called = called.load(SeqCst);
+    // contrast maps read the rows once more
+    let min_calls = if cfg!(feature = "dither-maps") { 6 } else { 5 };
+    assert!((min_calls..50).contains(&called), "{}", called);
+    assert_eq!(123, res.palette().len());
+}
+
+#[test]
+fn dither_maps_feature() {
+    let (width, height) = (64, 64);
+    let bitmap: Vec<_> = (0..width * height).map(|i| {
+        let (x, y) = (i % width, i / width);
+        RGBA::new((x * 4) as u8, (y * 4) as u8, ((x ^ y) * 4) as u8, 255)
+    }).collect();
+    let mut liq = new();
+    liq.set_speed(1).unwrap();
+    liq.set_max_colors(16).unwrap();
+    let mut img = liq.new_image(&bitmap[..], width, height, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    res.set_dithering_level(1.).unwrap();
+    res.remapped(&mut img).unwrap();
+    // without the feature, no maps are made, and dithering is uniform
+    assert_eq!(cfg!(feature = "dither-maps"), !img.maps.dither_strength(true).is_empty());
+    assert_eq!(cfg!(feature = "dither-maps"), !img.maps.smoothness().is_empty());
+}
+
+#[test]
+fn sizes() {
+    use pal::PalF;
+    use pal::Palette;
+    assert!(std::mem::size_of::<PalF>() < MAX_COLORS*(8*4)+32, "{}", std::mem::size_of::<PalF>());
+    assert!(std::mem::size_of::<QuantizationResult>() < std::mem::size_of::<PalF>() + std::mem::size_of::<Palette>() + 100, "{}", std::mem::size_of::<QuantizationResult>());
+    assert!(std::mem::size_of::<Attributes>() < 200);
+    assert!(std::mem::size_of::<Image>() < 300);
+    assert!(std::mem::size_of::<Histogram>() < 200);
+    assert!(std::mem::size_of::<crate::hist::HistItem>() <= 32);
+}
+
+#[test]
+fn ownership_bitflags() {
+    use seacow::liq_ownership;
+    assert_eq!(4+16, (liq_ownership::LIQ_OWN_ROWS | liq_ownership::LIQ_COPY_PIXELS).bits());
+}
+
+#[test]
+fn bench_helpers() {
+    let colors: Vec<_> = (0..5000u32).map(|i| HistogramEntry {
+        color: RGBA::new(i as u8, (i >> 4) as u8, (i.wrapping_mul(7) >> 3) as u8, 255),
+        count: 1 + i % 5,
+    }).collect();
+    // each run works on a copy of the histogram, so it can be repeated
+    let mut mediancut = _unstable_internal_mediancut_bench(&colors);
+    mediancut();
+    mediancut();
+    _unstable_internal_nearest_bench()();
+}
+
+#[doc(hidden)]
+pub fn _unstable_internal_kmeans_bench() -> impl FnMut() {
+    use crate::pal::PalF;
+    use crate::pal::PalPop;
+
+    let attr = new();
+    let mut h = hist::Histogram::new(&attr);
+
+    let e = (0..10000u32).map(|i| HistogramEntry {
+        count: i.wrapping_mul(17) % 12345,
+        color: RGBA::new(i as u8, (i.wrapping_mul(7) >> 2) as u8, (i.wrapping_mul(11) >> 11) as u8, 255),
+    }).collect::<Vec<_>>();
+
+    h.add_colors(&e, 0.).unwrap();
+    let mut hist = h.finalize_builder(0.45455, 0., None, 0, false);
+
+    let lut = pal::gamma_lut(0.45455);
+    let mut p = PalF::new();
+    for i in 0..=255 {
+        p.push(pal::f_pixel::from_rgba(&lut, RGBA::new(i|7, i, i, 255)), PalPop::new(1.));
+    }
+
+    move || {
+        kmeans::Kmeans::iteration(&mut hist, &mut p, false);
+    }
+}
+
+#[doc(hidden)]
+pub fn _unstable_internal_nearest_bench() -> impl FnMut() {
+    use crate::pal::PalF;
+    use crate::pal::PalPop;
+
+    let lut = pal::gamma_lut(0.45455);
+    let mut p = PalF::new();
+    for i in 0..=255u32 {
+        p.push(pal::f_pixel::from_rgba(&lut, RGBA::new(i as u8, (i.wrapping_mul(7) >> 2) as u8, (i.wrapping_mul(11) >> 3) as u8, 255)), PalPop::new(1.));
+    }
+    let pixels = (0..10000u32).map(|i| {
+        pal::f_pixel::from_rgba(&lut, RGBA::new(i as u8, (i.wrapping_mul(13) >> 3) as u8, (i.wrapping_mul(5) >> 5) as u8, 255))
+    }).collect::<Vec<_>>();
+
+    move || {
+        let n = nearest::Nearest::new(&p);
+        let mut last = 0;
+        for px in &pixels {
+            last = n.search(px, last).0;
+        }
+        assert!(usize::from(last) < p.len());
+    }
+}
+
+#[doc(hidden)]
+pub fn _unstable_internal_mediancut_bench(colors: &[HistogramEntry]) -> impl FnMut() {
+    let attr = new();
+    let mut h = hist::Histogram::new(&attr);
+    h.add_colors(colors, 0.).unwrap();
+    let hist = h.finalize_builder(0.45455, 0., None, 0, false);
+
+    move || {
+        let mut hist = hist.clone();
+        let palette = mediancut::mediancut(&mut hist, 256, 0., quant::quality_to_mse(51) * 1.2, false);
+        assert!(palette.len() > 0 && palette.len() <= 256);
     }
 }
@@ -11,6 +11,8 @@ struct MedianCutter<'hist> {
     boxes: Vec<MBox<'hist>>,
     hist_total_perceptual_weight: f64,
     target_colors: PalLen,
+    /// Alpha is quantized to a few levels separately, so boxes that mix them are split on alpha first
+    separate_alpha: bool,
 }
 
 struct MBox<'hist> {
@@ -90,7 +92,7 @@ impl<'hist> MBox<'hist> {
         e
     }
 
-    pub fn prepare_sort(&mut self) {
+    pub fn prepare_sort(&mut self, separate_alpha: bool) {
         struct ChanVariance {
             pub chan: usize,
             pub variance: f32,
@@ -105,6 +107,10 @@ impl<'hist> MBox<'hist> {
             ChanVariance { chan: 3, variance: vars[3] },
         ];
         channels.sort_by_key(|a| Reverse(OrdFloat::<f32>::unchecked_new(a.variance)));
+        if separate_alpha && vars[0] > 0. {
+            // alpha is channel 0, and the sort is stable
+            channels.sort_by_key(|a| a.chan != 0);
+        }
 
         for a in self.colors.iter_mut() {
             let chans = a.color.as_slice();
@@ -133,8 +139,8 @@ impl<'hist> MBox<'hist> {
     }
 
     #[inline]
-    pub fn split(mut self, other_boxes: &[MBox<'_>]) -> [Self; 2] {
-        self.prepare_sort();
+    pub fn split(mut self, other_boxes: &[MBox<'_>], separate_alpha: bool) -> [Self; 2] {
+        self.prepare_sort(separate_alpha);
         let half_weight = self.prepare_color_weight_total() / 2.;
         // yeah, there's some off-by-one error in there
         let break_at = hist_item_sort_half(self.colors, half_weight).max(1);
@@ -221,7 +227,7 @@ impl<'hist> MedianCutter<'hist> {
         true
     }
 
-    pub fn new(hist: &'hist mut HistogramInternal, target_colors: PalLen) -> Self {
+    pub fn new(hist: &'hist mut HistogramInternal, target_colors: PalLen, separate_alpha: bool) -> Self {
         let hist_total_perceptual_weight = hist.total_perceptual_weight;
 
         debug_assert!(hist.clusters[0].begin == 0);
@@ -251,6 +257,7 @@ impl<'hist> MedianCutter<'hist> {
             boxes,
             hist_total_perceptual_weight,
             target_colors,
+            separate_alpha,
         }
     }
 
@@ -280,7 +287,7 @@ impl<'hist> MedianCutter<'hist> {
                 None => break,
             };
 
-            self.boxes.extend(bi.split(&self.boxes));
+            self.boxes.extend(bi.split(&self.boxes, self.separate_alpha));
 
             if self.total_box_error_below_target(target_mse) {
                 break;
@@ -306,8 +313,8 @@ impl<'hist> MedianCutter<'hist> {
     }
 }
 
-pub(crate) fn mediancut(hist: &mut HistogramInternal, target_colors: PalLen, target_mse: f64, max_mse_per_color: f64) -> PalF {
-    MedianCutter::new(hist, target_colors).cut(target_mse, max_mse_per_color)
+pub(crate) fn mediancut(hist: &mut HistogramInternal, target_colors: PalLen, target_mse: f64, max_mse_per_color: f64, separate_alpha: bool) -> PalF {
+    MedianCutter::new(hist, target_colors, separate_alpha).cut(target_mse, max_mse_per_color)
 }
 
 fn weighed_average_color(hist: &[HistItem]) -> f_pixel {
@@ -0,0 +1,82 @@
+//! Watching how well a palette shared by many frames still fits them
+
+use crate::error::*;
+use crate::quant::{mse_to_quality, quality_to_mse, QuantizationResult};
+use crate::remap::{mse_to_standard_mse, standard_mse_to_mse};
+use std::collections::VecDeque;
+
+/// Tracks remapping error of recent frames remapped with the same palette, and tells when the palette has become
+/// too poor for them, e.g. in a long screen recording where the content changes over time.
+///
+/// When [`PaletteMonitor::add_frame`] returns `true`, make a new palette (e.g. from the latest frames), and [`reset`](PaletteMonitor::reset) the monitor.
+#[derive(Debug, Clone)]
+pub struct PaletteMonitor {
+    window: usize,
+    max_mse: f64,
+    errors: VecDeque<f64>,
+}
+
+impl PaletteMonitor {
+    /// Signals when average quality of the last `window` frames drops below `min_quality` (0-100, same scale as `set_quality`).
+    ///
+    /// Larger windows are less sensitive to a single bad frame, but react later.
+    pub fn new(window: usize, min_quality: u8) -> Result<Self, liq_error> {
+        if window == 0 || min_quality > 100 {
+            return Err(LIQ_VALUE_OUT_OF_RANGE);
+        }
+        Ok(Self {
+            window,
+            max_mse: mse_to_standard_mse(quality_to_mse(min_quality)),
+            errors: VecDeque::with_capacity(window),
+        })
+    }
+
+    /// Records remapping error of the frame that has just been remapped with the `result`.
+    /// Returns `true` if the palette should be replaced.
+    ///
+    /// Error is measured during remapping without dithering, or with a dither map (the default for images that aren't huge).
+    /// Frames remapped without the error measured are ignored. Use [`PaletteMonitor::add_error`] to report errors measured in another way.
+    pub fn add_frame(&mut self, result: &QuantizationResult) -> bool {
+        match result.remapping_error() {
+            Some(error) => self.add_error(error),
+            None => self.needs_new_palette(),
+        }
+    }
+
+    /// Records error of a frame, in the same units as [`QuantizationResult::remapping_error`].
+    /// Returns `true` if the palette should be replaced.
+    pub fn add_error(&mut self, error: f64) -> bool {
+        if self.errors.len() >= self.window {
+            self.errors.pop_front();
+        }
+        self.errors.push_back(error.max(0.));
+        self.needs_new_palette()
+    }
+
+    /// `true` if there are errors of `window` frames, and their average is too high
+    #[must_use]
+    pub fn needs_new_palette(&self) -> bool {
+        self.errors.len() >= self.window && matches!(self.average_error(), Some(e) if e > self.max_mse)
+    }
+
+    /// Average error of the recent frames, up to `window` of them
+    #[must_use]
+    pub fn average_error(&self) -> Option<f64> {
+        if self.errors.is_empty() {
+            return None;
+        }
+        Some(self.errors.iter().sum::<f64>() / self.errors.len() as f64)
+    }
+
+    /// Average error of the recent frames, as quality in 0-100 range
+    #[must_use]
+    pub fn average_quality(&self) -> Option<u8> {
+        self.average_error().map(|e| mse_to_quality(standard_mse_to_mse(e)))
+    }
+
+    /// Forgets errors of previous frames. Call it after switching to a new palette.
+    #[inline]
+    pub fn reset(&mut self) {
+        self.errors.clear();
+    }
+}
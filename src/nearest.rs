@@ -1,6 +1,8 @@
 use crate::OrdFloat;
-use crate::pal::PalIndex;
+use crate::hist::RgbaHasher;
+use crate::pal::{PalIndex, MAX_COLORS, RGBA};
 use crate::pal::{f_pixel, PalF};
+use std::collections::HashMap;
 
 impl<'pal> Nearest<'pal> {
     #[inline(never)]
@@ -11,7 +13,7 @@ impl<'pal> Nearest<'pal> {
         let mut handle = Nearest {
             root: vp_create_node(&mut indexes, palette),
             palette,
-            nearest_other_color_dist: [0.; 256],
+            nearest_other_color_dist: [0.; MAX_COLORS],
         };
         for (i, color) in palette.as_slice().iter().enumerate() {
             let mut best = Visitor {
@@ -47,12 +49,65 @@ impl Nearest<'_> {
         vp_search_node(&self.root, px, &mut best_candidate);
         (best_candidate.idx as PalIndex, best_candidate.distance * best_candidate.distance)
     }
+
+    /// Compares the color with every palette entry, instead of trusting the shortcuts of `search`,
+    /// which assume that the distance is a metric (it's only close to one)
+    pub fn search_exhaustive(&self, px: &f_pixel) -> (PalIndex, f32) {
+        let mut best = (0, f32::INFINITY);
+        for (i, color) in self.palette.as_slice().iter().enumerate() {
+            let diff = px.diff(color);
+            if diff < best.1 {
+                best = (i as PalIndex, diff);
+            }
+        }
+        best
+    }
+}
+
+/// User-supplied distance between an image pixel and a palette color, see `Remapper::with_metric`
+pub(crate) type Metric = dyn Fn(&RGBA, &RGBA) -> f32 + Send + Sync;
+
+/// Exhaustive search in an arbitrary metric, which has no structure for the vantage point tree to use.
+///
+/// Results are cached by color, since images usually have far fewer colors than pixels.
+pub(crate) struct MetricNearest<'a> {
+    palette: &'a [RGBA],
+    metric: &'a Metric,
+    cache: HashMap<u32, PalIndex, RgbaHasher>,
+}
+
+impl<'a> MetricNearest<'a> {
+    /// The cache is cleared when it gets this big, so that photos with millions of colors don't use too much memory
+    const MAX_CACHED: usize = 1 << 16;
+
+    pub(crate) fn new(palette: &'a [RGBA], metric: &'a Metric) -> Self {
+        Self { palette, metric, cache: HashMap::with_hasher(RgbaHasher(0)) }
+    }
+
+    pub(crate) fn search(&mut self, px: RGBA) -> PalIndex {
+        let key = u32::from_ne_bytes([px.r, px.g, px.b, px.a]);
+        if let Some(&idx) = self.cache.get(&key) {
+            return idx;
+        }
+        let mut best = (f32::INFINITY, 0);
+        for (i, color) in self.palette.iter().enumerate() {
+            let distance = (self.metric)(&px, color);
+            if distance < best.0 {
+                best = (distance, i as PalIndex);
+            }
+        }
+        if self.cache.len() >= Self::MAX_CACHED {
+            self.cache.clear();
+        }
+        self.cache.insert(key, best.1);
+        best.1
+    }
 }
 
 pub(crate) struct Nearest<'pal> {
     root: Node,
     palette: &'pal PalF,
-    nearest_other_color_dist: [f32; 256],
+    nearest_other_color_dist: [f32; MAX_COLORS],
 }
 
 pub struct MapIndex {
@@ -77,9 +132,72 @@ impl Visitor {
     }
 }
 
-pub struct Leaf {
-    pub color: f_pixel,
-    pub idx: PalIndex,
+/// Up to 4 leaf colors stored as separate channel planes, so that distances to all of them are computed at once
+#[repr(C, align(16))]
+pub struct LeafChunk {
+    pub a: [f32; 4],
+    pub r: [f32; 4],
+    pub g: [f32; 4],
+    pub b: [f32; 4],
+    pub idx: [PalIndex; 4],
+}
+
+impl LeafChunk {
+    /// Unused lanes repeat the last color, which is harmless to visit twice
+    fn new(leaves: &[MapIndex], palette: &[f_pixel]) -> Self {
+        debug_assert!(!leaves.is_empty() && leaves.len() <= 4);
+        let mut chunk = Self { a: [0.; 4], r: [0.; 4], g: [0.; 4], b: [0.; 4], idx: [0; 4] };
+        for lane in 0..4 {
+            let idx = leaves[lane.min(leaves.len() - 1)].idx;
+            let color = palette[usize::from(idx)];
+            chunk.a[lane] = color.a;
+            chunk.r[lane] = color.r;
+            chunk.g[lane] = color.g;
+            chunk.b[lane] = color.b;
+            chunk.idx[lane] = idx;
+        }
+        chunk
+    }
+
+    /// Same as `f_pixel::diff` from each of the colors to the `needle`
+    #[cfg(not(target_arch = "x86_64"))]
+    #[inline(always)]
+    fn diff(&self, needle: &f_pixel) -> [f32; 4] {
+        let mut res = [0.; 4];
+        for (lane, res) in res.iter_mut().enumerate() {
+            let alphas = needle.a - self.a[lane];
+            let black_r = self.r[lane] - needle.r;
+            let black_g = self.g[lane] - needle.g;
+            let black_b = self.b[lane] - needle.b;
+            let white_r = black_r + alphas;
+            let white_g = black_g + alphas;
+            let white_b = black_b + alphas;
+            *res = (black_r * black_r).max(white_r * white_r) +
+                (black_g * black_g).max(white_g * white_g) +
+                (black_b * black_b).max(white_b * white_b);
+        }
+        res
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[inline(always)]
+    fn diff(&self, needle: &f_pixel) -> [f32; 4] {
+        unsafe {
+            use std::arch::x86_64::*;
+
+            let alphas = _mm_sub_ps(_mm_set1_ps(needle.a), _mm_load_ps(self.a.as_ptr()));
+            let channel = |plane: &[f32; 4], needle: f32| {
+                let onblack = _mm_sub_ps(_mm_load_ps(plane.as_ptr()), _mm_set1_ps(needle));
+                let onwhite = _mm_add_ps(onblack, alphas);
+                _mm_max_ps(_mm_mul_ps(onwhite, onwhite), _mm_mul_ps(onblack, onblack))
+            };
+            let sum = _mm_add_ps(_mm_add_ps(channel(&self.r, needle.r), channel(&self.g, needle.g)), channel(&self.b, needle.b));
+
+            let mut res = [0.; 4];
+            _mm_storeu_ps(res.as_mut_ptr(), sum);
+            res
+        }
+    }
 }
 
 pub struct Node {
@@ -89,7 +207,7 @@ pub struct Node {
     pub radius: f32,
     pub radius_squared: f32,
     pub idx: PalIndex,
-    pub rest: Box<[Leaf]>,
+    pub rest: Box<[LeafChunk]>,
 }
 
 fn vp_create_node(indexes: &mut [MapIndex], items: &PalF) -> Node {
@@ -125,11 +243,8 @@ fn vp_create_node(indexes: &mut [MapIndex], items: &PalF) -> Node {
     let radius = radius_squared.sqrt();
 
     let (near, far, rest) = if num_indexes < 7 {
-        let mut rest = Vec::with_capacity(num_indexes);
-        rest.extend(near.iter().chain(far.iter()).map(|i| Leaf {
-            idx: i.idx,
-            color: palette[usize::from(i.idx)],
-        }));
+        let leaves: Vec<_> = near.iter().chain(far.iter()).map(|i| MapIndex { idx: i.idx }).collect();
+        let rest: Vec<_> = leaves.chunks(4).map(|leaves| LeafChunk::new(leaves, palette)).collect();
         (None, None, rest.into_boxed_slice())
     } else {
         (
@@ -156,9 +271,11 @@ fn vp_search_node(mut node: &Node, needle: &f_pixel, best_candidate: &mut Visito
         best_candidate.visit(distance, distance_squared, node.idx);
 
         if !node.rest.is_empty() {
-            for r in node.rest.iter() {
-                let distance_squared = r.color.diff(needle);
-                best_candidate.visit(distance_squared.sqrt(), distance_squared, r.idx);
+            for chunk in node.rest.iter() {
+                let distances_squared = chunk.diff(needle);
+                for (&distance_squared, &idx) in distances_squared.iter().zip(&chunk.idx) {
+                    best_candidate.visit(distance_squared.sqrt(), distance_squared, idx);
+                }
             }
             break;
         }
@@ -191,3 +308,36 @@ fn vp_search_node(mut node: &Node, needle: &f_pixel, best_candidate: &mut Visito
         break;
     }
 }
+
+#[test]
+fn leaf_chunk_diff() {
+    use crate::pal::ARGBF;
+    let palette: Vec<_> = (0..3u8).map(|i| f_pixel(ARGBF { a: 0.5 + f32::from(i) / 8., r: f32::from(i) / 4., g: 0.3, b: 1. - f32::from(i) / 3. })).collect();
+    let leaves: Vec<_> = (0..3).map(|idx| MapIndex { idx }).collect();
+    let chunk = LeafChunk::new(&leaves, &palette);
+    // the unused lane repeats the last color
+    assert_eq!([0, 1, 2, 2], chunk.idx);
+    let needle = f_pixel(ARGBF { a: 0.9, r: 0.1, g: 0.6, b: 0.4 });
+    for (&diff, &idx) in chunk.diff(&needle).iter().zip(&chunk.idx) {
+        assert!((diff - palette[usize::from(idx)].diff(&needle)).abs() < 1e-6);
+    }
+}
+
+#[test]
+fn search_matches_exhaustive() {
+    use crate::pal::{PalPop, ARGBF};
+    let color = |i: u32| f_pixel(ARGBF { a: 1., r: (i * 37 % 101) as f32 / 100., g: (i * 59 % 103) as f32 / 102., b: (i * 83 % 107) as f32 / 106. });
+    // leaves are made of nodes with fewer than 7 colors, so small palettes test full and partial chunks
+    for len in 1..20 {
+        let mut palette = PalF::new();
+        for i in 0..len {
+            palette.push(color(i), PalPop::new(1.));
+        }
+        let n = Nearest::new(&palette);
+        for i in 100..300 {
+            let px = color(i);
+            let (best, found) = (n.search_exhaustive(&px).1, n.search(&px, 0).1);
+            assert!((best - found).abs() <= best * 1e-5, "{} {} {} {}", len, i, best, found);
+        }
+    }
+}
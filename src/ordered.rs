@@ -0,0 +1,161 @@
+//! Positional (ordered) dithering with mixes of arbitrary palette colors (see `DitherKernel::Ordered`)
+//!
+//! This is the first of Joel Yliluoma's arbitrary-palette positional dithering algorithms: each color is approximated by a mix
+//! of two palette colors in some ratio, and the threshold matrix picks which of the two is used at each position.
+
+use crate::hist::RgbaHasher;
+use crate::nearest::Nearest;
+use crate::pal::{f_pixel, PalF, PalIndex};
+use crate::remap::MixLimit;
+use std::collections::HashMap;
+
+/// 8x8 Bayer threshold matrix
+const BAYER: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+/// Number of ratios a mix can have, one per position of the threshold matrix
+const RATIOS: u8 = 64;
+
+/// Mixing colors that are far apart looks noisy, even if their average is right.
+/// This fraction of the difference between the mixed colors is added to the error of a half-and-half mix (and less for uneven mixes).
+const MIX_PENALTY: f32 = 0.1;
+
+/// Palette entries tried as parts of a mix: the nearest one, and its nearest neighbors
+const CANDIDATES: usize = 9;
+
+/// Plans are cached by color, and the cache is cleared when it gets this big, so that photos with millions of colors don't use too much memory
+const MAX_CACHED_PLANS: usize = 1 << 16;
+
+/// Shift of the threshold matrix for the frame of an animation, see `RemapOptions::frame_index`.
+///
+/// 37 is coprime with 64, so every 64 frames the matrix visits all of its positions, and consecutive frames get distant ones.
+#[inline]
+pub(crate) fn frame_offset(frame_index: Option<u32>) -> (usize, usize) {
+    match frame_index {
+        Some(frame) => {
+            let pos = (frame.wrapping_mul(37) % 64) as usize;
+            (pos % 8, pos / 8)
+        },
+        None => (0, 0),
+    }
+}
+
+/// Two palette entries, and how many of 64 pixels use the second one
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct Mix {
+    first: PalIndex,
+    second: PalIndex,
+    ratio: u8,
+}
+
+impl Mix {
+    /// Entry of the mix used at the given position of the image
+    #[inline]
+    pub(crate) fn index_at(&self, x: usize, y: usize) -> PalIndex {
+        if BAYER[y & 7][x & 7] < self.ratio { self.second } else { self.first }
+    }
+}
+
+/// Finds and caches mixes for colors of the image.
+///
+/// Preparation is slower than a plain nearest color search, but the result of each pixel depends only on its color and position,
+/// so the same color looks the same everywhere, and static areas of animations don't flicker.
+pub(crate) struct MixingPlans<'pal> {
+    palette: &'pal [f_pixel],
+    nearest: Nearest<'pal>,
+    /// For each palette entry, entries closest to it (excluding itself), which are worth mixing with
+    neighbors: Vec<Vec<PalIndex>>,
+    /// Pairs that are too far apart to be mixed
+    limit: Option<MixLimit>,
+    cache: HashMap<u32, (f_pixel, Mix), RgbaHasher>,
+}
+
+impl<'pal> MixingPlans<'pal> {
+    pub(crate) fn new(palette: &'pal PalF, limit: Option<MixLimit>) -> Self {
+        let colors = palette.as_slice();
+        let neighbors = colors.iter().enumerate().map(|(i, color)| {
+            let mut others: Vec<_> = (0..colors.len()).filter(|&j| j != i).map(|j| (color.diff(&colors[j]), j as PalIndex)).collect();
+            others.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+            others.truncate(CANDIDATES - 1);
+            others.into_iter().map(|(_, j)| j).collect()
+        }).collect();
+        Self {
+            palette: colors,
+            nearest: Nearest::new(palette),
+            neighbors,
+            limit,
+            cache: HashMap::with_hasher(RgbaHasher(0)),
+        }
+    }
+
+    /// Nearest palette entry, without mixing
+    #[inline]
+    pub(crate) fn nearest(&self, px: &f_pixel) -> PalIndex {
+        self.nearest.search(px, 0).0
+    }
+
+    /// Best mix for the color. `dither_level` (0-1) moves the color towards its nearest palette entry, which needs fewer mixes.
+    pub(crate) fn plan(&mut self, px: f_pixel, dither_level: f32) -> Mix {
+        let nearest = self.nearest(&px);
+        if dither_level <= 0. {
+            return Mix { first: nearest, second: nearest, ratio: 0 };
+        }
+        let px = if dither_level < 1. {
+            let n = self.palette[nearest as usize];
+            f_pixel(n.0 + (px.0 - n.0) * dither_level)
+        } else { px };
+
+        let key = px.a.to_bits() ^ px.r.to_bits().rotate_left(8) ^ px.g.to_bits().rotate_left(16) ^ px.b.to_bits().rotate_left(24);
+        if let Some(&(color, mix)) = self.cache.get(&key) {
+            if color == px {
+                return mix;
+            }
+        }
+        let mix = self.best_mix(px, nearest);
+        if self.cache.len() >= MAX_CACHED_PLANS {
+            self.cache.clear();
+        }
+        self.cache.insert(key, (px, mix));
+        mix
+    }
+
+    fn best_mix(&self, px: f_pixel, nearest: PalIndex) -> Mix {
+        let mut candidates = Vec::with_capacity(CANDIDATES);
+        candidates.push(nearest);
+        candidates.extend_from_slice(&self.neighbors[nearest as usize]);
+
+        let mut best = (px.diff(&self.palette[nearest as usize]), Mix { first: nearest, second: nearest, ratio: 0 });
+        for (i, &first) in candidates.iter().enumerate() {
+            let a = self.palette[first as usize];
+            for &second in &candidates[i + 1..] {
+                if matches!(&self.limit, Some(limit) if !limit.allows(first, second)) {
+                    continue;
+                }
+                let b = self.palette[second as usize];
+                let ab = b.0 - a.0;
+                let len_sq = ab.a * ab.a + ab.r * ab.r + ab.g * ab.g + ab.b * ab.b;
+                if len_sq <= 0. {
+                    continue;
+                }
+                let ap = px.0 - a.0;
+                let t = ((ap.a * ab.a + ap.r * ab.r + ap.g * ab.g + ap.b * ab.b) / len_sq).clamp(0., 1.);
+                let ratio = (t * f32::from(RATIOS)).round() as u8;
+                let t = f32::from(ratio) / f32::from(RATIOS);
+                let mixed = f_pixel(a.0 + ab * t);
+                let error = px.diff(&mixed) + a.diff(&b) * MIX_PENALTY * 4. * t * (1. - t);
+                if error < best.0 {
+                    best = (error, Mix { first, second, ratio });
+                }
+            }
+        }
+        best.1
+    }
+}
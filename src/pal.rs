@@ -1,5 +1,13 @@
+use crate::error::*;
 use crate::hist::{FixedColorsSet, HashColor};
+use crate::labels::LabelFallback;
+use crate::levels::Levels;
+use crate::nearest::Nearest;
+use crate::quant::StageLog;
+use crate::remap::mse_to_standard_mse;
+#[cfg(not(feature = "large-palettes"))]
 use arrayvec::ArrayVec;
+use std::fmt;
 use std::ops::{Deref, DerefMut};
 use std::os::raw::c_uint;
 
@@ -21,6 +29,46 @@ pub const LIQ_WEIGHT_MSE: f64 = 0.45;
 pub const MIN_OPAQUE_A: f32 = 1. / 256. * LIQ_WEIGHT_A;
 pub const MAX_TRANSP_A: f32 = 255. / 256. * LIQ_WEIGHT_A;
 
+/// How the alpha channel of input pixels relates to their color, see [`Image::set_alpha_encoding`](crate::Image::set_alpha_encoding)
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AlphaEncoding {
+    /// Alpha is linear opacity. This is the norm, e.g. in PNG.
+    Linear,
+    /// Alpha has been gamma-encoded the same way as color channels, as done by some renderers.
+    Gamma,
+}
+
+impl Default for AlphaEncoding {
+    #[inline]
+    fn default() -> Self {
+        Self::Linear
+    }
+}
+
+impl AlphaEncoding {
+    /// Linear alpha in 0-1 range for each alpha value, or `None` if alpha is linear already
+    pub(crate) fn lut(self, gamma: f64) -> Option<[f32; 256]> {
+        match self {
+            Self::Linear => None,
+            Self::Gamma => {
+                let mut tmp = [0.; 256];
+                for (i, t) in tmp.iter_mut().enumerate() {
+                    *t = ((i as f32) / 255.).powf((1. / gamma) as f32);
+                }
+                Some(tmp)
+            },
+        }
+    }
+
+    /// Encodes alpha of a color made with `to_rgb`
+    pub(crate) fn encode(self, px: &f_pixel, rgba: &mut RGBA, gamma: f64) {
+        if self == Self::Gamma && rgba.a != 0 {
+            let a = (px.a / LIQ_WEIGHT_A).min(1.);
+            rgba.a = (a.powf(gamma as f32) * 256.).min(255.) as u8;
+        }
+    }
+}
+
 /// 4xf32 color using internal gamma.
 ///
 /// ARGB layout is important for x86 SIMD.
@@ -99,7 +147,21 @@ impl f_pixel {
     }
 
     pub fn from_rgba(gamma_lut: &[f32; 256], px: RGBA) -> Self {
-        let a = px.a as f32 / 255.;
+        Self::from_rgba_alpha(gamma_lut, px, px.a as f32 / 255.)
+    }
+
+    /// Like `from_rgba`, but alpha is converted with a lut from [`AlphaEncoding::lut`]
+    #[inline]
+    pub fn from_rgba_alpha_lut(gamma_lut: &[f32; 256], alpha_lut: Option<&[f32; 256]>, px: RGBA) -> Self {
+        match alpha_lut {
+            Some(alpha_lut) => Self::from_rgba_alpha(gamma_lut, px, alpha_lut[px.a as usize]),
+            None => Self::from_rgba(gamma_lut, px),
+        }
+    }
+
+    /// `a` is linear alpha in 0-1 range
+    #[inline(always)]
+    fn from_rgba_alpha(gamma_lut: &[f32; 256], px: RGBA, a: f32) -> Self {
         Self(ARGBF {
             a: a * LIQ_WEIGHT_A,
             r: gamma_lut[px.r as usize] * LIQ_WEIGHT_R * a,
@@ -162,17 +224,40 @@ impl PalPop {
     }
 }
 
-/// This could be increased to support > 256 colors
+/// Index of a color in the palette, as used in remapped images. It's `u16` if the `large-palettes` feature is enabled.
+#[cfg(not(feature = "large-palettes"))]
 pub type PalIndex = u8;
+#[cfg(feature = "large-palettes")]
+pub type PalIndex = u16;
 pub type PalLen = u16;
 
-pub(crate) const MAX_COLORS: usize = 256;
+/// Largest supported palette size. It's 4096 if the `large-palettes` feature is enabled.
+#[cfg(not(feature = "large-palettes"))]
+pub const MAX_COLORS: usize = 256;
+#[cfg(feature = "large-palettes")]
+pub const MAX_COLORS: usize = 4096;
+
+/// Large palettes are on the heap, because there are several copies of the palette on the stack
+#[cfg(not(feature = "large-palettes"))]
+type PalVec<T> = ArrayVec<T, MAX_COLORS>;
+#[cfg(feature = "large-palettes")]
+type PalVec<T> = Vec<T>;
 
 /// A palette of premultiplied ARGB 4xf32 colors in internal gamma
 #[derive(Clone)]
 pub(crate) struct PalF {
-    colors: ArrayVec<f_pixel, MAX_COLORS>,
-    pops: ArrayVec<PalPop, MAX_COLORS>,
+    colors: PalVec<f_pixel>,
+    pops: PalVec<PalPop>,
+    /// Stretch of the input that the colors are in, see `Attributes::set_auto_levels`
+    levels: Option<Levels>,
+    /// Number of alpha values the final colors can have, 0 if any. See `Attributes::set_alpha_levels`
+    alpha_levels: u8,
+    /// Indices of fixed colors that have been added with a tag, set once the palette is sorted
+    tags: Vec<(u32, PalIndex)>,
+    /// Colors are categories, which are remapped only by exact match. See `Attributes::set_label_mode`
+    label_fallback: Option<LabelFallback>,
+    /// Palettes of earlier stages of quantization, with the `debug-stages` feature
+    pub(crate) stages: StageLog,
 }
 
 impl PalF {
@@ -180,9 +265,96 @@ impl PalF {
     pub fn new() -> Self {
         debug_assert!(PalLen::MAX as usize >= MAX_COLORS);
         Self {
-            colors: ArrayVec::new(),
-            pops: ArrayVec::new(),
+            colors: PalVec::new(),
+            pops: PalVec::new(),
+            levels: None,
+            alpha_levels: 0,
+            tags: Vec::new(),
+            label_fallback: None,
+            stages: StageLog::default(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn levels(&self) -> Option<&Levels> {
+        self.levels.as_ref()
+    }
+
+    #[inline]
+    pub fn set_levels(&mut self, levels: Option<Levels>) {
+        self.levels = levels;
+    }
+
+    #[inline(always)]
+    pub fn alpha_levels(&self) -> u8 {
+        self.alpha_levels
+    }
+
+    #[inline]
+    pub fn set_alpha_levels(&mut self, alpha_levels: u8) {
+        self.alpha_levels = alpha_levels;
+    }
+
+    #[inline(always)]
+    pub fn label_fallback(&self) -> Option<LabelFallback> {
+        self.label_fallback
+    }
+
+    #[inline]
+    pub fn set_label_fallback(&mut self, fallback: Option<LabelFallback>) {
+        self.label_fallback = fallback;
+    }
+
+    #[inline]
+    pub fn tags(&self) -> &[(u32, PalIndex)] {
+        &self.tags
+    }
+
+    /// Tags found earlier with `set_tags`, e.g. in a saved palette
+    pub(crate) fn restore_tags(&mut self, tags: Vec<(u32, PalIndex)>) {
+        self.tags = tags;
+    }
+
+    /// Finds tagged fixed colors in the palette. Entries must not be reordered afterwards.
+    pub(crate) fn set_tags(&mut self, fixed_colors: &FixedColorsSet) {
+        self.tags = self.colors.iter().zip(self.pops.iter()).enumerate()
+            .filter(|(_, (_, pop))| pop.is_fixed())
+            .filter_map(|(i, (color, _))| Some((fixed_colors.get(&HashColor(*color))?.tag?, i as PalIndex)))
+            .collect();
+    }
+
+    /// Moves fixed entries of the given colors to the beginning of the palette, in the given order, keeping order of the other entries.
+    /// Returns `false` if some of the colors aren't in the palette (then it's unchanged).
+    pub(crate) fn move_to_front(&mut self, colors: &[f_pixel]) -> bool {
+        /// Fixed colors may have been converted to another color space and back
+        const SAME_COLOR: f32 = 1. / 256. / 256. / 16.;
+
+        let mut moved = vec![false; self.len()];
+        let mut order = Vec::with_capacity(self.len());
+        for color in colors {
+            let found = self.colors.iter().zip(self.pops.iter()).enumerate()
+                .filter(|&(i, (c, pop))| !moved[i] && pop.is_fixed() && c.diff(color) < SAME_COLOR)
+                .min_by(|(_, (a, _)), (_, (b, _))| a.diff(color).partial_cmp(&b.diff(color)).unwrap_or(std::cmp::Ordering::Equal));
+            match found {
+                Some((i, _)) => {
+                    moved[i] = true;
+                    order.push(i);
+                },
+                None => return false,
+            }
+        }
+        order.extend((0..self.len()).filter(|&i| !moved[i]));
+
+        let mut new_index = vec![0; self.len()];
+        for (new, &old) in order.iter().enumerate() {
+            new_index[old] = new as PalIndex;
         }
+        self.colors = order.iter().map(|&i| self.colors[i]).collect();
+        self.pops = order.iter().map(|&i| self.pops[i]).collect();
+        for (_, idx) in &mut self.tags {
+            *idx = new_index[*idx as usize];
+        }
+        true
     }
 
     #[inline(always)]
@@ -208,7 +380,7 @@ impl PalF {
 
         let mut new_pal = PalF::new();
         let is_fixed = &PalPop::new(1.).to_fixed();
-        let new_colors = fixed_colors.iter().map(move |HashColor(color)| (color, is_fixed))
+        let new_colors = fixed_colors.keys().map(move |HashColor(color)| (color, is_fixed))
             .chain(self.iter())
             .take(max_colors as usize);
 
@@ -238,6 +410,20 @@ impl PalF {
         c.iter().zip(pop)
     }
 
+    /// Removes entries that aren't marked to be kept in `keep` (one flag per entry), keeping order of the others
+    pub(crate) fn retain(&mut self, keep: &[bool]) {
+        debug_assert_eq!(keep.len(), self.len());
+        let mut keep_colors = keep.iter();
+        self.colors.retain(|_| keep_colors.next().copied().unwrap_or(true));
+        let mut keep_pops = keep.iter();
+        self.pops.retain(|_| keep_pops.next().copied().unwrap_or(true));
+    }
+
+    pub(crate) fn set(&mut self, i: usize, color: f_pixel, popularity: PalPop) {
+        self.colors[i] = color;
+        self.pops[i] = popularity;
+    }
+
     pub(crate) fn swap(&mut self, a: usize, b: usize) {
         self.colors.swap(a, b);
         self.pops.swap(a, b);
@@ -254,10 +440,66 @@ pub fn gamma_lut(gamma: f64) -> [f32; 256] {
     tmp
 }
 
+/// Differences between two palettes, see [`Palette::diff`]
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PaletteDiff {
+    /// Identical colors, as (index in the old palette, index in the new palette)
+    pub matched: Vec<(PalIndex, PalIndex)>,
+    /// Colors that changed a little, as (index in the old palette, index in the new palette, distance).
+    /// The distance is in the same units as [`QuantizationResult::quantization_error`](crate::QuantizationResult::quantization_error).
+    pub moved: Vec<(PalIndex, PalIndex, f64)>,
+    /// Indices of new palette's colors that aren't similar to any of the old ones
+    pub added: Vec<PalIndex>,
+    /// Indices of old palette's colors that aren't similar to any of the new ones
+    pub removed: Vec<PalIndex>,
+}
+
+impl PaletteDiff {
+    /// Colors further apart than this are treated as removed and added, rather than moved
+    pub const MAX_MOVE_DISTANCE: f64 = 100.;
+
+    /// True if both palettes have the same colors, possibly in a different order
+    #[must_use]
+    pub fn is_same(&self) -> bool {
+        self.moved.is_empty() && self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// Colors of a palette, in the same layout as `liq_palette` of the C API.
+///
+/// It dereferences to a slice of its `count` colors, so it can be indexed like one. Only the first `count` of `entries` are used.
 #[repr(C)]
+#[derive(Clone)]
 pub struct Palette {
     pub count: c_uint,
-    pub entries: [RGBA; 256],
+    pub entries: [RGBA; MAX_COLORS],
+}
+
+impl fmt::Debug for Palette {
+    #[cold]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.as_slice()).finish()
+    }
+}
+
+/// Palettes are equal if their used colors are, regardless of unused entries
+impl PartialEq for Palette {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl Eq for Palette {}
+
+impl<'a> IntoIterator for &'a Palette {
+    type Item = &'a RGBA;
+    type IntoIter = std::slice::Iter<'a, RGBA>;
+
+    #[inline]
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
 }
 
 impl std::ops::Deref for Palette {
@@ -277,6 +519,34 @@ impl std::ops::DerefMut for Palette {
 }
 
 impl Palette {
+    /// Palette of the given colors. There can be at most `MAX_COLORS` of them.
+    pub fn new(colors: &[RGBA]) -> Result<Self, liq_error> {
+        if colors.len() > MAX_COLORS {
+            return Err(LIQ_VALUE_OUT_OF_RANGE);
+        }
+        let mut entries = [RGBA::default(); MAX_COLORS];
+        entries[..colors.len()].copy_from_slice(colors);
+        Ok(Self { count: colors.len() as c_uint, entries })
+    }
+
+    /// Colors of the palette, in order of their indices
+    #[inline]
+    pub fn iter(&self) -> std::slice::Iter<'_, RGBA> {
+        self.as_slice().iter()
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn to_rgba_vec(&self) -> Vec<RGBA> {
+        self.as_slice().to_vec()
+    }
+
+    /// Indices of colors that aren't fully opaque, such as ones that need a `tRNS` entry in PNG files
+    #[inline]
+    pub fn transparent_entries(&self) -> impl Iterator<Item = PalIndex> + '_ {
+        self.iter().enumerate().filter(|(_, c)| c.a < 255).map(|(i, _)| i as PalIndex)
+    }
+
     #[inline(always)]
     pub fn as_slice(&self) -> &[RGBA] {
         &self.entries[..self.count as usize]
@@ -286,4 +556,93 @@ impl Palette {
     pub fn as_mut_slice(&mut self) -> &mut [RGBA] {
         &mut self.entries[..self.count as usize]
     }
+
+    /// Table translating indices of this palette to indices of the closest colors in the `other` palette.
+    ///
+    /// Allows converting an already-remapped image to another palette without remapping its pixels again:
+    /// `new_pixel = table[old_pixel]`. Colors are compared assuming sRGB gamma.
+    #[must_use]
+    pub fn mapping_to(&self, other: &Palette) -> [PalIndex; MAX_COLORS] {
+        let mut table = [0; MAX_COLORS];
+        if other.as_slice().is_empty() {
+            return table;
+        }
+
+        let lut = gamma_lut(0.45455);
+        let mut other_f = PalF::new();
+        for &color in other.as_slice() {
+            other_f.push(f_pixel::from_rgba(&lut, color), PalPop::new(1.));
+        }
+        let n = Nearest::new(&other_f);
+        let mut last_match = 0;
+        for (t, &color) in table.iter_mut().zip(self.as_slice()) {
+            last_match = n.search(&f_pixel::from_rgba(&lut, color), last_match).0;
+            *t = last_match;
+        }
+        table
+    }
+
+    /// Compact hash of colors in the palette, such that similar palettes get hashes that differ in few bits.
+    ///
+    /// Each bit is a cube of the RGB space (4 levels per channel), set if the palette has more colors in it than in the median cube.
+    /// Small palettes set bits of all cubes they have colors in. Large palettes, which have colors in almost every cube,
+    /// set bits only of cubes where their colors are concentrated, so that they don't all get the same hash.
+    ///
+    /// Colors are premultiplied by alpha, and their order doesn't matter. Compare hashes with `(a ^ b).count_ones()`,
+    /// e.g. to find candidates for sharing one palette (see `mapping_to` and `diff`) among thousands of sprites.
+    #[must_use]
+    pub fn perceptual_hash(&self) -> u64 {
+        let mut counts = [0u16; 64];
+        for c in self.iter() {
+            let level = |v: u8| (u16::from(v) * u16::from(c.a) / 255) >> 6;
+            counts[usize::from(level(c.r) << 4 | level(c.g) << 2 | level(c.b))] += 1;
+        }
+        let mut sorted = counts;
+        sorted.sort_unstable();
+        let median = sorted[sorted.len() / 2];
+        counts.iter().enumerate().fold(0, |hash, (i, &count)| if count > median { hash | 1 << i } else { hash })
+    }
+
+    /// Compares this (old) palette with `other` (new) palette.
+    ///
+    /// Each color is paired with at most one color of the other palette, the closest pairs first.
+    /// Colors are compared assuming sRGB gamma.
+    #[must_use]
+    pub fn diff(&self, other: &Palette) -> PaletteDiff {
+        let lut = gamma_lut(0.45455);
+        let old: Vec<_> = self.as_slice().iter().map(|&c| f_pixel::from_rgba(&lut, c)).collect();
+        let new: Vec<_> = other.as_slice().iter().map(|&c| f_pixel::from_rgba(&lut, c)).collect();
+
+        let mut pairs = Vec::new();
+        for (i, old_px) in old.iter().enumerate() {
+            for (j, new_px) in new.iter().enumerate() {
+                let distance = mse_to_standard_mse(old_px.diff(new_px).into());
+                if distance <= PaletteDiff::MAX_MOVE_DISTANCE {
+                    pairs.push((distance, i, j));
+                }
+            }
+        }
+        pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        let mut diff = PaletteDiff::default();
+        let mut old_used = vec![false; old.len()];
+        let mut new_used = vec![false; new.len()];
+        for (distance, i, j) in pairs {
+            if old_used[i] || new_used[j] {
+                continue;
+            }
+            old_used[i] = true;
+            new_used[j] = true;
+            if self[i] == other[j] {
+                diff.matched.push((i as PalIndex, j as PalIndex));
+            } else {
+                diff.moved.push((i as PalIndex, j as PalIndex, distance));
+            }
+        }
+        diff.matched.sort_unstable();
+        diff.moved.sort_by_key(|&(i, j, _)| (i, j));
+        diff.removed = old_used.iter().enumerate().filter(|&(_, &used)| !used).map(|(i, _)| i as PalIndex).collect();
+        diff.added = new_used.iter().enumerate().filter(|&(_, &used)| !used).map(|(j, _)| j as PalIndex).collect();
+        diff
+    }
 }
@@ -0,0 +1,137 @@
+//! Reading and writing palettes in formats used by art tools
+//!
+//! Supports GIMP `.gpl`, Photoshop `.act`, and JASC (Paint Shop Pro) `.pal` files.
+//! Palettes can be written from [`QuantizationResult::palette`](crate::QuantizationResult::palette),
+//! and palettes that have been read can be used with `add_fixed_color`.
+//!
+//! GPL and JASC formats don't support alpha. ACT supports only one transparent color.
+
+use crate::pal::RGBA;
+use std::io::{self, BufRead, Read, Write};
+
+#[cold]
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Parses "r g b" at the start of a line, ignoring anything after it
+fn parse_rgb(line: &str) -> io::Result<RGBA> {
+    let mut channels = line.split_whitespace().map(|c| c.parse::<u8>().map_err(|_| invalid_data("bad color value")));
+    let mut next = move || channels.next().unwrap_or_else(|| Err(invalid_data("missing color value")));
+    Ok(RGBA::new(next()?, next()?, next()?, 255))
+}
+
+/// Writes GIMP palette. Alpha is ignored.
+pub fn write_gpl(palette: &[RGBA], name: &str, mut out: impl Write) -> io::Result<()> {
+    writeln!(out, "GIMP Palette")?;
+    writeln!(out, "Name: {}", name.replace(['\r', '\n'], " "))?;
+    writeln!(out, "Columns: 16")?;
+    writeln!(out, "#")?;
+    for (i, c) in palette.iter().enumerate() {
+        writeln!(out, "{:3} {:3} {:3}\tIndex {}", c.r, c.g, c.b, i)?;
+    }
+    Ok(())
+}
+
+/// Reads GIMP palette. All colors are opaque.
+pub fn read_gpl(input: impl BufRead) -> io::Result<Vec<RGBA>> {
+    let mut lines = input.lines();
+    match lines.next() {
+        Some(Ok(header)) if header.trim() == "GIMP Palette" => {},
+        Some(Err(e)) => return Err(e),
+        _ => return Err(invalid_data("not a GIMP palette")),
+    }
+
+    let mut palette = Vec::new();
+    for line in lines {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with("Name:") || line.starts_with("Columns:") {
+            continue;
+        }
+        palette.push(parse_rgb(line)?);
+    }
+    Ok(palette)
+}
+
+/// Writes Photoshop color table. The first fully transparent color is marked as transparent, other alpha values are ignored.
+///
+/// Palettes longer than 256 colors are truncated.
+pub fn write_act(palette: &[RGBA], mut out: impl Write) -> io::Result<()> {
+    let palette = &palette[..palette.len().min(256)];
+    let mut data = [0u8; 768 + 4];
+    for (dst, c) in data.chunks_exact_mut(3).zip(palette) {
+        dst.copy_from_slice(&[c.r, c.g, c.b]);
+    }
+    let transparent_index = palette.iter().position(|c| c.a == 0).map_or(0xFFFF, |i| i as u16);
+    data[768..770].copy_from_slice(&(palette.len() as u16).to_be_bytes());
+    data[770..772].copy_from_slice(&transparent_index.to_be_bytes());
+    out.write_all(&data)
+}
+
+/// Reads Photoshop color table. Colors are opaque, except the color marked as transparent.
+pub fn read_act(mut input: impl Read) -> io::Result<Vec<RGBA>> {
+    let mut data = Vec::with_capacity(768 + 4);
+    input.read_to_end(&mut data)?;
+    if data.len() < 768 {
+        return Err(invalid_data("ACT file is too short"));
+    }
+
+    let (count, transparent_index) = match data.get(768..772) {
+        Some(extra) => (u16::from_be_bytes([extra[0], extra[1]]), u16::from_be_bytes([extra[2], extra[3]])),
+        None => (256, 0xFFFF),
+    };
+    let count = if count == 0 || count > 256 { 256 } else { count as usize };
+
+    Ok(data[..768].chunks_exact(3).take(count).enumerate().map(|(i, c)| {
+        RGBA::new(c[0], c[1], c[2], if i == transparent_index as usize { 0 } else { 255 })
+    }).collect())
+}
+
+/// Writes JASC (Paint Shop Pro) palette. Alpha is ignored.
+pub fn write_jasc_pal(palette: &[RGBA], mut out: impl Write) -> io::Result<()> {
+    write!(out, "JASC-PAL\r\n0100\r\n{}\r\n", palette.len())?;
+    for c in palette {
+        write!(out, "{} {} {}\r\n", c.r, c.g, c.b)?;
+    }
+    Ok(())
+}
+
+/// Reads JASC (Paint Shop Pro) palette. All colors are opaque.
+pub fn read_jasc_pal(input: impl BufRead) -> io::Result<Vec<RGBA>> {
+    let mut lines = input.lines();
+    let mut header = move || -> io::Result<String> {
+        Ok(lines.next().ok_or_else(|| invalid_data("JASC palette is too short"))??.trim().to_owned())
+    };
+    if header()? != "JASC-PAL" || header()? != "0100" {
+        return Err(invalid_data("not a JASC palette"));
+    }
+    let count: usize = header()?.parse().map_err(|_| invalid_data("bad number of colors"))?;
+    if count > 1 << 16 {
+        return Err(invalid_data("bad number of colors"));
+    }
+
+    let mut palette = Vec::with_capacity(count);
+    for _ in 0..count {
+        palette.push(parse_rgb(&header()?)?);
+    }
+    Ok(palette)
+}
+
+#[test]
+fn round_trip() {
+    let palette = [RGBA::new(1, 2, 3, 255), RGBA::new(255, 128, 0, 255), RGBA::new(0, 0, 0, 0)];
+    let opaque: Vec<_> = palette.iter().map(|c| RGBA { a: 255, ..*c }).collect();
+
+    let mut gpl = Vec::new();
+    write_gpl(&palette, "test", &mut gpl).unwrap();
+    assert_eq!(opaque, read_gpl(&gpl[..]).unwrap());
+
+    let mut act = Vec::new();
+    write_act(&palette, &mut act).unwrap();
+    assert_eq!(&palette[..], &read_act(&act[..]).unwrap()[..]);
+
+    let mut pal = Vec::new();
+    write_jasc_pal(&palette, &mut pal).unwrap();
+    assert_eq!(opaque, read_jasc_pal(&pal[..]).unwrap());
+}
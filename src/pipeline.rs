@@ -0,0 +1,366 @@
+//! Stages of quantization as separate steps, for adding custom processing between them.
+//!
+//! `Attributes::quantize` does the same as: [`HistogramBuilder`] → [`PaletteOptimizer::median_cut`] →
+//! [`PaletteOptimizer::kmeans_iteration`] (a few times) → [`PaletteOptimizer::into_remapper`].
+
+use crate::error::*;
+use crate::hist::{FixedColorsSet, HashColor, Histogram, HistogramInternal, RgbaHasher};
+use crate::image::Image;
+use crate::kmeans::Kmeans;
+use crate::mediancut::mediancut;
+use crate::nearest::Metric;
+use crate::pal::{f_pixel, gamma_lut, AlphaEncoding, PalF, PalIndex, PalLen, PalPop, MAX_COLORS, RGBA};
+use crate::quant::{quality_to_mse, QuantizationResult};
+use crate::remap::{mse_to_standard_mse, remap_to_palette_metric, DitherLevel, DitherState, DitherStateRef, RemapScratch};
+use crate::seacow::RowBitmapMut;
+use crate::Attributes;
+use fallible_collections::FallibleVec;
+use std::collections::HashMap;
+use std::mem::MaybeUninit;
+
+/// First stage: collects colors from images. It's the same type as [`Histogram`].
+pub type HistogramBuilder = Histogram;
+
+/// Settings of a single K-means iteration, see [`PaletteOptimizer::kmeans_iteration_with`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KmeansStep {
+    /// How far (0-1) colors move towards the average of the histogram colors they represent.
+    /// 1 is the usual K-means step. Smaller steps converge slower, but are less likely to get stuck.
+    pub step: f32,
+    /// Gives more weight to histogram colors that are represented poorly, as in the last iterations of `Attributes::quantize`.
+    /// The adjusted weights carry over to later iterations.
+    pub adjust_weights: bool,
+}
+
+impl Default for KmeansStep {
+    /// The same as `kmeans_iteration`
+    #[inline]
+    fn default() -> Self {
+        Self { step: 1., adjust_weights: false }
+    }
+}
+
+/// Second stage: chooses palette colors for the histogram.
+///
+/// The palette can be seeded with median cut or any colors you like, and then improved with K-means.
+pub struct PaletteOptimizer {
+    attr: Attributes,
+    hist: HistogramInternal,
+    fixed_colors: FixedColorsSet,
+    gamma: f64,
+    alpha_encoding: AlphaEncoding,
+    palette: PalF,
+    palette_error: Option<f64>,
+}
+
+impl PaletteOptimizer {
+    /// Takes colors from the histogram. The palette is empty until it's seeded.
+    ///
+    /// Fails with `LIQ_UNSUPPORTED` if the histogram is empty.
+    pub fn new(attr: &Attributes, histogram: &mut HistogramBuilder) -> Result<Self, liq_error> {
+        let (hist, fixed_colors, gamma) = histogram.finalize(attr)?;
+        Ok(Self {
+            attr: attr.clone(),
+            hist,
+            fixed_colors,
+            gamma,
+            alpha_encoding: histogram.alpha_encoding(),
+            palette: PalF::new(),
+            palette_error: None,
+        })
+    }
+
+    /// Seeds the palette using median cut, respecting `max_colors` and fixed colors
+    pub fn median_cut(&mut self) {
+        let (_, target_mse, _) = self.attr.target_mse(self.hist.items.len());
+        let max_colors = self.attr.max_colors;
+        let max_mse_per_color = target_mse.max(quality_to_mse(51)) * 1.2;
+        self.palette = mediancut(&mut self.hist, max_colors.saturating_sub(self.fixed_colors.len() as PalLen).max(1), target_mse, max_mse_per_color, self.attr.alpha_levels != 0)
+            .with_fixed_colors(max_colors, &self.fixed_colors);
+        self.palette_error = None;
+    }
+
+    /// Replaces the palette with the given colors, e.g. a palette that has been post-processed.
+    ///
+    /// Colors are in the gamma of the images in the histogram.
+    pub fn set_palette(&mut self, colors: &[RGBA]) -> Result<(), liq_error> {
+        if colors.is_empty() || colors.len() > MAX_COLORS {
+            return Err(LIQ_VALUE_OUT_OF_RANGE);
+        }
+        let lut = gamma_lut(self.gamma);
+        let alpha_lut = self.alpha_encoding.lut(self.gamma);
+        let mut palette = PalF::new();
+        for &c in colors {
+            palette.push(f_pixel::from_rgba_alpha_lut(&lut, alpha_lut.as_ref(), c), PalPop::new(1.));
+        }
+        self.palette = palette;
+        self.palette_error = None;
+        Ok(())
+    }
+
+    /// Moves palette colors closer to the histogram colors they represent. Seeds the palette with median cut if it's empty.
+    ///
+    /// Returns error of the palette before this iteration, in the same units as [`QuantizationResult::quantization_error`].
+    pub fn kmeans_iteration(&mut self) -> f64 {
+        self.kmeans_step(&KmeansStep::default())
+    }
+
+    /// Like `kmeans_iteration`, but with custom settings, for schedules of your own (e.g. annealing with growing steps).
+    ///
+    /// Colors frozen with `set_frozen` don't move, but histogram colors are still assigned to them.
+    /// Fails with `LIQ_VALUE_OUT_OF_RANGE` if the step isn't in the 0-1 range (excluding 0).
+    pub fn kmeans_iteration_with(&mut self, step: &KmeansStep) -> Result<f64, liq_error> {
+        if !(step.step > 0. && step.step <= 1.) {
+            return Err(LIQ_VALUE_OUT_OF_RANGE);
+        }
+        Ok(self.kmeans_step(step))
+    }
+
+    fn kmeans_step(&mut self, step: &KmeansStep) -> f64 {
+        if self.palette.as_slice().is_empty() {
+            self.median_cut();
+        }
+        let previous = if step.step < 1. { Some(self.palette.as_slice().to_vec()) } else { None };
+        let error = Kmeans::iteration(&mut self.hist, &mut self.palette, step.adjust_weights);
+        if let Some(previous) = previous {
+            // unused colors have been replaced with histogram colors, and aren't moved part of the way
+            for ((color, pop), old) in self.palette.iter_mut().zip(previous) {
+                if !pop.is_fixed() && pop.popularity() > 0. {
+                    *color = f_pixel(old.0 + (color.0 - old.0) * step.step);
+                }
+            }
+        }
+        self.palette_error = Some(error);
+        mse_to_standard_mse(error)
+    }
+
+    /// Freezes the palette color at `index`, so that K-means doesn't move it, or unfreezes it, e.g. to refine only some clusters.
+    ///
+    /// Fixed colors of the histogram can't be unfrozen. Freezing lasts until the palette is replaced by `median_cut` or `set_palette`.
+    /// Fails with `LIQ_VALUE_OUT_OF_RANGE` if there's no such color (the palette is empty until it's seeded).
+    pub fn set_frozen(&mut self, index: usize, frozen: bool) -> liq_error {
+        let fixed_colors = &self.fixed_colors;
+        let (color, pop) = match self.palette.iter_mut().nth(index) {
+            Some(entry) => entry,
+            None => return LIQ_VALUE_OUT_OF_RANGE,
+        };
+        if frozen {
+            *pop = pop.to_fixed();
+        } else if fixed_colors.contains_key(&HashColor(*color)) {
+            return LIQ_UNSUPPORTED;
+        } else {
+            *pop = PalPop::new(pop.popularity());
+        }
+        LIQ_OK
+    }
+
+    /// Reads value set with `set_frozen`. Fixed colors of the histogram are always frozen.
+    #[must_use]
+    pub fn is_frozen(&self, index: usize) -> bool {
+        matches!(self.palette.pop_as_slice().get(index), Some(pop) if pop.is_fixed())
+    }
+
+    /// Current palette colors
+    #[must_use]
+    pub fn palette(&self) -> Vec<RGBA> {
+        self.palette.as_slice().iter().map(|c| {
+            let mut rgba = c.to_rgb(self.gamma);
+            self.alpha_encoding.encode(c, &mut rgba, self.gamma);
+            rgba
+        }).collect()
+    }
+
+    /// Error as of the last `kmeans_iteration`, in the same units as [`QuantizationResult::quantization_error`]
+    #[must_use]
+    pub fn quantization_error(&self) -> Option<f64> {
+        self.palette_error.map(mse_to_standard_mse)
+    }
+
+    /// Last stage, see [`Remapper`]. Seeds the palette with median cut if it's empty.
+    ///
+    /// The palette will still be refined a bit when remapping, as with `Attributes::quantize`.
+    #[must_use]
+    pub fn into_remapper(mut self) -> Remapper {
+        if self.palette.as_slice().is_empty() {
+            self.median_cut();
+        }
+        Remapper {
+            result: QuantizationResult::with_palette(&self.attr, self.palette, self.palette_error, &self.fixed_colors, self.gamma, self.alpha_encoding),
+            metric: None,
+        }
+    }
+}
+
+/// Last stage: remaps images to a palette.
+pub struct Remapper {
+    result: QuantizationResult,
+    /// Replaces the usual remapping, see `with_metric`
+    metric: Option<Box<Metric>>,
+}
+
+impl Remapper {
+    /// Remaps to exactly these colors (apart from their order), without adjusting them to the images.
+    ///
+    /// Gamma is of the palette and the images, 0 means sRGB.
+    pub fn new(attr: &Attributes, colors: &[RGBA], gamma: f64) -> Result<Self, liq_error> {
+        if colors.is_empty() || colors.len() > MAX_COLORS || !(0. ..1.).contains(&gamma) {
+            return Err(LIQ_VALUE_OUT_OF_RANGE);
+        }
+        let gamma = if gamma > 0. { gamma } else { 0.45455 };
+        let lut = gamma_lut(gamma);
+        let mut palette = PalF::new();
+        for &c in colors {
+            palette.push(f_pixel::from_rgba(&lut, c), PalPop::new(1.).to_fixed());
+        }
+        Ok(Self {
+            result: QuantizationResult::with_palette(attr, palette, None, &HashMap::with_hasher(RgbaHasher(0)), gamma, AlphaEncoding::Linear),
+            metric: None,
+        })
+    }
+
+    /// Remaps each pixel to the palette color that is nearest in the given metric, e.g. a custom perceptual metric,
+    /// or a domain-specific distance, like one between classes of a label map. Smaller values are nearer.
+    ///
+    /// This is for research and experiments. Every palette color is checked (once per distinct pixel color), so it's much slower.
+    /// There's no dithering, and the palette isn't adjusted to the remapped images.
+    /// The metric gets a pixel of the image and a color of the palette, both as they are in [`palette()`](Self::palette).
+    #[must_use]
+    pub fn with_metric(mut self, metric: impl Fn(&RGBA, &RGBA) -> f32 + Send + Sync + 'static) -> Self {
+        self.metric = Some(Box::new(metric));
+        self
+    }
+
+    /// See [`QuantizationResult::set_dithering_level`]
+    #[inline]
+    pub fn set_dithering_level(&mut self, value: f32) -> liq_error {
+        self.result.set_dithering_level(value)
+    }
+
+    /// See [`QuantizationResult::set_dither_level`]
+    #[inline]
+    pub fn set_dither_level(&mut self, level: DitherLevel) -> liq_error {
+        self.result.set_dither_level(level)
+    }
+
+    /// See [`QuantizationResult::palette`]
+    #[inline]
+    pub fn palette(&mut self) -> &[RGBA] {
+        self.result.palette()
+    }
+
+    /// See [`QuantizationResult::remapped`]
+    pub fn remapped(&mut self, image: &mut Image<'_, '_>) -> Result<(Vec<RGBA>, Vec<PalIndex>), liq_error> {
+        if self.metric.is_none() {
+            return self.result.remapped(image);
+        }
+        let len = image.width() * image.height();
+        // Capacity is essential here, as it creates uninitialized buffer
+        unsafe {
+            let mut buf: Vec<PalIndex> = FallibleVec::try_with_capacity(len).map_err(|_| LIQ_OUT_OF_MEMORY)?;
+            let uninit_slice = std::slice::from_raw_parts_mut(buf.as_mut_ptr().cast::<MaybeUninit<PalIndex>>(), buf.capacity());
+            self.remap_into(image, uninit_slice)?;
+            buf.set_len(uninit_slice.len());
+            Ok((self.result.palette_vec()?, buf))
+        }
+    }
+
+    /// See [`QuantizationResult::remap_into`]
+    #[inline]
+    pub fn remap_into(&mut self, image: &mut Image<'_, '_>, output_buf: &mut [MaybeUninit<PalIndex>]) -> Result<(), liq_error> {
+        self.remap_into_with_scratch(image, output_buf, &mut RemapScratch::new())
+    }
+
+    /// See [`QuantizationResult::remap_into_with_scratch`]
+    #[inline]
+    pub fn remap_into_with_scratch(&mut self, image: &mut Image<'_, '_>, output_buf: &mut [MaybeUninit<PalIndex>], scratch: &mut RemapScratch) -> Result<(), liq_error> {
+        match &self.metric {
+            Some(metric) => remap_with_metric(&mut self.result, &**metric, image, output_buf, true),
+            None => self.result.remap_into_with_scratch(image, output_buf, scratch),
+        }
+    }
+
+    /// See [`QuantizationResult::remap_strip_into`]
+    #[inline]
+    pub fn remap_strip_into(&mut self, strip: &mut Image<'_, '_>, output_buf: &mut [MaybeUninit<PalIndex>], state: &mut DitherState) -> Result<(), liq_error> {
+        match &self.metric {
+            Some(metric) => remap_with_metric(&mut self.result, &**metric, strip, output_buf, false),
+            None => self.result.remap_strip_into(strip, output_buf, state),
+        }
+    }
+
+    /// Remaps one tile of an image that is too large to remap at once, e.g. one that is read from disk a strip at a time.
+    ///
+    /// `tile` has pixels of the area at `x`,`y` of the whole image, and `output_buf` receives `tile.width() * tile.height()` indices.
+    /// The palette isn't adjusted to the tiles, so every tile is remapped to the same colors, and `palette()` can be read at any point.
+    ///
+    /// Without `edge_errors`, every tile is dithered on its own. With them, dithering error flows from the bottom of a tile
+    /// to the top of the tile below it, which hides horizontal seams, as long as the tiles of each column are remapped top to bottom.
+    pub fn remap_tile(&mut self, x: usize, y: usize, tile: &mut Image<'_, '_>, output_buf: &mut [MaybeUninit<PalIndex>], edge_errors: Option<&mut TileErrors>) -> Result<(), liq_error> {
+        if let Some(metric) = &self.metric {
+            return remap_with_metric(&mut self.result, &**metric, tile, output_buf, false);
+        }
+        let (width, height) = (tile.width(), tile.height());
+        let output_buf = output_buf.get_mut(0..width * height).ok_or(LIQ_BUFFER_TOO_SMALL)?;
+        let mut scratch = RemapScratch::new();
+        let edge_errors = match edge_errors {
+            Some(edge) => {
+                let columns = x..x.checked_add(width).ok_or(LIQ_VALUE_OUT_OF_RANGE)?;
+                let next_rows = edge.next_rows.get_mut(columns.clone()).ok_or(LIQ_VALUE_OUT_OF_RANGE)?;
+                let errors = &mut edge.errors[columns];
+                // errors are only valid for the row right below the tile they came from
+                for (err, next_row) in errors.iter_mut().zip(next_rows.iter()) {
+                    if *next_row != y {
+                        *err = f_pixel::default();
+                    }
+                }
+                Some((errors, next_rows))
+            },
+            None => None,
+        };
+        match edge_errors {
+            Some((errors, next_rows)) => {
+                // tiles always start scanning forward, since tiles to the side would disagree on the direction
+                let mut scan_forward = true;
+                let state = DitherStateRef { errors, scan_forward: &mut scan_forward };
+                self.result.write_remapped_tile_rows_internal(tile, output_buf, &mut scratch, Some(state))?;
+                next_rows.fill(y + height);
+                Ok(())
+            },
+            None => self.result.write_remapped_tile_rows_internal(tile, output_buf, &mut scratch, None),
+        }
+    }
+
+    /// For other remapping options, like `freeze`
+    #[inline]
+    #[must_use]
+    pub fn into_result(self) -> QuantizationResult {
+        self.result
+    }
+}
+
+/// Nearest colors in the metric of `Remapper::with_metric`. Only whole images are interlaced, not strips or tiles.
+fn remap_with_metric(result: &mut QuantizationResult, metric: &Metric, image: &mut Image<'_, '_>, output_buf: &mut [MaybeUninit<PalIndex>], whole_image: bool) -> Result<(), liq_error> {
+    let width = image.width();
+    let output_buf = output_buf.get_mut(0..width * image.height()).ok_or(LIQ_BUFFER_TOO_SMALL)?;
+    let output_pixels = if whole_image { result.output_rows(output_buf, width) } else { RowBitmapMut::new_contiguous(output_buf, width) };
+    remap_to_palette_metric(image, output_pixels, result.int_palette().as_slice(), metric)
+}
+
+/// Dithering error at the bottom edges of tiles, carried over to tiles below them. See [`Remapper::remap_tile`].
+#[derive(Debug, Clone)]
+pub struct TileErrors {
+    errors: Vec<f_pixel>,
+    /// Row below the last remapped tile of each column
+    next_rows: Vec<usize>,
+}
+
+impl TileErrors {
+    /// For an image of this width (of the whole image, not a tile)
+    #[must_use]
+    pub fn new(image_width: usize) -> Self {
+        Self {
+            errors: vec![f_pixel::default(); image_width],
+            next_rows: vec![0; image_width],
+        }
+    }
+}
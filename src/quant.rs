@@ -1,94 +1,296 @@
-use crate::attr::{Attributes, ControlFlow};
+use crate::attr::{Attributes, ControlFlow, LogEvent, Refinement, TransparentPlacement};
+use crate::constraint::ConstraintProjection;
+use crate::cvd::ConfusionWeighting;
+use crate::duotone::{duotone_palette, small_palette, SMALL_PALETTE_MAX_COLORS};
 use crate::error::*;
-use crate::ffi::MagicTag;
-use crate::ffi::{LIQ_FREED_MAGIC, LIQ_RESULT_MAGIC};
-use crate::hist::{FixedColorsSet, HistogramInternal};
+#[cfg(feature = "capi")]
+use crate::ffi::{MagicTag, LIQ_FREED_MAGIC, LIQ_RESULT_MAGIC};
+use crate::hist::{FixedColorsSet, HashColor, HistogramInternal};
 use crate::image::Image;
 use crate::kmeans::Kmeans;
+use crate::levels::Levels;
 use crate::mediancut::mediancut;
-use crate::pal::{PalF, PalLen, PalPop, Palette, LIQ_WEIGHT_MSE, MAX_COLORS, MAX_TRANSP_A, RGBA};
-use crate::remap::{mse_to_standard_mse, DitherMapMode, Remapped};
-use crate::seacow::RowBitmapMut;
+use crate::nearest::Nearest;
+use crate::pal::{f_pixel, gamma_lut, AlphaEncoding, PalF, PalIndex, PalLen, PalPop, Palette, LIQ_WEIGHT_MSE, MAX_COLORS, MAX_TRANSP_A, RGBA};
+use crate::remap::{mse_to_standard_mse, DitherKernel, DitherLevel, DitherMapMode, DitherState, DitherStateRef, ProtectedColor, RemapOptions, RemapScratch, Remapped, RowSink};
+use crate::rows::temp_buf;
+use crate::seacow::{gif_interlaced_rows, RowBitmapMut};
 use crate::OrdFloat;
 use arrayvec::ArrayVec;
 use fallible_collections::FallibleVec;
 use std::cmp::Reverse;
 use std::fmt;
 use std::mem::MaybeUninit;
+use std::ops::Range;
 
 pub struct QuantizationResult {
+    #[cfg(feature = "capi")]
     pub(crate) magic_header: MagicTag,
     remapped: Option<Box<Remapped>>,
     pub(crate) palette: PalF,
+    /// Colors that are remapped without dithering
+    pub(crate) protected_colors: Vec<ProtectedColor>,
     progress_callback: Option<Box<dyn Fn(f32) -> ControlFlow + Send + Sync>>,
     pub(crate) int_palette: Palette,
-    pub(crate) dither_level: f32,
+    pub(crate) dither_level: DitherLevel,
     pub(crate) gamma: f64,
+    pub(crate) alpha_encoding: AlphaEncoding,
     pub(crate) palette_error: Option<f64>,
     pub(crate) min_posterization_output: u8,
     pub(crate) use_dither_map: DitherMapMode,
+    output_flags: OutputFlags,
+}
+
+bitflags::bitflags! {
+    /// Remapping options that don't change the palette. Packed, since every byte of the result counts.
+    struct OutputFlags: u8 {
+        /// Output rows are in order of GIF interlacing passes
+        const GIF_INTERLACED = 1;
+        /// Nearest colors are cached by exact input color
+        const REMAP_CACHE = 2;
+        /// Fully transparent pixels don't refine visible palette entries, see `Attributes::set_alpha_weighted_colors`
+        const ALPHA_WEIGHTED = 4;
+    }
 }
 
 impl QuantizationResult {
-    pub(crate) fn new(attr: &Attributes, hist: HistogramInternal, freeze_result_colors: bool, fixed_colors: &FixedColorsSet, gamma: f64) -> Result<Self, liq_error> {
-        if attr.progress(attr.progress_stage1 as f32) { return Err(LIQ_ABORTED); }
+    pub(crate) fn new(attr: &Attributes, mut hist: HistogramInternal, freeze_result_colors: bool, fixed_colors: &FixedColorsSet, gamma: f64, alpha_encoding: AlphaEncoding, levels: Option<Levels>) -> Result<Self, QuantizeError> {
+        if attr.progress(attr.progress_stage1 as f32) { return Err(LIQ_ABORTED.into()); }
         let (max_mse, target_mse, target_mse_is_zero) = attr.target_mse(hist.items.len());
-        let (mut palette, palette_error) = find_best_palette(attr, target_mse, target_mse_is_zero, max_mse, hist, fixed_colors).ok_or(LIQ_VALUE_OUT_OF_RANGE)?;
-        if freeze_result_colors {
+        let (mut palette, palette_error, mut quality_too_low) = match attr.duotone {
+            Some((dark, light)) => {
+                find_duotone_palette(attr, &mut hist, fixed_colors, gamma, dark, light)
+            },
+            None => {
+                let weighting = attr.color_vision_deficiency.map(ConfusionWeighting::new);
+                let constraint = attr.palette_constraint.as_ref().map(|c| ConstraintProjection::new(c, gamma, weighting));
+                match weighting {
+                    Some(weighting) => {
+                        weighting.weigh_histogram(&mut hist);
+                        let weighed_fixed_colors = weighting.weigh_fixed_colors(fixed_colors);
+                        let (mut palette, palette_error, quality_too_low) = find_best_palette(attr, target_mse, target_mse_is_zero, max_mse, hist, &weighed_fixed_colors, constraint.as_ref())?;
+                        weighting.unweigh_palette(&mut palette, fixed_colors);
+                        palette.stages.for_each_palette(|p| weighting.unweigh_palette(p, fixed_colors));
+                        (palette, palette_error, quality_too_low)
+                    },
+                    None => find_best_palette(attr, target_mse, target_mse_is_zero, max_mse, hist, fixed_colors, constraint.as_ref())?,
+                }
+            },
+        };
+        // remapping would adjust the palette to the image, and move it away from the allowed colors
+        if freeze_result_colors || (attr.palette_constraint.is_some() && attr.duotone.is_none()) {
             palette.iter_mut().for_each(|(_, p)| *p = p.to_fixed());
         }
         if attr.progress(attr.progress_stage1 as f32 + attr.progress_stage2 as f32 + attr.progress_stage3 as f32 * 0.95) {
-            return Err(LIQ_ABORTED);
+            return Err(LIQ_ABORTED.into());
         }
         if let (Some(palette_error), Some(max_mse)) = (palette_error, max_mse) {
             if palette_error > max_mse {
-                attr.verbose_print(format!(
-                    "  image degradation MSE={:0.3} (Q={}) exceeded limit of {:0.3} ({})",
-                    mse_to_standard_mse(palette_error),
-                    mse_to_quality(palette_error),
-                    mse_to_standard_mse(max_mse),
-                    mse_to_quality(max_mse)
-                ));
-                return Err(LIQ_QUALITY_TOO_LOW);
+                attr.verbose_event(LogEvent::MseExceeded { mse: mse_to_standard_mse(palette_error), limit: mse_to_standard_mse(max_mse) });
+                quality_too_low = true;
             }
         }
 
+        palette.set_levels(levels);
+        let result = Self::with_palette(attr, palette, palette_error, fixed_colors, gamma, alpha_encoding);
+        if quality_too_low {
+            return Err(QuantizeError::QualityTooLow(Box::new(result)));
+        }
+        Ok(result)
+    }
+
+    /// Result for a palette that has already been chosen
+    pub(crate) fn with_palette(attr: &Attributes, mut palette: PalF, palette_error: Option<f64>, fixed_colors: &FixedColorsSet, gamma: f64, alpha_encoding: AlphaEncoding) -> Self {
         sort_palette(attr, &mut palette);
+        palette.set_alpha_levels(attr.alpha_levels);
+        palette.set_tags(fixed_colors);
 
-        Ok(Self {
+        let protected_colors = fixed_colors.iter()
+            .filter(|&(_, fixed)| fixed.tolerance > 0.)
+            .map(|(HashColor(color), fixed)| ProtectedColor::Fixed(*color, fixed.tolerance))
+            .collect();
+
+        let mut res = Self::from_parts(palette, protected_colors, gamma, alpha_encoding, palette_error, attr.min_posterization(), attr.use_dither_map);
+        res.set_alpha_weighted(attr.alpha_weighted_colors());
+        res
+    }
+
+    /// Copy with the same palette, but without any state from remapping, as if it has just been quantized
+    pub(crate) fn unremapped_copy(&self) -> Self {
+        let mut res = Self::from_parts(self.palette.clone(), self.protected_colors.clone(), self.gamma, self.alpha_encoding, self.palette_error, self.min_posterization_output, self.use_dither_map);
+        res.set_alpha_weighted(self.alpha_weighted());
+        res
+    }
+
+    /// Result that hasn't remapped anything yet, with default remapping settings
+    pub(crate) fn from_parts(palette: PalF, protected_colors: Vec<ProtectedColor>, gamma: f64, alpha_encoding: AlphaEncoding, palette_error: Option<f64>, min_posterization_output: u8, use_dither_map: DitherMapMode) -> Self {
+        Self {
+            #[cfg(feature = "capi")]
             magic_header: LIQ_RESULT_MAGIC,
             palette,
+            protected_colors,
             gamma,
+            alpha_encoding,
             palette_error,
-            min_posterization_output: attr.min_posterization(),
-            use_dither_map: attr.use_dither_map,
+            min_posterization_output,
+            use_dither_map,
             remapped: None,
             progress_callback: None,
+            output_flags: OutputFlags::empty(),
             int_palette: Palette {
                 count: 0,
-                entries: [Default::default(); 256],
+                entries: [Default::default(); MAX_COLORS],
             },
-            dither_level: 0.,
-        })
+            dither_level: DitherLevel::Fixed(0.),
+        }
     }
 
-    pub(crate) fn write_remapped_image_rows_internal(&mut self, image: &mut Image, output_pixels: RowBitmapMut<'_, MaybeUninit<u8>>) -> Result<(), liq_error> {
-        if image.edges.is_none() && image.dither_map.is_none() && self.use_dither_map != DitherMapMode::None {
-            image.contrast_maps()?;
-        }
+    pub(crate) fn write_remapped_image_rows_internal(&mut self, image: &mut Image, output: &mut dyn RowSink, scratch: &mut RemapScratch) -> Result<(), liq_error> {
+        self.remapped = Some(Box::new(self.remap_rows(image, output, &self.remap_options(), true, scratch, None)?));
+        Ok(())
+    }
 
-        self.remapped = Some(Box::new(Remapped::new(self, image, output_pixels)?));
+    /// Remaps a strip or a tile of a larger image. The palette isn't refined, so that all parts of the image get the same palette.
+    pub(crate) fn write_remapped_tile_rows_internal(&mut self, tile: &mut Image, output_buf: &mut [MaybeUninit<PalIndex>], scratch: &mut RemapScratch, dither_state: Option<DitherStateRef<'_>>) -> Result<(), liq_error> {
+        let mut output_pixels = RowBitmapMut::new_contiguous(output_buf, tile.width());
+        self.remapped = Some(Box::new(self.remap_rows(tile, &mut output_pixels, &self.remap_options(), false, scratch, dither_state)?));
         Ok(())
     }
 
+    fn remap_rows(&self, image: &mut Image, output: &mut dyn RowSink, options: &RemapOptions, refine_palette: bool, scratch: &mut RemapScratch, dither_state: Option<DitherStateRef<'_>>) -> Result<Remapped, liq_error> {
+        image.set_levels(self.palette.levels().copied());
+        #[cfg(feature = "dither-maps")]
+        {
+            let progress_stage1 = if self.use_dither_map != DitherMapMode::None { 20. } else { 0. };
+            let progress = |done: f32| self.remap_progress(progress_stage1 * 0.25 * done);
+            if image.maps.edges.is_none() && image.maps.dither_map.is_none() && self.use_dither_map != DitherMapMode::None {
+                image.contrast_maps(&progress)?;
+            }
+            if image.maps.variance_map.is_none() && options.dither_level == DitherLevel::Auto {
+                image.contrast_maps(&progress)?;
+            }
+        }
+
+        Remapped::new(self, image, output, options, refine_palette, scratch, dither_state)
+    }
+
+    /// Settings set with `set_dithering_level`
+    #[inline]
+    fn remap_options(&self) -> RemapOptions {
+        RemapOptions {
+            dither_level: self.dither_level,
+            kernel: DitherKernel::FloydSteinberg,
+            max_mix_distance: None,
+            frame_index: None,
+        }
+    }
+
+    /// Makes the palette final, so that it can be shared between threads and used to remap many images at the same time.
+    ///
+    /// If an image has already been remapped, the palette improved by that remapping is kept.
+    /// The progress callback is removed.
+    #[must_use]
+    pub fn freeze(mut self) -> FrozenRemapper {
+        let lut = gamma_lut(self.gamma);
+        let alpha_lut = self.alpha_encoding.lut(self.gamma);
+        let levels = self.palette.levels().copied();
+        match self.remapped.take() {
+            Some(remapped) => {
+                for (f_color, &color) in self.palette.iter_mut().map(|(c, _)| c).zip(remapped.int_palette.as_slice()) {
+                    let color = levels.map_or(color, |l| l.stretch(color));
+                    *f_color = f_pixel::from_rgba_alpha_lut(&lut, alpha_lut.as_ref(), color);
+                }
+                self.int_palette = remapped.int_palette;
+            },
+            None => {
+                self.int_palette = Remapped::make_int_palette(&mut self.palette, self.gamma, self.alpha_encoding, self.min_posterization_output);
+            },
+        }
+        self.progress_callback = None;
+        FrozenRemapper { result: self }
+    }
+
     /// Set to 1.0 to get nice smooth image
     pub fn set_dithering_level(&mut self, value: f32) -> liq_error {
-        if !(0. ..=1.).contains(&value) {
+        self.set_dither_level(DitherLevel::Fixed(value))
+    }
+
+    /// Like `set_dithering_level`, but also allows [`DitherLevel::Auto`], which adapts dithering strength to each area of the image
+    pub fn set_dither_level(&mut self, level: DitherLevel) -> liq_error {
+        if !level.is_valid() {
             return LIQ_VALUE_OUT_OF_RANGE;
         }
 
         self.remapped = None;
-        self.dither_level = value;
+        self.dither_level = level;
+        LIQ_OK
+    }
+
+    /// Write rows of remapped images in the order of GIF interlacing passes, instead of top to bottom.
+    ///
+    /// Output of `remapped()`, `remap_into()`, `remapped_with()`, and remappers made with `freeze()` can then be given to GIF writers
+    /// for interlaced images as-is, without reordering. Default is `false`.
+    #[inline]
+    pub fn set_gif_interlaced(&mut self, interlaced: bool) {
+        self.output_flags.set(OutputFlags::GIF_INTERLACED, interlaced);
+    }
+
+    /// Whether rows are written in the order of GIF interlacing, see [`set_gif_interlaced`](Self::set_gif_interlaced)
+    #[inline]
+    #[must_use]
+    pub fn gif_interlaced(&self) -> bool {
+        self.output_flags.contains(OutputFlags::GIF_INTERLACED)
+    }
+
+    /// Remember the palette entry found for each exact input color, and reuse it for other pixels of the same color.
+    ///
+    /// This makes remapping without dithering much faster for screenshots, illustrations and other images with few unique colors.
+    /// The cache has a bounded size, and gives up on images that rarely repeat colors, such as photos. Default is `false`.
+    #[inline]
+    pub fn set_remap_cache(&mut self, enabled: bool) {
+        self.output_flags.set(OutputFlags::REMAP_CACHE, enabled);
+    }
+
+    /// Whether nearest colors are cached during remapping, see [`set_remap_cache`](Self::set_remap_cache)
+    #[inline]
+    #[must_use]
+    pub fn remap_cache(&self) -> bool {
+        self.output_flags.contains(OutputFlags::REMAP_CACHE)
+    }
+
+    #[inline]
+    pub(crate) fn set_alpha_weighted(&mut self, enabled: bool) {
+        self.output_flags.set(OutputFlags::ALPHA_WEIGHTED, enabled);
+    }
+
+    /// Whether the palette has been made with `Attributes::set_alpha_weighted_colors`
+    #[inline]
+    pub(crate) fn alpha_weighted(&self) -> bool {
+        self.output_flags.contains(OutputFlags::ALPHA_WEIGHTED)
+    }
+
+    /// Rows of the buffer in the order they're written
+    pub(crate) fn output_rows<'a>(&self, output_buf: &'a mut [MaybeUninit<PalIndex>], width: usize) -> RowBitmapMut<'a, MaybeUninit<PalIndex>> {
+        if self.gif_interlaced() {
+            RowBitmapMut::new_gif_interlaced(output_buf, width)
+        } else {
+            RowBitmapMut::new_contiguous(output_buf, width)
+        }
+    }
+
+    /// Areas of this color won't be dithered, and won't spread dithering error to their neighbors.
+    ///
+    /// Useful for keeping flat areas like text backgrounds or brand colors clean, while the rest of the image is dithered.
+    /// Pixels are matched against the color exactly (with a tiny tolerance), and are remapped to the closest palette entry.
+    /// RGB values of the color are assumed to have the same gamma as the remapped image.
+    pub fn add_undithered_color(&mut self, color: RGBA) -> liq_error {
+        if self.protected_colors.iter().filter(|c| matches!(c, ProtectedColor::Undithered(_))).count() >= MAX_COLORS {
+            return LIQ_UNSUPPORTED;
+        }
+        self.remapped = None;
+        // compared with pixels of the image after stretching
+        let color = self.palette.levels().map_or(color, |l| l.stretch(color));
+        self.protected_colors.push(ProtectedColor::Undithered(color));
         LIQ_OK
     }
 
@@ -104,6 +306,41 @@ impl QuantizationResult {
         LIQ_OK
     }
 
+    /// Palette as it was after a stage of quantization, e.g. to find which stage has caused a regression.
+    ///
+    /// Colors are rounded like the final palette, but they're in the order the stage made them, not sorted.
+    /// `None` if the stage didn't run (e.g. for images with few colors, or with `Refinement::None`).
+    #[cfg(feature = "debug-stages")]
+    #[must_use]
+    pub fn stage_palette(&self, stage: PaletteStage) -> Option<Vec<RGBA>> {
+        let mut palette = self.palette.stages.get(stage)?.clone();
+        palette.set_levels(self.palette.levels().copied());
+        palette.set_alpha_levels(self.palette.alpha_levels());
+        Some(Remapped::make_int_palette(&mut palette, self.gamma, self.alpha_encoding, self.min_posterization_output).as_slice().to_vec())
+    }
+
+    /// Palette index of the fixed color added with the `tag` (see `Image::add_fixed_color_tagged`).
+    ///
+    /// `None` if there's no such color, or it didn't fit in the palette. If several colors have the same tag, the lowest index is returned.
+    #[must_use]
+    pub fn tagged_index(&self, tag: u32) -> Option<PalIndex> {
+        self.palette.tags().iter().filter(|&&(t, _)| t == tag).map(|&(_, idx)| idx).min()
+    }
+
+    /// Tag of the fixed color at the palette index, if it has been added with one
+    #[must_use]
+    pub fn palette_tag(&self, index: PalIndex) -> Option<u32> {
+        self.palette.tags().iter().find(|&&(_, idx)| idx == index).map(|&(tag, _)| tag)
+    }
+
+    /// Black and white points that the image has been stretched from, if `Attributes::set_auto_levels` has been used and the image needed it.
+    ///
+    /// The palette is already mapped back to the original levels. Alpha of these colors is always 255.
+    #[must_use]
+    pub fn auto_levels(&self) -> Option<(RGBA, RGBA)> {
+        self.palette.levels().map(Levels::black_and_white_points)
+    }
+
     /// Approximate gamma correction value used for the output
     ///
     /// Colors are converted from input gamma to this gamma
@@ -137,15 +374,38 @@ impl QuantizationResult {
             .map(mse_to_quality)
     }
 
+    /// Index of the palette entry used for transparent pixels, e.g. for GIF's transparent color. Available after remapping.
+    ///
+    /// If the image has a background (see `Image::set_background`), it's the entry used for pixels that are left to show the background.
+    /// Otherwise it's the first fully transparent entry, if the palette has one.
+    #[must_use]
+    pub fn transparent_index(&self) -> Option<PalIndex> {
+        self.remapped.as_ref().and_then(|re| re.transparent_index)
+    }
+
     /// Final palette, copied.
     ///
     /// It's slighly better if you get palette from the `remapped()` call instead
-    #[must_use]
-    pub fn palette_vec(&mut self) -> Vec<RGBA> {
+    pub fn palette_vec(&mut self) -> Result<Vec<RGBA>, liq_error> {
         let pal = self.palette();
-        let mut out: Vec<RGBA> = FallibleVec::try_with_capacity(pal.len()).unwrap();
+        let mut out: Vec<RGBA> = FallibleVec::try_with_capacity(pal.len()).map_err(|_| LIQ_OUT_OF_MEMORY)?;
         out.extend_from_slice(pal);
-        out
+        Ok(out)
+    }
+
+    /// Final palette in linear light, e.g. for GPU pipelines that upload palettes as linear (not sRGB) textures.
+    ///
+    /// These are the same colors as `palette()`, decoded with the output gamma (see `set_output_gamma`), with channels in 0-1 range.
+    /// Alpha is linear opacity, and isn't premultiplied.
+    pub fn palette_linear(&mut self) -> Vec<rgb::RGBA<f32>> {
+        let gamma = self.gamma;
+        let alpha_lut = self.alpha_encoding.lut(gamma);
+        let decode = (1. / gamma) as f32;
+        self.palette().iter().map(|c| {
+            let ch = |v: u8| (f32::from(v) / 255.).powf(decode);
+            let a = alpha_lut.as_ref().map_or(f32::from(c.a) / 255., |lut| lut[c.a as usize]);
+            rgb::RGBA::new(ch(c.r), ch(c.g), ch(c.b), a)
+        }).collect()
     }
 
     /// Final palette
@@ -164,7 +424,7 @@ impl QuantizationResult {
             }
             None => {
                 if self.int_palette.count == 0 {
-                    self.int_palette = Remapped::make_int_palette(&mut self.palette, self.gamma, self.min_posterization_output);
+                    self.int_palette = Remapped::make_int_palette(&mut self.palette, self.gamma, self.alpha_encoding, self.min_posterization_output);
                 }
                 &self.int_palette
             },
@@ -188,18 +448,44 @@ impl QuantizationResult {
     /// Remap image into a palette + indices.
     ///
     /// Returns the palette and a 1-byte-per-pixel uncompressed bitmap
-    pub fn remapped(&mut self, image: &mut Image<'_, '_>) -> Result<(Vec<RGBA>, Vec<u8>), liq_error> {
+    pub fn remapped(&mut self, image: &mut Image<'_, '_>) -> Result<(Vec<RGBA>, Vec<PalIndex>), liq_error> {
         let len = image.width() * image.height();
         // Capacity is essential here, as it creates uninitialized buffer
         unsafe {
-            let mut buf: Vec<u8> = FallibleVec::try_with_capacity(len).map_err(|_| LIQ_OUT_OF_MEMORY)?;
-            let uninit_slice = std::slice::from_raw_parts_mut(buf.as_mut_ptr().cast::<MaybeUninit<u8>>(), buf.capacity());
+            let mut buf: Vec<PalIndex> = FallibleVec::try_with_capacity(len).map_err(|_| LIQ_OUT_OF_MEMORY)?;
+            let uninit_slice = std::slice::from_raw_parts_mut(buf.as_mut_ptr().cast::<MaybeUninit<PalIndex>>(), buf.capacity());
             self.remap_into(image, uninit_slice)?;
             buf.set_len(uninit_slice.len());
-            Ok((self.palette_vec(), buf))
+            Ok((self.palette_vec()?, buf))
         }
     }
 
+    /// Like `remapped()`, but returns everything needed to encode the image together, including its dimensions
+    pub fn remapped_image(&mut self, image: &mut Image<'_, '_>) -> Result<RemappedImage, liq_error> {
+        let (palette, indices) = self.remapped(image)?;
+        let transparent_index = self.transparent_index();
+        Ok(RemappedImage {
+            width: image.width(),
+            height: image.height(),
+            palette,
+            indices,
+            transparent_index,
+            error: self.remapping_error(),
+        })
+    }
+
+    /// Like `remapped()`, but returns runs of consecutive pixels remapped to the same palette entry, instead of every pixel.
+    ///
+    /// Runs are in the order of the output rows (GIF interlacing order if [`set_gif_interlaced`](Self::set_gif_interlaced) was set),
+    /// ready for RLE or LZW encoders that work on runs. If `per_row` is `true`, runs end at the end of every row,
+    /// otherwise a run can continue at the start of the next output row.
+    pub fn remapped_runs(&mut self, image: &mut Image<'_, '_>, per_row: bool) -> Result<(Vec<RGBA>, Vec<IndexRun>), liq_error> {
+        let mut runs = RunsSink::new(image.width(), image.height());
+        self.write_remapped_image_rows_internal(image, &mut runs, &mut RemapScratch::new())?;
+        let runs = runs.into_runs(self.gif_interlaced(), per_row);
+        Ok((self.palette_vec()?, runs))
+    }
+
     /// Remap image into an existing buffer.
     ///
     /// This is a low-level call for use when existing memory has to be reused. Use `remapped()` if possible.
@@ -209,44 +495,369 @@ impl QuantizationResult {
     /// You should call `palette()` or `palette_ref()` _after_ this call, but not before it,
     /// because remapping changes the palette.
     #[inline]
-    pub fn remap_into(&mut self, image: &mut Image<'_, '_>, output_buf: &mut [MaybeUninit<u8>]) -> Result<(), liq_error> {
+    pub fn remap_into(&mut self, image: &mut Image<'_, '_>, output_buf: &mut [MaybeUninit<PalIndex>]) -> Result<(), liq_error> {
+        self.remap_into_with_scratch(image, output_buf, &mut RemapScratch::new())
+    }
+
+    /// Like `remap_into()`, but uses buffers from `scratch` instead of allocating new ones.
+    ///
+    /// Reuse the same `RemapScratch` for a series of images, such as frames of a video.
+    pub fn remap_into_with_scratch(&mut self, image: &mut Image<'_, '_>, output_buf: &mut [MaybeUninit<PalIndex>], scratch: &mut RemapScratch) -> Result<(), liq_error> {
+        let required_size = (image.width()) * (image.height());
+        let output_buf = output_buf.get_mut(0..required_size).ok_or(LIQ_BUFFER_TOO_SMALL)?;
+
+        let mut rows = self.output_rows(output_buf, image.width());
+        self.write_remapped_image_rows_internal(image, &mut rows, scratch)
+    }
+
+    /// Remaps the image and writes colors of the palette instead of indices, e.g. for previews, or for processing that expects RGBA pixels.
+    ///
+    /// The buffer must have at least `width * height` pixels. Rows are always in order, even if the result is set to GIF interlacing.
+    pub fn remap_to_rgba_into(&mut self, image: &mut Image<'_, '_>, output_buf: &mut [RGBA]) -> Result<(), liq_error> {
+        let width = image.width();
+        let required_size = width * image.height();
+        let output_buf = output_buf.get_mut(0..required_size).ok_or(LIQ_BUFFER_TOO_SMALL)?;
+        self.write_remapped_image_rows_internal(image, &mut RgbaSink::new(output_buf, width), &mut RemapScratch::new())
+    }
+
+    /// Remaps one horizontal strip of an image that is fed in strips from top to bottom, e.g. by a streaming decoder.
+    ///
+    /// All strips must have the same width, otherwise it fails with `LIQ_VALUE_OUT_OF_RANGE`. `state` carries dithering error
+    /// from the bottom of the previous strip, so there are no seams between the strips. Use a new [`DitherState`] for every image.
+    /// The palette isn't adjusted to the strips, so every strip is remapped to the same colors.
+    /// Edges that limit dithering (at low speed settings) are found within each strip, so pixels at the ends of strips may differ
+    /// slightly from remapping of the whole image.
+    pub fn remap_strip_into(&mut self, strip: &mut Image<'_, '_>, output_buf: &mut [MaybeUninit<PalIndex>], state: &mut DitherState) -> Result<(), liq_error> {
+        let required_size = (strip.width()) * (strip.height());
+        let output_buf = output_buf.get_mut(0..required_size).ok_or(LIQ_BUFFER_TOO_SMALL)?;
+        let state = state.for_width(strip.width())?;
+        self.write_remapped_tile_rows_internal(strip, output_buf, &mut RemapScratch::new(), Some(state))
+    }
+
+    /// Like `remapped()`, but uses the given dithering settings instead of the ones set on this object.
+    ///
+    /// It doesn't modify the result, so one result can be used to remap multiple images with different settings at the same time.
+    /// The palette is refined for each remapped image, so each call returns its own palette.
+    pub fn remapped_with(&self, image: &mut Image<'_, '_>, options: &RemapOptions) -> Result<(Vec<RGBA>, Vec<PalIndex>), liq_error> {
+        let len = image.width() * image.height();
+        // Capacity is essential here, as it creates uninitialized buffer
+        unsafe {
+            let mut buf: Vec<PalIndex> = FallibleVec::try_with_capacity(len).map_err(|_| LIQ_OUT_OF_MEMORY)?;
+            let uninit_slice = std::slice::from_raw_parts_mut(buf.as_mut_ptr().cast::<MaybeUninit<PalIndex>>(), buf.capacity());
+            let palette = self.remap_into_with(image, uninit_slice, options)?;
+            buf.set_len(uninit_slice.len());
+            Ok((palette, buf))
+        }
+    }
+
+    /// Like `remap_into()`, but uses the given dithering settings instead of the ones set on this object.
+    ///
+    /// Returns the palette for the remapped pixels.
+    pub fn remap_into_with(&self, image: &mut Image<'_, '_>, output_buf: &mut [MaybeUninit<PalIndex>], options: &RemapOptions) -> Result<Vec<RGBA>, liq_error> {
+        if !options.is_valid() {
+            return Err(LIQ_VALUE_OUT_OF_RANGE);
+        }
+        let required_size = (image.width()) * (image.height());
+        let output_buf = output_buf.get_mut(0..required_size).ok_or(LIQ_BUFFER_TOO_SMALL)?;
+
+        let mut rows = self.output_rows(output_buf, image.width());
+        let remapped = self.remap_rows(image, &mut rows, options, true, &mut RemapScratch::new(), None)?;
+        Ok(remapped.int_palette.as_slice().to_vec())
+    }
+
+    /// False-color image of how far each remapped pixel is from its original color, for finding where the quantization is the worst.
+    ///
+    /// `indices` are from remapping `image` with this result. Pixels are blue where there's no error, and go through cyan,
+    /// green and yellow to red where the error is `max_error` or more (in the same units as `remapping_error()`, e.g. 50).
+    ///
+    /// Returns `width * height` opaque pixels, which can be saved as an image as-is.
+    pub fn error_heatmap(&mut self, image: &Image<'_, '_>, indices: &[PalIndex], max_error: f64) -> Result<Vec<RGBA>, liq_error> {
+        let width = image.width();
+        let indices = indices.get(..width * image.height()).ok_or(LIQ_BUFFER_TOO_SMALL)?;
+        if max_error.is_nan() || max_error <= 0. {
+            return Err(LIQ_VALUE_OUT_OF_RANGE);
+        }
+        // palette may have been converted to a different output gamma
+        let palette_lut = gamma_lut(self.gamma);
+        let palette: Vec<_> = self.palette().iter().map(|&c| f_pixel::from_rgba(&palette_lut, c)).collect();
+        let lut = gamma_lut(image.gamma());
+
+        let rows = image.px.rgba_rows_iter()?;
+        let mut temp_row = temp_buf(width);
+        let mut heatmap: Vec<RGBA> = FallibleVec::try_with_capacity(indices.len()).map_err(|_| LIQ_OUT_OF_MEMORY)?;
+        for (row, row_indices) in indices.chunks_exact(width).enumerate() {
+            for (&px, &idx) in rows.row_rgba(&mut temp_row, row).iter().zip(row_indices) {
+                let remapped = palette.get(idx as usize).ok_or(LIQ_VALUE_OUT_OF_RANGE)?;
+                let error = mse_to_standard_mse(f_pixel::from_rgba(&lut, px).diff(remapped).into());
+                heatmap.push(heat_color((error / max_error) as f32));
+            }
+        }
+        Ok(heatmap)
+    }
+}
+
+/// Blue → cyan → green → yellow → red for 0 → 1
+fn heat_color(t: f32) -> RGBA {
+    let t = t.clamp(0., 1.) * 4.;
+    let ramp = |t: f32| (t.clamp(0., 1.) * 255.).round() as u8;
+    RGBA::new(ramp(t - 2.), ramp(t).min(ramp(4. - t)), ramp(2. - t), 255)
+}
+
+/// Pixels remapped to the same palette entry, one after another, see [`QuantizationResult::remapped_runs`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct IndexRun {
+    /// Position of the first pixel of the run in the image
+    pub x: usize,
+    pub y: usize,
+    /// Number of pixels, which may go past the end of the row (unless runs were made per row)
+    pub len: usize,
+    pub index: PalIndex,
+}
+
+/// Pixels in a band of rows remapped at a time by the sinks below, so that they don't need indices of the whole image
+const SINK_BAND_PIXELS: usize = 1 << 16;
+
+/// Rows of indices of one band, until they're converted
+fn band_buffer(buf: &mut Vec<MaybeUninit<PalIndex>>, rows: Range<usize>, width: usize) -> Vec<&mut [MaybeUninit<PalIndex>]> {
+    buf.clear();
+    buf.resize(rows.len() * width, MaybeUninit::uninit());
+    buf.chunks_exact_mut(width).collect()
+}
+
+/// Band of indices that have been written, row by row
+fn band_indices(buf: &[MaybeUninit<PalIndex>], width: usize) -> impl Iterator<Item = &[PalIndex]> {
+    // every row of the band has been remapped
+    buf.chunks_exact(width).map(|row| unsafe { std::slice::from_raw_parts(row.as_ptr().cast::<PalIndex>(), row.len()) })
+}
+
+/// Writes colors of the palette, see [`QuantizationResult::remap_to_rgba_into`]
+struct RgbaSink<'a> {
+    output: &'a mut [RGBA],
+    width: usize,
+    palette: Vec<RGBA>,
+    indices: Vec<MaybeUninit<PalIndex>>,
+}
+
+impl<'a> RgbaSink<'a> {
+    fn new(output: &'a mut [RGBA], width: usize) -> Self {
+        Self { output, width, palette: Vec::new(), indices: Vec::new() }
+    }
+}
+
+impl RowSink for RgbaSink<'_> {
+    fn band_height(&self) -> usize {
+        SINK_BAND_PIXELS / self.width.max(1)
+    }
+
+    fn set_palette(&mut self, palette: &Palette) {
+        self.palette = palette.as_slice().to_vec();
+    }
+
+    fn band_mut(&mut self, rows: Range<usize>) -> Vec<&mut [MaybeUninit<PalIndex>]> {
+        band_buffer(&mut self.indices, rows, self.width)
+    }
+
+    fn band_done(&mut self, rows: Range<usize>) {
+        let output = self.output[rows.start * self.width..rows.end * self.width].chunks_exact_mut(self.width);
+        for (out, indices) in output.zip(band_indices(&self.indices, self.width)) {
+            for (out, &idx) in out.iter_mut().zip(indices) {
+                *out = self.palette[idx as usize];
+            }
+        }
+    }
+}
+
+/// Collects runs of indices, see [`QuantizationResult::remapped_runs`]
+struct RunsSink {
+    width: usize,
+    /// Runs of every row, in order of the image. Runs end at the end of every row.
+    runs: Vec<IndexRun>,
+    /// Index in `runs` of the first run of every row
+    row_starts: Vec<usize>,
+    indices: Vec<MaybeUninit<PalIndex>>,
+}
+
+impl RunsSink {
+    fn new(width: usize, height: usize) -> Self {
+        Self { width, runs: Vec::new(), row_starts: Vec::with_capacity(height), indices: Vec::new() }
+    }
+
+    /// Runs in the order of output rows. Unless `per_row` is set, runs continue at the start of the next row.
+    fn into_runs(self, gif_interlaced: bool, per_row: bool) -> Vec<IndexRun> {
+        let height = self.row_starts.len();
+        let row_runs = |y: usize| &self.runs[self.row_starts[y]..self.row_starts.get(y + 1).copied().unwrap_or(self.runs.len())];
+        let row_order: Box<dyn Iterator<Item = usize>> = if gif_interlaced { Box::new(gif_interlaced_rows(height)) } else { Box::new(0..height) };
+
+        let mut runs: Vec<IndexRun> = Vec::with_capacity(self.runs.len());
+        for y in row_order {
+            let mut row = row_runs(y);
+            if let (Some(last), Some(first), false) = (runs.last_mut(), row.first(), per_row) {
+                if last.index == first.index {
+                    last.len += first.len;
+                    row = &row[1..];
+                }
+            }
+            runs.extend_from_slice(row);
+        }
+        runs
+    }
+}
+
+impl RowSink for RunsSink {
+    fn band_height(&self) -> usize {
+        SINK_BAND_PIXELS / self.width.max(1)
+    }
+
+    fn band_mut(&mut self, rows: Range<usize>) -> Vec<&mut [MaybeUninit<PalIndex>]> {
+        band_buffer(&mut self.indices, rows, self.width)
+    }
+
+    fn band_done(&mut self, rows: Range<usize>) {
+        for (y, indices) in rows.zip(band_indices(&self.indices, self.width)) {
+            self.row_starts.push(self.runs.len());
+            for (x, &index) in indices.iter().enumerate() {
+                match self.runs.last_mut() {
+                    Some(run) if run.index == index && x > 0 => run.len += 1,
+                    _ => self.runs.push(IndexRun { x, y, len: 1, index }),
+                }
+            }
+        }
+    }
+}
+
+/// Remapped image with its palette, see [`QuantizationResult::remapped_image`]
+#[derive(Debug, Clone)]
+pub struct RemappedImage {
+    /// Width of the image in pixels
+    pub width: usize,
+    /// Height of the image in pixels
+    pub height: usize,
+    /// Colors of the palette
+    pub palette: Vec<RGBA>,
+    /// Palette index of every pixel, `width * height` of them, row by row (in GIF interlacing order if [`QuantizationResult::set_gif_interlaced`] was set)
+    pub indices: Vec<PalIndex>,
+    /// Index of a fully transparent palette entry (for PNG's `tRNS` or GIF's transparent color), if the palette has one
+    pub transparent_index: Option<PalIndex>,
+    /// Mean square error of the remapped image, like [`QuantizationResult::remapping_error`]
+    pub error: Option<f64>,
+}
+
+/// Palette and remapping settings that can't change any more. Created with [`QuantizationResult::freeze`].
+///
+/// Unlike `QuantizationResult`, it can remap images through a shared reference, so it can be used from multiple threads at once.
+pub struct FrozenRemapper {
+    result: QuantizationResult,
+}
+
+impl FrozenRemapper {
+    /// Final palette. It's the same for every remapped image.
+    #[inline]
+    #[must_use]
+    pub fn palette(&self) -> &[RGBA] {
+        self.result.int_palette.as_slice()
+    }
+
+    /// Remap image into 1-byte-per-pixel indices of the palette
+    pub fn remapped(&self, image: &mut Image<'_, '_>) -> Result<Vec<PalIndex>, liq_error> {
+        let len = image.width() * image.height();
+        // Capacity is essential here, as it creates uninitialized buffer
+        unsafe {
+            let mut buf: Vec<PalIndex> = FallibleVec::try_with_capacity(len).map_err(|_| LIQ_OUT_OF_MEMORY)?;
+            let uninit_slice = std::slice::from_raw_parts_mut(buf.as_mut_ptr().cast::<MaybeUninit<PalIndex>>(), buf.capacity());
+            self.remap_into(image, uninit_slice)?;
+            buf.set_len(uninit_slice.len());
+            Ok(buf)
+        }
+    }
+
+    /// Remap image into an existing buffer. See [`QuantizationResult::remap_into`].
+    #[inline]
+    pub fn remap_into(&self, image: &mut Image<'_, '_>, output_buf: &mut [MaybeUninit<PalIndex>]) -> Result<(), liq_error> {
+        self.remap_into_with_scratch(image, output_buf, &mut RemapScratch::new())
+    }
+
+    /// Like `remap_into()`, but uses buffers from `scratch` instead of allocating new ones.
+    ///
+    /// Each thread remapping at the same time needs its own `RemapScratch`.
+    pub fn remap_into_with_scratch(&self, image: &mut Image<'_, '_>, output_buf: &mut [MaybeUninit<PalIndex>], scratch: &mut RemapScratch) -> Result<(), liq_error> {
         let required_size = (image.width()) * (image.height());
         let output_buf = output_buf.get_mut(0..required_size).ok_or(LIQ_BUFFER_TOO_SMALL)?;
 
-        let rows = RowBitmapMut::new_contiguous(output_buf, image.width());
-        self.write_remapped_image_rows_internal(image, rows)
+        let mut rows = self.result.output_rows(output_buf, image.width());
+        self.result.remap_rows(image, &mut rows, &self.result.remap_options(), false, scratch, None)?;
+        Ok(())
+    }
+}
+
+impl fmt::Debug for FrozenRemapper {
+    #[cold]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "FrozenRemapper({} colors)", self.palette().len())
     }
 }
 
 fn sort_palette(attr: &Attributes, palette: &mut PalF) {
-    let last_index_transparent = attr.last_index_transparent;
+    let placement = attr.transparent_placement;
 
     let mut tmp: ArrayVec<_, {MAX_COLORS}> = palette.iter_mut().map(|(c,p)| (*c, *p)).collect();
     tmp.sort_by_key(|(color, pop)| {
         let is_transparent = color.a <= MAX_TRANSP_A;
-        (is_transparent == last_index_transparent, Reverse(OrdFloat::<f32>::unchecked_new(pop.popularity())))
+        let after_others = match placement {
+            TransparentPlacement::First => !is_transparent,
+            TransparentPlacement::Last => is_transparent,
+            TransparentPlacement::Keep => false,
+        };
+        (after_others, Reverse(OrdFloat::<f32>::unchecked_new(pop.popularity())))
     });
     palette.iter_mut().zip(tmp).for_each(|((dcol, dpop), (scol, spop))| {
         *dcol = scol;
         *dpop = spop;
     });
 
-    if last_index_transparent {
-        let alpha_index = palette.as_slice().iter().enumerate()
-            .filter(|(_, c)| c.a <= MAX_TRANSP_A)
-            .min_by_key(|(_, c)| OrdFloat::<f32>::unchecked_new(c.a))
-            .map(|(i, _)| i);
-        if let Some(alpha_index) = alpha_index {
-            let last_index = palette.as_slice().len() - 1;
-            palette.swap(last_index, alpha_index);
-        }
-    } else {
-        let num_transparent = palette.as_slice().iter().enumerate()
-            .filter(|(_, c)| c.a <= MAX_TRANSP_A)
-            .map(|(i, _)| i + 1) // num entries, not index
-            .max();
-        if let Some(num_transparent) = num_transparent {
-            attr.verbose_print(format!("  eliminated opaque tRNS-chunk entries...{} entr{} transparent", num_transparent, if num_transparent == 1 { "y" } else { "ies" }));
+    match placement {
+        TransparentPlacement::Last => {
+            let alpha_index = palette.as_slice().iter().enumerate()
+                .filter(|(_, c)| c.a <= MAX_TRANSP_A)
+                .min_by_key(|(_, c)| OrdFloat::<f32>::unchecked_new(c.a))
+                .map(|(i, _)| i);
+            if let Some(alpha_index) = alpha_index {
+                let last_index = palette.as_slice().len() - 1;
+                palette.swap(last_index, alpha_index);
+            }
+        },
+        TransparentPlacement::First => {
+            let num_transparent = palette.as_slice().iter().enumerate()
+                .filter(|(_, c)| c.a <= MAX_TRANSP_A)
+                .map(|(i, _)| i + 1) // num entries, not index
+                .max();
+            if let Some(num_transparent) = num_transparent {
+                attr.verbose_event(LogEvent::TrnsEliminated { count: num_transparent });
+            }
+        },
+        TransparentPlacement::Keep => {},
+    }
+}
+
+/// Makes a copy of the palette and remapping settings, so that it can be used independently, e.g. in another thread.
+///
+/// The progress callback is not copied.
+impl Clone for QuantizationResult {
+    fn clone(&self) -> Self {
+        Self {
+            #[cfg(feature = "capi")]
+            magic_header: self.magic_header,
+            remapped: self.remapped.clone(),
+            palette: self.palette.clone(),
+            protected_colors: self.protected_colors.clone(),
+            progress_callback: None,
+            int_palette: self.int_palette.clone(),
+            dither_level: self.dither_level,
+            gamma: self.gamma,
+            alpha_encoding: self.alpha_encoding,
+            palette_error: self.palette_error,
+            min_posterization_output: self.min_posterization_output,
+            use_dither_map: self.use_dither_map,
+            output_flags: self.output_flags,
         }
     }
 }
@@ -258,40 +869,96 @@ impl fmt::Debug for QuantizationResult {
     }
 }
 
+/// Stage of the palette search, see [`QuantizationResult::stage_palette`]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PaletteStage {
+    /// The first palette made by median cut
+    MedianCut,
+    /// The best palette of the feedback loop (median cut repeated with adjusted weights), before refinement
+    FeedbackLoop,
+    /// After the final K-means iterations
+    Kmeans,
+}
+
+/// Copies of the palette after stages of `find_best_palette`. It's always empty without the `debug-stages` feature.
+#[derive(Clone, Default)]
+pub(crate) struct StageLog {
+    #[cfg(feature = "debug-stages")]
+    palettes: Vec<(PaletteStage, PalF)>,
+}
+
+impl StageLog {
+    #[inline(always)]
+    #[allow(unused_variables)]
+    fn record(&mut self, stage: PaletteStage, palette: &PalF) {
+        #[cfg(feature = "debug-stages")]
+        self.palettes.push((stage, palette.clone()));
+    }
+
+    #[inline(always)]
+    #[allow(unused_variables, unused_mut)]
+    fn for_each_palette(&mut self, mut f: impl FnMut(&mut PalF)) {
+        #[cfg(feature = "debug-stages")]
+        self.palettes.iter_mut().for_each(|(_, palette)| f(palette));
+    }
+
+    #[cfg(feature = "debug-stages")]
+    fn get(&self, stage: PaletteStage) -> Option<&PalF> {
+        self.palettes.iter().find(|(s, _)| *s == stage).map(|(_, palette)| palette)
+    }
+}
+
 /// Repeats mediancut with different histogram weights to find palette with minimum error.
 ///
 ///  feedback_loop_trials controls how long the search will take. < 0 skips the iteration.
+///
+/// The last value is true if the palette has been found, but some pixels are worse than `max_pixel_error` allows.
 #[allow(clippy::or_fun_call)]
-pub(crate) fn find_best_palette(attr: &Attributes, target_mse: f64, target_mse_is_zero: bool, max_mse: Option<f64>, mut hist: HistogramInternal, fixed_colors: &FixedColorsSet) -> Option<(PalF, Option<f64>)> {
+pub(crate) fn find_best_palette(attr: &Attributes, target_mse: f64, target_mse_is_zero: bool, max_mse: Option<f64>, mut hist: HistogramInternal, fixed_colors: &FixedColorsSet, constraint: Option<&ConstraintProjection>) -> Result<(PalF, Option<f64>, bool), liq_error> {
     let few_input_colors = hist.items.len() + fixed_colors.len() <= attr.max_colors as usize;
     // actual target_mse passed to this method has extra diff from posterization
-    if few_input_colors && target_mse_is_zero {
-        return Some(palette_from_histogram(&hist, attr.max_colors, fixed_colors))
+    if few_input_colors && target_mse_is_zero && constraint.is_none() {
+        let (mut palette, palette_error) = palette_from_histogram(&hist, attr.max_colors, fixed_colors);
+        if attr.keep_exact_colors() {
+            freeze_exact_colors(&mut palette, &hist);
+        }
+        return Ok((palette, palette_error, false));
     }
 
     let mut max_colors = attr.max_colors;
-    let total_trials = attr.feedback_loop_trials(hist.items.len()) as i16;
+    let total_trials = if attr.refinement == Refinement::Full { attr.feedback_loop_trials(hist.items.len()) as i16 } else { 0 };
     let mut trials_left = total_trials;
     let mut best_palette = None;
-    let mut target_mse_overshoot = if total_trials > 0 { 1.05 } else { 1. };
+    let mut target_mse_overshoot = if total_trials > 0 { attr.overshoot_strategy.initial() } else { 1. };
     let mut fails_in_a_row = 0;
     let mut palette_error = None;
+    let mut stages = StageLog::default();
     let mut palette = loop {
         let max_mse_per_color = target_mse.max(palette_error.unwrap_or(quality_to_mse(1))).max(quality_to_mse(51)) * 1.2;
-        let mut new_palette = mediancut(&mut hist, max_colors - fixed_colors.len() as PalLen, target_mse * target_mse_overshoot, max_mse_per_color)
+        let mut new_palette = mediancut(&mut hist, max_colors.saturating_sub(fixed_colors.len() as PalLen), target_mse * target_mse_overshoot, max_mse_per_color, attr.alpha_levels != 0)
             .with_fixed_colors(max_colors, fixed_colors);
+        if let Some(constraint) = constraint {
+            constraint.project_palette(&mut new_palette);
+        }
+        if attr.keep_exact_colors() {
+            freeze_exact_colors(&mut new_palette, &hist);
+        }
+        if trials_left == total_trials && best_palette.is_none() {
+            stages.record(PaletteStage::MedianCut, &new_palette);
+        }
 
         let stage_done = 1. - (trials_left.max(0) as f32 / (total_trials + 1) as f32).powi(2);
         let overall_done = attr.progress_stage1 as f32 + stage_done * attr.progress_stage2 as f32;
-        attr.verbose_print(format!("  selecting colors...{}%", (100. * stage_done) as u8));
+        attr.verbose_event(LogEvent::ColorsSelected { percent: (100. * stage_done) as u8 });
 
         if trials_left <= 0 { break Some(new_palette); }
 
         let first_run_of_target_mse = best_palette.is_none() && target_mse > 0.;
-        let total_error = Kmeans::iteration(&mut hist, &mut new_palette, !first_run_of_target_mse);
+        let total_error = constrained_kmeans_iteration(&mut hist, &mut new_palette, !first_run_of_target_mse, constraint);
         if best_palette.is_none() || total_error < palette_error.unwrap_or(f64::MAX) || (total_error <= target_mse && new_palette.len() < max_colors as usize) {
             if total_error < target_mse && total_error > 0. {
-                target_mse_overshoot = if (target_mse_overshoot * 1.25) < (target_mse / total_error) {target_mse_overshoot * 1.25 } else {target_mse / total_error }; // if number of colors could be reduced, try to keep it that way
+                let growth = attr.overshoot_strategy.growth();
+                target_mse_overshoot = if (target_mse_overshoot * growth) < (target_mse / total_error) {target_mse_overshoot * growth } else {target_mse / total_error }; // if number of colors could be reduced, try to keep it that way
             }
             palette_error = Some(total_error);
             max_colors = max_colors.min(new_palette.len() as PalLen + 1);
@@ -306,16 +973,132 @@ pub(crate) fn find_best_palette(attr: &Attributes, target_mse: f64, target_mse_i
         if attr.progress(overall_done) || trials_left <= 0 {
             break best_palette;
         }
-    }?;
+    }.ok_or(LIQ_VALUE_OUT_OF_RANGE)?;
+
+    if max_colors <= SMALL_PALETTE_MAX_COLORS && fixed_colors.is_empty() && attr.refinement != Refinement::None {
+        let mut small = small_palette(&hist, max_colors);
+        if let Some(constraint) = constraint {
+            constraint.project_palette(&mut small);
+        }
+        if attr.keep_exact_colors() {
+            freeze_exact_colors(&mut small, &hist);
+        }
+        let small_error = constrained_kmeans_iteration(&mut hist, &mut small, false, constraint);
+        let error = palette_error.unwrap_or_else(|| constrained_kmeans_iteration(&mut hist, &mut palette, false, constraint));
+        if small_error < error {
+            attr.verbose_print("  using palette split by brightness");
+            palette = small;
+            palette_error = Some(small_error);
+        } else {
+            palette_error = Some(error);
+        }
+    }
+
+    if attr.keep_exact_colors() {
+        // K-means of the trials could have moved other entries onto colors of the image
+        freeze_exact_colors(&mut palette, &hist);
+    }
+    stages.record(PaletteStage::FeedbackLoop, &palette);
+    if attr.refinement == Refinement::None {
+        if palette_error.is_none() && max_mse.is_some() {
+            // measured on a copy, so that the palette stays as median cut has made it
+            palette_error = Some(Kmeans::iteration(&mut hist, &mut palette.clone(), false));
+        }
+    } else {
+        refine_palette(&mut palette, attr, &mut hist, max_mse, &mut palette_error, constraint);
+        if attr.min_entry_population > 0. {
+            remove_unpopular_entries(&mut palette, attr, &mut hist, &mut palette_error, constraint);
+        }
+        if attr.preserve_rare_colors() {
+            preserve_rare_colors(&mut palette, attr, &mut hist, &mut palette_error, constraint);
+        }
+        stages.record(PaletteStage::Kmeans, &palette);
+    }
+    palette.stages = stages;
+
+    if attr.exact_final_iteration() && attr.refinement != Refinement::None {
+        Kmeans::exact_iteration(&mut hist, &mut palette);
+        if let Some(constraint) = constraint {
+            constraint.project_palette(&mut palette);
+        }
+        // the iteration has moved the palette, so the error is measured again, on a copy, for the colors that will be used
+        palette_error = Some(Kmeans::exact_iteration(&mut hist, &mut palette.clone()));
+    }
+
+    if let Some(max_pixel_error) = attr.max_pixel_error {
+        let worst_error = refine_palette_minimax(&mut palette, attr, &mut hist, max_pixel_error, &mut palette_error, constraint);
+        if worst_error > max_pixel_error {
+            attr.verbose_print(format!(
+                "  worst pixel error MSE={:0.3} exceeded limit of {:0.3}",
+                mse_to_standard_mse(worst_error),
+                mse_to_standard_mse(max_pixel_error)
+            ));
+            return Ok((palette, palette_error, true));
+        }
+    }
+
+    Ok((palette, palette_error, false))
+}
 
-    refine_palette(&mut palette, attr, &mut hist, max_mse, &mut palette_error);
 
-    Some((palette, palette_error))
+/// Marks palette entries that are a color of the histogram as fixed, so that K-means doesn't move them (see `Attributes::set_keep_exact_colors`)
+fn freeze_exact_colors(palette: &mut PalF, hist: &HistogramInternal) {
+    /// Averages of boxes of a single color may be off by rounding
+    const TOLERANCE: f32 = 1. / 256. / 256. / 16.;
+
+    let mut exact = vec![None; palette.len()];
+    let n = Nearest::new(palette);
+    for item in hist.items.iter() {
+        let (idx, diff) = n.search(&item.color, 0);
+        if diff <= TOLERANCE {
+            exact[idx as usize] = Some(item.color);
+        }
+    }
+    drop(n);
+    for ((color, pop), exact) in palette.iter_mut().zip(exact) {
+        if let Some(exact) = exact {
+            if !pop.is_fixed() {
+                *color = exact;
+                *pop = pop.to_fixed();
+            }
+        }
+    }
 }
 
+/// K-means iteration that keeps the palette within `Attributes::set_palette_constraint`
+fn constrained_kmeans_iteration(hist: &mut HistogramInternal, palette: &mut PalF, adjust_weight: bool, constraint: Option<&ConstraintProjection>) -> f64 {
+    let error = Kmeans::iteration(hist, palette, adjust_weight);
+    if let Some(constraint) = constraint {
+        constraint.project_palette(palette);
+    }
+    error
+}
 
-fn refine_palette(palette: &mut PalF, attr: &Attributes, hist: &mut HistogramInternal, max_mse: Option<f64>, palette_error: &mut Option<f64>) {
-    let (iterations, iteration_limit) = attr.kmeans_iterations(hist.items.len(), palette_error.is_some());
+/// Palette from `Attributes::set_duotone`. Its colors are fixed, so K-means only measures the error.
+///
+/// The last value is true if some pixels are worse than `max_pixel_error` allows. The tones can't be moved to fix that.
+fn find_duotone_palette(attr: &Attributes, hist: &mut HistogramInternal, fixed_colors: &FixedColorsSet, gamma: f64, dark: RGBA, light: RGBA) -> (PalF, Option<f64>, bool) {
+    let lut = gamma_lut(gamma);
+    let tones = attr.max_colors.saturating_sub(fixed_colors.len() as PalLen);
+    let mut palette = duotone_palette(hist, tones, f_pixel::from_rgba(&lut, dark), f_pixel::from_rgba(&lut, light))
+        .with_fixed_colors(attr.max_colors, fixed_colors);
+    let palette_error = Kmeans::iteration(hist, &mut palette, false);
+    if let Some(max_pixel_error) = attr.max_pixel_error {
+        let (worst_error, _) = worst_pixel_error(hist, &palette);
+        if worst_error > max_pixel_error {
+            attr.verbose_print(format!(
+                "  worst pixel error MSE={:0.3} exceeded limit of {:0.3}",
+                mse_to_standard_mse(worst_error),
+                mse_to_standard_mse(max_pixel_error)
+            ));
+            return (palette, Some(palette_error), true);
+        }
+    }
+    (palette, Some(palette_error), false)
+}
+
+fn refine_palette(palette: &mut PalF, attr: &Attributes, hist: &mut HistogramInternal, max_mse: Option<f64>, palette_error: &mut Option<f64>, constraint: Option<&ConstraintProjection>) {
+    let (iterations, iteration_limit) = if attr.refinement == Refinement::Quick { (1, 0.) } else { attr.kmeans_iterations(hist.items.len(), palette_error.is_some()) };
     if iterations > 0 {
         attr.verbose_print("  moving colormap towards local minimum");
         let mut i = 0;
@@ -326,7 +1109,7 @@ fn refine_palette(palette: &mut PalF, attr: &Attributes, hist: &mut HistogramInt
                 break;
             }
 
-            let pal_err = Kmeans::iteration(hist, palette, false);
+            let pal_err = constrained_kmeans_iteration(hist, palette, false, constraint);
             debug_assert!(pal_err < 1e20);
             let previous_palette_error = *palette_error;
             *palette_error = Some(pal_err);
@@ -341,6 +1124,144 @@ fn refine_palette(palette: &mut PalF, attr: &Attributes, hist: &mut HistogramInt
     }
 }
 
+/// Removes entries that are nearest to too little of the histogram, and lets K-means merge their colors into the other entries
+/// (see `Attributes::set_min_entry_population`)
+fn remove_unpopular_entries(palette: &mut PalF, attr: &Attributes, hist: &mut HistogramInternal, palette_error: &mut Option<f64>, constraint: Option<&ConstraintProjection>) {
+    let min_weight = f64::from(attr.min_entry_population) * hist.total_perceptual_weight;
+    let mut populations = vec![0.; palette.len()];
+    let n = Nearest::new(palette);
+    for item in hist.items.iter_mut() {
+        let (matched, _) = n.search(&item.color, unsafe { item.tmp.likely_palette_index });
+        item.tmp.likely_palette_index = matched;
+        populations[matched as usize] += f64::from(item.perceptual_weight);
+    }
+    drop(n);
+
+    let keep: Vec<_> = populations.iter().zip(palette.pop_as_slice()).map(|(&population, pop)| pop.is_fixed() || population >= min_weight).collect();
+    let removed = keep.iter().filter(|&&keep| !keep).count();
+    // at least one entry has to stay to take over the pixels
+    if removed == 0 || removed == keep.len() {
+        return;
+    }
+    attr.verbose_print(format!("  removed {} rarely used colors", removed));
+    palette.retain(&keep);
+    *palette_error = Some(constrained_kmeans_iteration(hist, palette, false, constraint));
+}
+
+/// Gives entries to histogram colors that are too far from all palette entries, and boosts their weight so that K-means keeps the entries there
+/// (see `Attributes::set_preserve_rare_colors`)
+fn preserve_rare_colors(palette: &mut PalF, attr: &Attributes, hist: &mut HistogramInternal, palette_error: &mut Option<f64>, constraint: Option<&ConstraintProjection>) {
+    /// Rare colors get at least this much weight of the average entry
+    const MIN_SHARE: f32 = 0.5;
+
+    let distinctness = attr.rare_color_distinctness;
+    let max_rare = (attr.max_colors as usize / 4).max(1);
+    let n = Nearest::new(palette);
+    let mut rare: Vec<_> = hist.items.iter().enumerate().filter_map(|(i, item)| {
+        let (_, diff) = n.search(&item.color, unsafe { item.tmp.likely_palette_index });
+        if diff > distinctness { Some((diff, i)) } else { None }
+    }).collect();
+    drop(n);
+    if rare.is_empty() {
+        return;
+    }
+
+    // the most distinct colors first, and only one of each group of similar colors
+    rare.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+    let mut picked: Vec<f_pixel> = Vec::new();
+    for &(_, i) in &rare {
+        let color = hist.items[i].color;
+        if picked.len() < max_rare && picked.iter().all(|p| p.diff(&color) > distinctness) {
+            picked.push(color);
+        }
+    }
+    attr.verbose_print(format!("  preserving {} rare colors", picked.len()));
+
+    let min_weight = MIN_SHARE * hist.total_perceptual_weight as f32 / attr.max_colors as f32;
+    // only colors that a new entry will take, the others would just pull existing entries away from the rest of the image
+    for &(_, i) in &rare {
+        let item = &mut hist.items[i];
+        if picked.iter().any(|p| p.diff(&item.color) <= distinctness) {
+            item.adjusted_weight = item.adjusted_weight.max(min_weight);
+        }
+    }
+    // entries of rare colors, which mustn't be replaced by the next rare color
+    let mut is_rare = vec![false; attr.max_colors as usize];
+    for &color in &picked {
+        if palette.len() < attr.max_colors as usize {
+            is_rare[palette.len()] = true;
+            palette.push(color, PalPop::new(min_weight));
+            continue;
+        }
+        // replaces the least used entry
+        let least_used = palette.iter().enumerate()
+            .filter(|&(i, (_, pop))| !pop.is_fixed() && !is_rare[i])
+            .min_by(|(_, (_, a)), (_, (_, b))| a.popularity().partial_cmp(&b.popularity()).unwrap_or(std::cmp::Ordering::Equal))
+            .map(|(i, _)| i);
+        if let Some(i) = least_used {
+            is_rare[i] = true;
+            palette.set(i, color, PalPop::new(min_weight));
+        }
+    }
+    *palette_error = Some(constrained_kmeans_iteration(hist, palette, false, constraint));
+}
+
+/// Minimax refinement: histogram colors that are too far from their palette entry get their weight boosted,
+/// so that K-means pulls the palette towards them, even at cost of the average error.
+///
+/// Returns the worst error of the palette it has settled on.
+fn refine_palette_minimax(palette: &mut PalF, attr: &Attributes, hist: &mut HistogramInternal, max_pixel_error: f64, palette_error: &mut Option<f64>, constraint: Option<&ConstraintProjection>) -> f64 {
+    const MINIMAX_ITERATIONS: usize = 16;
+
+    let (mut worst_error, mut worst_index) = worst_pixel_error(hist, palette);
+    let mut best = (worst_error, palette.clone(), *palette_error);
+    for _ in 0..MINIMAX_ITERATIONS {
+        if worst_error <= max_pixel_error {
+            break;
+        }
+        attr.verbose_print(format!("  reducing worst pixel error MSE={:0.3}", mse_to_standard_mse(worst_error)));
+
+        // if there's room in the palette, the outlier can simply get its own color
+        if palette.len() < attr.max_colors as usize {
+            if let Some(item) = worst_index.and_then(|i| hist.items.get(i)) {
+                palette.push(item.color, PalPop::new(item.perceptual_weight));
+            }
+        }
+
+        let n = Nearest::new(palette);
+        for item in hist.items.iter_mut() {
+            let (matched, diff) = n.search(&item.color, unsafe { item.tmp.likely_palette_index });
+            item.tmp.likely_palette_index = matched;
+            if diff as f64 > max_pixel_error {
+                item.adjusted_weight *= (diff / max_pixel_error as f32).min(16.);
+            }
+        }
+
+        *palette_error = Some(constrained_kmeans_iteration(hist, palette, false, constraint));
+        let (err, idx) = worst_pixel_error(hist, palette);
+        worst_error = err;
+        worst_index = idx;
+        if worst_error < best.0 {
+            best = (worst_error, palette.clone(), *palette_error);
+        }
+    }
+
+    let (worst_error, best_palette, best_palette_error) = best;
+    *palette = best_palette;
+    *palette_error = best_palette_error;
+    worst_error
+}
+
+/// Largest difference between any histogram color and its closest palette entry, and index of that histogram item
+fn worst_pixel_error(hist: &HistogramInternal, palette: &PalF) -> (f64, Option<usize>) {
+    let n = Nearest::new(palette);
+    hist.items.iter().enumerate()
+        .map(|(i, item)| (n.search(&item.color, unsafe { item.tmp.likely_palette_index }).1, i))
+        .max_by_key(|&(diff, _)| OrdFloat::<f32>::unchecked_new(diff))
+        .map(|(diff, i)| (diff as f64, Some(i)))
+        .unwrap_or((0., None))
+}
+
 fn palette_from_histogram(hist: &HistogramInternal, max_colors: PalLen, fixed_colors: &FixedColorsSet) -> (PalF, Option<f64>) {
     let mut hist_pal = PalF::new();
     for item in hist.items.iter() {
@@ -355,7 +1276,10 @@ impl Drop for QuantizationResult {
         self.int_palette.count = 0;
         self.int_palette.entries.fill_with(Default::default);
 
-        self.magic_header = LIQ_FREED_MAGIC;
+        #[cfg(feature = "capi")]
+        {
+            self.magic_header = LIQ_FREED_MAGIC;
+        }
     }
 }
 
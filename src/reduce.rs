@@ -0,0 +1,55 @@
+//! Quantization of palettes themselves, without images (see `reduce_palette`)
+
+use crate::error::*;
+use crate::hist::Histogram;
+use crate::pal::{f_pixel, gamma_lut, PalIndex, RGBA};
+
+/// Weights are scaled so that the largest one is this many pixels
+const MAX_WEIGHT_COUNT: f32 = 65535.;
+
+/// Result of [`reduce_palette`]
+#[derive(Debug, Clone)]
+pub struct ReducedPalette {
+    pub palette: Vec<RGBA>,
+    /// For every color of the input, index of the color in `palette` that replaces it
+    pub mapping: Vec<PalIndex>,
+}
+
+/// Makes a palette of at most `target_count` colors (2-256) that best replaces the given `colors`, without any image.
+///
+/// `weights` are importance of each color, e.g. how many pixels use it, and must have the same length as `colors`.
+/// Useful for merging palettes of many frames into one global palette: pass their colors concatenated,
+/// and use the `mapping` to convert existing indexed pixels without remapping images again.
+///
+/// If there are no more than `target_count` distinct colors, they're kept exactly.
+pub fn reduce_palette(colors: &[RGBA], weights: &[f32], target_count: u32) -> Result<ReducedPalette, liq_error> {
+    if colors.is_empty() || colors.len() != weights.len() || weights.iter().any(|w| !w.is_finite() || *w < 0.) {
+        return Err(LIQ_VALUE_OUT_OF_RANGE);
+    }
+    let max_weight = weights.iter().copied().fold(0., f32::max);
+    if max_weight <= 0. {
+        return Err(LIQ_VALUE_OUT_OF_RANGE);
+    }
+
+    let mut attr = crate::new();
+    attr.set_max_colors(target_count).ok()?;
+    let counts: Vec<_> = colors.iter().zip(weights).map(|(&c, &w)| {
+        // small weights still count, so that rare colors aren't dropped from the histogram entirely
+        let count = if w > 0. { ((w / max_weight * MAX_WEIGHT_COUNT).round() as u32).max(1) } else { 0 };
+        (c, count)
+    }).collect();
+    let mut hist = Histogram::new(&attr);
+    hist.add_color_counts(&counts, 0.)?;
+    let mut res = hist.quantize(&attr)?;
+
+    let palette = res.palette().to_vec();
+    let lut = gamma_lut(res.output_gamma());
+    let palette_f: Vec<_> = palette.iter().map(|&c| f_pixel::from_rgba(&lut, c)).collect();
+    let mapping = colors.iter().map(|&c| {
+        let px = f_pixel::from_rgba(&lut, c);
+        palette_f.iter().enumerate()
+            .min_by(|(_, a), (_, b)| px.diff(a).partial_cmp(&px.diff(b)).unwrap_or(std::cmp::Ordering::Equal))
+            .map_or(0, |(i, _)| i as PalIndex)
+    }).collect();
+    Ok(ReducedPalette { palette, mapping })
+}
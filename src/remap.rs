@@ -1,17 +1,25 @@
 use crate::error::*;
+#[cfg(feature = "dither-maps")]
+use fallible_collections::FallibleVec;
+use crate::hist::{ColorHash, Histogram, RgbaHasher};
 use crate::image::Image;
 use crate::kmeans::Kmeans;
-use crate::nearest::Nearest;
-use crate::pal::{ARGBF, LIQ_WEIGHT_MSE, MIN_OPAQUE_A, PalF, PalIndex, Palette, f_pixel, gamma_lut};
+use crate::labels::LabelFallback;
+use crate::nearest::{Metric, MetricNearest, Nearest};
+use crate::ordered::{frame_offset, MixingPlans};
+use crate::pal::{ARGBF, AlphaEncoding, LIQ_WEIGHT_A, LIQ_WEIGHT_B, LIQ_WEIGHT_G, LIQ_WEIGHT_MSE, LIQ_WEIGHT_R, MIN_OPAQUE_A, PalF, PalIndex, PalPop, Palette, MAX_COLORS, RGBA, f_pixel, gamma_lut};
 use crate::quant::{quality_to_mse, QuantizationResult};
 use crate::rows::temp_buf;
-use crate::seacow::{RowBitmap, RowBitmapMut};
-use rayon::iter::ParallelBridge;
-use rayon::iter::ParallelIterator;
+use crate::seacow::RowBitmapMut;
+use rayon::iter::{IndexedParallelIterator, ParallelIterator};
+use rayon::slice::ParallelSliceMut;
 use rgb::ComponentMap;
-use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
 use std::mem::MaybeUninit;
-use thread_local::ThreadLocal;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
 
 #[repr(u8)]
 #[derive(Eq, PartialEq, Clone, Copy)]
@@ -21,18 +29,591 @@ pub enum DitherMapMode {
     Always = 2,
 }
 
+/// Pattern used for dithering
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum DitherKernel {
+    /// Classic Floyd-Steinberg. Spreads error to 4 neighbors.
+    FloydSteinberg,
+    /// Sierra Lite. Spreads error to 3 neighbors, which gives a bit coarser, but less noisy look.
+    SierraLite,
+    /// Floyd-Steinberg with noise shaping. Along with the error, it spreads a high-pass filtered error (change from the previous pixel's error),
+    /// which moves the noise to higher frequencies. Smooth gradients, like skies, get finer dithering with fewer visible patterns.
+    NoiseShaped,
+    /// Not error diffusion, but positional (ordered) dithering with mixes of arbitrary palette colors, as in Yliluoma's algorithm.
+    /// Each color is replaced with a pair of palette colors in a fixed 8x8 pattern.
+    ///
+    /// It's slower, but every pixel depends only on its color and position, so the same color always gets the same pattern.
+    /// This is great for pixel art that is going to be scaled, and for animations, where static areas don't flicker.
+    /// The dither map isn't used.
+    Ordered,
+}
+
+impl DitherKernel {
+    /// Weights of (next pixel, pixel below ahead, pixel below, pixel below behind)
+    #[inline]
+    pub(crate) fn weights(self) -> [f32; 4] {
+        match self {
+            Self::FloydSteinberg | Self::NoiseShaped => [7. / 16., 1. / 16., 5. / 16., 3. / 16.],
+            Self::SierraLite => [2. / 4., 0., 1. / 4., 1. / 4.],
+            // doesn't spread any error
+            Self::Ordered => [0.; 4],
+        }
+    }
+
+    /// Fraction of the high-pass filtered error spread in addition to the error itself.
+    ///
+    /// The filtered error sums to nothing over a row, so it doesn't change the average color. More than 0.5 becomes unstable with small palettes.
+    #[inline]
+    pub(crate) fn noise_shaping(self) -> f32 {
+        match self {
+            Self::NoiseShaped => 0.5,
+            Self::FloydSteinberg | Self::SierraLite | Self::Ordered => 0.,
+        }
+    }
+}
+
+/// Strength of dithering, see [`QuantizationResult::set_dither_level`]
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DitherLevel {
+    /// 0-1, where 0 disables dithering. The same everywhere, except edges if the dither map is used.
+    Fixed(f32),
+    /// Strength picked for each area from its local variance: full in smooth gradients, where banding is most visible,
+    /// and weak in textured areas, which hide the quantization anyway and where dithering only adds noise.
+    /// Strength goes from full (as `Fixed(1.)`) where colors around a pixel are smooth, to none where they vary by tens of levels.
+    ///
+    /// Without the `dither-maps` feature it's the same as `Fixed(1.)`.
+    Auto,
+}
+
+impl DitherLevel {
+    #[inline]
+    pub(crate) fn is_valid(self) -> bool {
+        match self {
+            Self::Fixed(level) => (0. ..=1.).contains(&level),
+            Self::Auto => true,
+        }
+    }
+}
+
+impl From<f32> for DitherLevel {
+    #[inline]
+    fn from(level: f32) -> Self {
+        Self::Fixed(level)
+    }
+}
+
+/// Settings for a single remapping, see [`QuantizationResult::remap_into_with`]
+///
+/// Start from `RemapOptions::default()` and change it with the setters, since more options may be added.
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub struct RemapOptions {
+    /// See [`QuantizationResult::set_dither_level`]
+    pub dither_level: DitherLevel,
+    /// Dithering pattern
+    pub kernel: DitherKernel,
+    /// Palette colors further apart than this (Euclidean distance of RGBA in 0-255 units) are never mixed by dithering.
+    ///
+    /// With few saturated colors, dithering can approximate a color with a checkerboard of two wildly different colors,
+    /// which looks worse than a flat color. `None` allows any colors.
+    pub max_mix_distance: Option<f32>,
+    /// Number of the frame of an animation, which moves the threshold pattern of `DitherKernel::Ordered` for every frame.
+    ///
+    /// Normally every frame gets the same pattern, so moving content drags the pattern along ("crawls") over a static grid.
+    /// With the frame number, the dither noise changes from frame to frame instead, which looks like a fine shimmer,
+    /// and averages out over time (each pixel gets all thresholds every 64 frames). `None` keeps the pattern fixed.
+    pub frame_index: Option<u32>,
+}
+
+impl RemapOptions {
+    /// See [`QuantizationResult::set_dither_level`]
+    pub fn set_dither_level(&mut self, level: DitherLevel) -> liq_error {
+        if !level.is_valid() {
+            return LIQ_VALUE_OUT_OF_RANGE;
+        }
+        self.dither_level = level;
+        LIQ_OK
+    }
+
+    /// Dithering pattern. Default is Floyd-Steinberg.
+    #[inline]
+    pub fn set_kernel(&mut self, kernel: DitherKernel) {
+        self.kernel = kernel;
+    }
+
+    /// See [`RemapOptions::max_mix_distance`]. The distance can't be negative.
+    pub fn set_max_mix_distance(&mut self, distance: Option<f32>) -> liq_error {
+        if matches!(distance, Some(d) if d.is_nan() || d < 0.) {
+            return LIQ_VALUE_OUT_OF_RANGE;
+        }
+        self.max_mix_distance = distance;
+        LIQ_OK
+    }
+
+    /// See [`RemapOptions::frame_index`]
+    #[inline]
+    pub fn set_frame_index(&mut self, frame_index: Option<u32>) {
+        self.frame_index = frame_index;
+    }
+
+    #[inline]
+    pub(crate) fn is_valid(&self) -> bool {
+        self.dither_level.is_valid() && !matches!(self.max_mix_distance, Some(d) if d.is_nan() || d < 0.)
+    }
+}
+
+impl Default for RemapOptions {
+    /// Full Floyd-Steinberg dithering
+    #[inline]
+    fn default() -> Self {
+        Self {
+            dither_level: DitherLevel::Fixed(1.),
+            kernel: DitherKernel::FloydSteinberg,
+            max_mix_distance: None,
+            frame_index: None,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub(crate) struct Remapped {
     pub(crate) int_palette: Palette,
     pub(crate) palette_error: Option<f64>,
+    pub(crate) transparent_index: Option<PalIndex>,
+}
+
+/// Non-dithered remapping that the dither map of an image has been made from.
+///
+/// Remapping the image again with the same palette, but a different dithering level, only needs to repeat the error diffusion.
+#[cfg(feature = "dither-maps")]
+pub(crate) struct UnditheredPrepass {
+    /// Palette of the result, before it has been adjusted to the image
+    source: Vec<f_pixel>,
+    /// The palette after K-means adjustments of the pre-pass
+    palette: PalF,
+    palette_error: f64,
+    /// Row by row, as if the output wasn't interlaced
+    indices: Vec<PalIndex>,
+}
+
+/// Colors that are always remapped to their closest palette entry, without dithering
+#[derive(Clone, Copy)]
+pub(crate) enum ProtectedColor {
+    /// Fixed color with a tolerance, in the image's gamma
+    Fixed(f_pixel, f32),
+    /// Color set with `add_undithered_color`
+    Undithered(RGBA),
+}
+
+/// Pixels this close to a color added with `add_undithered_color` are considered to be that color
+const UNDITHERED_COLOR_TOLERANCE: f32 = 2. / 256. / 256.;
+
+/// Rows remapped together by one thread, which share their K-means sums
+const ROWS_PER_CHUNK: usize = 16;
+
+/// Protected colors paired with the palette entry they're remapped to
+pub(crate) fn protected_palette_entries(protected_colors: &[ProtectedColor], n: &Nearest<'_>, input_lut: &[f32; 256]) -> Vec<(f_pixel, f32, PalIndex)> {
+    protected_colors.iter()
+        .map(|&p| match p {
+            ProtectedColor::Fixed(color, tolerance) => (color, tolerance),
+            ProtectedColor::Undithered(color) => (f_pixel::from_rgba(input_lut, color), UNDITHERED_COLOR_TOLERANCE),
+        })
+        .map(|(color, tolerance)| (color, tolerance, n.search(&color, 0).0))
+        .collect()
+}
+
+/// Row of a map with a value for every pixel of an image `width` pixels wide, or empty if the map doesn't have it
+#[inline]
+pub(crate) fn map_row<T>(map: &[T], row: usize, width: usize) -> &[T] {
+    row.checked_mul(width)
+        .and_then(|start| map.get(start..start.checked_add(width)?))
+        .unwrap_or(&[])
+}
+
+#[inline]
+fn protected_match(protected: &[(f_pixel, f32, PalIndex)], px: &f_pixel) -> Option<PalIndex> {
+    protected.iter()
+        .find(|(color, tolerance, _)| px.diff(color) <= *tolerance)
+        .map(|&(_, _, idx)| idx)
+}
+
+/// Pairs of palette entries that dithering is allowed to mix, see [`RemapOptions::max_mix_distance`]
+pub(crate) struct MixLimit {
+    colors: Vec<RGBA>,
+    max_distance_sq: f32,
+}
+
+impl MixLimit {
+    /// `None` if there's no limit
+    pub(crate) fn new(palette: &PalF, gamma: f64, max_distance: Option<f32>) -> Option<Self> {
+        let max_distance = max_distance?;
+        Some(Self {
+            colors: palette.as_slice().iter().map(|c| c.to_rgb(gamma)).collect(),
+            max_distance_sq: max_distance * max_distance,
+        })
+    }
+
+    #[inline]
+    pub(crate) fn allows(&self, a: PalIndex, b: PalIndex) -> bool {
+        let (a, b) = (self.colors[a as usize], self.colors[b as usize]);
+        let d = |a: u8, b: u8| (f32::from(a) - f32::from(b)).powi(2);
+        d(a.r, b.r) + d(a.g, b.g) + d(a.b, b.b) + d(a.a, b.a) <= self.max_distance_sq
+    }
+}
+
+/// Palette entries by their exact value. When all input colors are in the palette,
+/// this is much faster than searching for the nearest color.
+struct ExactColors<'pal> {
+    indices: HashMap<u32, PalIndex, RgbaHasher>,
+    colors: &'pal [f_pixel],
+}
+
+impl<'pal> ExactColors<'pal> {
+    /// If `enabled` is false, it won't find anything, without cost of building the map
+    fn new(colors: &'pal [f_pixel], enabled: bool) -> Self {
+        let mut indices = HashMap::with_hasher(RgbaHasher(0));
+        if enabled {
+            indices.reserve(colors.len());
+            for (idx, c) in colors.iter().enumerate() {
+                // on collision, the first one wins, and the other one needs a regular search
+                indices.entry(Self::key(c)).or_insert(idx as PalIndex);
+            }
+        }
+        Self { indices, colors }
+    }
+
+    #[inline(always)]
+    fn key(px: &f_pixel) -> u32 {
+        px.a.to_bits() ^ px.r.to_bits().rotate_left(8) ^ px.g.to_bits().rotate_left(16) ^ px.b.to_bits().rotate_left(24)
+    }
+
+    #[inline]
+    fn get(&self, px: &f_pixel) -> Option<PalIndex> {
+        if self.indices.is_empty() {
+            return None;
+        }
+        let idx = *self.indices.get(&Self::key(px))?;
+        if self.colors[idx as usize] == *px { Some(idx) } else { None }
+    }
+}
+
+/// Nearest palette entries of exact input colors, see `QuantizationResult::set_remap_cache`
+struct ColorCache {
+    entries: HashMap<u32, (f_pixel, PalIndex, f32), RgbaHasher>,
+    enabled: bool,
+    lookups: u32,
+    hits: u32,
+}
+
+/// Bound of the cache, so that images with millions of colors don't use too much memory
+const MAX_CACHED_COLORS: usize = 1 << 12;
+
+/// After this many lookups the cache gives up if fewer than half of them were hits, since photos rarely repeat colors exactly
+const CACHE_TRIAL_LOOKUPS: u32 = 1024;
+
+impl Default for ColorCache {
+    fn default() -> Self {
+        Self { entries: HashMap::with_hasher(RgbaHasher(0)), enabled: false, lookups: 0, hits: 0 }
+    }
+}
+
+impl ColorCache {
+    /// Empties the cache, keeping its memory
+    fn reset(&mut self, enabled: bool) {
+        self.entries.clear();
+        self.enabled = enabled;
+        self.lookups = 0;
+        self.hits = 0;
+    }
+
+    #[inline]
+    fn get(&mut self, px: &f_pixel) -> Option<(PalIndex, f32)> {
+        if !self.enabled {
+            return None;
+        }
+        self.lookups += 1;
+        match self.entries.get(&ExactColors::key(px)) {
+            Some(&(color, idx, diff)) if color == *px => {
+                self.hits += 1;
+                Some((idx, diff))
+            },
+            _ => {
+                if self.lookups >= CACHE_TRIAL_LOOKUPS && self.hits < self.lookups / 2 {
+                    self.enabled = false;
+                }
+                None
+            },
+        }
+    }
+
+    #[inline]
+    fn insert(&mut self, px: f_pixel, idx: PalIndex, diff: f32) {
+        if self.enabled && self.entries.len() < MAX_CACHED_COLORS {
+            self.entries.insert(ExactColors::key(&px), (px, idx, diff));
+        }
+    }
+}
+
+/// Buffers for remapping, which can be kept between remapped images to avoid allocating them for every image.
+///
+/// Pass the same scratch to [`QuantizationResult::remap_into_with_scratch`] or [`FrozenRemapper::remap_into_with_scratch`](crate::FrozenRemapper::remap_into_with_scratch)
+/// when remapping many images in a row, such as frames of a video. The buffers grow to fit the largest image, and are kept until the scratch is dropped.
+///
+/// It can also keep the largest buffers of images and histograms that are done with: give them to [`RemapScratch::reclaim_image`]
+/// and [`RemapScratch::reclaim_histogram`], and the next image or histogram can reuse the buffers after [`Image::reuse_buffers`]
+/// or [`Histogram::reuse_buffers`]. The scratch is owned by the caller, so all of these buffers live as long as the caller wants them to.
+#[derive(Default)]
+pub struct RemapScratch {
+    /// Rows for remapping without dithering, lent to each piece of work done in parallel
+    threads: Mutex<Vec<ThreadScratch>>,
+    /// Input row for dithered remapping
+    temp_row: Vec<MaybeUninit<RGBA>>,
+    /// Dithering errors of the current and the next row
+    error_rows: Vec<f_pixel>,
+    /// Buffers of maps of reclaimed images (importance, edges, dithering), one byte per pixel
+    maps: Vec<Box<[u8]>>,
+    /// Emptied color hash of a reclaimed histogram
+    color_hash: Option<ColorHash>,
+}
+
+#[derive(Default)]
+struct ThreadScratch {
+    temp_row: Vec<MaybeUninit<RGBA>>,
+    temp_row_f: Vec<MaybeUninit<f_pixel>>,
+    temp_row_f_bg: Vec<MaybeUninit<f_pixel>>,
+    color_cache: ColorCache,
+}
+
+impl RemapScratch {
+    /// Empty buffers. They're allocated when the first image is remapped.
+    #[inline]
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Buffers that no other thread is using. They're not locked while in use, so remapping can't block or panic
+    /// if it's started again on the same thread, e.g. by another task of the same rayon pool.
+    fn take_thread_scratch(&self) -> ThreadScratch {
+        match self.threads.lock() {
+            Ok(mut threads) => threads.pop().unwrap_or_default(),
+            Err(_) => ThreadScratch::default(),
+        }
+    }
+
+    fn return_thread_scratch(&self, t: ThreadScratch) {
+        if let Ok(mut threads) = self.threads.lock() {
+            threads.push(t);
+        }
+    }
+
+    /// Keeps buffers of the image's maps, which are made for weighing the histogram and for adaptive dithering,
+    /// so that [`Image::reuse_buffers`] can give them to another image of the same size.
+    pub fn reclaim_image(&mut self, mut image: Image<'_, '_>) {
+        image.take_map_buffers(&mut self.maps);
+    }
+
+    /// Keeps the color hash of the histogram, the largest buffer it has, so that [`Histogram::reuse_buffers`] can give it to another histogram.
+    pub fn reclaim_histogram(&mut self, mut histogram: Histogram) {
+        let hashmap = histogram.take_color_hash();
+        if self.color_hash.as_ref().map_or(0, |h| h.capacity()) <= hashmap.capacity() {
+            self.color_hash = Some(hashmap);
+        }
+    }
+
+    /// Removes kept map buffers of exactly `len` bytes
+    pub(crate) fn take_map_buffers(&mut self, len: usize) -> Vec<Box<[u8]>> {
+        let (fit, rest) = std::mem::take(&mut self.maps).into_iter().partition(|m| m.len() == len);
+        self.maps = rest;
+        fit
+    }
+
+    pub(crate) fn take_color_hash(&mut self) -> Option<ColorHash> {
+        self.color_hash.take()
+    }
+}
+
+impl fmt::Debug for RemapScratch {
+    #[cold]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RemapScratch({} px)", self.temp_row.len())
+    }
+}
+
+/// Dithering error that hasn't been diffused yet at the bottom of a remapped image.
+///
+/// Pass the same state to [`QuantizationResult::remap_strip_into`] for each horizontal strip of an image, from top to bottom,
+/// and dithering will continue across strips as if the whole image was remapped at once, without visible seams.
+#[derive(Debug, Clone)]
+pub struct DitherState {
+    /// Error diffused to the row below
+    pub(crate) errors: Vec<f_pixel>,
+    /// Direction of the serpentine scan of the next row
+    pub(crate) scan_forward: bool,
+}
+
+impl DitherState {
+    /// State for the top of an image, where there's no error yet
+    #[inline]
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Default for DitherState {
+    fn default() -> Self {
+        Self { errors: Vec::new(), scan_forward: true }
+    }
+}
+
+impl DitherState {
+    /// Borrows the state for remapping an image of this width. A new state fits any width, and then only that width.
+    pub(crate) fn for_width(&mut self, width: usize) -> Result<DitherStateRef<'_>, liq_error> {
+        if self.errors.is_empty() {
+            self.errors.resize(width, f_pixel::default());
+        }
+        if self.errors.len() != width {
+            return Err(LIQ_VALUE_OUT_OF_RANGE);
+        }
+        Ok(DitherStateRef { errors: &mut self.errors, scan_forward: &mut self.scan_forward })
+    }
+}
+
+/// Dithering error carried into an image from above it, borrowed from a [`DitherState`] or from columns of [`TileErrors`](crate::TileErrors).
+/// Receives the error at the bottom of the image.
+pub(crate) struct DitherStateRef<'a> {
+    /// Error diffused to the row below, exactly one per column of the image
+    pub errors: &'a mut [f_pixel],
+    /// Direction of the serpentine scan of the next row
+    pub scan_forward: &'a mut bool,
+}
+
+/// The first `len` elements of the buffer, growing it if needed
+#[inline]
+fn scratch_row<T>(buf: &mut Vec<MaybeUninit<T>>, len: usize) -> &mut [MaybeUninit<T>] {
+    if buf.len() < len {
+        buf.resize_with(len, MaybeUninit::uninit);
+    }
+    &mut buf[..len]
+}
+
+/// Progress of rows processed in parallel, in any order, so it's reported by the number of rows done
+pub(crate) struct RowProgress<'a> {
+    rows_done: AtomicUsize,
+    aborted: AtomicBool,
+    height: usize,
+    /// Gets the fraction of rows done so far, and aborts if it returns `true`
+    callback: &'a (dyn Fn(f32) -> bool + Sync),
+}
+
+impl<'a> RowProgress<'a> {
+    /// Progress is reported every this many rows. Must be a power of two.
+    const INTERVAL: usize = 64;
+
+    #[inline]
+    pub fn new(height: usize, callback: &'a (dyn Fn(f32) -> bool + Sync)) -> Self {
+        Self { rows_done: AtomicUsize::new(0), aborted: AtomicBool::new(false), height, callback }
+    }
+
+    /// Counts a row as done. Returns `false` if processing has been aborted, and the row should be skipped.
+    #[inline]
+    pub fn next_row(&self) -> bool {
+        if self.aborted.load(Ordering::Relaxed) {
+            return false;
+        }
+        let done = self.rows_done.fetch_add(1, Ordering::Relaxed) + 1;
+        if done & (Self::INTERVAL - 1) == 0 && (self.callback)(done as f32 / self.height as f32) {
+            self.aborted.store(true, Ordering::Relaxed);
+            return false;
+        }
+        true
+    }
+
+    #[inline]
+    pub fn is_aborted(&self) -> bool {
+        self.aborted.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn finish(self) -> Result<(), liq_error> {
+        if self.aborted.into_inner() { Err(LIQ_ABORTED) } else { Ok(()) }
+    }
+}
+
+/// Where remapped indices go. Rows are remapped from the top of the image in bands, and each band is passed on when it's done,
+/// so that outputs that aren't indices don't need a buffer for the whole image.
+pub(crate) trait RowSink {
+    /// Number of rows in a band. Remapping without dithering may round it up, so that rows are split between threads the same way in every band.
+    fn band_height(&self) -> usize;
+
+    /// Gets the final palette before any rows are remapped
+    fn set_palette(&mut self, _palette: &Palette) {}
+
+    /// Rows of the image in `rows`, to write their indices to
+    fn band_mut(&mut self, rows: Range<usize>) -> Vec<&mut [MaybeUninit<PalIndex>]>;
+
+    /// Every row of the band last returned by `band_mut` has been written
+    fn band_done(&mut self, rows: Range<usize>);
+}
+
+/// Writes to the output rows directly, as a single band
+impl RowSink for RowBitmapMut<'_, MaybeUninit<PalIndex>> {
+    #[inline]
+    fn band_height(&self) -> usize {
+        usize::MAX
+    }
+
+    #[inline]
+    fn band_mut(&mut self, rows: Range<usize>) -> Vec<&mut [MaybeUninit<PalIndex>]> {
+        self.rows_range_mut(rows).collect()
+    }
+
+    #[inline]
+    fn band_done(&mut self, _rows: Range<usize>) {}
+}
+
+/// Splits `height` rows into bands of `band_height` rows (the last one may be shorter)
+fn bands(height: usize, band_height: usize) -> impl Iterator<Item = Range<usize>> {
+    let band_height = band_height.max(1);
+    (0..height).step_by(band_height).map(move |start| start..start.saturating_add(band_height).min(height))
 }
 
+/// Settings of the result for `remap_to_palette`
+pub(crate) struct UnditheredOptions<'a> {
+    /// Colors that are always remapped to their closest palette entry
+    pub protected_colors: &'a [ProtectedColor],
+    /// Enables a fast path for images that have all their colors in the palette
+    pub input_fits_palette: bool,
+    /// See `QuantizationResult::set_remap_cache`
+    pub cache_colors: bool,
+    /// Fully transparent pixels don't refine the palette, see `Attributes::set_alpha_weighted_colors`
+    pub alpha_weighted: bool,
+}
+
+impl<'a> UnditheredOptions<'a> {
+    pub fn new(result: &'a QuantizationResult, input_fits_palette: bool) -> Self {
+        Self {
+            protected_colors: &result.protected_colors,
+            input_fits_palette,
+            cache_colors: result.remap_cache(),
+            alpha_weighted: result.alpha_weighted(),
+        }
+    }
+}
+
+/// `progress` gets the fraction of rows done so far, and aborts remapping if it returns `true`
 #[inline(never)]
-pub(crate) fn remap_to_palette<'x, 'b: 'x>(image: &mut Image, output_pixels: &'x mut RowBitmapMut<'b, MaybeUninit<u8>>, palette: &mut PalF) -> Result<(f64, RowBitmap<'x, u8>), liq_error> {
+pub(crate) fn remap_to_palette(image: &mut Image, output: &mut dyn RowSink, palette: &mut PalF, options: &UnditheredOptions<'_>, progress: &(dyn Fn(f32) -> bool + Sync), scratch: &mut RemapScratch) -> Result<f64, liq_error> {
     let width = image.width();
+    let height = image.height();
 
+    let alpha_levels = palette.alpha_levels();
     let n = Nearest::new(palette);
     let colors = palette.as_slice();
     let palette_len = colors.len();
+    let exact = ExactColors::new(colors, options.input_fits_palette);
+    let protected = protected_palette_entries(options.protected_colors, &n, &gamma_lut(image.gamma()));
 
     let mut background = image.background.as_mut();
     let transparent_index = if background.is_some() {
@@ -43,56 +624,152 @@ pub(crate) fn remap_to_palette<'x, 'b: 'x>(image: &mut Image, output_pixels: &'x
         background = None;
     }
 
-    let tls = ThreadLocal::new();
-    let per_thread_buffers = move || (RefCell::new((Kmeans::new(palette_len), temp_buf(width), temp_buf(width), temp_buf(width))));
-
-    let tls_tmp1 = tls.get_or(per_thread_buffers);
-    let mut tls_tmp = tls_tmp1.borrow_mut();
-
-    let input_rows = image.px.rows_iter(&mut tls_tmp.1)?;
-    let background = background.map(|bg| bg.px.rows_iter(&mut tls_tmp.1)).transpose()?;
-    drop(tls_tmp);
+    let input_rows = image.px.rows_iter(scratch_row(&mut scratch.temp_row, width))?;
+    let background = background.map(|bg| bg.px.rows_iter(scratch_row(&mut scratch.temp_row, width))).transpose()?;
+    let scratch = &*scratch;
 
-    let remapping_error = output_pixels.rows_mut().enumerate().par_bridge().map(|(row, output_pixels_row)| {
-        let mut remapping_error = 0.;
-        let (kmeans, temp_row, temp_row_f, temp_row_f_bg) = &mut *tls.get_or(per_thread_buffers).borrow_mut();
+    let row_progress = RowProgress::new(height, progress);
+    let mut chunks = Vec::new();
+    // every band starts at a chunk boundary
+    let band_height = output.band_height().min(height).next_multiple_of(ROWS_PER_CHUNK);
+    for band in bands(height, band_height) {
+        let first_chunk = band.start / ROWS_PER_CHUNK;
+        let mut rows = output.band_mut(band.clone());
+        // Each piece of work rayon splits the band into borrows buffers for all of its chunks.
+        // Chunks have a fixed size and are merged in order, so that sums (and the refined palette) don't depend on scheduling of threads.
+        chunks.extend(rows.par_chunks_mut(ROWS_PER_CHUNK).enumerate().fold(|| (scratch.take_thread_scratch(), Vec::new()), |(mut t, mut done), (chunk, rows)| {
+            let chunk = first_chunk + chunk;
+            let mut remapping_error = 0.;
+            let mut kmeans = Kmeans::new(palette_len);
+            // the cache starts empty for every chunk, so that results don't depend on which thread got which rows
+            t.color_cache.reset(options.cache_colors);
 
-        let output_pixels_row = &mut output_pixels_row[..width];
-        let row_pixels = &input_rows.row_f2(temp_row, temp_row_f, row)[..width];
-        let bg_pixels = if let Some(background) = &background  {
-            &background.row_f2(temp_row, temp_row_f_bg, row)[..width]
-        } else { &[] };
+            for (row, output_pixels_row) in (chunk * ROWS_PER_CHUNK..).zip(rows.iter_mut()) {
+                if !row_progress.next_row() {
+                    break;
+                }
+                let temp_row = scratch_row(&mut t.temp_row, width);
+                let output_pixels_row = &mut output_pixels_row[..width];
+                let row_pixels = &input_rows.row_f2(temp_row, scratch_row(&mut t.temp_row_f, width), row)[..width];
+                let bg_pixels = if let Some(background) = &background  {
+                    &background.row_f2(temp_row, scratch_row(&mut t.temp_row_f_bg, width), row)[..width]
+                } else { &[] };
 
-        let mut last_match = 0;
-        for (col, (inp, out)) in row_pixels.iter().zip(output_pixels_row).enumerate() {
-            let (idx, mut diff) = n.search(inp, last_match);
-            last_match = idx;
-            if !bg_pixels.is_empty() {
-                let bg_diff = bg_pixels[col].diff(&colors[last_match as usize]);
-                if bg_diff <= diff {
-                    diff = bg_diff;
-                    last_match = transparent_index as PalIndex;
+                let mut last_match = 0;
+                for (col, (inp, out)) in row_pixels.iter().zip(output_pixels_row).enumerate() {
+                    let snapped;
+                    let inp = if alpha_levels >= 2 {
+                        // nearest color in premultiplied space isn't always at the nearest alpha level
+                        snapped = snap_alpha(inp, alpha_levels);
+                        &snapped
+                    } else { inp };
+                    let (idx, mut diff) = match t.color_cache.get(inp) {
+                        Some(cached) => cached,
+                        None => {
+                            let (idx, diff) = match protected_match(&protected, inp) {
+                                Some(idx) => (idx, inp.diff(&colors[idx as usize])),
+                                None => match exact.get(inp) {
+                                    Some(idx) => (idx, 0.),
+                                    None => n.search(inp, last_match),
+                                },
+                            };
+                            t.color_cache.insert(*inp, idx, diff);
+                            (idx, diff)
+                        },
+                    };
+                    last_match = idx;
+                    if !bg_pixels.is_empty() {
+                        let bg_diff = bg_pixels[col].diff(&colors[last_match as usize]);
+                        if bg_diff <= diff {
+                            diff = bg_diff;
+                            last_match = transparent_index as PalIndex;
+                        }
+                    }
+                    out.write(last_match);
+                    remapping_error += diff as f64;
+                    // with alpha weighting, garbage under transparent pixels doesn't fade visible colors
+                    if last_match as i16 != transparent_index && !(options.alpha_weighted && inp.a <= 0.) {
+                        kmeans.update_color(*inp, 1., last_match);
+                    }
                 }
             }
-            out.write(last_match);
-            remapping_error += diff as f64;
-            if last_match as i16 != transparent_index {
-                kmeans.update_color(*inp, 1., last_match);
-            }
+            done.push((chunk, remapping_error, kmeans));
+            (t, done)
+        }).map(|(t, done)| {
+            scratch.return_thread_scratch(t);
+            done
+        }).reduce(Vec::new, |mut a, b| { a.extend(b); a }));
+        drop(rows);
+        // rows of an aborted band haven't all been written
+        if row_progress.is_aborted() {
+            break;
         }
-        remapping_error
-    })
-    .sum::<f64>();
+        output.band_done(band);
+    }
 
-    if let Some(kmeans) = tls.into_iter()
-        .map(|t| RefCell::into_inner(t).0)
-        .reduce(Kmeans::merge) { kmeans.finalize(palette); }
+    row_progress.finish()?;
+
+    chunks.sort_unstable_by_key(|&(chunk, ..)| chunk);
+    let mut remapping_error = 0.;
+    let mut kmeans = Kmeans::new(palette_len);
+    for (_, chunk_error, chunk_kmeans) in &chunks {
+        remapping_error += chunk_error;
+        kmeans.add(chunk_kmeans);
+    }
+    kmeans.finalize(palette);
 
-    let remapping_error = remapping_error / (image.px.width * image.px.height) as f64;
-    Ok((remapping_error, unsafe { output_pixels.assume_init() }))
+    let remapping_error = remapping_error / (image.width() * image.height()) as f64;
+    Ok(remapping_error)
+}
+
+/// Keeps colors that are in the palette, and remaps other colors as `fallback` says, without dithering (see `Attributes::set_label_mode`)
+pub(crate) fn remap_to_palette_labels(input_image: &mut Image, output: &mut dyn RowSink, palette: &PalF, fallback: LabelFallback, gamma: f64) -> Result<(), liq_error> {
+    /// Colors converted from the same 8-bit values may differ by rounding
+    const EXACT_MATCH: f32 = 1. / 256. / 256. / 16.;
+
+    let width = input_image.width();
+    let height = input_image.height();
+    let n = Nearest::new(palette);
+    let fallback_index = match fallback {
+        LabelFallback::Nearest => None,
+        LabelFallback::Color(color) => Some(n.search(&f_pixel::from_rgba(&gamma_lut(gamma), color), 0).0),
+    };
+    let mut temp_row = temp_buf(width);
+    let mut rows = input_image.px.rows_iter(&mut temp_row)?;
+    let mut last_match = 0;
+    for band in bands(height, output.band_height()) {
+        for (row, output_pixels_row) in band.clone().zip(output.band_mut(band.clone())) {
+            let row_pixels = rows.row_f(&mut temp_row, row).get(..width).ok_or(LIQ_BUFFER_TOO_SMALL)?;
+            for (out, px) in output_pixels_row.iter_mut().zip(row_pixels) {
+                let (idx, diff) = n.search(px, last_match);
+                last_match = idx;
+                out.write(match fallback_index {
+                    Some(fallback_index) if diff > EXACT_MATCH => fallback_index,
+                    _ => idx,
+                });
+            }
+        }
+        output.band_done(band);
+    }
+    Ok(())
+}
+
+/// Remaps each pixel to the palette color nearest in a user-supplied metric, without dithering (see `Remapper::with_metric`)
+pub(crate) fn remap_to_palette_metric(image: &mut Image, mut output_pixels: RowBitmapMut<'_, MaybeUninit<PalIndex>>, palette: &[RGBA], metric: &Metric) -> Result<(), liq_error> {
+    let width = image.width();
+    let mut nearest = MetricNearest::new(palette, metric);
+    let rows = image.px.rgba_rows_iter()?;
+    let mut temp_row = temp_buf(width);
+    for (row, output_pixels_row) in output_pixels.rows_mut().enumerate() {
+        let row_pixels = rows.row_rgba(&mut temp_row, row).get(..width).ok_or(LIQ_BUFFER_TOO_SMALL)?;
+        for (out, &px) in output_pixels_row.iter_mut().zip(row_pixels) {
+            out.write(nearest.search(px));
+        }
+    }
+    Ok(())
 }
 
-fn get_dithered_pixel(dither_level: f32, max_dither_error: f32, thiserr: f_pixel, px: f_pixel) -> f_pixel {
+pub(crate) fn get_dithered_pixel(dither_level: f32, max_dither_error: f32, thiserr: f_pixel, px: f_pixel) -> f_pixel {
     let s = thiserr.0 * dither_level;
     // This prevents gaudy green pixels popping out of the blue (or red or black! ;)
     let dither_error = s.r * s.r + s.g * s.g + s.b * s.b + s.a * s.a;
@@ -133,125 +810,326 @@ fn get_dithered_pixel(dither_level: f32, max_dither_error: f32, thiserr: f_pixel
 
 /// Uses edge/noise map to apply dithering only to flat areas. Dithering on edges creates jagged lines, and noisy areas are "naturally" dithered.
 ///
-///  If output_image_is_remapped is true, only pixels noticeably changed by error diffusion will be written to output image.
+/// `undithered` are indices of the image remapped without dithering (or empty), which are used as the first guesses of nearest colors.
+///
+/// `dither_state` continues dithering from where the image above has ended, and receives the state at the end of this image (for remapping in strips or tiles).
 #[inline(never)]
-pub(crate) fn remap_to_palette_floyd(input_image: &mut Image, mut output_pixels: RowBitmapMut<'_, MaybeUninit<u8>>, quant: &QuantizationResult, max_dither_error: f32, output_image_is_remapped: bool) -> Result<(), liq_error> {
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn remap_to_palette_floyd(input_image: &mut Image, output: &mut dyn RowSink, quant: &QuantizationResult, options: &RemapOptions, max_dither_error: f32, undithered: &[PalIndex], scratch: &mut RemapScratch, dither_state: Option<DitherStateRef<'_>>) -> Result<(), liq_error> {
     let progress_stage1 = if quant.use_dither_map != DitherMapMode::None { 20 } else { 0 };
 
     let width = input_image.width();
     let height = input_image.height();
 
-    let mut temp_row = temp_buf(width);
+    let temp_row = scratch_row(&mut scratch.temp_row, width);
+    let input_lut = gamma_lut(input_image.gamma());
 
-    let dither_map = if quant.use_dither_map != DitherMapMode::None {
-        input_image.dither_map.as_deref().or(input_image.edges.as_deref()).unwrap_or(&[])
-    } else {
-        &[]
-    };
-    let mut input_image_iter = input_image.px.rows_iter(&mut temp_row)?;
-    let mut background = input_image.background.as_mut().map(|bg| bg.px.rows_iter(&mut temp_row)).transpose()?;
+    // a dither map that has been made already is used, even if this result wouldn't make one (e.g. for `set_undithered_flat_areas`)
+    let dither_map = input_image.maps.dither_strength(quant.use_dither_map != DitherMapMode::None);
+    let mut input_image_iter = input_image.px.rows_iter(temp_row)?;
+    let mut background = input_image.background.as_mut().map(|bg| bg.px.rows_iter(temp_row)).transpose()?;
 
     let errwidth = width + 2; // +2 saves from checking out of bounds access
-    let mut thiserr_data = vec![f_pixel::default(); errwidth * 2];
+    let thiserr_data = &mut scratch.error_rows;
+    thiserr_data.clear();
+    thiserr_data.resize(errwidth * 2, f_pixel::default());
     let (mut thiserr, mut nexterr) = thiserr_data.split_at_mut(errwidth);
+    let mut scan_forward = true;
+    if let Some(state) = &dither_state {
+        thiserr[1..=width].copy_from_slice(state.errors);
+        scan_forward = *state.scan_forward;
+    }
     let n = Nearest::new(&quant.palette);
     let palette = quant.palette.as_slice();
+    let protected = protected_palette_entries(&quant.protected_colors, &n, &input_lut);
+    let exact = ExactColors::new(palette, quant.palette_error == Some(0.));
+    let mix_limit = MixLimit::new(&quant.palette, quant.gamma, options.max_mix_distance);
 
     let transparent_index = if background.is_some() { n.search(&f_pixel::default(), 0).0 } else { 0 };
     if background.is_some() && palette[transparent_index as usize].a > MIN_OPAQUE_A {
         background = None;
     }
+    let (dither_level, variance_map) = match options.dither_level {
+        DitherLevel::Fixed(level) => (level, &[][..]),
+        DitherLevel::Auto => (1., input_image.maps.smoothness()),
+    };
     // response to this value is non-linear and without it any value < 0.8 would give almost no dithering
-    let mut base_dithering_level = (1. - (1. - quant.dither_level) * (1. - quant.dither_level)) * (15. / 16.); // prevent small errors from accumulating
+    let mut base_dithering_level = (1. - (1. - dither_level) * (1. - dither_level)) * (15. / 16.); // prevent small errors from accumulating
+    let [weight_next, weight_below_ahead, weight_below, weight_below_behind] = options.kernel.weights();
+    let noise_shaping = options.kernel.noise_shaping();
     if !dither_map.is_empty() {
         base_dithering_level *= 1. / 255.; // dither_map is in 0-255 scale
     }
-    let mut scan_forward = true;
-    let mut temp_row = temp_buf(width);
+    if !variance_map.is_empty() {
+        base_dithering_level *= 1. / 255.;
+    }
 
-    for (row, output_pixels_row) in output_pixels.rows_mut().enumerate() {
-        if quant.remap_progress(progress_stage1 as f32 + row as f32 * (100. - progress_stage1 as f32) / height as f32) {
-            return Err(LIQ_ABORTED);
-        }
-        nexterr.fill_with(f_pixel::default);
-        let mut col = if scan_forward { 0 } else { width - 1 };
-        let row_pixels = input_image_iter.row_f(&mut temp_row, row as _);
-        let bg_pixels = background.as_mut().map(|b| b.row_f(&mut temp_row, row as _)).unwrap_or(&[]);
-        let dither_map = dither_map.get(row * width .. row * width + width).unwrap_or(&[]);
-        let mut undithered_bg_used = 0;
-        let mut last_match = 0;
-        loop {
-            let mut dither_level = base_dithering_level;
-            if let Some(&l) = dither_map.get(col) {
-                dither_level *= l as f32;
+    for band in bands(height, output.band_height()) {
+        for (row, output_pixels_row) in band.clone().zip(output.band_mut(band.clone())) {
+            if quant.remap_progress(progress_stage1 as f32 + row as f32 * (100. - progress_stage1 as f32) / height as f32) {
+                return Err(LIQ_ABORTED);
             }
-            let input_px = row_pixels[col];
-            let spx = get_dithered_pixel(dither_level, max_dither_error, thiserr[col + 1], input_px);
-            let guessed_match = if output_image_is_remapped {
-                unsafe { output_pixels_row[col].assume_init() }
-            } else {
-                last_match
-            };
-            let (dither_index, dither_diff) = n.search(&spx, guessed_match);
-            last_match = dither_index;
-            let mut output_px = palette[last_match as usize];
-            if let Some(bg_pixel) = bg_pixels.get(col) {
-                // if the background makes better match *with* dithering, it's a definitive win
-                let bg_for_dither_diff = spx.diff(bg_pixel);
-                if bg_for_dither_diff <= dither_diff {
-                    output_px = *bg_pixel;
-                    last_match = transparent_index;
-                } else if undithered_bg_used > 1 {
-                    // the undithered fallback can cause artifacts when too many undithered pixels accumulate a big dithering error
-                    // so periodically ignore undithered fallback to prevent that
-                    undithered_bg_used = 0;
-                } else {
-                    // if dithering is not applied, there's a high risk of creating artifacts (flat areas, error accumulating badly),
-                    // OTOH poor dithering disturbs static backgrounds and creates oscilalting frames that break backgrounds
-                    // back and forth in two differently bad ways
-                    let max_diff = input_px.diff(bg_pixel);
-                    let dithered_diff = input_px.diff(&output_px);
-                    // if dithering is worse than natural difference between frames
-                    // (this rule dithers moving areas, but does not dither static areas)
-                    if dithered_diff > max_diff {
-                        // then see if an undithered color is closer to the ideal
-                        let guessed_px = palette[guessed_match as usize];
-                        let undithered_diff = input_px.diff(&guessed_px); // If dithering error is crazy high, don't propagate it that much
-                        if undithered_diff < max_diff {
-                            undithered_bg_used += 1;
-                            output_px = guessed_px;
-                            last_match = guessed_match;
+            nexterr.fill_with(f_pixel::default);
+            let mut col = if scan_forward { 0 } else { width - 1 };
+            let row_pixels = input_image_iter.row_f(temp_row, row as _).get(..width).ok_or(LIQ_BUFFER_TOO_SMALL)?;
+            let output_pixels_row = output_pixels_row.get_mut(..width).ok_or(LIQ_BUFFER_TOO_SMALL)?;
+            let bg_pixels = background.as_mut().map(|b| b.row_f(temp_row, row as _)).unwrap_or(&[]);
+            let dither_map = map_row(dither_map, row, width);
+            let variance_map = map_row(variance_map, row, width);
+            let undithered = map_row(undithered, row, width);
+            let mut undithered_bg_used = 0;
+            let mut last_match = 0;
+            let mut prev_err = ARGBF::default();
+            loop {
+                let mut dither_level = base_dithering_level;
+                if let Some(&l) = dither_map.get(col) {
+                    dither_level *= l as f32;
+                }
+                if let Some(&v) = variance_map.get(col) {
+                    dither_level *= v as f32;
+                }
+                let input_px = row_pixels[col];
+                let protected_index = protected_match(&protected, &input_px);
+                let mut spx = get_dithered_pixel(dither_level, max_dither_error, thiserr[col + 1], input_px);
+                let guessed_match = undithered.get(col).copied().unwrap_or(last_match);
+                let (dither_index, mut dither_diff) = match exact.get(&spx) {
+                    Some(idx) => (idx, 0.),
+                    None => n.search(&spx, guessed_match),
+                };
+                last_match = dither_index;
+                if let Some(limit) = &mix_limit {
+                    let undithered = n.search(&input_px, guessed_match).0;
+                    if !limit.allows(undithered, dither_index) {
+                        // the error that asked for a too different color is dropped, and the pixel isn't dithered
+                        last_match = undithered;
+                        spx = input_px;
+                        dither_diff = spx.diff(&palette[undithered as usize]);
+                    }
+                }
+                let mut output_px = *palette.get(last_match as usize).ok_or(LIQ_VALUE_OUT_OF_RANGE)?;
+                if let Some(bg_pixel) = bg_pixels.get(col) {
+                    // if the background makes better match *with* dithering, it's a definitive win
+                    let bg_for_dither_diff = spx.diff(bg_pixel);
+                    if bg_for_dither_diff <= dither_diff {
+                        output_px = *bg_pixel;
+                        last_match = transparent_index;
+                    } else if undithered_bg_used > 1 {
+                        // the undithered fallback can cause artifacts when too many undithered pixels accumulate a big dithering error
+                        // so periodically ignore undithered fallback to prevent that
+                        undithered_bg_used = 0;
+                    } else {
+                        // if dithering is not applied, there's a high risk of creating artifacts (flat areas, error accumulating badly),
+                        // OTOH poor dithering disturbs static backgrounds and creates oscilalting frames that break backgrounds
+                        // back and forth in two differently bad ways
+                        let max_diff = input_px.diff(bg_pixel);
+                        let dithered_diff = input_px.diff(&output_px);
+                        // if dithering is worse than natural difference between frames
+                        // (this rule dithers moving areas, but does not dither static areas)
+                        if dithered_diff > max_diff {
+                            // then see if an undithered color is closer to the ideal
+                            let guessed_px = *palette.get(guessed_match as usize).ok_or(LIQ_VALUE_OUT_OF_RANGE)?;
+                            let undithered_diff = input_px.diff(&guessed_px); // If dithering error is crazy high, don't propagate it that much
+                            if undithered_diff < max_diff {
+                                undithered_bg_used += 1;
+                                output_px = guessed_px;
+                                last_match = guessed_match;
+                            }
                         }
                     }
                 }
+                if let Some(protected_index) = protected_index {
+                    last_match = protected_index;
+                }
+                output_pixels_row[col].write(last_match);
+                let mut err = spx.0 - output_px.0;
+                // This prevents crazy geen pixels popping out of the blue (or red or black! ;)
+                if err.r * err.r + err.g * err.g + err.b * err.b + err.a * err.a > max_dither_error {
+                    err *= 0.75;
+                }
+                let high_pass = err - prev_err;
+                prev_err = err;
+                err += high_pass * noise_shaping;
+                if protected_index.is_some() {
+                    // protected colors are never dithered, and don't spread dithering error to their neighbors
+                    err = ARGBF::default();
+                }
+                if scan_forward {
+                    thiserr[col + 2].0 += err * weight_next;
+                    nexterr[col + 2].0 = err * weight_below_ahead;
+                    nexterr[col + 1].0 += err * weight_below;
+                    nexterr[col].0 += err * weight_below_behind;
+                } else {
+                    thiserr[col].0 += err * weight_next;
+                    nexterr[col + 2].0 += err * weight_below_behind;
+                    nexterr[col + 1].0 += err * weight_below;
+                    nexterr[col].0 = err * weight_below_ahead;
+                }
+                if scan_forward {
+                    col += 1;
+                    if col >= width {
+                        break;
+                    }
+                } else {
+                    if col == 0 {
+                        break;
+                    }
+                    col -= 1;
+                }
             }
-            output_pixels_row[col].write(last_match);
-            let mut err = spx.0 - output_px.0;
-            // This prevents crazy geen pixels popping out of the blue (or red or black! ;)
-            if err.r * err.r + err.g * err.g + err.b * err.b + err.a * err.a > max_dither_error {
-                err *= 0.75;
+            std::mem::swap(&mut thiserr, &mut nexterr);
+            scan_forward = !scan_forward;
+        }
+        output.band_done(band);
+    }
+    if let Some(state) = dither_state {
+        state.errors.copy_from_slice(&thiserr[1..=width]);
+        *state.scan_forward = scan_forward;
+    }
+    Ok(())
+}
+
+/// Remaps with `DitherKernel::Ordered`. Protected colors and pixels better left to the background aren't dithered.
+///
+/// `palette` is the final (rounded) palette.
+#[inline(never)]
+pub(crate) fn remap_to_palette_ordered(input_image: &mut Image, output: &mut dyn RowSink, quant: &QuantizationResult, palette: &PalF, options: &RemapOptions, scratch: &mut RemapScratch) -> Result<(), liq_error> {
+    let width = input_image.width();
+    let height = input_image.height();
+
+    let temp_row = scratch_row(&mut scratch.temp_row, width);
+    let input_lut = gamma_lut(input_image.gamma());
+    let mut input_image_iter = input_image.px.rows_iter(temp_row)?;
+    let mut background = input_image.background.as_mut().map(|bg| bg.px.rows_iter(temp_row)).transpose()?;
+
+    let mut plans = MixingPlans::new(palette, MixLimit::new(palette, quant.gamma, options.max_mix_distance));
+    let colors = palette.as_slice();
+    let protected = protected_palette_entries(&quant.protected_colors, &Nearest::new(palette), &input_lut);
+
+    let transparent_index = if background.is_some() { plans.nearest(&f_pixel::default()) } else { 0 };
+    if background.is_some() && colors[transparent_index as usize].a > MIN_OPAQUE_A {
+        background = None;
+    }
+    let (dither_level, variance_map) = match options.dither_level {
+        DitherLevel::Fixed(level) => (level, &[][..]),
+        DitherLevel::Auto => (1., input_image.maps.smoothness()),
+    };
+    let (dx, dy) = frame_offset(options.frame_index);
+
+    for band in bands(height, output.band_height()) {
+        for (row, output_pixels_row) in band.clone().zip(output.band_mut(band.clone())) {
+            if quant.remap_progress(row as f32 * 100. / height as f32) {
+                return Err(LIQ_ABORTED);
             }
-            if scan_forward {
-                thiserr[col + 2].0 += err * (7. / 16.);
-                nexterr[col + 2].0 = err * (1. / 16.);
-                nexterr[col + 1].0 += err * (5. / 16.);
-                nexterr[col].0 += err * (3. / 16.);
-            } else {
-                thiserr[col].0 += err * (7. / 16.);
-                nexterr[col + 2].0 += err * (3. / 16.);
-                nexterr[col + 1].0 += err * (5. / 16.);
-                nexterr[col].0 = err * (1. / 16.);
+            let row_pixels = input_image_iter.row_f(temp_row, row as _).get(..width).ok_or(LIQ_BUFFER_TOO_SMALL)?;
+            let output_pixels_row = output_pixels_row.get_mut(..width).ok_or(LIQ_BUFFER_TOO_SMALL)?;
+            let bg_pixels = background.as_mut().map(|b| b.row_f(temp_row, row as _)).unwrap_or(&[]);
+            let variance_map = map_row(variance_map, row, width);
+            for (col, (px, out)) in row_pixels.iter().zip(output_pixels_row).enumerate() {
+                let mut idx = match protected_match(&protected, px) {
+                    Some(idx) => idx,
+                    None => {
+                        let level = variance_map.get(col).map_or(dither_level, |&v| f32::from(v) / 255.);
+                        plans.plan(*px, level).index_at(col + dx, row + dy)
+                    },
+                };
+                if let Some(bg_pixel) = bg_pixels.get(col) {
+                    if px.diff(bg_pixel) <= px.diff(&colors[idx as usize]) {
+                        idx = transparent_index;
+                    }
+                }
+                out.write(idx);
             }
-            if scan_forward {
-                col += 1;
-                if col >= width {
-                    break;
+        }
+        output.band_done(band);
+    }
+    Ok(())
+}
+
+/// Remaps to colors of a device that has a fixed palette, such as a display panel or a terminal.
+///
+/// The device can't show transparency, so pixels are blended with the `background`. Dithering error is clamped to `max_dither_error`
+/// (a fraction of each channel's range), because colors outside of the device's gamut have errors that never go away,
+/// and would smear across large flat areas. Writes indices of `colors` plus `first_index`.
+pub(crate) fn remap_to_device_palette(image: &mut Image, output_buf: &mut [MaybeUninit<u8>], colors: &[RGBA], first_index: u8, background: RGBA, options: &RemapOptions, max_dither_error: f32) -> Result<(), liq_error> {
+    let width = image.width();
+    let output_buf = output_buf.get_mut(0..width * image.height()).ok_or(LIQ_BUFFER_TOO_SMALL)?;
+
+    let lut = gamma_lut(image.gamma());
+    let mut palette = PalF::new();
+    for &c in colors {
+        palette.push(f_pixel::from_rgba(&lut, c), PalPop::new(1.).to_fixed());
+    }
+    let palette_px = palette.as_slice();
+    let n = Nearest::new(&palette);
+    let mut mix_limit = MixLimit::new(&palette, image.gamma(), options.max_mix_distance);
+    let mut plans = if options.kernel == DitherKernel::Ordered { Some(MixingPlans::new(&palette, mix_limit.take())) } else { None };
+    let background = f_pixel::from_rgba(&lut, RGBA { a: 255, ..background });
+    let (dx, dy) = frame_offset(options.frame_index);
+
+    let max_error = ARGBF { a: 0., r: max_dither_error * LIQ_WEIGHT_R, g: max_dither_error * LIQ_WEIGHT_G, b: max_dither_error * LIQ_WEIGHT_B };
+    let [weight_next, weight_below_ahead, weight_below, weight_below_behind] = options.kernel.weights();
+    let noise_shaping = options.kernel.noise_shaping();
+    // device remappers only have fixed levels
+    let dither_level = match options.dither_level {
+        DitherLevel::Fixed(level) => level,
+        DitherLevel::Auto => 1.,
+    };
+
+    let mut temp_row = temp_buf(width);
+    let mut rows = image.px.rows_iter(&mut temp_row)?;
+    let errwidth = width + 2; // +2 saves from checking out of bounds access
+    let mut thiserr = vec![f_pixel::default(); errwidth];
+    let mut nexterr = vec![f_pixel::default(); errwidth];
+    let mut last_match = 0;
+    let mut scan_forward = true;
+
+    for (row, output_row) in output_buf.chunks_exact_mut(width).enumerate() {
+        let row_pixels = rows.row_f(&mut temp_row, row).get(..width).ok_or(LIQ_BUFFER_TOO_SMALL)?;
+        nexterr.fill_with(f_pixel::default);
+        let mut prev_err = ARGBF::default();
+        for i in 0..width {
+            let col = if scan_forward { i } else { width - 1 - i };
+            let px = row_pixels[col];
+            // premultiplied color over opaque background
+            let flat = f_pixel(px.0 + background.0 * (1. - px.a / LIQ_WEIGHT_A));
+            if let Some(plans) = &mut plans {
+                #[allow(clippy::unnecessary_cast)]
+                output_row[col].write(first_index + plans.plan(flat, dither_level).index_at(col + dx, row + dy) as u8);
+                continue;
+            }
+            let mut spx = f_pixel(flat.0 + thiserr[col + 1].0 * dither_level);
+            last_match = n.search(&spx, last_match).0;
+            if let Some(limit) = &mix_limit {
+                let undithered = n.search(&flat, last_match).0;
+                if !limit.allows(undithered, last_match) {
+                    // the error that asked for a too different color is dropped
+                    last_match = undithered;
+                    spx = flat;
                 }
+            }
+            #[allow(clippy::unnecessary_cast)] // PalIndex is u16 with the large-palettes feature
+            output_row[col].write(first_index + last_match as u8);
+
+            let err = spx.0 - palette_px[last_match as usize].0;
+            let err = ARGBF {
+                a: 0.,
+                r: err.r.clamp(-max_error.r, max_error.r),
+                g: err.g.clamp(-max_error.g, max_error.g),
+                b: err.b.clamp(-max_error.b, max_error.b),
+            };
+            let high_pass = err - prev_err;
+            prev_err = err;
+            let err = err + high_pass * noise_shaping;
+            if scan_forward {
+                thiserr[col + 2].0 += err * weight_next;
+                nexterr[col + 2].0 = err * weight_below_ahead;
+                nexterr[col + 1].0 += err * weight_below;
+                nexterr[col].0 += err * weight_below_behind;
             } else {
-                if col == 0 {
-                    break;
-                }
-                col -= 1;
+                thiserr[col].0 += err * weight_next;
+                nexterr[col + 2].0 += err * weight_below_behind;
+                nexterr[col + 1].0 += err * weight_below;
+                nexterr[col].0 = err * weight_below_ahead;
             }
         }
         std::mem::swap(&mut thiserr, &mut nexterr);
@@ -262,7 +1140,8 @@ pub(crate) fn remap_to_palette_floyd(input_image: &mut Image, mut output_pixels:
 
 impl Remapped {
     #[allow(clippy::or_fun_call)]
-    pub fn new(result: &QuantizationResult, image: &mut Image, mut output_pixels: RowBitmapMut<'_, MaybeUninit<u8>>) -> Result<Self, liq_error> {
+    /// If `refine_palette` is false, the palette of the result is used as-is, without K-means adjustments from this image
+    pub fn new(result: &QuantizationResult, image: &mut Image, output: &mut dyn RowSink, options: &RemapOptions, refine_palette: bool, scratch: &mut RemapScratch, dither_state: Option<DitherStateRef<'_>>) -> Result<Self, liq_error> {
         let mut palette = result.palette.clone();
         let progress_stage1 = if result.use_dither_map != DitherMapMode::None { 20 } else { 0 };
 
@@ -271,53 +1150,131 @@ impl Remapped {
             return Err(LIQ_ABORTED);
         }
 
+        // the same entry that remapping uses for pixels that are better left to the background
+        let background_index = if image.background.is_some() {
+            let idx = Nearest::new(&palette).search(&f_pixel::default(), 0).0;
+            Some(idx).filter(|&idx| palette.as_slice()[idx as usize].a <= MIN_OPAQUE_A)
+        } else { None };
+
         let mut palette_error = result.palette_error;
+        // palette made directly from the histogram has all colors of the image (unless it's a different image)
+        let input_fits_palette = palette_error == Some(0.);
         let int_palette;
-        if result.dither_level == 0. {
-            int_palette = Self::make_int_palette(&mut palette, result.gamma, posterize);
-            palette_error = Some(remap_to_palette(image, &mut output_pixels, &mut palette)?.0);
+        if let Some(fallback) = palette.label_fallback() {
+            // labels are never mixed by dithering, nor adjusted to the image
+            int_palette = Self::make_int_palette(&mut palette, result.gamma, result.alpha_encoding, posterize);
+            output.set_palette(&int_palette);
+            remap_to_palette_labels(image, output, &palette, fallback, result.gamma)?;
+        } else if options.dither_level == DitherLevel::Fixed(0.) {
+            int_palette = Self::make_int_palette(&mut palette, result.gamma, result.alpha_encoding, posterize);
+            output.set_palette(&int_palette);
+            let progress = |done: f32| result.remap_progress(progress_stage1 as f32 * 0.25 + done * (100. - progress_stage1 as f32 * 0.25));
+            palette_error = Some(remap_to_palette(image, output, &mut palette, &UnditheredOptions::new(result, input_fits_palette), &progress, scratch)?);
+        } else if options.kernel == DitherKernel::Ordered {
+            // patterns are fixed, so they can't be adjusted for edges, and there's no K-means iteration either
+            int_palette = Self::make_int_palette(&mut palette, result.gamma, result.alpha_encoding, posterize);
+            output.set_palette(&int_palette);
+            remap_to_palette_ordered(image, output, result, &palette, options, scratch)?;
         } else {
-            let is_image_huge = (image.px.width * image.px.height) > 2000 * 2000;
-            let allow_dither_map = result.use_dither_map == DitherMapMode::Always || (!is_image_huge && result.use_dither_map != DitherMapMode::None);
-            let generate_dither_map = allow_dither_map && (image.edges.is_some() && image.dither_map.is_none());
-            if generate_dither_map {
-                // If dithering (with dither map) is required, this image is used to find areas that require dithering
-                let (tmp_re, row_pointers_remapped) = remap_to_palette(image, &mut output_pixels, &mut palette)?;
-                palette_error = Some(tmp_re);
-                image.update_dither_map(&row_pointers_remapped, &mut palette);
-            }
-            let output_image_is_remapped = generate_dither_map;
+            #[cfg(feature = "dither-maps")]
+            let prepass = Self::dither_map_prepass(result, image, &mut palette, &mut palette_error, input_fits_palette, scratch)?;
+            #[cfg(feature = "dither-maps")]
+            let undithered = prepass.as_ref().map_or(&[][..], |p| &p.indices);
+            #[cfg(not(feature = "dither-maps"))]
+            let undithered = &[];
 
-            if result.remap_progress(progress_stage1 as f32 * 0.5) {
+            if result.remap_progress(progress_stage1 as f32 * 0.75) {
                 return Err(LIQ_ABORTED);
             }
 
+            if !refine_palette {
+                palette = result.palette.clone();
+            }
             // remapping above was the last chance to do K-Means iteration, hence the final palette is set after remapping
-            int_palette = Self::make_int_palette(&mut palette, result.gamma, posterize);
+            int_palette = Self::make_int_palette(&mut palette, result.gamma, result.alpha_encoding, posterize);
+            output.set_palette(&int_palette);
             let max_dither_error = (palette_error.unwrap_or(quality_to_mse(80)) * 2.4).max(quality_to_mse(35)) as f32;
-            remap_to_palette_floyd(image, output_pixels, result, max_dither_error, output_image_is_remapped)?;
+            let remapped = remap_to_palette_floyd(image, output, result, options, max_dither_error, undithered, scratch, dither_state);
+            #[cfg(feature = "dither-maps")]
+            {
+                image.maps.undithered_prepass = prepass;
+            }
+            remapped?;
         }
 
+        let transparent_index = background_index
+            .or_else(|| int_palette.as_slice().iter().position(|c| c.a == 0).map(|idx| idx as PalIndex));
         Ok(Self {
-            int_palette, palette_error,
+            int_palette, palette_error, transparent_index,
         })
     }
 
+    /// Remaps the image without dithering, to find edges between the palette colors, which are dithered less (if the result uses a dither map).
+    ///
+    /// Returns the pre-pass taken out of the image (to be put back after dithering), if `palette` has been refined for the image by it.
+    #[cfg(feature = "dither-maps")]
+    fn dither_map_prepass(result: &QuantizationResult, image: &mut Image, palette: &mut PalF, palette_error: &mut Option<f64>, input_fits_palette: bool, scratch: &mut RemapScratch) -> Result<Option<Box<UnditheredPrepass>>, liq_error> {
+        let progress_stage1 = if result.use_dither_map != DitherMapMode::None { 20 } else { 0 };
+        let is_image_huge = image.width() * image.height() > 2000 * 2000;
+        let allow_dither_map = result.use_dither_map == DitherMapMode::Always || (!is_image_huge && result.use_dither_map != DitherMapMode::None);
+        // only the dithering level may have changed since the last remapping of this image
+        if image.maps.undithered_prepass.as_deref().is_some_and(|p| allow_dither_map && image.maps.dither_map.is_some() && p.source == result.palette.as_slice()) {
+            let prepass = image.maps.undithered_prepass.take();
+            if let Some(prepass) = &prepass {
+                *palette = prepass.palette.clone();
+                *palette_error = Some(prepass.palette_error);
+            }
+            return Ok(prepass);
+        }
+        if !allow_dither_map || image.maps.edges.is_none() || image.maps.dither_map.is_some() {
+            return Ok(None);
+        }
+        // If dithering (with dither map) is required, this image is used to find areas that require dithering
+        let width = image.width();
+        let len = width * image.height();
+        let mut indices: Vec<PalIndex> = FallibleVec::try_with_capacity(len).map_err(|_| LIQ_OUT_OF_MEMORY)?;
+        let uninit_slice = unsafe { std::slice::from_raw_parts_mut(indices.as_mut_ptr().cast::<MaybeUninit<PalIndex>>(), len) };
+        let mut output_pixels = RowBitmapMut::new_contiguous(uninit_slice, width);
+        let progress = |done: f32| result.remap_progress(progress_stage1 as f32 * (0.25 + done * 0.25));
+        let tmp_re = remap_to_palette(image, &mut output_pixels, palette, &UnditheredOptions::new(result, input_fits_palette), &progress, scratch)?;
+        // just remapped
+        let row_pointers_remapped = unsafe { output_pixels.assume_init() };
+        *palette_error = Some(tmp_re);
+        let progress = |done: f32| result.remap_progress(progress_stage1 as f32 * (0.5 + done * 0.25));
+        image.update_dither_map(&row_pointers_remapped, palette, &progress)?;
+        drop(output_pixels);
+        unsafe { indices.set_len(len) };
+        Ok(Some(Box::new(UnditheredPrepass {
+            source: result.palette.as_slice().to_vec(),
+            palette: palette.clone(),
+            palette_error: tmp_re,
+            indices,
+        })))
+    }
+
     /// Also rounds the input pal
-    pub fn make_int_palette(palette: &mut PalF, gamma: f64, posterize: u8) -> Palette {
+    /// If the palette has been made for stretched levels, the colors are mapped back to the original levels.
+    /// Alpha is rounded to the palette's alpha levels, if it has them.
+    pub fn make_int_palette(palette: &mut PalF, gamma: f64, alpha_encoding: AlphaEncoding, posterize: u8) -> Palette {
         let mut int_palette = Palette {
             count: palette.len() as _,
-            entries: [Default::default(); 256],
+            entries: [Default::default(); MAX_COLORS],
         };
         let lut = gamma_lut(gamma);
+        let alpha_lut = alpha_encoding.lut(gamma);
+        let levels = palette.levels().copied();
+        let alpha_levels = palette.alpha_levels();
         for ((f_color, f_pop), int_pal) in palette.iter_mut().zip(int_palette.as_mut_slice()) {
-            let mut px = f_color.to_rgb(gamma)
-                .map(move |c| posterize_channel(c, posterize));
-            *f_color = f_pixel::from_rgba(&lut, px);
+            let mut px = f_color.to_rgb(gamma);
+            alpha_encoding.encode(f_color, &mut px, gamma);
+            let px = levels.map_or(px, |l| l.unstretch(px));
+            let mut px = px.map(move |c| posterize_channel(c, posterize));
+            px.a = alpha_to_level(px.a, alpha_levels);
+            *f_color = f_pixel::from_rgba_alpha_lut(&lut, alpha_lut.as_ref(), levels.map_or(px, |l| l.stretch(px)));
             if px.a == 0 && !f_pop.is_fixed() {
-                px.r = 71u8;
-                px.g = 112u8;
-                px.b = 76u8;
+                px.r = posterize_channel(71u8, posterize);
+                px.g = posterize_channel(112u8, posterize);
+                px.b = posterize_channel(76u8, posterize);
             }
             *int_pal = px;
         }
@@ -329,6 +1286,32 @@ pub(crate) fn mse_to_standard_mse(mse: f64) -> f64 {
     (mse * 65536. / 6.) / LIQ_WEIGHT_MSE // parallelized dither map might speed up floyd remapping
 }
 
+pub(crate) fn standard_mse_to_mse(standard_mse: f64) -> f64 {
+    (standard_mse * LIQ_WEIGHT_MSE) * 6. / 65536.
+}
+
+/// Rounds alpha to the nearest of `levels` evenly spaced values (0 and 255 included). 0 levels keep alpha unchanged.
+#[inline]
+pub(crate) fn alpha_to_level(a: u8, levels: u8) -> u8 {
+    if levels < 2 {
+        return a;
+    }
+    let steps = u32::from(levels - 1);
+    let level = (u32::from(a) * steps + 127) / 255;
+    ((level * 255 + steps / 2) / steps) as u8
+}
+
+/// Moves alpha of the pixel to the nearest of `levels` evenly spaced values, keeping its color. Like `alpha_to_level`, for the input.
+#[inline]
+fn snap_alpha(px: &f_pixel, levels: u8) -> f_pixel {
+    let steps = f32::from(levels - 1);
+    let a = ((px.a / LIQ_WEIGHT_A).min(1.) * steps).round() / steps * LIQ_WEIGHT_A;
+    if a <= 0. || px.a <= 0. {
+        return f_pixel::default();
+    }
+    f_pixel(px.0 * (a / px.a))
+}
+
 #[inline]
 fn posterize_channel(color: u8, bits: u8) -> u8 {
     if bits == 0 {
@@ -342,5 +1325,96 @@ fn posterize_channel(color: u8, bits: u8) -> u8 {
 fn send() {
     fn is_send<T: Send>() {}
 
-    is_send::<RowBitmapMut<'_, MaybeUninit<u8>>>();
+    is_send::<RowBitmapMut<'_, MaybeUninit<PalIndex>>>();
+}
+
+#[test]
+fn color_cache_hits() {
+    let mut cache = ColorCache::default();
+    cache.reset(true);
+    let px = f_pixel(ARGBF { a: 1., r: 0.5, g: 0.25, b: 0. });
+    assert_eq!(None, cache.get(&px));
+    cache.insert(px, 3, 0.5);
+    for _ in 0..2000 {
+        assert_eq!(Some((3, 0.5)), cache.get(&px));
+    }
+    assert!(cache.enabled);
+    assert_eq!(2000, cache.hits);
+
+    // only the exact color is a hit
+    let other = f_pixel(ARGBF { a: 1., r: 0.25, g: 0.5, b: 0. });
+    assert_eq!(None, cache.get(&other));
+
+    cache.reset(false);
+    cache.insert(px, 3, 0.5);
+    assert_eq!(None, cache.get(&px));
+    assert!(cache.entries.is_empty());
+}
+
+#[test]
+fn color_cache_gives_up() {
+    let mut cache = ColorCache::default();
+    cache.reset(true);
+    let px = |i: u32| f_pixel(ARGBF { a: 1., r: i as f32 / 4096., g: 0., b: 0. });
+    // every 4th lookup is a hit, so it's given up on at the first miss after the trial
+    for i in 0..=CACHE_TRIAL_LOOKUPS {
+        let px = px(if i % 4 == 3 { i - 1 } else { i });
+        if cache.get(&px).is_none() {
+            cache.insert(px, 1, 0.);
+        }
+    }
+    assert!(cache.hits < CACHE_TRIAL_LOOKUPS / 2);
+    assert!(!cache.enabled);
+    let entries = cache.entries.len();
+    assert_eq!(None, cache.get(&px(0)));
+    cache.insert(px(CACHE_TRIAL_LOOKUPS), 1, 0.);
+    assert_eq!(entries, cache.entries.len());
+
+    // half of lookups being hits is enough
+    cache.reset(true);
+    for i in 0..CACHE_TRIAL_LOOKUPS * 2 {
+        let px = px(i / 2 * 2);
+        if cache.get(&px).is_none() {
+            cache.insert(px, 1, 0.);
+        }
+    }
+    assert!(cache.enabled);
+}
+
+#[test]
+fn reclaimed_buffers() {
+    let attr = crate::Attributes::new();
+    let (width, height) = (64, 64);
+    let pixels: Vec<RGBA> = (0..width * height).map(|i| RGBA::new((i % width * 4) as u8, (i / width * 4) as u8, (i * 7 % 256) as u8, 255)).collect();
+    let remap = |scratch: &mut RemapScratch| {
+        let mut hist = Histogram::new(&attr);
+        hist.reuse_buffers(scratch);
+        let mut img = attr.new_image(&pixels[..], width, height, 0.).unwrap();
+        img.reuse_buffers(scratch);
+        hist.add_image(&attr, &mut img).unwrap();
+        let mut res = hist.quantize(&attr).unwrap();
+        res.set_dithering_level(1.).unwrap();
+        let mut out = vec![MaybeUninit::uninit(); width * height];
+        res.remap_into_with_scratch(&mut img, &mut out, scratch).unwrap();
+        scratch.reclaim_image(img);
+        scratch.reclaim_histogram(hist);
+        out.into_iter().map(|px| unsafe { px.assume_init() }).collect::<Vec<PalIndex>>()
+    };
+    let kept_maps = |scratch: &RemapScratch| {
+        let mut ptrs: Vec<_> = scratch.maps.iter().map(|m| m.as_ptr()).collect();
+        ptrs.sort();
+        ptrs
+    };
+
+    let mut scratch = RemapScratch::new();
+    let first = remap(&mut scratch);
+    let maps = kept_maps(&scratch);
+    assert_eq!(cfg!(feature = "dither-maps"), !maps.is_empty());
+    let color_hash = scratch.color_hash.as_ref().map(|h| (h.capacity(), h.len())).unwrap();
+    assert!(color_hash.0 > 0 && color_hash.1 == 0);
+
+    assert_eq!(first, remap(&mut scratch));
+    // the second image has used the same buffers, and given them back
+    assert_eq!(maps, kept_maps(&scratch));
+    assert!(scratch.color_hash.is_some());
 }
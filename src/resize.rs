@@ -0,0 +1,84 @@
+//! Downscaling in linear light, so that averages of light and dark pixels don't come out too dark
+
+/// How pixels are averaged when an image is downscaled with [`Image::resized`](crate::Image::resized)
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum ResizeFilter {
+    /// Plain average of the pixels covered by each new pixel. Fastest.
+    Box,
+    /// Weighted average that also includes some of the neighboring pixels. Smoother, with less aliasing.
+    Triangle,
+}
+
+impl ResizeFilter {
+    /// Radius of the filter, in pixels of the downscaled image
+    fn support(self) -> f32 {
+        match self {
+            Self::Box => 0.5,
+            Self::Triangle => 1.,
+        }
+    }
+
+    fn weight(self, x: f32) -> f32 {
+        match self {
+            Self::Box => if x.abs() <= 0.5 { 1. } else { 0. },
+            Self::Triangle => (1. - x.abs()).max(0.),
+        }
+    }
+
+    /// First source pixel, and weights of source pixels from it, for every pixel of the downscaled axis
+    fn contributions(self, src_len: usize, dst_len: usize) -> Vec<(usize, Vec<f32>)> {
+        let scale = src_len as f32 / dst_len as f32;
+        let radius = self.support() * scale;
+        (0..dst_len).map(|i| {
+            let center = (i as f32 + 0.5) * scale;
+            let start = (center - radius).floor().max(0.) as usize;
+            let end = ((center + radius).ceil() as usize).min(src_len);
+            let mut weights: Vec<f32> = (start..end).map(|j| self.weight((j as f32 + 0.5 - center) / scale)).collect();
+            let sum: f32 = weights.iter().sum();
+            weights.iter_mut().for_each(|w| *w /= sum);
+            (start, weights)
+        }).collect()
+    }
+}
+
+/// Downscales premultiplied linear RGBA pixels. Rows of the source are read one by one, in order, with `source_row(y, row)`,
+/// so that the full-size image never has to be converted at once.
+pub(crate) fn downscale(width: usize, height: usize, new_width: usize, new_height: usize, filter: ResizeFilter, mut source_row: impl FnMut(usize, &mut [[f32; 4]])) -> Vec<[f32; 4]> {
+    fn add(sum: &mut [f32; 4], px: &[f32; 4], weight: f32) {
+        for (s, c) in sum.iter_mut().zip(px) {
+            *s += c * weight;
+        }
+    }
+
+    let horizontal = filter.contributions(width, new_width);
+    let vertical = filter.contributions(height, new_height);
+    let mut row = vec![[0.; 4]; width];
+    let mut narrow = vec![[0.; 4]; new_width];
+    let mut out = vec![[0.; 4]; new_width * new_height];
+    // rows of the output that the current source row contributes to start here. Ranges of source rows only move forward.
+    let mut first_out_row = 0;
+    for y in 0..height {
+        while matches!(vertical.get(first_out_row), Some((start, weights)) if start + weights.len() <= y) {
+            first_out_row += 1;
+        }
+
+        source_row(y, &mut row);
+        for (sum, (start, weights)) in narrow.iter_mut().zip(&horizontal) {
+            *sum = [0.; 4];
+            for (px, &w) in row[*start..].iter().zip(weights) {
+                add(sum, px, w);
+            }
+        }
+
+        for ((start, weights), out_row) in vertical.iter().zip(out.chunks_exact_mut(new_width)).skip(first_out_row) {
+            let w = match y.checked_sub(*start) {
+                Some(offset) => weights.get(offset).copied().unwrap_or(0.),
+                None => break,
+            };
+            for (sum, px) in out_row.iter_mut().zip(&narrow) {
+                add(sum, px, w);
+            }
+        }
+    }
+    out
+}
@@ -1,13 +1,27 @@
 use crate::error::*;
-use crate::pal::{f_pixel, gamma_lut, RGBA};
-use crate::seacow::{liq_ownership, SeaCow};
+use crate::levels::Levels;
+use crate::pal::{f_pixel, gamma_lut, AlphaEncoding, MIN_OPAQUE_A, RGBA};
+#[cfg(feature = "capi")]
+use crate::seacow::liq_ownership;
+use crate::seacow::SeaCow;
+#[cfg(feature = "yuv")]
+use crate::yuv::{yuv_row, YuvPlanes, YuvToRgb};
 use crate::LIQ_HIGH_MEMORY_LIMIT;
 use std::mem::MaybeUninit;
 
 pub(crate) type RowCallback = dyn Fn(&mut [MaybeUninit<RGBA>], usize) + Send + Sync;
 
 pub(crate) enum PixelsSource<'pixels, 'rows> {
-    Pixels { rows: SeaCow<'rows, *const RGBA>, pixels: Option<SeaCow<'pixels, RGBA>> },
+    Pixels {
+        rows: SeaCow<'rows, *const RGBA>,
+        /// Keeps the pixels that `rows` point to alive. Only the C API reads it, to take ownership.
+        #[cfg_attr(not(feature = "capi"), allow(dead_code))]
+        pixels: Option<SeaCow<'pixels, RGBA>>,
+    },
+    /// Separate `width * height` planes of each channel, interleaved one row at a time. Opaque if there's no alpha plane.
+    Planar { r: &'pixels [u8], g: &'pixels [u8], b: &'pixels [u8], a: Option<&'pixels [u8]> },
+    #[cfg(feature = "yuv")]
+    Yuv { planes: YuvPlanes<'pixels>, conversion: YuvToRgb },
     Callback(Box<RowCallback>),
 }
 
@@ -17,6 +31,11 @@ pub(crate) struct DynamicRows<'pixels, 'rows> {
     f_pixels: Option<Box<[f_pixel]>>,
     pixels: PixelsSource<'pixels, 'rows>,
     pub(crate) gamma: f64,
+    pub(crate) alpha_encoding: AlphaEncoding,
+    /// Stretch applied when pixels are converted to `f_pixel`
+    levels: Option<Levels>,
+    /// Color and per-channel tolerance of pixels that are made transparent, see `Image::set_chroma_key`
+    chroma_key: Option<(RGBA, u8)>,
 }
 
 pub(crate) struct DynamicRowsIter<'parent, 'pixels, 'rows> {
@@ -30,10 +49,11 @@ impl<'a, 'pixels, 'rows> DynamicRowsIter<'a, 'pixels, 'rows> {
             Some(pixels) => &pixels[self.px.width as usize * row as usize..],
             None => {
                 let lut = gamma_lut(self.px.gamma);
+                let alpha_lut = self.px.alpha_encoding.lut(self.px.gamma);
                 let row_pixels = self.px.row_rgba(temp_row, row);
 
                 let t = self.temp_f_row.as_mut().unwrap();
-                DynamicRows::convert_row_to_f(t, row_pixels, &lut)
+                DynamicRows::convert_row_to_f(t, row_pixels, &lut, alpha_lut.as_ref(), self.px.levels.as_ref())
             },
         }
     }
@@ -43,9 +63,10 @@ impl<'a, 'pixels, 'rows> DynamicRowsIter<'a, 'pixels, 'rows> {
             Some(pixels) => &pixels[self.px.width as usize * row as usize..],
             None => {
                 let lut = gamma_lut(self.px.gamma);
+                let alpha_lut = self.px.alpha_encoding.lut(self.px.gamma);
                 let row_pixels = self.px.row_rgba(temp_row, row);
 
-                DynamicRows::convert_row_to_f(temp_row_f, row_pixels, &lut)
+                DynamicRows::convert_row_to_f(temp_row_f, row_pixels, &lut, alpha_lut.as_ref(), self.px.levels.as_ref())
             },
         }
     }
@@ -59,14 +80,58 @@ impl<'pixels,'rows> DynamicRows<'pixels,'rows> {
     #[inline]
     pub(crate) fn new(width: u32, height: u32, pixels: PixelsSource<'pixels, 'rows>, gamma: f64) -> Self {
         debug_assert!(gamma > 0.);
-        Self { width, height, f_pixels: None, pixels, gamma }
+        Self { width, height, f_pixels: None, pixels, gamma, alpha_encoding: AlphaEncoding::Linear, levels: None, chroma_key: None }
     }
 
     fn row_rgba<'px>(&'px self, temp_row: &'px mut [MaybeUninit<RGBA>], row: usize) -> &[RGBA] {
+        let (key, tolerance) = match self.chroma_key {
+            Some(key) => key,
+            None => return self.source_row_rgba(temp_row, row),
+        };
+        let width = self.width();
+        // keyed pixels are replaced in a copy, since the source may be borrowed
+        if let PixelsSource::Pixels { rows, .. } = &self.pixels {
+            let source = unsafe { std::slice::from_raw_parts(rows.as_slice()[row], width) };
+            for (dst, &px) in temp_row.iter_mut().zip(source) {
+                dst.write(px);
+            }
+        } else {
+            self.source_row_rgba(temp_row, row);
+        }
+        // initialized by either of the above
+        let temp_row = unsafe { slice_assume_init_mut(&mut temp_row[..width]) };
+        let near = |a: u8, b: u8| (i16::from(a) - i16::from(b)).abs() <= i16::from(tolerance);
+        for px in temp_row.iter_mut() {
+            if near(px.r, key.r) && near(px.g, key.g) && near(px.b, key.b) {
+                *px = RGBA::new(0, 0, 0, 0);
+            }
+        }
+        temp_row
+    }
+
+    fn source_row_rgba<'px>(&'px self, temp_row: &'px mut [MaybeUninit<RGBA>], row: usize) -> &'px [RGBA] {
         match &self.pixels {
             PixelsSource::Pixels { rows, .. } => unsafe {
                 std::slice::from_raw_parts(rows.as_slice()[row], self.width())
             },
+            PixelsSource::Planar { r, g, b, a } => {
+                let width = self.width();
+                let range = row * width..row * width + width;
+                let temp_row = &mut temp_row[..width];
+                let rgb = r[range.clone()].iter().zip(&g[range.clone()]).zip(&b[range.clone()]);
+                match a {
+                    Some(a) => for ((px, ((&r, &g), &b)), &a) in temp_row.iter_mut().zip(rgb).zip(&a[range]) {
+                        px.write(RGBA::new(r, g, b, a));
+                    },
+                    None => for (px, ((&r, &g), &b)) in temp_row.iter_mut().zip(rgb) {
+                        px.write(RGBA::new(r, g, b, 255));
+                    },
+                }
+                // just initialized
+                unsafe { slice_assume_init_mut(temp_row) }
+            },
+            #[cfg(feature = "yuv")]
+            PixelsSource::Yuv { planes, conversion } => yuv_row(planes, conversion, self.width(), row, temp_row),
             PixelsSource::Callback(cb) => {
                 cb(temp_row, row);
                 // FIXME: cb needs to be marked as unsafe, since it's responsible for initialization :(
@@ -75,11 +140,16 @@ impl<'pixels,'rows> DynamicRows<'pixels,'rows> {
         }
     }
 
-    fn convert_row_to_f<'f>(row_f_pixels: &'f mut [MaybeUninit<f_pixel>], row_pixels: &[RGBA], gamma_lut: &[f32; 256]) -> &'f mut [f_pixel] {
+    fn convert_row_to_f<'f>(row_f_pixels: &'f mut [MaybeUninit<f_pixel>], row_pixels: &[RGBA], gamma_lut: &[f32; 256], alpha_lut: Option<&[f32; 256]>, levels: Option<&Levels>) -> &'f mut [f_pixel] {
         let len = row_pixels.len();
         let row_f_pixels = &mut row_f_pixels[..len];
-        for (dst, src) in row_f_pixels.iter_mut().zip(row_pixels) {
-            dst.write(f_pixel::from_rgba(gamma_lut, *src));
+        match levels {
+            Some(levels) => for (dst, src) in row_f_pixels.iter_mut().zip(row_pixels) {
+                dst.write(f_pixel::from_rgba_alpha_lut(gamma_lut, alpha_lut, levels.stretch(*src)));
+            },
+            None => for (dst, src) in row_f_pixels.iter_mut().zip(row_pixels) {
+                dst.write(f_pixel::from_rgba_alpha_lut(gamma_lut, alpha_lut, *src));
+            },
         }
         // Safe, just initialized
         unsafe { slice_assume_init_mut(row_f_pixels) }
@@ -109,10 +179,11 @@ impl<'pixels,'rows> DynamicRows<'pixels,'rows> {
 
         let width = self.width();
         let lut = gamma_lut(self.gamma);
+        let alpha_lut = self.alpha_encoding.lut(self.gamma);
         let mut f_pixels = temp_buf(self.width() * self.height());
         for (row, f_row) in f_pixels.chunks_exact_mut(width).enumerate() {
             let row_pixels = self.row_rgba(temp_row, row);
-            Self::convert_row_to_f(f_row, row_pixels, &lut);
+            Self::convert_row_to_f(f_row, row_pixels, &lut, alpha_lut.as_ref(), self.levels.as_ref());
         }
         // just initialized
         self.f_pixels = Some(unsafe { box_assume_init(f_pixels) });
@@ -138,7 +209,41 @@ impl<'pixels,'rows> DynamicRows<'pixels,'rows> {
         Ok(DynamicRowsIter { px: self, temp_f_row: None })
     }
 
+    /// Checks that all rows can be read, and asks the callback (if any) for the first row
+    pub(crate) fn validate(&self) -> Result<(), ImageError> {
+        if self.f_pixels.is_some() {
+            return Ok(());
+        }
+        match &self.pixels {
+            PixelsSource::Pixels { rows, .. } => {
+                let rows = rows.as_slice();
+                if rows.is_empty() {
+                    return Err(ImageError::PixelsNotAvailable);
+                }
+                if rows.len() < self.height() {
+                    return Err(ImageError::MissingRows { rows: rows.len(), height: self.height() });
+                }
+                if let Some(row) = rows[..self.height()].iter().position(|r| r.is_null()) {
+                    return Err(ImageError::NullRow(row));
+                }
+            },
+            PixelsSource::Callback(cb) => {
+                let mut temp_row = temp_buf(self.width());
+                let probe = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| cb(&mut temp_row, 0)));
+                if probe.is_err() {
+                    return Err(ImageError::RowCallbackFailed);
+                }
+            },
+            // sizes of planes are checked when the image is created
+            PixelsSource::Planar { .. } => {},
+            #[cfg(feature = "yuv")]
+            PixelsSource::Yuv { .. } => {},
+        }
+        Ok(())
+    }
+
     #[inline]
+    #[cfg(feature = "dither-maps")]
     pub fn all_rows_f(&mut self) -> Result<&[f_pixel], liq_error> {
         if self.f_pixels.is_some() {
             return Ok(self.f_pixels.as_ref().unwrap()); // borrow-checker :(
@@ -148,6 +253,7 @@ impl<'pixels,'rows> DynamicRows<'pixels,'rows> {
     }
 
     /// Not recommended
+    #[cfg(feature = "capi")]
     pub(crate) unsafe fn set_memory_ownership(&mut self, ownership_flags: liq_ownership) -> Result<(), liq_error> {
         let both = liq_ownership::LIQ_OWN_ROWS | liq_ownership::LIQ_OWN_PIXELS;
 
@@ -158,7 +264,9 @@ impl<'pixels,'rows> DynamicRows<'pixels,'rows> {
         if ownership_flags.contains(liq_ownership::LIQ_OWN_ROWS) {
             match &mut self.pixels {
                 PixelsSource::Pixels { rows, .. } => rows.make_owned(),
-                PixelsSource::Callback(_) => return Err(LIQ_VALUE_OUT_OF_RANGE),
+                PixelsSource::Planar { .. } | PixelsSource::Callback(_) => return Err(LIQ_VALUE_OUT_OF_RANGE),
+                #[cfg(feature = "yuv")]
+                PixelsSource::Yuv { .. } => return Err(LIQ_VALUE_OUT_OF_RANGE),
             }
         }
 
@@ -171,9 +279,61 @@ impl<'pixels,'rows> DynamicRows<'pixels,'rows> {
                     let ptr = rows.as_slice().iter().copied().min().ok_or(LIQ_UNSUPPORTED)?;
                     *pixels = Some(SeaCow::c_owned(ptr as *mut _, len));
                 },
-                PixelsSource::Callback(_) => return Err(LIQ_VALUE_OUT_OF_RANGE),
+                PixelsSource::Planar { .. } | PixelsSource::Callback(_) => return Err(LIQ_VALUE_OUT_OF_RANGE),
+                #[cfg(feature = "yuv")]
+                PixelsSource::Yuv { .. } => return Err(LIQ_VALUE_OUT_OF_RANGE),
+            }
+        }
+        Ok(())
+    }
+
+    /// Changes stretch of pixels converted to `f_pixel`.
+    ///
+    /// The RGBA source may have been freed after making the histogram, so already converted pixels are converted again in place.
+    pub(crate) fn set_levels(&mut self, levels: Option<Levels>) {
+        if self.levels == levels {
+            return;
+        }
+        if let Some(f_pixels) = self.f_pixels.as_mut() {
+            let lut = gamma_lut(self.gamma);
+            let alpha_lut = self.alpha_encoding.lut(self.gamma);
+            for px in f_pixels.iter_mut().filter(|px| px.a >= MIN_OPAQUE_A) {
+                let mut rgba = px.to_rgb(self.gamma);
+                self.alpha_encoding.encode(px, &mut rgba, self.gamma);
+                let rgba = self.levels.map_or(rgba, |l| l.unstretch(rgba));
+                *px = f_pixel::from_rgba_alpha_lut(&lut, alpha_lut.as_ref(), levels.map_or(rgba, |l| l.stretch(rgba)));
             }
         }
+        self.levels = levels;
+    }
+
+    #[inline]
+    pub(crate) fn chroma_key(&self) -> Option<(RGBA, u8)> {
+        self.chroma_key
+    }
+
+    /// Pointers to `height` rows from row `y`, starting at column `x`, for a view of a part of the image.
+    ///
+    /// `None` if the pixels aren't RGBA rows in memory, or have been freed.
+    pub(crate) fn view_rows(&self, x: usize, y: usize, height: usize) -> Option<Box<[*const RGBA]>> {
+        match &self.pixels {
+            PixelsSource::Pixels { rows, .. } => {
+                let rows = rows.as_slice().get(y..y.checked_add(height)?)?;
+                // the caller checks that x is within the width
+                Some(rows.iter().map(|&row| unsafe { row.add(x) }).collect())
+            },
+            _ => None,
+        }
+    }
+
+    /// Pixels of this color (within `tolerance` in each of RGB channels) are read as fully transparent.
+    ///
+    /// Pixels that have already been converted can't be changed.
+    pub(crate) fn set_chroma_key(&mut self, key: Option<(RGBA, u8)>) -> Result<(), liq_error> {
+        if self.f_pixels.is_some() {
+            return Err(LIQ_UNSUPPORTED);
+        }
+        self.chroma_key = key;
         Ok(())
     }
 
@@ -216,6 +376,6 @@ unsafe fn box_assume_init<T>(s: Box<[MaybeUninit<T>]>) -> Box<[T]> {
 }
 
 #[inline(always)]
-unsafe fn slice_assume_init_mut<T>(s: &mut [MaybeUninit<T>]) -> &mut [T] {
+pub(crate) unsafe fn slice_assume_init_mut<T>(s: &mut [MaybeUninit<T>]) -> &mut [T] {
     std::mem::transmute(s)
 }
@@ -0,0 +1,112 @@
+//! Finding scene cuts in sequences of frames, so that each scene gets its own palette (see `SceneDetector`)
+
+use crate::error::*;
+use crate::image::Image;
+use crate::rows::temp_buf;
+use std::ops::Range;
+
+/// Colors are counted with 3 bits per channel, and all transparent pixels share one more bin
+const BINS: usize = 8 * 8 * 8 + 1;
+
+/// Compares colors of consecutive frames of an animation or a video, and tells where a new scene starts,
+/// so that a palette is made only at cuts, and reused for the other frames of the scene.
+///
+/// Frames are compared by their coarse color histograms, so movement within a scene doesn't count as a cut,
+/// but a change of the colors used does. Indices of scene starts are kept, e.g. for splitting a GIF into segments with their own palettes.
+#[derive(Debug, Clone)]
+pub struct SceneDetector {
+    threshold: f32,
+    previous: Option<Vec<f32>>,
+    last_distance: Option<f32>,
+    frames: usize,
+    cuts: Vec<usize>,
+}
+
+impl SceneDetector {
+    /// `threshold` (0-1) is the fraction of pixels that must change their color between two frames to start a new scene.
+    ///
+    /// Around 0.3 finds cuts of typical videos. Lower values also start new scenes on fades and large moving objects.
+    pub fn new(threshold: f32) -> Result<Self, liq_error> {
+        if !(threshold > 0. && threshold <= 1.) {
+            return Err(LIQ_VALUE_OUT_OF_RANGE);
+        }
+        Ok(Self {
+            threshold,
+            previous: None,
+            last_distance: None,
+            frames: 0,
+            cuts: Vec::new(),
+        })
+    }
+
+    /// Compares the next frame with the previous one. Returns `true` if the frame starts a new scene (the first frame always does),
+    /// and a new palette should be made for it.
+    ///
+    /// Fails if the image has been quantized already, so call it before the frame is quantized.
+    pub fn add_frame(&mut self, image: &Image<'_, '_>) -> Result<bool, liq_error> {
+        let histogram = coarse_histogram(image)?;
+        let distance = self.previous.as_ref().map(|previous| {
+            previous.iter().zip(&histogram).map(|(a, b)| (a - b).abs()).sum::<f32>() / 2.
+        });
+        let is_cut = match distance {
+            Some(distance) => distance >= self.threshold,
+            None => true,
+        };
+        if is_cut {
+            self.cuts.push(self.frames);
+        }
+        self.previous = Some(histogram);
+        self.last_distance = distance;
+        self.frames += 1;
+        Ok(is_cut)
+    }
+
+    /// Indices of frames that start a new scene, in order. The first one is 0, unless no frames have been added.
+    #[must_use]
+    pub fn cuts(&self) -> &[usize] {
+        &self.cuts
+    }
+
+    /// Ranges of frame indices of every scene found so far. The last one ends at the last frame added.
+    pub fn scenes(&self) -> impl Iterator<Item = Range<usize>> + '_ {
+        self.cuts.iter().enumerate().map(move |(i, &start)| start..self.cuts.get(i + 1).copied().unwrap_or(self.frames))
+    }
+
+    /// Number of frames added
+    #[must_use]
+    pub fn frame_count(&self) -> usize {
+        self.frames
+    }
+
+    /// Difference (0-1) between the last two frames added, as a fraction of pixels with different colors
+    #[must_use]
+    pub fn last_distance(&self) -> Option<f32> {
+        self.last_distance
+    }
+
+    /// Forgets all frames, to start a new sequence
+    pub fn reset(&mut self) {
+        self.previous = None;
+        self.last_distance = None;
+        self.frames = 0;
+        self.cuts.clear();
+    }
+}
+
+/// Fractions of pixels in each bin
+fn coarse_histogram(image: &Image<'_, '_>) -> Result<Vec<f32>, liq_error> {
+    let (width, height) = (image.width(), image.height());
+    let rows = image.px.rgba_rows_iter()?;
+    let mut temp_row = temp_buf(width);
+    let mut counts = vec![0u32; BINS];
+    for row in 0..height {
+        for px in rows.row_rgba(&mut temp_row, row) {
+            let bin = if px.a == 0 { BINS - 1 } else {
+                usize::from(px.r >> 5) << 6 | usize::from(px.g >> 5) << 3 | usize::from(px.b >> 5)
+            };
+            counts[bin] += 1;
+        }
+    }
+    let total = (width * height) as f32;
+    Ok(counts.into_iter().map(|c| c as f32 / total).collect())
+}
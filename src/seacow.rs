@@ -1,4 +1,6 @@
+#[cfg(feature = "dither-maps")]
 use std::mem::MaybeUninit;
+use std::ops::Range;
 use std::os::raw::c_int;
 
 pub struct SeaCow<'a, T> {
@@ -28,6 +30,7 @@ impl<'a, T> SeaCow<'a, T> {
 
     /// The pointer must be `malloc`-allocated
     #[inline]
+    #[cfg(feature = "capi")]
     pub unsafe fn c_owned(ptr: *mut T, len: usize) -> Self {
         debug_assert!(!ptr.is_null());
         debug_assert!(len > 0);
@@ -38,6 +41,7 @@ impl<'a, T> SeaCow<'a, T> {
     }
 
     #[inline]
+    #[cfg(feature = "capi")]
     pub(crate) fn make_owned(&mut self) {
         if let SeaCowInner::Borrowed(slice) = self.inner {
             self.inner = SeaCowInner::Owned { ptr: slice.as_ptr() as *mut _, len: slice.len() };
@@ -46,11 +50,14 @@ impl<'a, T> SeaCow<'a, T> {
 }
 
 enum SeaCowInner<'a, T> {
+    /// `malloc`-allocated memory from the C API
+    #[cfg(feature = "capi")]
     Owned { ptr: *mut T, len: usize },
     Borrowed(&'a [T]),
     Boxed(Box<[T]>),
 }
 
+#[cfg(feature = "capi")]
 impl<'a, T> Drop for SeaCowInner<'a, T> {
     fn drop(&mut self) {
         if let Self::Owned { ptr, .. } = self {
@@ -64,14 +71,26 @@ impl<'a, T> Drop for SeaCowInner<'a, T> {
 impl<'a, T> SeaCow<'a, T> {
     pub fn as_slice(&self) -> &[T] {
         match &self.inner {
+            #[cfg(feature = "capi")]
             SeaCowInner::Owned { ptr, len } => unsafe { std::slice::from_raw_parts(*ptr, *len) },
             SeaCowInner::Borrowed(a) => a,
             SeaCowInner::Boxed(x) => x,
         }
     }
 
+    /// Takes the data if it's been allocated by Rust, leaving it empty
+    #[cfg(feature = "dither-maps")]
+    pub(crate) fn take_boxed(&mut self) -> Option<Box<[T]>> {
+        match &mut self.inner {
+            SeaCowInner::Boxed(x) => Some(std::mem::take(x)),
+            _ => None,
+        }
+    }
+
+    #[cfg(feature = "dither-maps")]
     pub fn as_mut_slice(&mut self) -> &mut [T] {
         match &mut self.inner {
+            #[cfg(feature = "capi")]
             SeaCowInner::Owned { ptr, len } => (unsafe { std::slice::from_raw_parts_mut(*ptr, *len) }),
             SeaCowInner::Boxed(x) => (x),
             SeaCowInner::Borrowed(_) => panic!("can't"),
@@ -79,10 +98,12 @@ impl<'a, T> SeaCow<'a, T> {
     }
 }
 
+#[cfg(feature = "dither-maps")]
 pub(crate) struct RowBitmap<'a, T> {
     rows: &'a [*const T],
     width: usize,
 }
+#[cfg(feature = "dither-maps")]
 unsafe impl<T: Send + Sync> Send for RowBitmap<'_, T> {}
 
 pub(crate) struct RowBitmapMut<'a, T> {
@@ -91,6 +112,7 @@ pub(crate) struct RowBitmapMut<'a, T> {
 }
 unsafe impl<T: Send + Sync> Send for RowBitmapMut<'_, T> {}
 
+#[cfg(feature = "dither-maps")]
 impl<'a, T> RowBitmapMut<'a, MaybeUninit<T>> {
     #[inline]
     pub(crate) unsafe fn assume_init<'maybeowned>(&'maybeowned mut self) -> RowBitmap<'maybeowned, T> {
@@ -101,6 +123,7 @@ impl<'a, T> RowBitmapMut<'a, MaybeUninit<T>> {
     }
 }
 
+#[cfg(feature = "dither-maps")]
 impl<'a, T> RowBitmap<'a, T> {
     pub fn rows(&self) -> impl Iterator<Item = &[T]> {
         let width = self.width;
@@ -112,6 +135,8 @@ impl<'a, T> RowBitmap<'a, T> {
 
 enum MutCow<'a, T: ?Sized> {
     Owned(Box<T>),
+    /// Row pointers from the C API
+    #[cfg_attr(not(feature = "capi"), allow(dead_code))]
     Borrowed(&'a mut T),
 }
 
@@ -133,8 +158,25 @@ impl<'a, T: Sync + Send + Copy + 'static> RowBitmapMut<'a, T> {
         }
     }
 
+    /// Rows are stored in order of the 4 passes of GIF interlacing: every 8th row from 0, every 8th row from 4,
+    /// every 4th row from 2, and then every other row from 1.
+    pub fn new_gif_interlaced(data: &mut [T], width: usize) -> Self {
+        let stored_rows = data.chunks_exact_mut(width).map(|r| r.as_mut_ptr());
+        let height = stored_rows.len();
+        let mut rows = vec![std::ptr::null_mut(); height].into_boxed_slice();
+        // every row is in exactly one of the passes
+        for (y, ptr) in gif_interlaced_rows(height).zip(stored_rows) {
+            rows[y] = ptr;
+        }
+        Self {
+            rows: MutCow::Owned(rows),
+            width,
+        }
+    }
+
     /// Innter pointers must be valid for `'a` too, and at least `width` large each
     #[inline]
+    #[cfg(feature = "capi")]
     pub unsafe fn new(rows: &'a mut [*mut T], width: usize) -> Self {
         Self {
             rows: MutCow::Borrowed(rows),
@@ -143,17 +185,28 @@ impl<'a, T: Sync + Send + Copy + 'static> RowBitmapMut<'a, T> {
     }
 
     pub fn rows_mut(&mut self) -> impl Iterator<Item = &mut [T]> + Send {
+        let height = self.rows.borrow().len();
+        self.rows_range_mut(0..height)
+    }
+
+    /// Like `rows_mut`, but only rows in `range`
+    pub fn rows_range_mut(&mut self, range: Range<usize>) -> impl Iterator<Item = &mut [T]> + Send {
         let width = self.width;
         // Rust is pessimistic about `*mut` pointers
         struct ItIsSync<T>(*mut T);
         unsafe impl<T: Send + Sync> Sync for ItIsSync<T> {}
-        let send_slice = unsafe { std::mem::transmute::<&mut [*mut T], &mut [ItIsSync<T>]>(self.rows.borrow()) };
+        let send_slice = unsafe { std::mem::transmute::<&mut [*mut T], &mut [ItIsSync<T>]>(&mut self.rows.borrow()[range]) };
         send_slice.iter().map(move |row| {
             unsafe { std::slice::from_raw_parts_mut(row.0, width) }
         })
     }
 }
 
+/// Image rows in the order they're stored with GIF interlacing
+pub(crate) fn gif_interlaced_rows(height: usize) -> impl Iterator<Item = usize> {
+    [(0, 8), (4, 8), (2, 4), (1, 2)].iter().flat_map(move |&(first, step)| (first..height).step_by(step))
+}
+
 bitflags::bitflags! {
     #[repr(C)]
     pub struct liq_ownership: c_int {
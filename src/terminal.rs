@@ -0,0 +1,150 @@
+//! Remapping to colors of text terminals, for showing images with colored character cells
+
+use crate::error::*;
+use crate::image::Image;
+use crate::pal::RGBA;
+use crate::remap::{remap_to_device_palette, DitherKernel, DitherLevel, RemapOptions};
+use fallible_collections::FallibleVec;
+use std::mem::MaybeUninit;
+
+/// Color codes supported by terminals
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum TerminalPalette {
+    /// 256-color mode of xterm and most modern terminals (`ESC[38;5;<code>m`).
+    ///
+    /// Only codes 16-255 (the 6×6×6 color cube and grays) are used, because terminals let users change the colors of codes 0-15.
+    Xterm256,
+    /// The basic 16 colors (codes 0-15, which are `ESC[30m`-`ESC[37m` and `ESC[90m`-`ESC[97m`), with xterm's default colors
+    Ansi16,
+}
+
+const fn rgb(r: u8, g: u8, b: u8) -> RGBA {
+    RGBA { r, g, b, a: 255 }
+}
+
+const ANSI_16: [RGBA; 16] = [
+    rgb(0, 0, 0), rgb(205, 0, 0), rgb(0, 205, 0), rgb(205, 205, 0),
+    rgb(0, 0, 238), rgb(205, 0, 205), rgb(0, 205, 205), rgb(229, 229, 229),
+    rgb(127, 127, 127), rgb(255, 0, 0), rgb(0, 255, 0), rgb(255, 255, 0),
+    rgb(92, 92, 255), rgb(255, 0, 255), rgb(0, 255, 255), rgb(255, 255, 255),
+];
+
+const fn xterm_256() -> [RGBA; 256] {
+    const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+    let mut colors = [rgb(0, 0, 0); 256];
+    let mut i = 0;
+    while i < 16 {
+        colors[i] = ANSI_16[i];
+        i += 1;
+    }
+    while i < 232 {
+        let c = i - 16;
+        colors[i] = rgb(CUBE_LEVELS[c / 36], CUBE_LEVELS[c / 6 % 6], CUBE_LEVELS[c % 6]);
+        i += 1;
+    }
+    while i < 256 {
+        let gray = 8 + 10 * (i - 232) as u8;
+        colors[i] = rgb(gray, gray, gray);
+        i += 1;
+    }
+    colors
+}
+
+static XTERM_256: [RGBA; 256] = xterm_256();
+
+impl TerminalPalette {
+    /// Colors of all color codes, indexed by the code
+    #[must_use]
+    pub fn palette(self) -> &'static [RGBA] {
+        match self {
+            Self::Xterm256 => &XTERM_256,
+            Self::Ansi16 => &ANSI_16,
+        }
+    }
+
+    /// Images are remapped to codes from this one up
+    fn first_used_code(self) -> u8 {
+        match self {
+            Self::Xterm256 => 16,
+            Self::Ansi16 => 0,
+        }
+    }
+}
+
+/// Remaps images to terminal color codes.
+///
+/// Every pixel becomes a whole character cell, so dithering patterns are very coarse.
+/// By default dithering is weaker than for regular images, and uses a less noisy kernel with tightly clamped error.
+#[derive(Clone)]
+pub struct TerminalRemapper {
+    palette: TerminalPalette,
+    background: RGBA,
+    dither_level: f32,
+    kernel: DitherKernel,
+}
+
+impl TerminalRemapper {
+    /// Half-strength Sierra Lite dithering, on black background
+    #[must_use]
+    pub fn new(palette: TerminalPalette) -> Self {
+        Self {
+            palette,
+            background: rgb(0, 0, 0),
+            dither_level: 0.5,
+            kernel: DitherKernel::SierraLite,
+        }
+    }
+
+    /// Colors of all color codes, see [`TerminalPalette::palette`]
+    #[inline]
+    #[must_use]
+    pub fn palette(&self) -> &'static [RGBA] {
+        self.palette.palette()
+    }
+
+    /// Color of the terminal's background, which semi-transparent pixels are blended with. Default is black. Its alpha is ignored.
+    #[inline]
+    pub fn set_background(&mut self, color: RGBA) {
+        self.background = color;
+    }
+
+    /// 0-1. Default is 0.5.
+    pub fn set_dithering_level(&mut self, value: f32) -> liq_error {
+        if !(0. ..=1.).contains(&value) {
+            return LIQ_VALUE_OUT_OF_RANGE;
+        }
+        self.dither_level = value;
+        LIQ_OK
+    }
+
+    /// Error diffusion pattern. Default is Sierra Lite.
+    #[inline]
+    pub fn set_dither_kernel(&mut self, kernel: DitherKernel) {
+        self.kernel = kernel;
+    }
+
+    /// Remap image into a new `Vec` of color codes
+    pub fn remapped(&self, image: &mut Image<'_, '_>) -> Result<Vec<u8>, liq_error> {
+        let len = image.width() * image.height();
+        // Capacity is essential here, as it creates uninitialized buffer
+        unsafe {
+            let mut buf: Vec<u8> = FallibleVec::try_with_capacity(len).map_err(|_| LIQ_OUT_OF_MEMORY)?;
+            let uninit_slice = std::slice::from_raw_parts_mut(buf.as_mut_ptr().cast::<MaybeUninit<u8>>(), buf.capacity());
+            self.remap_into(image, uninit_slice)?;
+            buf.set_len(len);
+            Ok(buf)
+        }
+    }
+
+    /// Remap image into `output_buf` of color codes. The buffer must be `width * height` large.
+    pub fn remap_into(&self, image: &mut Image<'_, '_>, output_buf: &mut [MaybeUninit<u8>]) -> Result<(), liq_error> {
+        /// Large cells make error spread far, and bright speckles from accumulated error stand out
+        const MAX_DITHER_ERROR: f32 = 0.15;
+
+        let first = self.palette.first_used_code();
+        let mut options = RemapOptions::default();
+        options.set_dither_level(DitherLevel::Fixed(self.dither_level)).ok()?;
+        options.set_kernel(self.kernel);
+        remap_to_device_palette(image, output_buf, &self.palette()[first.into()..], first, self.background, &options, MAX_DITHER_ERROR)
+    }
+}
@@ -0,0 +1,153 @@
+//! Frames from video decoders and screen capture, in YUV 4:2:0 formats
+//!
+//! Conversion to RGB happens when rows of the image are read, so a converted copy of the frame is never made.
+//! Planes must be tightly packed (without row padding). Chroma planes have half of the width and height,
+//! rounded up for odd dimensions.
+
+use crate::attr::Attributes;
+use crate::error::*;
+use crate::image::{image_size, Image};
+use crate::pal::RGBA;
+use crate::rows::{slice_assume_init_mut, PixelsSource};
+use std::mem::MaybeUninit;
+
+/// Memory layout of a YUV 4:2:0 frame
+#[derive(Debug, Copy, Clone)]
+pub enum YuvPlanes<'pixels> {
+    /// Luma plane, followed by a plane of interleaved U and V samples
+    Nv12 { y: &'pixels [u8], uv: &'pixels [u8] },
+    /// Luma plane, and separate U and V planes (also known as YUV420p)
+    I420 { y: &'pixels [u8], u: &'pixels [u8], v: &'pixels [u8] },
+}
+
+/// Color matrix the frame has been encoded with
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum YuvMatrix {
+    /// Standard definition video and JPEG
+    Bt601,
+    /// HD video
+    Bt709,
+}
+
+/// Range of sample values
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum YuvRange {
+    /// Luma in 16-235, chroma in 16-240 range. Most video uses this.
+    Limited,
+    /// All of 0-255 range, e.g. JPEG and some screen capture APIs
+    Full,
+}
+
+/// Precomputed coefficients of YUV to RGB conversion
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct YuvToRgb {
+    y_offset: f32,
+    y_scale: f32,
+    c_scale: f32,
+    cr_r: f32,
+    cb_g: f32,
+    cr_g: f32,
+    cb_b: f32,
+}
+
+impl YuvToRgb {
+    fn new(matrix: YuvMatrix, range: YuvRange) -> Self {
+        let (kr, kb) = match matrix {
+            YuvMatrix::Bt601 => (0.299, 0.114),
+            YuvMatrix::Bt709 => (0.2126, 0.0722),
+        };
+        let kg = 1. - kr - kb;
+        let (y_offset, y_scale, c_scale) = match range {
+            YuvRange::Limited => (16., 255. / 219., 255. / 224.),
+            YuvRange::Full => (0., 1., 1.),
+        };
+        Self {
+            y_offset,
+            y_scale,
+            c_scale,
+            cr_r: 2. * (1. - kr),
+            cb_g: 2. * (1. - kb) * kb / kg,
+            cr_g: 2. * (1. - kr) * kr / kg,
+            cb_b: 2. * (1. - kb),
+        }
+    }
+
+    #[inline(always)]
+    fn rgba(&self, y: u8, cb: u8, cr: u8) -> RGBA {
+        let y = (f32::from(y) - self.y_offset) * self.y_scale;
+        let cb = (f32::from(cb) - 128.) * self.c_scale;
+        let cr = (f32::from(cr) - 128.) * self.c_scale;
+        let to_u8 = |c: f32| c.round().clamp(0., 255.) as u8;
+        RGBA::new(
+            to_u8(y + self.cr_r * cr),
+            to_u8(y - self.cb_g * cb - self.cr_g * cr),
+            to_u8(y + self.cb_b * cb),
+            255,
+        )
+    }
+}
+
+/// Size of chroma planes in 4:2:0 subsampling
+#[inline]
+fn half_rounded_up(luma_size: usize) -> usize {
+    luma_size / 2 + luma_size % 2
+}
+
+/// Converts one row of the frame. Planes have been checked to be large enough.
+pub(crate) fn yuv_row<'px>(planes: &YuvPlanes<'_>, conversion: &YuvToRgb, width: usize, row: usize, temp_row: &'px mut [MaybeUninit<RGBA>]) -> &'px mut [RGBA] {
+    let chroma_width = half_rounded_up(width);
+    let chroma_start = row / 2 * chroma_width;
+    let temp_row = &mut temp_row[..width];
+    let luma = &planes.y()[row * width..row * width + width];
+    match planes {
+        YuvPlanes::Nv12 { uv, .. } => {
+            let uv = &uv[chroma_start * 2..(chroma_start + chroma_width) * 2];
+            for ((px, &y), uv) in temp_row.iter_mut().zip(luma).zip(uv.chunks_exact(2).flat_map(|uv| [uv, uv])) {
+                px.write(conversion.rgba(y, uv[0], uv[1]));
+            }
+        },
+        YuvPlanes::I420 { u, v, .. } => {
+            let u = &u[chroma_start..chroma_start + chroma_width];
+            let v = &v[chroma_start..chroma_start + chroma_width];
+            let chroma = u.iter().zip(v).flat_map(|uv| [uv, uv]);
+            for ((px, &y), (&u, &v)) in temp_row.iter_mut().zip(luma).zip(chroma) {
+                px.write(conversion.rgba(y, u, v));
+            }
+        },
+    }
+    // just initialized
+    unsafe { slice_assume_init_mut(temp_row) }
+}
+
+impl YuvPlanes<'_> {
+    #[inline]
+    fn y(&self) -> &[u8] {
+        match *self {
+            Self::Nv12 { y, .. } | Self::I420 { y, .. } => y,
+        }
+    }
+
+    fn is_large_enough(&self, width: usize, height: usize) -> bool {
+        let chroma_len = half_rounded_up(width).saturating_mul(half_rounded_up(height));
+        let chroma_ok = match *self {
+            Self::Nv12 { uv, .. } => uv.len() / 2 >= chroma_len,
+            Self::I420 { u, v, .. } => u.len() >= chroma_len && v.len() >= chroma_len,
+        };
+        chroma_ok && matches!(width.checked_mul(height), Some(len) if self.y().len() >= len)
+    }
+}
+
+impl<'pixels> Image<'pixels, 'static> {
+    /// Describes a YUV 4:2:0 frame. It's converted to RGB as it's read, without making a converted copy of the whole frame.
+    ///
+    /// The image is opaque, and its gamma is sRGB, which is a close enough match for video.
+    pub fn new_yuv(attr: &Attributes, planes: YuvPlanes<'pixels>, matrix: YuvMatrix, range: YuvRange, width: usize, height: usize) -> Result<Self, liq_error> {
+        let (width_u32, height_u32) = image_size(attr, width, height)?;
+        if !planes.is_large_enough(width, height) {
+            attr.verbose_print(format!("YUV planes are too small for {}×{} pixels", width, height));
+            return Err(LIQ_BUFFER_TOO_SMALL);
+        }
+        let conversion = YuvToRgb::new(matrix, range);
+        Image::new_internal(attr, PixelsSource::Yuv { planes, conversion }, width_u32, height_u32, 0.)
+    }
+}
@@ -0,0 +1,99 @@
+//! Quantizes images in `tests/golden/` and compares palettes and pixels with previously saved results.
+//!
+//! Any change to the output is a failure, so changes to mediancut, K-means or dithering that are meant to change
+//! the output must be reviewed (compare images before and after), and then golden files updated with:
+//!
+//! ```sh
+//! UPDATE_GOLDEN=1 cargo test --test golden
+//! ```
+//!
+//! Floating-point math is done in a different order when running on multiple threads,
+//! so the tests run on a single thread to be deterministic.
+//!
+//! Golden files have 8-bit indices, so they're not checked with the `large-palettes` feature,
+//! and they're made with adaptive dithering, so they need the `dither-maps` feature.
+#![cfg(all(feature = "dither-maps", not(feature = "large-palettes")))]
+
+use imagequant::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+struct Case {
+    image: &'static str,
+    speed: i32,
+    dithering_level: f32,
+}
+
+const CASES: &[Case] = &[
+    Case { image: "gradient", speed: 4, dithering_level: 0. },
+    Case { image: "gradient", speed: 4, dithering_level: 1. },
+    Case { image: "photo", speed: 1, dithering_level: 0. },
+    Case { image: "photo", speed: 4, dithering_level: 1. },
+    Case { image: "screenshot", speed: 4, dithering_level: 0. },
+    Case { image: "screenshot", speed: 10, dithering_level: 1. },
+];
+
+fn golden_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden")
+}
+
+/// Reads RGBA PAM file (`P7`, `TUPLTYPE RGB_ALPHA`)
+fn read_pam(path: &Path) -> (Vec<RGBA>, usize, usize) {
+    let data = fs::read(path).unwrap();
+    let header_end = data.windows(7).position(|w| w == b"ENDHDR\n").expect("PAM header") + 7;
+    let header = std::str::from_utf8(&data[..header_end]).unwrap();
+    let field = |name: &str| -> usize {
+        header.lines().find_map(|l| l.strip_prefix(name)).and_then(|v| v.trim().parse().ok()).unwrap()
+    };
+    let (width, height) = (field("WIDTH"), field("HEIGHT"));
+    assert_eq!(4, field("DEPTH"));
+    let pixels: Vec<_> = data[header_end..].chunks_exact(4).map(|c| RGBA::new(c[0], c[1], c[2], c[3])).collect();
+    assert_eq!(width * height, pixels.len());
+    (pixels, width, height)
+}
+
+/// Palette size (u16 LE), palette as RGBA bytes, and then one palette index per pixel
+fn serialize(palette: &[RGBA], pixels: &[u8]) -> Vec<u8> {
+    let mut out = (palette.len() as u16).to_le_bytes().to_vec();
+    out.extend(palette.iter().flat_map(|c| [c.r, c.g, c.b, c.a]));
+    out.extend_from_slice(pixels);
+    out
+}
+
+fn quantize(case: &Case) -> (Vec<RGBA>, Vec<u8>) {
+    let (pixels, width, height) = read_pam(&golden_dir().join(format!("{}.pam", case.image)));
+    let mut liq = Attributes::new();
+    liq.set_speed(case.speed).unwrap();
+    let mut img = liq.new_image(&pixels[..], width, height, 0.).unwrap();
+    let mut res = liq.quantize(&mut img).unwrap();
+    res.set_dithering_level(case.dithering_level).unwrap();
+    res.remapped(&mut img).unwrap()
+}
+
+#[test]
+fn golden() {
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+    let update = std::env::var_os("UPDATE_GOLDEN").is_some();
+
+    let mut failures = Vec::new();
+    for case in CASES {
+        let (palette, pixels) = pool.install(|| quantize(case));
+        let actual = serialize(&palette, &pixels);
+
+        let golden_path = golden_dir().join(format!("{}-s{}-d{}.golden", case.image, case.speed, case.dithering_level));
+        if update {
+            fs::write(&golden_path, &actual).unwrap();
+            continue;
+        }
+
+        let expected = fs::read(&golden_path).unwrap_or_else(|e| panic!("{}: {}", golden_path.display(), e));
+        if expected != actual {
+            let expected_colors = u16::from_le_bytes([expected[0], expected[1]]);
+            let changed_pixels = expected.get(2 + 4 * usize::from(expected_colors)..).unwrap_or_default().iter()
+                .zip(&pixels).filter(|(a, b)| a != b).count();
+            failures.push(format!("{}: palette {} -> {} colors, {} pixels changed",
+                golden_path.display(), expected_colors, palette.len(), changed_pixels));
+        }
+    }
+    assert!(failures.is_empty(), "output differs from golden files:\n{}", failures.join("\n"));
+}